@@ -1,8 +1,8 @@
 import type { EditorState } from "@codemirror/state";
 import type { EditorView } from "@codemirror/view";
 import * as d3 from "d3";
-import { getEvents } from "~/utils/cm";
-import type { NoteEvent } from "~/utils/cm";
+import { getBars, getEvents } from "~/utils/cm";
+import type { BarBoundary, NoteEvent } from "~/utils/cm";
 import { getNoteColorFromFreq } from "~/utils/colors";
 import { useSmoothWheelScroll } from "~/composables/useSmoothWheelScroll";
 import { playNotesInSelection, subscribePlaybackState } from "~/utils/cm";
@@ -62,6 +62,7 @@ export function usePianoRoll({ editorState, editorView, containerRef, svgRef }:
     const { height, width } = useElementSize(containerRef);
     const NEG_MARGIN_PIXEL = 200;
     const latestEvents = shallowRef<NoteEvent[]>([]);
+    const latestBars = shallowRef<BarBoundary[]>([]);
 
     const contentSize = reactive({
         width: 1,
@@ -262,18 +263,12 @@ export function usePianoRoll({ editorState, editorView, containerRef, svgRef }:
         });
     }
 
-    function toRenderMeasures(events: NoteEvent[]): RenderMeasure[] {
-        return [{
-            type: "NewMeasure",
-            start_bar: 0,
-            start_sec: 0,
-        }, ...events]
-            .filter((event) => event.type === "NewMeasure")
-            .map((event, index) => ({
-                id: `${event.start_bar}-${event.start_sec}-${index}`,
-                x: (event.start_sec - axisBounds.minSecond) * layoutParams.pixelPerSecond,
-                bar: event.start_bar + 1,
-            }));
+    function toRenderMeasures(bars: BarBoundary[]): RenderMeasure[] {
+        return bars.map((boundary) => ({
+            id: `${boundary.bar}-${boundary.seconds}`,
+            x: (boundary.seconds - axisBounds.minSecond) * layoutParams.pixelPerSecond,
+            bar: boundary.bar + 1,
+        }));
     }
 
     function toRenderBaseLines(events: NoteEvent[]): RenderBaseLine[] {
@@ -368,7 +363,7 @@ export function usePianoRoll({ editorState, editorView, containerRef, svgRef }:
             const bottom = note.y + note.height;
             return !(right < virtualLeft || note.x > virtualRight || bottom < virtualTop || note.y > virtualBottom);
         });
-        const renderMeasures = toRenderMeasures(events).filter((measure) => {
+        const renderMeasures = toRenderMeasures(latestBars.value).filter((measure) => {
             return !(measure.x < virtualLeft || measure.x > virtualRight);
         });
         const renderBaseLines = toRenderBaseLines(events).filter((line) => {
@@ -844,6 +839,7 @@ export function usePianoRoll({ editorState, editorView, containerRef, svgRef }:
         (newState) => {
             if (!newState) {
                 latestEvents.value = [];
+                latestBars.value = [];
                 cursorVisual.visible = false;
                 stopPlaybackCursorFallback();
                 lastSyncedEditorCursorPos = null;
@@ -853,6 +849,7 @@ export function usePianoRoll({ editorState, editorView, containerRef, svgRef }:
                 return;
             }
             latestEvents.value = getEvents(newState);
+            latestBars.value = getBars(newState);
             syncCursorFromEditorState(newState, latestEvents.value);
             redraw();
 
@@ -78,20 +78,25 @@ export function activateNoteHighlight(view: EditorView, note: NoteEvent) {
         view.dispatch({ effects: removeActiveNoteEffect.of(id) });
     }, holdMs + FADE_OUT_MS);
 
-    if (note.span_invoked_from && note.span_invoked_to) {
+    // 高亮整条宏调用链；没有调用链时退回到 range_invoked
+    const invokedSpans: [number, number][] = note.macro_spans?.length
+        ? note.macro_spans
+        : note.span_invoked_from && note.span_invoked_to
+          ? [[note.span_invoked_from, note.span_invoked_to]]
+          : [];
+    for (const [from, to] of invokedSpans) {
         const invokedId = `${Date.now()}-${Math.random().toString(36).slice(2)}`;
         view.dispatch({
             effects: addActiveNoteEffect.of({
                 id: invokedId,
-                from: note.span_invoked_from,
-                to: note.span_invoked_to,
+                from,
+                to,
                 holdMs,
             }),
         });
         window.setTimeout(() => {
             view.dispatch({ effects: removeActiveNoteEffect.of(invokedId) });
         }, holdMs + FADE_OUT_MS);
-
     }
 
 }
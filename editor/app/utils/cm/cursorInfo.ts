@@ -19,20 +19,25 @@ export const cursorInfo = reactive<CursorInfo>({
     seconds: null,
 });
 
-function updateCursorInfo(view: EditorView) {
+let cursorReqId = 0;
+
+async function updateCursorInfo(view: EditorView, fileId: string) {
+    const myReqId = ++cursorReqId;
     const pos = view.state.selection.main.head;
     const line = view.state.doc.lineAt(pos);
     cursorInfo.line = line.number;
     cursorInfo.column = pos - line.from + 1;
 
-    let events = findEventsAtPos(view.state, pos);
+    let events = await findEventsAtPos(fileId, pos);
     if (events.length == 0) {
         let nextLine = view.state.doc.line(
             view.state.doc.lines > line.number ? line.number + 1 : line.number
         );
         let endOfNextLinePos = nextLine.to;
-        events = findEventsInRange(view.state, pos + 1, endOfNextLinePos);
+        events = await findEventsInRange(fileId, pos + 1, endOfNextLinePos);
     }
+    // 光标已再次移动，结果以更新的查询为准
+    if (myReqId !== cursorReqId) return;
     if (events.length > 0) {
         const event = events[0]!;
         cursorInfo.bar = event.start_bar + 1;
@@ -46,15 +51,15 @@ function updateCursorInfo(view: EditorView) {
     }
 }
 
-export function createCursorInfoPlugin(): Extension {
+export function createCursorInfoPlugin(getFileId: () => string): Extension {
     return ViewPlugin.fromClass(class {
         constructor(view: EditorView) {
-            updateCursorInfo(view);
+            void updateCursorInfo(view, getFileId());
         }
 
         update(update: ViewUpdate) {
             if (update.selectionSet || update.docChanged || update.viewportChanged) {
-                updateCursorInfo(update.view);
+                void updateCursorInfo(update.view, getFileId());
             }
         }
     });
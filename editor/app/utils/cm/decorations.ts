@@ -1,6 +1,6 @@
 import { type Range, StateEffect, StateField } from "@codemirror/state";
 import { Decoration, EditorView, type DecorationSet } from "@codemirror/view";
-import type { Diagnostic } from "./types";
+import type { Diagnostic, SemanticToken } from "./types";
 import { buildDiagnosticDecorations } from "./diagnostics";
 import { buildTokenDecorations } from "./tokenTheme";
 
@@ -25,7 +25,7 @@ export const decorationsField = StateField.define<DecorationSet>({
 /**
  * 调用后端获取 tokens，并构建 DecorationSet。
  */
-export function buildDecorations(tokens: [string, number, number][], diagnostics: Diagnostic[]): DecorationSet {
+export function buildDecorations(tokens: SemanticToken[], diagnostics: Diagnostic[]): DecorationSet {
     const decorations: Range<Decoration>[] = [];
     const tokenDecorations = buildTokenDecorations(tokens);
     for (const deco of tokenDecorations) {
@@ -69,7 +69,7 @@ export function createDiagnosticsHoverTooltip(): Extension {
                     row.style.alignItems = "baseline";
 
                     const badge = document.createElement("span");
-                    badge.textContent = diag.severity;
+                    badge.textContent = diag.code ? `${diag.severity} ${diag.code}` : diag.severity;
                     badge.style.fontWeight = "600";
                     badge.style.color = diag.severity === "Warning" ? "#F59E0B" : "#EF4444";
 
@@ -2,7 +2,7 @@ import { EditorState, type Extension, StateEffect, StateField } from "@codemirro
 import { EditorView, hoverTooltip } from "@codemirror/view";
 import { invoke } from "@tauri-apps/api/core";
 import { activateNoteHighlight } from "./activeNote";
-import type { NoteEvent } from "./types";
+import type { BarBoundary, CompileEvent, NoteEvent } from "./types";
 
 export const setEventsEffect = StateEffect.define<NoteEvent[]>();
 
@@ -18,31 +18,93 @@ export const eventsField = StateField.define<NoteEvent[]>({
     },
 });
 
+export const setBarsEffect = StateEffect.define<BarBoundary[]>();
+
+export const barsField = StateField.define<BarBoundary[]>({
+    create() {
+        return [];
+    },
+    update(value, tr) {
+        for (const effect of tr.effects) {
+            if (effect.is(setBarsEffect)) return effect.value;
+        }
+        return value;
+    },
+});
+
+/**
+ * 将 `get_events` 返回的 `CompileEvent` 展平为编辑器使用的 `NoteEvent`。
+ */
+export function toNoteEvent(event: CompileEvent): NoteEvent {
+    const common = {
+        start_sec: event.start_time.seconds,
+        start_bar: event.start_time.bars,
+        start_tick: event.start_time.ticks,
+        span_from: event.range[0],
+        span_to: event.range[1],
+        span_invoked_from: event.range_invoked?.[0],
+        span_invoked_to: event.range_invoked?.[1],
+        macro_spans: event.macro_trace.map((call) => call.range),
+    };
+    if ("Note" in event.body) {
+        const note = event.body.Note;
+        return {
+            ...common,
+            type: "Note",
+            freq: note.freq,
+            duration_sec: note.duration_seconds,
+            duration_tick: note.duration,
+            pitch_ratio: note.pitch_ratio,
+            drum_key: note.drum_key,
+            portamento_from: note.portamento_from,
+            // 与 `Note::gain` 一致：力度相对默认力度 100 缩放音量
+            volume: note.volume * (note.velocity ?? 100) / 100,
+        };
+    }
+    if ("NewMeasure" in event.body) {
+        return {
+            ...common,
+            type: "NewMeasure",
+            freq: 0,
+            start_bar: event.body.NewMeasure,
+            duration_sec: 0,
+            duration_tick: [0, 1],
+        };
+    }
+    return {
+        ...common,
+        type: "BaseFrequencyDef",
+        freq: event.body.BaseFequencyDef,
+        duration_sec: 0,
+        duration_tick: [0, 1],
+    };
+}
 
 export function getEvents(state: EditorState): NoteEvent[] {
     return state.field(eventsField);
 }
 
-export function findEventsAtPos(state: EditorState, pos: number): NoteEvent[] {
-    const events = getEvents(state);
-    return events.filter((e) => {
-        const eventFrom = e.span_invoked_from ?? e.span_from;
-        const eventTo = e.span_invoked_to ?? e.span_to;
-        return pos >= eventFrom && pos <= eventTo;
-    });
+export function getBars(state: EditorState): BarBoundary[] {
+    return state.field(barsField);
 }
 
-export function findEventsInRange(state: EditorState, from: number, to: number): NoteEvent[] {
-    const events = getEvents(state);
-    return events.filter((e) => {
-        const eventFrom = e.span_invoked_from ?? e.span_from;
-        const eventTo = e.span_invoked_to ?? e.span_to;
-        return !(eventTo < from || eventFrom > to);
-    });
+/**
+ * 查询区间（含两端）覆盖 `pos` 的事件；由后端按源码位置建立的索引查找，而非逐个比较。
+ */
+export function findEventsAtPos(fileId: string, pos: number): Promise<NoteEvent[]> {
+    return findEventsInRange(fileId, pos, pos);
 }
 
-export function playNotesAt(view: EditorView, pos: number) {
-    const hits = findEventsAtPos(view.state, pos);
+/**
+ * 查询区间与 `from..=to` 相交的事件，顺序与 `get_events` 相同。
+ */
+export async function findEventsInRange(fileId: string, from: number, to: number): Promise<NoteEvent[]> {
+    const events = await invoke("get_events_at", { fileId, from, to }) as CompileEvent[];
+    return events.map(toNoteEvent);
+}
+
+export async function playNotesAt(view: EditorView, fileId: string, pos: number) {
+    const hits = await findEventsAtPos(fileId, pos);
     for (const note of hits) {
         playNotes(view, note);
     }
@@ -66,13 +128,22 @@ export async function playNotes(view: EditorView, note: NoteEvent | NoteEvent[])
 
 export function playNote(view: EditorView, note: NoteEvent) {
     activateNoteHighlight(view, note);
-    invoke("play_note", { frequency: note.freq, durationSec: note.duration_sec });
+    const volume = note.volume ?? 1;
+    if (note.drum_key != null) {
+        invoke("play_drum", { key: note.drum_key, durationSec: note.duration_sec, volume });
+        return;
+    }
+    if (note.portamento_from != null) {
+        invoke("play_glide", { from: note.portamento_from, to: note.freq, durationSec: note.duration_sec, volume });
+        return;
+    }
+    invoke("play_note", { frequency: note.freq, durationSec: note.duration_sec, volume });
 }
 
 /**
  * Ctrl + 点击事件：播放该位置的音符事件。
  */
-export function createCtrlClickEventLogger(): Extension {
+export function createCtrlClickEventLogger(getFileId: () => string): Extension {
     return EditorView.domEventHandlers({
         mousedown(event, view) {
             if (!(event as MouseEvent).ctrlKey) return false;
@@ -81,7 +152,7 @@ export function createCtrlClickEventLogger(): Extension {
                 y: (event as MouseEvent).clientY,
             });
             if (pos == null) return false;
-            playNotesAt(view, pos);
+            void playNotesAt(view, getFileId(), pos);
             return true;
         },
     });
@@ -0,0 +1,39 @@
+import { type Extension } from "@codemirror/state";
+import { EditorView } from "@codemirror/view";
+import { invoke } from "@tauri-apps/api/core";
+
+/**
+ * 格式化整个文档：由后端 `symi::format` 规范空白、对齐同时发声的行并约分时值。
+ * 无法解析的文档原样返回，不做修改。
+ */
+export async function formatDocument(view: EditorView): Promise<boolean> {
+	const source = view.state.doc.toString();
+	const formatted = await invoke("format_document", { source }) as string;
+	// 等待期间文档已被编辑，放弃本次结果
+	if (formatted === source || view.state.doc.toString() !== source) return false;
+	view.dispatch({
+		changes: { from: 0, to: view.state.doc.length, insert: formatted },
+		userEvent: "format",
+	});
+	return true;
+}
+
+/**
+ * Shift + Alt + F：格式化文档。
+ */
+export function createFormatDocumentHandler(): Extension {
+	return EditorView.domEventHandlers({
+		keydown(event, view) {
+			const keyboardEvent = event as KeyboardEvent;
+			const isFormat = keyboardEvent.shiftKey
+				&& keyboardEvent.altKey
+				&& !keyboardEvent.ctrlKey
+				&& !keyboardEvent.metaKey
+				&& keyboardEvent.code === "KeyF";
+			if (!isFormat) return false;
+			event.preventDefault();
+			void formatDocument(view);
+			return true;
+		},
+	});
+}
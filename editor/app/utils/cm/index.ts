@@ -8,4 +8,6 @@ export * from './tokenTheme'
 export * from './play'
 export * from './animatedCursor'
 export * from './cursorInfo'
-export * from './basicExtensions'
\ No newline at end of file
+export * from './metadata'
+export * from './basicExtensions'
+export * from './format'
\ No newline at end of file
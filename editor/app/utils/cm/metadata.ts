@@ -0,0 +1,19 @@
+import { reactive } from "vue";
+
+export type PieceMetadata = {
+    title: string | null;
+    composer: string | null;
+    copyright: string | null;
+};
+
+export const pieceMetadata = reactive<PieceMetadata>({
+    title: null,
+    composer: null,
+    copyright: null,
+});
+
+export function setPieceMetadata(metadata: PieceMetadata) {
+    pieceMetadata.title = metadata.title;
+    pieceMetadata.composer = metadata.composer;
+    pieceMetadata.copyright = metadata.copyright;
+}
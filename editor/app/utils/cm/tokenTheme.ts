@@ -1,5 +1,6 @@
 import { type Extension, type Range } from "@codemirror/state";
 import { Decoration, EditorView } from "@codemirror/view";
+import type { SemanticToken } from "./types";
 
 /**
  * Token 上色主题（十六进制颜色码）
@@ -12,6 +13,7 @@ export const TOKEN_STYLES = {
     "Whitespace": "#00000000", // transparent
     "Newline": "#00000000", // transparent
     "Comment": "#64748B", // slate-500
+    "LineContinuation": "#64748B",
 
     // Punctuation / operators
     "Comma": "#94A3B8", // slate-400
@@ -24,6 +26,17 @@ export const TOKEN_STYLES = {
     "ParenthesisPair": "#A78BFA", // violet-400
     "LParen": "#A78BFA",
     "RParen": "#A78BFA",
+    "GraceOpen": "#A78BFA",
+    "PickupOpen": "#A78BFA",
+    "TransposeOpen": "#A78BFA",
+    "CapoOpen": "#A78BFA",
+    "TuningOpen": "#A78BFA",
+    "TuningSpec": "#ffd876",
+    "AtOpen": "#A78BFA",
+    "ChoiceOpen": "#A78BFA",
+    "ArpeggioOpen": "#A78BFA",
+    "Pipe": "#A78BFA",
+    "RBrace": "#A78BFA",
     "LAngle": "#FBBF24", // amber-400
     "RAngle": "#FBBF24",
 
@@ -36,28 +49,65 @@ export const TOKEN_STYLES = {
     "PitchFrequency": "#60A5FA", // blue-400
     "PitchRatio": "#22D3EE", // cyan-400
     "PitchEdo": "#2DD4BF", // teal-400
+    "PitchSubharmonic": "#2DD4BF",
     "PitchCents": "#F472B6", // pink-400
     "PitchRest": "#94A3B8", // slate-400
     "PitchSustain": "#94A3B8", // slate-400
+    "PitchTie": "#94A3B8",
+    "MultiBarRest": "#94A3B8", // slate-400
+    "BarRepeat": "#94A3B8", // slate-400
+    "VoicePrefix": "#E2E8F0",
+    "PercussionPrefix": "#E2E8F0",
+    "OctaveMode": "#A78BFA",
 
     // Durations / quantize
     "DurationCommas": "#cfbf96", // amber-400
     "DurationFraction": "#ffd876",
     "Quantize": "#FB7185", // rose-400
+    "QuantizeOpen": "#FB7185",
+    "RepeatCount": "#FB7185",
+    "TimeSeconds": "#FB7185",
+    "Ornament": "#C084FC", // purple-400
+    "Fermata": "#C084FC",
+    "Portamento": "#C084FC",
+    "BendEnvelope": "#C084FC",
+    "Volume": "#C084FC",
+    "JiChord": "#22D3EE",
+
+    // Annotations
+    "Lyric": "#FDE68A", // amber-200
+    "MetaField": "#94A3B8", // slate-400
+    "IfDirective": "#C084FC", // purple-400
+    "EndifDirective": "#C084FC",
+};
+
+/**
+ * 语义角色的颜色，覆盖同一 Token 按词法种类得到的颜色。
+ *
+ * 只列出与词法种类含义不同的角色，例如 `(120)` 中的数字是速度而非频率。
+ */
+export const ROLE_STYLES = {
+    "BasePitch": "#FBBF24", // amber-400
+    "Tempo": "#FB7185", // rose-400
+    "TimeSignature": "#FB7185",
+    "MacroName": "#F0ABFC", // fuchsia-300
 };
 
 /**
  * 构建 token 高亮的 Decorations。
  */
-export function buildTokenDecorations(tokens: [string, number, number][]): Range<Decoration>[] {
+export function buildTokenDecorations(tokens: SemanticToken[]): Range<Decoration>[] {
     const ranges: Range<Decoration>[] = [];
 
-    for (const [type, from, to] of tokens) {
-        const color = TOKEN_STYLES[type as keyof typeof TOKEN_STYLES];
-        if (color) {
+    for (const { kind, role, modifier, from, to } of tokens) {
+        const classes: string[] = [];
+        if (TOKEN_STYLES[kind as keyof typeof TOKEN_STYLES]) classes.push(`cm-symi-highlight-${kind}`);
+        if (ROLE_STYLES[role as keyof typeof ROLE_STYLES]) classes.push(`cm-symi-role-${role}`);
+        if (modifier) classes.push(`cm-symi-modifier-${modifier}`);
+        if (classes.length > 0) {
             // 用 mark decoration 给 [from, to) 这段文本添加 class，由 theme 统一上色。
             const decoration = Decoration.mark({
-                class: `cm-symi-highlight-${type}`,
+                class: classes.join(" "),
             }).range(from, to);
             ranges.push(decoration);
         }
@@ -72,6 +122,11 @@ export function createTokenTheme(): Extension {
     for (const [type, color] of Object.entries(TOKEN_STYLES)) {
         styles[`.cm-symi-highlight-${type}`] = { color };
     }
+    // 写在词法种类之后，同优先级时覆盖其颜色
+    for (const [role, color] of Object.entries(ROLE_STYLES)) {
+        styles[`.cm-symi-role-${role}`] = { color };
+    }
+    styles[".cm-symi-modifier-Definition"] = { fontWeight: "bold" };
 
     styles = {
         ".cm-cursor": { boxShadow: "0 0 2px 2px lime", border: "none" },
@@ -1,10 +1,58 @@
 export type Diagnostic = {
     message: string;
     severity: "Warning" | "Error" | string;
+    code?: string | null;
     from: number;
     to: number;
 };
 
+export type Rational = [number, number];
+
+/** `get_tokens` 返回的 Token：词法种类加语义分类，区间以字符计。 */
+export type SemanticToken = {
+    kind: string;
+    role: string;
+    /** 名称的定义处（"Definition"）或引用处（"Reference"） */
+    modifier: string | null;
+    from: number;
+    to: number;
+};
+
+/** `get_events` 返回的序列化 `CompileEvent`，区间以字符计。 */
+export type CompileEvent = {
+    body:
+        | { Note: CompiledNote }
+        | { NewMeasure: number }
+        | { BaseFequencyDef: number };
+    start_time: { seconds: number; bars: number; ticks: Rational; position: Rational };
+    range: [number, number];
+    range_invoked: [number, number] | null;
+    /** 产生该事件的宏调用链，最外层在前 */
+    macro_trace: { name: string; range: [number, number] }[];
+    voice: string | null;
+    /** 指定的 MIDI 通道（0-15） */
+    channel: number | null;
+    /** 指定的 General MIDI 音色 */
+    instrument: number | null;
+};
+
+export type CompiledNote = {
+    freq: number;
+    duration: Rational;
+    duration_seconds: number;
+    pitch_ratio: number;
+    drum_key: number | null;
+    portamento_from: number | null;
+    volume: number;
+    /** 满音量时的力度，缺省为 100 */
+    velocity: number | null;
+    /** NoteOff 的松开力度，缺省用导出设置 */
+    release: number | null;
+    tie: boolean;
+    /** 是否带颤音记号 `~vib` */
+    vibrato: boolean;
+};
+
 export type NoteEvent = {
     type: "Note" | "NewMeasure" | "BaseFrequencyDef"
     freq: number;
@@ -17,7 +65,19 @@ export type NoteEvent = {
     span_to: number;
     span_invoked_from?: number;
     span_invoked_to?: number;
+    /** 宏调用链中各次调用的区间，最外层在前 */
+    macro_spans?: [number, number][];
     pitch_ratio?: number;
+    drum_key?: number | null;
+    portamento_from?: number | null;
+    volume?: number;
+};
+
+/** `Timeline` 中一小节的起点 */
+export type BarBoundary = {
+    bar: number;
+    position: Rational;
+    seconds: number;
 };
 
 export type ActiveNoteHighlight = {
@@ -7,15 +7,20 @@ import { activeNotesField, createActiveNoteTheme } from "./activeNote";
 import { createDiagnosticsHoverTooltip, diagnosticsField, setDiagnosticsEffect } from "./diagnostics";
 import { buildDecorations, decorationsField, setDecorationsEffect } from "./decorations";
 import {
+    barsField,
     createCtrlClickEventLogger,
     eventsField,
+    setBarsEffect,
     setEventsEffect,
+    toNoteEvent,
 } from "./events";
 import { createCtrlSlashCommentHandler } from "./comment";
+import { createFormatDocumentHandler } from "./format";
 import { createShiftSpacePlayHandler } from "./play";
 import { createTokenTheme } from "./tokenTheme";
-import type { Diagnostic, NoteEvent } from "./types";
+import type { BarBoundary, CompileEvent, Diagnostic, SemanticToken } from "./types";
 import { createCursorInfoPlugin } from "./cursorInfo";
+import { setPieceMetadata, type PieceMetadata } from "./metadata";
 
 export const FILE_ID_TMP = "000";
 
@@ -56,21 +61,25 @@ export const createViewPlugin = (options?: ViewPluginOptions): Extension => {
 
             try {
                 await invoke("file_update", { fileId, source });
-                const [tokens, diagnostics, events] = await Promise.all([
-                    invoke("get_tokens", { fileId }) as Promise<[string, number, number][]>,
+                const [tokens, diagnostics, events, bars, metadata] = await Promise.all([
+                    invoke("get_tokens", { fileId }) as Promise<SemanticToken[]>,
                     invoke("get_diagnostics", { fileId }) as Promise<Diagnostic[]>,
-                    invoke("get_events", { fileId }) as Promise<NoteEvent[]>,
+                    invoke("get_events", { fileId }) as Promise<CompileEvent[]>,
+                    invoke("get_bar_boundaries", { fileId }) as Promise<BarBoundary[]>,
+                    invoke("get_metadata", { fileId }) as Promise<PieceMetadata>,
                 ]);
                 const decos = buildDecorations(tokens, diagnostics);
                 if (myReqId !== this.#reqId) {
                     return;
                 }
+                setPieceMetadata(metadata);
 
                 view.dispatch({
                     effects: [
                         setDecorationsEffect.of(decos),
                         setDiagnosticsEffect.of(diagnostics),
-                        setEventsEffect.of(events),
+                        setEventsEffect.of(events.map(toNoteEvent)),
+                        setBarsEffect.of(bars),
                     ],
                 });
 
@@ -87,17 +96,19 @@ export const createViewPlugin = (options?: ViewPluginOptions): Extension => {
     const tokenTheme = createTokenTheme();
     const activeNoteTheme = createActiveNoteTheme();
     const diagnosticsTooltip = createDiagnosticsHoverTooltip();
-    const ctrlClickLogger = createCtrlClickEventLogger();
+    const ctrlClickLogger = createCtrlClickEventLogger(getFileId);
     const ctrlSlashComment = createCtrlSlashCommentHandler();
+    const formatDocument = createFormatDocumentHandler();
     const shiftSpacePlay = createShiftSpacePlayHandler();
     const animatedCursorTheme = createAnimatedCursorTheme();
     const animatedCursor = createAnimatedCursor();
-    const cursorInfoPlugin = createCursorInfoPlugin();
+    const cursorInfoPlugin = createCursorInfoPlugin(getFileId);
 
     return [
         decorationsField,
         diagnosticsField,
         eventsField,
+        barsField,
         activeNotesField,
         tokenTheme,
         activeNoteTheme,
@@ -105,6 +116,7 @@ export const createViewPlugin = (options?: ViewPluginOptions): Extension => {
         diagnosticsTooltip,
         shiftSpacePlay,
         ctrlSlashComment,
+        formatDocument,
         ctrlClickLogger,
         animatedCursor,
         cursorInfoPlugin,
@@ -1,24 +1,127 @@
-use symi::compiler::types::EventBody;
-use symi::Compiler;
+use symi::compiler::analysis;
+use symi::compiler::timeline::BarBoundary;
+use symi::compiler::types::CompileEvent;
+use symi::rowan::{semantic::semantic_tokens, TextRange};
 use tauri::Emitter;
 
-fn build_midi_bytes(
+/// MIDI export settings of the export dialog.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MidiExportOptions {
+    pub pitch_bend_range_semitones: u16,
+    // cents added to the bend range, for ranges such as 2.5 semitones
+    #[serde(default)]
+    pub pitch_bend_range_cents: u8,
+    pub ticks_per_quarter: u32,
+    // SMPTE frame rate of a timecode file, metrical timing when unset
+    #[serde(default)]
+    pub smpte_fps: Option<u8>,
+    #[serde(default)]
+    pub smpte_subframes: u8,
+    pub time_tolerance_seconds: f64,
+    pub pitch_tolerance_cents: f64,
+    // release velocity of NoteOffs, 0 as most files
+    #[serde(default)]
+    pub note_off_velocity: u8,
+    // tune keys with MTS SysEx instead of pitch bends
+    #[serde(default)]
+    pub mts_tuning: bool,
+    // tempos per segment of a tempo ramp, 0 keeps tempo changes as written
+    #[serde(default)]
+    pub tempo_ramp_steps: u32,
+    // chars `from..=to` of the selection to export, the whole piece when unset
+    #[serde(default)]
+    pub selection: Option<(u32, u32)>,
+    // one file per voice, named after the target with the voice appended
+    #[serde(default)]
+    pub split_by_voice: bool,
+    #[serde(default)]
+    pub track_assignment: TrackAssignmentOption,
+    // voices played as drum hits on channel 10, keys as written
+    #[serde(default)]
+    pub percussion_voices: Vec<String>,
+    // voices whose channels get a pitch bend range of their own
+    #[serde(default)]
+    pub voice_bend_ranges: Vec<VoiceBendRangeOption>,
+    // a MIDI 2.0 clip file with exact per-note pitches instead of an SMF
+    #[serde(default)]
+    pub clip_file: bool,
+}
+
+/// Pitch bend range of a voice in the export dialog, as [`symi::midi::writer::VoiceBendRange`].
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VoiceBendRangeOption {
+    pub voice: String,
+    pub semitones: u16,
+    #[serde(default)]
+    pub cents: u8,
+}
+
+/// Track layout choices of the export dialog, as [`symi::midi::writer::TrackAssignment`].
+#[derive(Debug, Clone, Copy, Default, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum TrackAssignmentOption {
+    #[default]
+    LowestIndex,
+    PitchProximity,
+    ExplicitVoice,
+    SingleTrack,
+}
+
+impl From<TrackAssignmentOption> for symi::midi::writer::TrackAssignment {
+    fn from(option: TrackAssignmentOption) -> Self {
+        match option {
+            TrackAssignmentOption::LowestIndex => Self::LowestIndex,
+            TrackAssignmentOption::PitchProximity => Self::PitchProximity,
+            TrackAssignmentOption::ExplicitVoice => Self::ExplicitVoice,
+            TrackAssignmentOption::SingleTrack => Self::SingleTrack,
+        }
+    }
+}
+
+/// Seconds from the start of the first note of the selected source to the end of the last.
+fn selection_seconds(
+    piece: &symi::compiler::piece::CompiledPiece,
+    from: u32,
+    to: u32,
+) -> Option<(f64, f64)> {
+    let range = TextRange::new(from.min(to).into(), to.max(from).into());
+    piece
+        .event_indices_in_span(range)
+        .filter_map(|i| {
+            let event = &piece.events[i];
+            match &event.body {
+                symi::compiler::types::EventBody::Note(note) if !note.is_rest() => {
+                    let start = event.start_time.seconds;
+                    Some((start, start + note.duration_seconds))
+                }
+                _ => None,
+            }
+        })
+        .reduce(|(a_start, a_end), (b_start, b_end)| (a_start.min(b_start), a_end.max(b_end)))
+}
+
+/// Runs `f` on `file_id` compiled from `source`, unless it fails to parse or compile.
+fn with_compiled_file<T>(
     file_id: String,
     source: String,
-    pitch_bend_range_semitones: u16,
-    ticks_per_quarter: u32,
-    time_tolerance_seconds: f64,
-    pitch_tolerance_cents: f64,
-) -> Result<Vec<u8>, String> {
+    f: impl FnOnce(&crate::manager::LanguageManager) -> Result<T, String>,
+) -> Result<T, String> {
+    let cancel = crate::manager::restart_compile(&file_id);
     crate::manager::MANAGER
         .write()
-        .update_file(file_id.clone(), source);
+        .update_file(file_id.clone(), source, &cancel);
 
     let manager = crate::manager::MANAGER.read();
     let Some(lang_manager) = manager.files.get(&file_id) else {
         return Err("file not found".to_string());
     };
 
+    if lang_manager.compiler.cancelled {
+        return Err("compile cancelled by a newer edit".to_string());
+    }
+
     if let Some(parse_err) = lang_manager.parse.errors().first() {
         return Err(format!("parse error: {}", parse_err.message));
     }
@@ -32,22 +135,109 @@ fn build_midi_bytes(
         return Err(format!("compile error: {}", diag.message));
     }
 
-    let config = symi::midi::writer::MidiWriterConfig {
-        pitch_bend_range_semitones,
-        ticks_per_quarter,
-        time_tolerance_seconds,
-        pitch_tolerance_cents,
+    f(lang_manager)
+}
+
+/// Writer settings of the export dialog for the file of `lang_manager`.
+fn midi_writer_config(
+    options: &MidiExportOptions,
+    lang_manager: &crate::manager::LanguageManager,
+) -> Result<symi::midi::writer::MidiWriterConfig, String> {
+    let (start_second, end_second) = match options.selection {
+        Some((from, to)) => {
+            let (start, end) = selection_seconds(&lang_manager.piece, from, to)
+                .ok_or_else(|| "selection contains no notes".to_string())?;
+            (start, Some(end))
+        }
+        None => (0.0, None),
     };
 
-    symi::midi::writer::export_smf_format1(&lang_manager.compiler.events, config)
-        .map_err(|e| format!("midi export failed: {e}"))
+    Ok(symi::midi::writer::MidiWriterConfig {
+        pitch_bend_range_semitones: options.pitch_bend_range_semitones,
+        pitch_bend_range_cents: options.pitch_bend_range_cents,
+        ticks_per_quarter: options.ticks_per_quarter,
+        timing: match options.smpte_fps {
+            Some(fps) => symi::midi::writer::MidiTiming::Timecode {
+                fps,
+                subframes: options.smpte_subframes,
+            },
+            None => symi::midi::writer::MidiTiming::Metrical,
+        },
+        time_tolerance_seconds: options.time_tolerance_seconds,
+        pitch_tolerance_cents: options.pitch_tolerance_cents,
+        tuning: if options.mts_tuning {
+            symi::midi::writer::MidiTuning::Mts
+        } else {
+            symi::midi::writer::MidiTuning::PitchBend
+        },
+        track_assignment: options.track_assignment.into(),
+        velocity_curve: Default::default(),
+        note_off_velocity: options.note_off_velocity,
+        tempo_ramp_steps: options.tempo_ramp_steps,
+        controller_automation: Default::default(),
+        humanize: None,
+        percussion_voices: options
+            .percussion_voices
+            .iter()
+            .map(|voice| symi::midi::writer::PercussionVoice {
+                voice: voice.clone(),
+                key_map: Vec::new(),
+            })
+            .collect(),
+        voice_bend_ranges: options
+            .voice_bend_ranges
+            .iter()
+            .map(|range| symi::midi::writer::VoiceBendRange {
+                voice: range.voice.clone(),
+                semitones: range.semitones,
+                cents: range.cents,
+            })
+            .collect(),
+        start_second,
+        end_second,
+    })
+}
+
+/// MIDI files to export as (voice, bytes); a single file has no voice name.
+fn build_midi_files(
+    lang_manager: &crate::manager::LanguageManager,
+    options: &MidiExportOptions,
+) -> Result<Vec<(String, Vec<u8>)>, String> {
+    let config = midi_writer_config(options, lang_manager)?;
+    let events = &lang_manager.compiler.events;
+    let metadata = &lang_manager.compiler.metadata;
+    if options.clip_file {
+        symi::midi::clip::export_clip_file(events, &config)
+            .map(|bytes| vec![(String::new(), bytes)])
+    } else if options.split_by_voice {
+        symi::midi::writer::export_split_by_voice_with_metadata(events, metadata, config)
+    } else {
+        symi::midi::writer::export_smf_format1_with_metadata(events, metadata, config)
+            .map(|bytes| vec![(String::new(), bytes)])
+    }
+    .map_err(|e| format!("midi export failed: {e}"))
+}
+
+/// `target_path` with `-voice` appended to the file stem, unchanged without a voice.
+fn voice_path(target_path: &str, voice: &str) -> std::path::PathBuf {
+    let path = std::path::Path::new(target_path);
+    if voice.is_empty() {
+        return path.to_path_buf();
+    }
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(ext) => format!("{stem}-{voice}.{}", ext.to_string_lossy()),
+        None => format!("{stem}-{voice}"),
+    };
+    path.with_file_name(name)
 }
 
 #[tauri::command]
 pub fn file_update(app: tauri::AppHandle, file_id: String, source: String) {
+    let cancel = crate::manager::restart_compile(&file_id);
     crate::manager::MANAGER
         .write()
-        .update_file(file_id, source.clone());
+        .update_file(file_id, source.clone(), &cancel);
     app.emit("file_updated", ()).unwrap();
 }
 
@@ -57,31 +247,34 @@ pub fn file_close(app: tauri::AppHandle, file_id: String) {
     app.emit("file_closed", ()).unwrap();
 }
 
+/// A token with its syntax kind and semantic classification, spans in chars.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Token {
+    pub kind: &'static str,
+    pub role: &'static str,
+    // Definition or Reference, for names only
+    pub modifier: Option<&'static str>,
+    pub from: u32,
+    pub to: u32,
+}
+
 #[tauri::command]
-pub async fn get_tokens(file_id: String) -> Vec<(String, u32, u32)> {
+pub async fn get_tokens(file_id: String) -> Vec<Token> {
     let manager = crate::manager::MANAGER.read();
-    if let Some(lang_manager) = manager.files.get(&file_id) {
-        let tokens: Vec<(String, u32, u32)> = lang_manager
-            .parse
-            .tokens
-            .iter()
-            .map(|t| {
-                let kind_str: &'static str = t.kind.into();
-                (
-                    kind_str.to_string(),
-                    lang_manager
-                        .byte_char_mapper
-                        .byte_to_char(t.range.start().into()),
-                    lang_manager
-                        .byte_char_mapper
-                        .byte_to_char(t.range.end().into()),
-                )
-            })
-            .collect();
-        tokens
-    } else {
-        Vec::new()
-    }
+    let Some(lang_manager) = manager.files.get(&file_id) else {
+        return Vec::new();
+    };
+    let mapper = &lang_manager.byte_char_mapper;
+    semantic_tokens(&lang_manager.parse.syntax_node())
+        .into_iter()
+        .map(|t| Token {
+            kind: t.kind.into(),
+            role: t.role.into(),
+            modifier: t.modifier.map(Into::into),
+            from: mapper.byte_to_char(t.range.start().into()),
+            to: mapper.byte_to_char(t.range.end().into()),
+        })
+        .collect()
 }
 
 #[derive(Debug, Clone, serde::Serialize)]
@@ -89,6 +282,8 @@ pub struct Diagnostic {
     pub message: String,
     // Warning, Error, Info, etc.
     pub severity: String,
+    // E0005 etc., None for parse errors
+    pub code: Option<String>,
     pub from: u32,
     pub to: u32,
 }
@@ -110,16 +305,20 @@ pub async fn get_diagnostics(file_id: String) -> Vec<Diagnostic> {
         diagnostics.push(Diagnostic {
             message: err.message.clone(),
             severity: "Error".to_string(),
+            code: None,
             from: if start == end { start - 1 } else { start },
             to: if start == end { end } else { end },
         });
     }
 
-    // 编译诊断
-    let mut compiler = Compiler::new();
-    let root = lang_manager.parse.syntax_node();
-    compiler.compile(&root);
-    for diag in &compiler.diagnostics {
+    // 编译诊断，以及宏使用情况与音符冲突分析
+    let compiler = &lang_manager.compiler;
+    let mut findings = analysis::macro_diagnostics(&lang_manager.parse.syntax_node(), compiler);
+    findings.extend(analysis::collision_diagnostics(
+        &compiler.events,
+        &analysis::note_collisions(&compiler.events, analysis::DEFAULT_UNISON_CENTS),
+    ));
+    for diag in lang_manager.compiler.diagnostics.iter().chain(&findings) {
         let start = mapper.byte_to_char(diag.span.start().into());
         let end = mapper.byte_to_char(diag.span.end().into());
         let severity = match diag.level {
@@ -129,6 +328,7 @@ pub async fn get_diagnostics(file_id: String) -> Vec<Diagnostic> {
         diagnostics.push(Diagnostic {
             message: diag.message.clone(),
             severity: severity.to_string(),
+            code: Some(diag.code.to_string()),
             from: if start == end { start - 1 } else { start },
             to: if start == end { end } else { end },
         });
@@ -137,99 +337,111 @@ pub async fn get_diagnostics(file_id: String) -> Vec<Diagnostic> {
     diagnostics
 }
 
-#[derive(Debug, Clone, serde::Serialize)]
-pub struct NoteEvent {
-    pub r#type: &'static str,
-    pub freq: f32,
-    pub start_sec: f64,
-    pub start_bar: u32,
-    pub start_tick: (i32, i32),
-    pub duration_sec: f64,
-    pub duration_tick: (i32, i32),
-    pub span_from: u32,
-    pub span_to: u32,
-    pub span_invoked_from: Option<u32>,
-    pub span_invoked_to: Option<u32>,
-    pub pitch_ratio: f32,
+/// Events the editor draws and plays, with spans in chars instead of bytes.
+#[tauri::command]
+pub fn get_events(file_id: String) -> Vec<CompileEvent> {
+    let manager = crate::manager::MANAGER.read();
+    let Some(lang_manager) = manager.files.get(&file_id) else {
+        return Vec::new();
+    };
+    lang_manager.piece.events.clone()
 }
 
+/// Bar starts of the piece, for the ruler of the piano roll.
 #[tauri::command]
-pub fn get_events(file_id: String) -> Vec<NoteEvent> {
+pub fn get_bar_boundaries(file_id: String) -> Vec<BarBoundary> {
     let manager = crate::manager::MANAGER.read();
     let Some(lang_manager) = manager.files.get(&file_id) else {
         return Vec::new();
     };
+    lang_manager.timeline.bar_boundaries().to_vec()
+}
 
-    let btc = |byte: usize| lang_manager.byte_char_mapper.byte_to_char(byte as u32);
-
-    lang_manager
-        .compiler
-        .events
-        .iter()
-        .filter_map(|event| match &event.body {
-            EventBody::Note(note) => Some(NoteEvent {
-                r#type: "Note",
-                freq: note.freq,
-                start_sec: event.start_time.seconds,
-                start_bar: event.start_time.bars,
-                start_tick: (
-                    *event.start_time.ticks.numer(),
-                    *event.start_time.ticks.denom(),
-                ),
-                duration_sec: note.duration_seconds,
-                duration_tick: (*note.duration.numer(), *note.duration.denom()),
-                span_from: btc(event.range.start().into()),
-                span_to: btc(event.range.end().into()),
-                span_invoked_from: event.range_invoked.map(|r| btc(r.start().into())),
-                span_invoked_to: event.range_invoked.map(|r| btc(r.end().into())),
-                pitch_ratio: note.pitch_ratio,
-            }),
-            EventBody::NewMeasure(bar) => Some(NoteEvent {
-                r#type: "NewMeasure",
-                freq: 0.0,
-                start_sec: event.start_time.seconds,
-                start_bar: *bar,
-                start_tick: (
-                    *event.start_time.ticks.numer(),
-                    *event.start_time.ticks.denom(),
-                ),
-                duration_sec: 0.0,
-                duration_tick: (0, 1),
-                span_from: btc(event.range.start().into()),
-                span_to: btc(event.range.end().into()),
-                span_invoked_from: event.range_invoked.map(|r| btc(r.start().into())),
-                span_invoked_to: event.range_invoked.map(|r| btc(r.end().into())),
-                pitch_ratio: 0.0,
-            }),
-            EventBody::BaseFequencyDef(f) => Some(NoteEvent {
-                r#type: "BaseFrequencyDef",
-                freq: *f,
-                start_sec: event.start_time.seconds,
-                start_bar: event.start_time.bars,
-                start_tick: (
-                    *event.start_time.ticks.numer(),
-                    *event.start_time.ticks.denom(),
-                ),
-                duration_sec: 0.0,
-                duration_tick: (0, 1),
-                span_from: btc(event.range.start().into()),
-                span_to: btc(event.range.end().into()),
-                span_invoked_from: event.range_invoked.map(|r| btc(r.start().into())),
-                span_invoked_to: event.range_invoked.map(|r| btc(r.end().into())),
-                pitch_ratio: 0.0,
-            }),
-            _ => None,
-        })
+/// Events of [`get_events`] whose span overlaps the chars `from..=to`, in `get_events` order.
+#[tauri::command]
+pub fn get_events_at(file_id: String, from: u32, to: u32) -> Vec<CompileEvent> {
+    let manager = crate::manager::MANAGER.read();
+    let Some(lang_manager) = manager.files.get(&file_id) else {
+        return Vec::new();
+    };
+    let piece = &lang_manager.piece;
+    let range = TextRange::new(from.into(), to.max(from).into());
+    let mut indices: Vec<usize> = piece.event_indices_in_span(range).collect();
+    indices.sort_unstable();
+    indices
+        .into_iter()
+        .map(|i| piece.events[i].clone())
         .collect()
 }
 
+/// Clashing notes for the piano roll to highlight, as indices into [`get_events`]. Notes
+/// starting together count as clashing under `unison_cents` apart.
+#[tauri::command]
+pub fn get_collisions(file_id: String, unison_cents: f64) -> Vec<analysis::Collision> {
+    let manager = crate::manager::MANAGER.read();
+    let Some(lang_manager) = manager.files.get(&file_id) else {
+        return Vec::new();
+    };
+    analysis::note_collisions(&lang_manager.piece.events, unison_cents)
+}
+
+/// Source formatted by `symi::format`; unchanged when it does not parse.
+#[tauri::command]
+pub fn format_document(source: String) -> String {
+    symi::format(&source)
+}
+
+#[tauri::command]
+pub fn get_metadata(file_id: String) -> symi::PieceMetadata {
+    let manager = crate::manager::MANAGER.read();
+    manager
+        .files
+        .get(&file_id)
+        .map(|lang_manager| lang_manager.compiler.metadata.clone())
+        .unwrap_or_default()
+}
+
+#[tauri::command]
+pub async fn play_note(frequency: f32, duration_sec: f32, volume: f32) {
+    crate::manager::AUDIO_MANAGER
+        .play_note(frequency, duration_sec, volume)
+        .await;
+}
+
 #[tauri::command]
-pub async fn play_note(frequency: f32, duration_sec: f32) {
+pub async fn play_glide(from: f32, to: f32, duration_sec: f32, volume: f32) {
     crate::manager::AUDIO_MANAGER
-        .play_note(frequency, duration_sec)
+        .play_glide(from, to, duration_sec, volume)
         .await;
 }
 
+#[tauri::command]
+pub async fn play_drum(key: u8, duration_sec: f32, volume: f32) {
+    crate::manager::AUDIO_MANAGER
+        .play_drum(key, duration_sec, volume)
+        .await;
+}
+
+/// Plays the whole compiled file, returning once its last note has died away or playback
+/// is stopped. A piece already playing is stopped first.
+#[tauri::command]
+pub async fn play_piece(file_id: String, source: String) -> Result<(), String> {
+    let cancel = crate::manager::restart_playback();
+    let events = with_compiled_file(file_id, source, |lang_manager| {
+        Ok(lang_manager.compiler.events.clone())
+    })?;
+    crate::manager::AUDIO_MANAGER
+        .play_events(&events, &cancel)
+        .await;
+    Ok(())
+}
+
+/// Stops the piece started by `play_piece`, silencing the notes still sounding.
+#[tauri::command]
+pub fn stop_piece() {
+    crate::manager::stop_playback();
+}
+
 #[tauri::command]
 pub fn set_volume(volume: f32) -> f32 {
     crate::manager::AUDIO_MANAGER.set_volume(volume);
@@ -241,24 +453,77 @@ pub fn get_volume() -> f32 {
     crate::manager::AUDIO_MANAGER.volume()
 }
 
+/// A problem of the export found before writing, with the span of its note in chars.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct MidiExportIssue {
+    pub kind: symi::midi::writer::MidiExportIssueKind,
+    pub message: String,
+    // Error or Warning
+    pub severity: String,
+    // None for problems of the whole export
+    pub from: Option<u32>,
+    pub to: Option<u32>,
+}
+
+/// Every problem exporting with `options` would run into; an empty list means it would
+/// succeed without losing accuracy.
 #[tauri::command]
 pub fn validate_midi_export(
     file_id: String,
     source: String,
-    pitch_bend_range_semitones: u16,
-    ticks_per_quarter: u32,
-    time_tolerance_seconds: f64,
-    pitch_tolerance_cents: f64,
-) -> Result<(), String> {
-    build_midi_bytes(
-        file_id,
-        source,
-        pitch_bend_range_semitones,
-        ticks_per_quarter,
-        time_tolerance_seconds,
-        pitch_tolerance_cents,
-    )
-    .map(|_| ())
+    options: MidiExportOptions,
+) -> Result<Vec<MidiExportIssue>, String> {
+    use symi::midi::writer::{validate_export, MidiExportIssueKind};
+
+    with_compiled_file(file_id, source, |lang_manager| {
+        let config = midi_writer_config(&options, lang_manager)?;
+        let mut report = validate_export(&lang_manager.compiler.events, &config);
+        if options.split_by_voice || options.clip_file {
+            // every voice has the channels of a file to itself, and a clip has 16 groups
+            report.issues.retain(|issue| {
+                !matches!(
+                    issue.kind,
+                    MidiExportIssueKind::TooManyTracks | MidiExportIssueKind::ExtraPort
+                )
+            });
+        }
+        if options.clip_file {
+            // clips carry exact pitches, and no loop markers
+            report.issues.retain(|issue| {
+                !matches!(
+                    issue.kind,
+                    MidiExportIssueKind::BendOutOfRange | MidiExportIssueKind::UnpairedLoop
+                )
+            });
+        }
+        if (options.split_by_voice || options.clip_file) && !report.has_errors() {
+            if let Err(message) = build_midi_files(lang_manager, &options) {
+                report.issues.push(symi::midi::writer::MidiExportIssue {
+                    kind: MidiExportIssueKind::Unexportable,
+                    message,
+                    range: None,
+                });
+            }
+        }
+
+        let mapper = &lang_manager.byte_char_mapper;
+        Ok(report
+            .issues
+            .into_iter()
+            .map(|issue| MidiExportIssue {
+                kind: issue.kind,
+                severity: if issue.kind.is_error() {
+                    "Error"
+                } else {
+                    "Warning"
+                }
+                .to_string(),
+                message: issue.message,
+                from: issue.range.map(|r| mapper.byte_to_char(r.start().into())),
+                to: issue.range.map(|r| mapper.byte_to_char(r.end().into())),
+            })
+            .collect())
+    })
 }
 
 #[tauri::command]
@@ -266,21 +531,15 @@ pub fn export_midi(
     file_id: String,
     source: String,
     target_path: String,
-    pitch_bend_range_semitones: u16,
-    ticks_per_quarter: u32,
-    time_tolerance_seconds: f64,
-    pitch_tolerance_cents: f64,
+    options: MidiExportOptions,
 ) -> Result<(), String> {
-    let bytes = build_midi_bytes(
-        file_id,
-        source,
-        pitch_bend_range_semitones,
-        ticks_per_quarter,
-        time_tolerance_seconds,
-        pitch_tolerance_cents,
-    )?;
-
-    std::fs::write(&target_path, &bytes).map_err(|e| format!("write file failed: {e}"))?;
+    let files = with_compiled_file(file_id, source, |lang_manager| {
+        build_midi_files(lang_manager, &options)
+    })?;
+    for (voice, bytes) in files {
+        std::fs::write(voice_path(&target_path, &voice), &bytes)
+            .map_err(|e| format!("write file failed: {e}"))?;
+    }
 
     Ok(())
 }
@@ -25,7 +25,16 @@ pub fn run() {
             commands::file_close,
             commands::get_diagnostics,
             commands::play_note,
+            commands::play_drum,
+            commands::play_glide,
+            commands::play_piece,
+            commands::stop_piece,
             commands::get_events,
+            commands::get_events_at,
+            commands::get_bar_boundaries,
+            commands::get_collisions,
+            commands::get_metadata,
+            commands::format_document,
             commands::set_volume,
             commands::get_volume,
             commands::validate_midi_export,
@@ -1,10 +1,20 @@
 use std::{
     collections::BTreeMap,
+    mem::replace,
     sync::{Arc, LazyLock},
 };
 
-use parking_lot::RwLock;
-use symi::{parse_source, AudioHandle, Compiler, Parse};
+use parking_lot::{Mutex, RwLock};
+use symi::{
+    compiler::playback::playback_events,
+    parse_lossy,
+    rowan::{
+        parser::{reparse, TextEdit},
+        TextRange,
+    },
+    AudioHandle, CancellationToken, CompileEvent, CompiledPiece, Compiler, EventBody, MacroCall,
+    Parse, Timeline,
+};
 
 use crate::byte_char_mapper::ByteCharMapper;
 pub type FileId = String;
@@ -14,21 +24,79 @@ pub struct LanguageManager {
     pub parse: Parse,
     pub compiler: Compiler,
     pub byte_char_mapper: ByteCharMapper,
+    /// Events the editor draws and plays, with spans in chars instead of bytes
+    pub piece: CompiledPiece,
+    /// Bars and tempos of the piece, for the ruler of the piano roll
+    pub timeline: Timeline,
+}
+
+/// Indexes the events of `compiler` the editor draws and plays, with spans converted to chars.
+fn editor_piece(compiler: &Compiler, mapper: &ByteCharMapper) -> CompiledPiece {
+    let to_chars = |range: TextRange| {
+        TextRange::new(
+            mapper.byte_to_char(range.start().into()).into(),
+            mapper.byte_to_char(range.end().into()).into(),
+        )
+    };
+
+    // capo only affects what is heard, so playback reads the transformed events
+    let events = playback_events(&compiler.events)
+        .into_iter()
+        .filter(|event| {
+            matches!(
+                event.body,
+                EventBody::Note(_) | EventBody::NewMeasure(_) | EventBody::BaseFequencyDef(_)
+            )
+        })
+        .map(|event| CompileEvent {
+            range: to_chars(event.range),
+            range_invoked: event.range_invoked.map(to_chars),
+            macro_trace: event
+                .macro_trace
+                .into_iter()
+                .map(|call| MacroCall {
+                    range: to_chars(call.range),
+                    ..call
+                })
+                .collect(),
+            ..event
+        })
+        .collect();
+    CompiledPiece::new(events)
 }
 
 impl LanguageManager {
-    pub fn new(source: Arc<str>) -> Self {
-        let parse = parse_source(source.clone());
+    pub fn new(source: Arc<str>, cancel: &CancellationToken) -> Self {
+        let parse = parse_lossy(source.clone());
         let mut compiler = Compiler::new();
         let byte_char_mapper = ByteCharMapper::new(&source);
-        compiler.compile(&parse.syntax_node());
+        compiler.compile_cancellable(&parse.syntax_node(), cancel);
+        let piece = editor_piece(&compiler, &byte_char_mapper);
+        let timeline = Timeline::new(&compiler.events);
         LanguageManager {
             source,
             parse,
             compiler,
             byte_char_mapper,
+            piece,
+            timeline,
         }
     }
+
+    /// Re-parses and recompiles only the top-level items touched by the change from the
+    /// previous source. A cancelled compile keeps the lines it reached; the next update
+    /// compiles the rest.
+    pub fn update(&mut self, source: Arc<str>, cancel: &CancellationToken) {
+        let edit = TextEdit::between(&self.source, &source);
+        let dirty = edit.delete;
+        self.parse = reparse(&self.parse, edit);
+        self.compiler
+            .recompile_cancellable(&self.parse.syntax_node(), dirty, cancel);
+        self.byte_char_mapper = ByteCharMapper::new(&source);
+        self.piece = editor_piece(&self.compiler, &self.byte_char_mapper);
+        self.timeline = Timeline::new(&self.compiler.events);
+        self.source = source;
+    }
 }
 
 pub struct PolyManager {
@@ -42,15 +110,58 @@ impl PolyManager {
         })
     }
 
-    pub fn update_file(&mut self, file_id: FileId, source: String) {
-        let lang_manager = LanguageManager::new(Arc::from(source));
-        self.files.insert(file_id, lang_manager);
+    pub fn update_file(&mut self, file_id: FileId, source: String, cancel: &CancellationToken) {
+        let source: Arc<str> = Arc::from(source);
+        match self.files.get_mut(&file_id) {
+            Some(lang_manager) => lang_manager.update(source, cancel),
+            None => {
+                self.files
+                    .insert(file_id, LanguageManager::new(source, cancel));
+            }
+        }
     }
 
     pub fn close_file(&mut self, file_id: &str) {
         self.files.remove(file_id);
+        if let Some(token) = COMPILE_TOKENS.lock().remove(file_id) {
+            token.cancel();
+        }
     }
 }
+
+/// Token of the compile in progress for each file, cancelled by a newer edit before it waits
+/// for the manager lock.
+static COMPILE_TOKENS: LazyLock<Mutex<BTreeMap<FileId, CancellationToken>>> =
+    LazyLock::new(|| Mutex::new(BTreeMap::new()));
+
+/// Cancels the compile in progress for `file_id` and returns the token for the next one.
+pub fn restart_compile(file_id: &str) -> CancellationToken {
+    let token = CancellationToken::new();
+    if let Some(previous) = COMPILE_TOKENS
+        .lock()
+        .insert(file_id.to_string(), token.clone())
+    {
+        previous.cancel();
+    }
+    token
+}
+
+/// Token of the piece playing, cancelled when another piece starts or playback is stopped.
+static PLAYBACK_TOKEN: LazyLock<Mutex<CancellationToken>> =
+    LazyLock::new(|| Mutex::new(CancellationToken::new()));
+
+/// Stops the piece playing, if any, and returns the token for the next one.
+pub fn restart_playback() -> CancellationToken {
+    let token = CancellationToken::new();
+    replace(&mut *PLAYBACK_TOKEN.lock(), token.clone()).cancel();
+    token
+}
+
+/// Stops the piece playing, if any.
+pub fn stop_playback() {
+    PLAYBACK_TOKEN.lock().cancel();
+}
+
 pub static MANAGER: LazyLock<Arc<RwLock<PolyManager>>> =
     LazyLock::new(|| Arc::new(RwLock::new(PolyManager::new().unwrap())));
 pub static AUDIO_MANAGER: LazyLock<Arc<AudioHandle>> = LazyLock::new(|| {
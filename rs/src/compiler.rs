@@ -1,4 +1,13 @@
 pub mod types;
 pub mod compile;
+pub mod chords;
+pub mod drums;
+pub mod instruments;
 pub mod helpers;
-pub mod rational;
\ No newline at end of file
+pub mod piece;
+pub mod playback;
+pub mod rational;
+pub mod random;
+pub mod scala;
+pub mod timeline;
+pub mod analysis;
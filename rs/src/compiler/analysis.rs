@@ -0,0 +1,286 @@
+//! Analyses of a compiled piece as a whole, run on request after compilation. They report
+//! what no single line is wrong about, such as macros nothing uses.
+
+use std::collections::HashSet;
+
+use rowan::TextRange;
+
+use smol_str::SmolStr;
+
+use crate::{
+    compiler::{
+        compile::Compiler,
+        piece::source_span,
+        types::{CompileEvent, Diagnostic, DiagnosticCode, DiagnosticLevel, EventBody, Note},
+    },
+    rowan::{
+        ast::{self, AstNode},
+        lexer::SyntaxKind,
+        parser::{SyntaxNode, SyntaxToken},
+    },
+};
+
+/// Warns about each macro defined in `tree` that nothing refers to, and sums up every use
+/// of an undefined name `compiler` reported into one diagnostic listing their spans.
+///
+/// Macros whose name (after any namespace) starts with `_` are meant to be kept unused and
+/// are not reported, nor are definitions from a prelude outside `tree`.
+pub fn macro_diagnostics(tree: &SyntaxNode, compiler: &Compiler) -> Vec<Diagnostic> {
+    let mut used: HashSet<SmolStr> = HashSet::new();
+    for token in tree
+        .descendants_with_tokens()
+        .filter_map(|element| element.into_token())
+    {
+        let name = match token.kind() {
+            SyntaxKind::Identifier if !is_definition_name(&token) => token.text(),
+            SyntaxKind::Quantize | SyntaxKind::QuantizeOpen => token
+                .text()
+                .trim_start_matches('{')
+                .trim_end_matches(['}', ':']),
+            _ => continue,
+        };
+        used.insert(compiler.macros.resolve(name, &scope_of(&token)));
+    }
+
+    let mut unused: Vec<_> = compiler
+        .macros
+        .definitions
+        .iter()
+        .filter(|&(name, &range)| {
+            let leaf = name
+                .rsplit_once('.')
+                .map_or(name.as_str(), |(_, leaf)| leaf);
+            !used.contains(name)
+                && !leaf.starts_with('_')
+                && tree.text_range().contains_range(range)
+                && tree
+                    .covering_element(range)
+                    .as_token()
+                    .is_some_and(|t| t.text() == name.as_str())
+        })
+        .collect();
+    unused.sort_by_key(|&(_, range)| range.start());
+    let mut diagnostics: Vec<Diagnostic> = unused
+        .into_iter()
+        .map(|(name, &range)| {
+            Diagnostic::new(
+                DiagnosticLevel::Warning,
+                DiagnosticCode::UnusedMacro,
+                format!("Macro {} is never used", name),
+                range,
+            )
+        })
+        .collect();
+
+    let undefined: Vec<_> = compiler
+        .diagnostics
+        .iter()
+        .filter(|d| {
+            d.code == DiagnosticCode::UndefinedName && tree.text_range().contains_range(d.span)
+        })
+        .collect();
+    if let Some(first) = undefined.first() {
+        let text = |d: &Diagnostic| tree.text().slice(d.span).to_string();
+        let mut names: Vec<String> = undefined.iter().map(|&d| text(d)).collect();
+        names.sort();
+        names.dedup();
+        let summary = Diagnostic::new(
+            DiagnosticLevel::Warning,
+            DiagnosticCode::UndefinedNames,
+            format!(
+                "{} undefined name(s) used {} time(s): {}",
+                names.len(),
+                undefined.len(),
+                names.join(", ")
+            ),
+            first.span,
+        );
+        diagnostics.push(undefined.iter().fold(summary, |summary, &d| {
+            summary.with_related(format!("{} is used here", text(d)), d.span)
+        }));
+    }
+    diagnostics
+}
+
+/// Pitch distance under which notes are reported as clashing by default.
+pub const DEFAULT_UNISON_CENTS: f64 = 20.0;
+
+/// Notes starting less apart than this in seconds start together.
+const SAME_START_SECONDS: f64 = 1e-6;
+
+/// How two notes clash.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+pub enum CollisionKind {
+    /// A note starts on the pitch of an earlier note of the same voice that still sounds
+    Overlap,
+    /// Notes of any voices start together at nearly the same pitch
+    NearUnison,
+}
+
+/// Two clashing notes, by their indices in the analyzed events, for the piano roll to
+/// highlight.
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct Collision {
+    pub kind: CollisionKind,
+    /// The earlier note, or the first in order of notes starting together
+    pub first: usize,
+    pub second: usize,
+    /// Distance between the two pitches
+    pub cents: f64,
+}
+
+/// Finds notes clashing with one another: a note starting on the same voice less than
+/// `unison_cents` from an earlier note still sounding, and notes of any voices starting
+/// together less than `unison_cents` apart. Rests and drum hits never clash.
+pub fn note_collisions(events: &[CompileEvent], unison_cents: f64) -> Vec<Collision> {
+    let mut notes: Vec<(usize, &CompileEvent, &Note)> = events
+        .iter()
+        .enumerate()
+        .filter_map(|(idx, event)| match &event.body {
+            EventBody::Note(note)
+                if note.freq > 0.0
+                    && note.drum_key.is_none()
+                    && !(note.is_rest() || note.is_sustain() || note.is_tie()) =>
+            {
+                Some((idx, event, note))
+            }
+            _ => None,
+        })
+        .collect();
+    notes.sort_by(|a, b| a.1.start_time.seconds.total_cmp(&b.1.start_time.seconds));
+
+    let mut collisions = Vec::new();
+    // notes still sounding, as (index in `notes`, end in seconds)
+    let mut sounding: Vec<(usize, f64)> = Vec::new();
+    for (pos, &(idx, event, note)) in notes.iter().enumerate() {
+        let start = event.start_time.seconds;
+        sounding.retain(|&(_, end)| end > start + SAME_START_SECONDS);
+        for &(other_pos, _) in &sounding {
+            let (other_idx, other_event, other_note) = notes[other_pos];
+            let cents = (1200.0 * (note.freq / other_note.freq).log2()).abs();
+            if cents >= unison_cents {
+                continue;
+            }
+            let kind = if (start - other_event.start_time.seconds).abs() < SAME_START_SECONDS {
+                CollisionKind::NearUnison
+            } else if event.voice == other_event.voice {
+                CollisionKind::Overlap
+            } else {
+                continue;
+            };
+            collisions.push(Collision {
+                kind,
+                first: other_idx,
+                second: idx,
+                cents,
+            });
+        }
+        sounding.push((pos, start + note.duration_seconds.max(0.0)));
+    }
+    collisions
+}
+
+/// Warnings for `collisions` found in `events`, one per clashing place in the source: notes
+/// a macro produces many times are reported once at the invocation.
+pub fn collision_diagnostics(events: &[CompileEvent], collisions: &[Collision]) -> Vec<Diagnostic> {
+    let mut reported: HashSet<(TextRange, TextRange)> = HashSet::new();
+    collisions
+        .iter()
+        .filter_map(|collision| {
+            let span = source_span(&events[collision.second]);
+            let other = source_span(&events[collision.first]);
+            if !reported.insert((span, other)) {
+                return None;
+            }
+            let (code, message) = match collision.kind {
+                CollisionKind::Overlap => (
+                    DiagnosticCode::NoteOverlap,
+                    "Note overlaps an earlier note of the same pitch on its voice".to_string(),
+                ),
+                CollisionKind::NearUnison => (
+                    DiagnosticCode::NearUnison,
+                    format!(
+                        "Notes starting together are only {:.1} cents apart",
+                        collision.cents
+                    ),
+                ),
+            };
+            let diagnostic = Diagnostic::new(DiagnosticLevel::Warning, code, message, span);
+            Some(diagnostic.with_related("Clashes with this note".to_string(), other))
+        })
+        .collect()
+}
+
+/// Whether `token` is the name a macro definition defines.
+fn is_definition_name(token: &SyntaxToken) -> bool {
+    token
+        .parent()
+        .and_then(ast::MacroDef::cast)
+        .and_then(|def| def.name())
+        .is_some_and(|name| &name == token)
+}
+
+/// Namespace names in the body of the macro around `token` are looked up from first, like
+/// the compiler does for the body of `lib.x`.
+fn scope_of(token: &SyntaxToken) -> String {
+    token
+        .parent_ancestors()
+        .filter_map(ast::MacroDef::cast)
+        .find_map(|def| def.name())
+        .and_then(|name| {
+            name.text()
+                .rsplit_once('.')
+                .map(|(namespace, _)| namespace.to_string())
+        })
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::rowan::parse_fn::parse_source;
+
+    fn analyze(source: &str) -> Vec<Diagnostic> {
+        let tree = parse_source(Arc::from(source)).syntax_node();
+        let mut compiler = Compiler::new();
+        compiler.compile(&tree);
+        macro_diagnostics(&tree, &compiler)
+    }
+
+    #[test]
+    fn reports_unused_macros_and_sums_up_undefined_names() {
+        let source =
+            "a = C4:E4\nb = D4\n_keep = F4\nlib.x = G4\nlib.y = 3/2@x\n\na,nope,nope,lib.y,\n";
+        let diagnostics = analyze(source);
+        let messages: Vec<_> = diagnostics.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "Macro b is never used",
+                "1 undefined name(s) used 2 time(s): nope",
+            ]
+        );
+        assert_eq!(diagnostics[1].related.len(), 2);
+        assert_eq!(&source[diagnostics[1].span], "nope");
+    }
+
+    #[test]
+    fn finds_overlaps_and_near_unisons() {
+        // the chord starts two C4s together, and the half note D4 still sounds at the next D4
+        let tree = parse_source(Arc::from("v1: C4:C4,D4[2],D4,\n")).syntax_node();
+        let mut compiler = Compiler::new();
+        compiler.compile(&tree);
+        let collisions = note_collisions(&compiler.events, DEFAULT_UNISON_CENTS);
+        let kinds: Vec<_> = collisions.iter().map(|c| c.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![CollisionKind::NearUnison, CollisionKind::Overlap]
+        );
+        assert!(collisions[0].cents < 1e-9);
+        let diagnostics = collision_diagnostics(&compiler.events, &collisions);
+        assert_eq!(diagnostics.len(), 2);
+        assert_eq!(diagnostics[1].code, DiagnosticCode::NoteOverlap);
+    }
+}
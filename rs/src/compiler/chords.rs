@@ -0,0 +1,99 @@
+use std::collections::HashMap;
+
+use crate::compiler::{rational::Rational64, types::Pitch};
+
+/// Chord qualities available to chord symbols (`Cmaj`, `D:min7`), as cent offsets from the root.
+pub const BUILTIN_CHORD_QUALITIES: &[(&str, &[i32])] = &[
+    ("maj", &[0, 400, 700]),
+    ("min", &[0, 300, 700]),
+    ("dim", &[0, 300, 600]),
+    ("aug", &[0, 400, 800]),
+    ("sus2", &[0, 200, 700]),
+    ("sus4", &[0, 500, 700]),
+    ("maj7", &[0, 400, 700, 1100]),
+    ("min7", &[0, 300, 700, 1000]),
+    ("dom7", &[0, 400, 700, 1000]),
+    ("dim7", &[0, 300, 600, 900]),
+    ("hdim7", &[0, 300, 600, 1000]),
+];
+
+/// Splits a chord symbol such as `Cmaj`, `Eb4min7` or `min7` into its optional root and the
+/// cent offsets of its quality. The longest matching quality wins.
+pub fn split_chord_symbol(
+    symbol: &str,
+    qualities: &HashMap<String, Vec<i32>>,
+) -> Option<(Option<Pitch>, Vec<i32>)> {
+    symbol.char_indices().find_map(|(i, _)| {
+        let offsets = qualities.get(&symbol[i..])?;
+        let root = &symbol[..i];
+        if root.is_empty() {
+            return Some((None, offsets.clone()));
+        }
+        let root = Pitch::parse_spell_octave(root).or_else(|| Pitch::parse_spell_simple(root))?;
+        Some((Some(root), offsets.clone()))
+    })
+}
+
+/// Ratios of a JI chord relative to its first member: `o:4-5-6` stacks harmonics,
+/// `u:4-5-6` stacks subharmonics.
+pub fn ji_chord_ratios(symbol: &str) -> Option<Vec<Rational64>> {
+    let (mode, members) = symbol.split_once(':')?;
+    let members: Vec<i64> = members
+        .split('-')
+        .map(|m| m.parse::<i64>().ok().filter(|&m| m > 0))
+        .collect::<Option<_>>()?;
+    let first = *members.first()?;
+    members
+        .iter()
+        .map(|&m| match mode {
+            "o" => Some(Rational64::new(m, first)),
+            "u" => Some(Rational64::new(first, m)),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn builtin_qualities() -> HashMap<String, Vec<i32>> {
+        BUILTIN_CHORD_QUALITIES
+            .iter()
+            .map(|&(name, offsets)| (name.to_string(), offsets.to_vec()))
+            .collect()
+    }
+
+    #[test]
+    fn chord_symbols_split_into_root_and_quality() {
+        let qualities = builtin_qualities();
+        let (root, offsets) = split_chord_symbol("Cmaj", &qualities).expect("Cmaj is a chord");
+        assert_eq!(root, Pitch::parse_spell_simple("C"));
+        assert_eq!(offsets, vec![0, 400, 700]);
+        let (root, _) = split_chord_symbol("Cdim7", &qualities).expect("Cdim7 is a chord");
+        assert_eq!(root, Pitch::parse_spell_simple("C"));
+        let (root, _) = split_chord_symbol("Eb4min7", &qualities).expect("Eb4min7 is a chord");
+        assert_eq!(root, Pitch::parse_spell_octave("Eb4"));
+        assert_eq!(
+            split_chord_symbol("min7", &qualities).map(|c| c.0),
+            Some(None)
+        );
+        assert!(split_chord_symbol("melody", &qualities).is_none());
+    }
+
+    #[test]
+    fn ji_chords_stack_harmonics() {
+        let o = ji_chord_ratios("o:4-5-6").expect("otonal chord");
+        assert_eq!(
+            o,
+            vec![
+                Rational64::new(4, 4),
+                Rational64::new(5, 4),
+                Rational64::new(6, 4)
+            ]
+        );
+        let u = ji_chord_ratios("u:4-5-6").expect("utonal chord");
+        assert_eq!(u[2], Rational64::new(4, 6));
+        assert!(ji_chord_ratios("o:0-3").is_none());
+    }
+}
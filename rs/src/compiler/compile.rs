@@ -1,50 +1,306 @@
 use std::{
-    collections::HashMap,
-    mem::take,
-    ops::Neg,
+    collections::{HashMap, HashSet},
+    fs, iter,
+    mem::{replace, take},
+    ops::{Neg, Range},
+    path::PathBuf,
+    str::FromStr,
+    sync::Arc,
     vec,
 };
 
-use rowan::{NodeOrToken, TextRange};
+use rayon::prelude::*;
+use rowan::{GreenNode, GreenToken, NodeOrToken, TextRange, TextSize};
+use smol_str::SmolStr;
 
 use crate::{
     compiler::{
+        chords::{BUILTIN_CHORD_QUALITIES, ji_chord_ratios, split_chord_symbol},
+        drums::drum_key,
         helpers::SyntaxNodeEx,
-        rational::Rational32,
+        instruments::{gm_instruments, normalize_instrument_name},
+        random::SeededRng,
+        rational::Rational64,
+        scala::parse_scala,
         types::{
-            CompileEvent, CompileState, Diagnostic, DiagnosticLevel, EventBody, MacroRegistry,
-            Note, Pitch, TimeStamp, freq2spell,
+            BendEnvelope, CancellationToken, CompileEvent, CompileLimits, CompileState,
+            CompilerConfig, Diagnostic, DiagnosticCode, DiagnosticLevel, EventBody, KeySignature,
+            MacroCall, MacroRegistry, MacroValue, MixLevel, Note, ParsePitchError, PieceMetadata,
+            Pitch, PitchChain, TempoMap, TimeSignature, TimeStamp, Tuning, freq2spell,
         },
     },
     rowan::{
+        ast::{self, AstNode},
         lexer::SyntaxKind,
-        parser::{SyntaxNode, SyntaxToken},
+        parse_fn::parse_source,
+        parser::{SyntaxElementRef, SyntaxNode, SyntaxToken},
     },
 };
 
+/// Grace notes waiting for the duration of the notes they decorate.
+struct GraceAttachment {
+    target: Range<usize>,
+    notes: Vec<Note>,
+    range: TextRange,
+}
+
+#[derive(Clone, Copy)]
+enum Ornament {
+    Trill,
+    Tremolo,
+}
+
+/// Ornament waiting for the duration of the notes it expands.
+struct OrnamentAttachment {
+    target: Range<usize>,
+    kind: Ornament,
+    range: TextRange,
+}
+
+/// Notes of the current sub-group whose durations are not resolved yet.
+#[derive(Default)]
+struct PendingSubGroup {
+    events: Vec<CompileEvent>,
+    graces: Vec<GraceAttachment>,
+    ornaments: Vec<OrnamentAttachment>,
+    /// Range of a fermata marking any note of the sub-group
+    fermata: Option<TextRange>,
+    /// Frequency the notes of this sub-group glide from, after a `~` connector
+    portamento_from: Option<f64>,
+}
+
+/// Compiler state before a top-level item, from which `recompile` can resume.
+struct Checkpoint {
+    /// Range of the item in the compiled source; empty at the end of the source
+    range: TextRange,
+    green: Option<NodeOrToken<GreenNode, GreenToken>>,
+    state: CompileState,
+    /// Shared between checkpoints until a macro definition changes the registry
+    macros: Arc<MacroRegistry>,
+    metadata: PieceMetadata,
+    last_bar: Option<(Vec<CompileEvent>, Rational64)>,
+    rng: SeededRng,
+    events: usize,
+    diagnostics: usize,
+}
+
+impl Checkpoint {
+    /// Whether compilation resumed with `compiler` continues exactly as it did from here,
+    /// once the source ranges recorded here are moved by `shift`.
+    fn resumes(&self, compiler: &Compiler, shift: &impl Fn(TextRange) -> TextRange) -> bool {
+        compiler
+            .macros_snapshot
+            .as_ref()
+            .is_some_and(|macros| Arc::ptr_eq(macros, &self.macros))
+            && self.state == compiler.state
+            && self.rng == compiler.rng
+            && self.metadata == compiler.metadata
+            && shift_last_bar(&self.last_bar, shift) == compiler.last_bar
+    }
+}
+
+fn shift_events(
+    events: &[CompileEvent],
+    shift: &impl Fn(TextRange) -> TextRange,
+) -> Vec<CompileEvent> {
+    events
+        .iter()
+        .map(|e| CompileEvent {
+            range: shift(e.range),
+            range_invoked: e.range_invoked.map(shift),
+            macro_trace: e
+                .macro_trace
+                .iter()
+                .map(|call| MacroCall {
+                    range: shift(call.range),
+                    ..call.clone()
+                })
+                .collect(),
+            ..e.clone()
+        })
+        .collect()
+}
+
+fn shift_diagnostic(
+    diagnostic: &Diagnostic,
+    shift: &impl Fn(TextRange) -> TextRange,
+) -> Diagnostic {
+    let mut diagnostic = diagnostic.clone();
+    diagnostic.span = shift(diagnostic.span);
+    for related in &mut diagnostic.related {
+        related.span = shift(related.span);
+    }
+    for fix in &mut diagnostic.fixes {
+        fix.span = shift(fix.span);
+    }
+    diagnostic
+}
+
+/// Definitions that change the settings of `from` to those of `to`.
+fn def_changes(from: &CompileState, to: &CompileState) -> Vec<EventBody> {
+    let mut defs = Vec::new();
+    if to.base_note != from.base_note || to.base_frequency != from.base_frequency {
+        defs.push(EventBody::BaseNoteDef(to.base_note));
+        defs.push(EventBody::BaseFequencyDef(to.base_frequency));
+    }
+    if to.beat_duration != from.beat_duration {
+        defs.push(EventBody::BeatDurationDef(to.beat_duration));
+    }
+    if to.bpm != from.bpm || to.beat_duration != from.beat_duration {
+        defs.push(EventBody::BPMDef(to.bpm));
+    }
+    if to.time_signature != from.time_signature {
+        let bar = to.time_signature;
+        defs.push(EventBody::TimeSignatureDef(TimeSignature::simple(
+            *bar.numer() as u32,
+            *bar.denom() as u32,
+        )));
+    }
+    if to.quantize != from.quantize {
+        defs.push(EventBody::QuantizeDef(to.quantize));
+    }
+    defs
+}
+
+fn shift_last_bar(
+    last_bar: &Option<(Vec<CompileEvent>, Rational64)>,
+    shift: &impl Fn(TextRange) -> TextRange,
+) -> Option<(Vec<CompileEvent>, Rational64)> {
+    last_bar
+        .as_ref()
+        .map(|(events, span)| (shift_events(events, shift), *span))
+}
+
+/// Takes over the remaining top-level items at the given one, returning whether it did.
+type ResumeFn<'a> = dyn FnMut(&mut Compiler, &SyntaxElementRef) -> bool + 'a;
+
+fn green_element(element: &SyntaxElementRef) -> NodeOrToken<GreenNode, GreenToken> {
+    match element {
+        NodeOrToken::Node(n) => NodeOrToken::Node(n.green().into_owned()),
+        NodeOrToken::Token(t) => NodeOrToken::Token(t.green().to_owned()),
+    }
+}
+
+/// Interval of the upper neighbour used by trills.
+const TRILL_INTERVAL_CENTS: f64 = 200.0;
+
 pub struct Compiler {
     pub diagnostics: Vec<Diagnostic>,
     pub macros: MacroRegistry,
     pub state: CompileState,
     pub events: Vec<CompileEvent>,
+    pub metadata: PieceMetadata,
+    /// Whether the last compilation stopped at a line boundary because its token was
+    /// cancelled; events and diagnostics then cover only the lines before that point
+    pub cancelled: bool,
+    /// Note events of the last completed line (relative ticks) and its length, for `%`
+    last_bar: Option<(Vec<CompileEvent>, Rational64)>,
+    rng: SeededRng,
+    in_arpeggio: bool,
+    variants: HashSet<String>,
+    /// Voice of the line being compiled, set by a `v2:` prefix
+    line_voice: Option<SmolStr>,
+    /// Whether the line being compiled has a `drums:` prefix
+    in_percussion: bool,
+    /// Pitch chain of the previous note in the current chord, the root of `D:min7`
+    chord_root: Option<PitchChain>,
+    /// Quantize values enclosing the `{8: ...}` blocks being compiled
+    quantize_stack: Vec<Rational64>,
+    /// Directory relative `.scl` tuning paths are resolved against
+    scale_dir: Option<PathBuf>,
+    limits: CompileLimits,
+    /// Namespace of the macro being defined, where unqualified names are looked up first
+    macro_scope: String,
+    /// State before each top-level item of the last compilation, plus one at the end
+    checkpoints: Vec<Checkpoint>,
+    /// Events of the last compilation before the finalizing passes
+    raw_events: Vec<CompileEvent>,
+    /// Diagnostics reported by top-level items, before the finalizing passes
+    item_diagnostics: usize,
+    /// Registry shared by the checkpoints since the last macro definition
+    macros_snapshot: Option<Arc<MacroRegistry>>,
+}
+
+impl Default for Compiler {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Compiler {
     pub fn new() -> Self {
-        let macros = MacroRegistry::default();
-        let state = CompileState::new();
-        Self {
+        Self::with_config(CompilerConfig::default())
+    }
+
+    pub fn with_config(config: CompilerConfig) -> Self {
+        let macros = MacroRegistry {
+            chord_qualities: BUILTIN_CHORD_QUALITIES
+                .iter()
+                .map(|&(name, offsets)| (name.to_string(), offsets.to_vec()))
+                .chain(config.chord_qualities)
+                .collect(),
+            instruments: gm_instruments()
+                .map(|(name, program)| (name.to_string(), program))
+                .chain(
+                    config
+                        .instruments
+                        .iter()
+                        .map(|(name, program)| (normalize_instrument_name(name), *program)),
+                )
+                .collect(),
+            ..Default::default()
+        };
+        let mut state = CompileState::new();
+        state.base_note = config.base_note;
+        state.base_frequency = config.base_frequency;
+        state.set_tempo(config.bpm, state.beat_duration);
+        state.time_signature = config.time_signature;
+        state.quantize = config.quantize;
+        state.tuning = config.edo.map(Tuning::Edo);
+        state.arpeggio_offset = config.arpeggio_offset;
+        state.fermata_factor = config.fermata_factor;
+        let mut compiler = Self {
             diagnostics: Vec::new(),
             macros,
             state,
             events: vec![],
+            metadata: PieceMetadata::default(),
+            cancelled: false,
+            last_bar: None,
+            rng: SeededRng::new(config.seed),
+            in_arpeggio: false,
+            variants: config.variants.into_iter().collect(),
+            line_voice: None,
+            in_percussion: false,
+            chord_root: None,
+            quantize_stack: Vec::new(),
+            scale_dir: config.scale_dir,
+            limits: config.limits,
+            macro_scope: String::new(),
+            checkpoints: Vec::new(),
+            raw_events: Vec::new(),
+            item_diagnostics: 0,
+            macros_snapshot: None,
+        };
+        compiler.push_initial_defs();
+        compiler
+    }
+
+    /// Pushes definitions at the start of the piece for the settings it starts from that
+    /// differ from the defaults, so readers of the events that assume the defaults, like the
+    /// MIDI writer, see the same tempo, meter and base note as the compiler.
+    fn push_initial_defs(&mut self) {
+        for body in def_changes(&CompileState::new(), &self.state) {
+            self.push_event(body, TextRange::default());
         }
     }
 
     fn reset_ticks(&mut self) {
         if self.state.time.ticks.numer() > &0 {
+            // the anacrusis only applies to the first bar
+            self.state.pickup = None;
             self.state.time.bars += 1;
-            self.state.time.ticks = Rational32::new(0, *self.state.quantize.denom());
+            self.state.time.ticks = Rational64::new(0, *self.state.quantize.denom());
             self.push_event(
                 EventBody::NewMeasure(self.state.time.bars),
                 TextRange::default(),
@@ -53,90 +309,638 @@ impl Compiler {
     }
 
     pub fn compile(&mut self, tree: &SyntaxNode) {
-        for child in tree.children_with_tokens() {
-            match child {
-                NodeOrToken::Node(node) => match node.kind() {
-                    SyntaxKind::NODE_MACRODEF_ALIAS
-                    |
-                    SyntaxKind::NODE_MACRODEF_SIMPLE
-                    | SyntaxKind::NODE_MACRODEF_COMPLEX => {
-                        self.compile_macro_def(&node);
-                    }
-                    SyntaxKind::NODE_NORMAL_LINE | SyntaxKind::NODE_GHOST_LINE => {
-                        self.compile_normal_line(&node);
+        self.compile_cancellable(tree, &CancellationToken::default());
+    }
+
+    /// Compiles `tree` starting from a `state` and `macros` snapshot, typically cloned from the
+    /// compiler of a prelude, so its settings and macro definitions carry into this file. The
+    /// file still starts at the beginning of its first bar, with the inherited tempo, meter and
+    /// base note defined there.
+    pub fn compile_with_state(
+        &mut self,
+        tree: &SyntaxNode,
+        state: CompileState,
+        macros: MacroRegistry,
+    ) {
+        self.state = state;
+        self.state.time = TimeStamp::default();
+        self.state.pickup = None;
+        self.state.tempo = TempoMap::new(self.state.seconds_per_whole_note());
+        self.macros = macros;
+        self.macros_snapshot = None;
+        self.last_bar = None;
+        self.events.clear();
+        self.push_initial_defs();
+        self.compile(tree);
+    }
+
+    /// [`Self::compile`], stopping before the next top-level line once `cancel` is cancelled.
+    pub fn compile_cancellable(&mut self, tree: &SyntaxNode, cancel: &CancellationToken) {
+        let children: Vec<_> = tree.children_with_tokens().collect();
+        self.checkpoints.clear();
+        self.cancelled = false;
+        self.compile_top_level(&children, tree.text_range().end(), None, cancel);
+        self.finalize();
+    }
+
+    /// Compiles `tree` like [`Self::compile`], yielding each event as soon as no later item
+    /// can change it instead of collecting the whole piece in `events`. Events come in the
+    /// same order as after `compile`; diagnostics still collect in `diagnostics`, and no
+    /// checkpoints are kept for `recompile`.
+    pub fn compile_iter<'a>(
+        &'a mut self,
+        tree: &SyntaxNode,
+    ) -> impl Iterator<Item = CompileEvent> + 'a {
+        let children: Vec<_> = tree.children_with_tokens().collect();
+        self.checkpoints.clear();
+        iter::from_coroutine(
+            #[coroutine]
+            move || {
+                for child in children {
+                    self.compile_item(child);
+                    let settled = self.settled_len();
+                    for event in self.take_finalized(settled) {
+                        yield event;
                     }
-                    SyntaxKind::Newline => {
-                        // Ignore top-level newlines
+                }
+                for event in self.take_finalized(self.events.len()) {
+                    yield event;
+                }
+            },
+        )
+    }
+
+    /// Compiles independent sections of a piece, such as the movements of a large file, on
+    /// the rayon thread pool. Each section is the green tree of source starting at the given
+    /// offset, ending at the end of a line, and compiles from the state `config` describes, as
+    /// if it were a piece of its own. Sections then follow one another: each starts where the
+    /// one before it ends, in time and in bars, and the settings `config` describes are
+    /// defined again there when the section before changed them. Diagnostics follow section
+    /// order, and metadata comes from the first section. No checkpoints are kept, so a later
+    /// `recompile` compiles anew.
+    pub fn compile_parallel(config: &CompilerConfig, sections: &[(TextSize, GreenNode)]) -> Self {
+        let compiled: Vec<_> = sections
+            .par_iter()
+            .map(|(offset, green)| {
+                let mut compiler = Self::with_config(config.clone());
+                compiler.compile(&SyntaxNode::new_root(green.clone()));
+                let shift = |range: TextRange| {
+                    if range == TextRange::default() {
+                        range
+                    } else {
+                        range + *offset
                     }
-                    _ => {
-                        self.error(
-                            format!("Unexpected node kind: {:?}", node.kind()),
-                            node.text_range(),
-                        );
+                };
+                let diagnostics: Vec<_> = compiler
+                    .diagnostics
+                    .iter()
+                    .map(|d| shift_diagnostic(d, &shift))
+                    .collect();
+                (
+                    shift_events(&compiler.events, &shift),
+                    diagnostics,
+                    compiler.metadata,
+                    compiler.state,
+                )
+            })
+            .collect();
+
+        let mut merged = Self::with_config(config.clone());
+        // the first section defines the configured settings itself
+        merged.events.clear();
+        let start = merged.state.clone();
+        let mut previous: Option<CompileState> = None;
+        // 与单段编译一致：位置溢出或超出限制时报告一次，并跳过之后的内容
+        'sections: for (events, diagnostics, metadata, state) in compiled {
+            let offset = merged.state.time;
+            match &previous {
+                None => merged.metadata = metadata,
+                Some(previous) => {
+                    for body in def_changes(previous, &start) {
+                        merged.push_event(body, TextRange::default());
                     }
-                },
-                NodeOrToken::Token(token) => {
-                    if !token.kind().is_trivia() && !token.kind().is_newline() {
-                        self.error(
-                            format!("Unexpected token: {}", token.text()),
-                            token.text_range(),
-                        );
+                }
+            }
+            merged.diagnostics.extend(diagnostics);
+            for mut e in events {
+                let Some(start_time) = e.start_time.checked_after(offset) else {
+                    merged.report_duration_overflow(e.range);
+                    break 'sections;
+                };
+                e.start_time = start_time;
+                merged.state.time = start_time;
+                if !merged.within_limits(e.range) {
+                    break 'sections;
+                }
+                if let EventBody::NewMeasure(bar) = &mut e.body {
+                    *bar += offset.bars;
+                }
+                merged.events.push(e);
+            }
+            let Some(end) = state.time.checked_after(offset) else {
+                merged.report_duration_overflow(TextRange::default());
+                break;
+            };
+            merged.state.time = end;
+            previous = Some(state);
+        }
+        merged
+    }
+
+    /// Recompiles `tree` after the range `dirty` of the previously compiled source was edited.
+    /// Top-level items before the edit are not re-evaluated, and once compilation after the
+    /// edit reaches the state it had before, the remaining events are spliced in unchanged.
+    pub fn recompile(&mut self, tree: &SyntaxNode, dirty: TextRange) {
+        self.recompile_cancellable(tree, dirty, &CancellationToken::default());
+    }
+
+    /// [`Self::recompile`], stopping before the next top-level line once `cancel` is
+    /// cancelled.
+    pub fn recompile_cancellable(
+        &mut self,
+        tree: &SyntaxNode,
+        dirty: TextRange,
+        cancel: &CancellationToken,
+    ) {
+        let children: Vec<_> = tree.children_with_tokens().collect();
+        // a cancelled compilation has no checkpoints past where it stopped to splice from
+        let complete = !take(&mut self.cancelled);
+        let old_checkpoints = take(&mut self.checkpoints);
+        let old_len = old_checkpoints
+            .last()
+            .map_or(0, |c| u32::from(c.range.end()));
+        let delta = i64::from(u32::from(tree.text_range().end())) - i64::from(old_len);
+        let unchanged = old_checkpoints
+            .iter()
+            .zip(&children)
+            .take_while(|(checkpoint, child)| {
+                checkpoint.range.end() < dirty.start()
+                    && checkpoint.range == child.text_range()
+                    && checkpoint.green.as_ref() == Some(&green_element(child))
+            })
+            .count()
+            .min(old_checkpoints.len().saturating_sub(1));
+        let Some(resume) = old_checkpoints.get(unchanged) else {
+            self.compile_cancellable(tree, cancel);
+            return;
+        };
+        let (resume_events, resume_diagnostics) = (resume.events, resume.diagnostics);
+        self.restore(resume);
+
+        let mut old_events = take(&mut self.raw_events);
+        let mut old_diagnostics = take(&mut self.diagnostics);
+        old_diagnostics.truncate(self.item_diagnostics);
+        let old_tail_events = old_events.split_off(resume_events);
+        let old_tail_diagnostics = old_diagnostics.split_off(resume_diagnostics);
+        self.events = old_events;
+        self.diagnostics = old_diagnostics;
+        let mut checkpoints = old_checkpoints;
+        let old_checkpoints = checkpoints.split_off(unchanged);
+        self.checkpoints = checkpoints;
+
+        // an item starting after the edit corresponds to the old item `delta` bytes earlier
+        let shift = |range: TextRange| {
+            if range.start() < dirty.end() || range == TextRange::default() {
+                range
+            } else {
+                let at = |pos: TextSize| TextSize::from((i64::from(u32::from(pos)) + delta) as u32);
+                TextRange::new(at(range.start()), at(range.end()))
+            }
+        };
+        let splice = |compiler: &mut Compiler, at: usize| {
+            let from = &old_checkpoints[at];
+            let event_offset = compiler.events.len() as isize - from.events as isize;
+            let diagnostic_offset = compiler.diagnostics.len() as isize - from.diagnostics as isize;
+            let first_event = from.events - resume_events;
+            let first_diagnostic = from.diagnostics - resume_diagnostics;
+            compiler
+                .events
+                .extend(shift_events(&old_tail_events[first_event..], &shift));
+            compiler.diagnostics.extend(
+                old_tail_diagnostics[first_diagnostic..]
+                    .iter()
+                    .map(|d| shift_diagnostic(d, &shift)),
+            );
+            // complex macros defined after the edit recorded the old ranges of their bodies
+            let mut registries: Vec<(Arc<MacroRegistry>, Arc<MacroRegistry>)> = Vec::new();
+            for checkpoint in &old_checkpoints[at..] {
+                let macros = match registries
+                    .iter()
+                    .find(|(old, _)| Arc::ptr_eq(old, &checkpoint.macros))
+                {
+                    Some((_, macros)) => macros.clone(),
+                    None => {
+                        let mut macros = checkpoint.macros.clone();
+                        if delta != 0 && !registries.is_empty() {
+                            let registry = Arc::make_mut(&mut macros);
+                            for events in registry.complex_macros.values_mut() {
+                                *events = shift_events(events, &shift).into();
+                            }
+                            for range in registry.definitions.values_mut() {
+                                *range = shift(*range);
+                            }
+                        }
+                        registries.push((checkpoint.macros.clone(), macros.clone()));
+                        macros
                     }
+                };
+                compiler.checkpoints.push(Checkpoint {
+                    range: shift(checkpoint.range),
+                    green: checkpoint.green.clone(),
+                    state: checkpoint.state.clone(),
+                    macros,
+                    metadata: checkpoint.metadata.clone(),
+                    last_bar: shift_last_bar(&checkpoint.last_bar, &shift),
+                    rng: checkpoint.rng.clone(),
+                    events: (checkpoint.events as isize + event_offset) as usize,
+                    diagnostics: (checkpoint.diagnostics as isize + diagnostic_offset) as usize,
+                });
+            }
+            let end = compiler.checkpoints.pop().expect("end checkpoint");
+            compiler.restore(&end);
+            compiler.checkpoints.push(end);
+        };
+        let edited_end = shift(TextRange::empty(dirty.end())).start();
+        self.compile_top_level(
+            &children[unchanged..],
+            tree.text_range().end(),
+            Some(&mut |compiler: &mut Compiler, child: &SyntaxElementRef| {
+                if !complete || child.text_range().start() < edited_end {
+                    return false;
                 }
+                let at = old_checkpoints
+                    .partition_point(|c| shift(c.range).start() < child.text_range().start());
+                let Some(old) = old_checkpoints.get(at) else {
+                    return false;
+                };
+                if shift(old.range) != child.text_range()
+                    || old.green != Some(green_element(child))
+                    || !old.resumes(compiler, &shift)
+                {
+                    return false;
+                }
+                splice(compiler, at);
+                true
+            }),
+            cancel,
+        );
+        self.finalize();
+    }
+
+    /// Compiles top-level items, recording a checkpoint before each of them and at the end.
+    /// Stops early when `resume` takes over the rest of the items or `cancel` is cancelled.
+    fn compile_top_level(
+        &mut self,
+        children: &[SyntaxElementRef],
+        end: TextSize,
+        mut resume: Option<&mut ResumeFn>,
+        cancel: &CancellationToken,
+    ) {
+        for child in children {
+            if let Some(resume) = resume.as_mut()
+                && resume(self, child)
+            {
+                return;
             }
-            self.reset_ticks();
+            self.push_checkpoint(child.text_range(), Some(green_element(child)));
+            if cancel.is_cancelled() {
+                // the checkpoint just pushed lets `recompile` resume from here
+                self.cancelled = true;
+                return;
+            }
+            if self.within_limits(child.text_range()) {
+                self.compile_item(child.clone());
+            }
+        }
+        self.push_checkpoint(TextRange::empty(end), None);
+    }
+
+    fn push_checkpoint(
+        &mut self,
+        range: TextRange,
+        green: Option<NodeOrToken<GreenNode, GreenToken>>,
+    ) {
+        let macros = self
+            .macros_snapshot
+            .get_or_insert_with(|| Arc::new(self.macros.clone()))
+            .clone();
+        self.checkpoints.push(Checkpoint {
+            range,
+            green,
+            state: self.state.clone(),
+            macros,
+            metadata: self.metadata.clone(),
+            last_bar: self.last_bar.clone(),
+            rng: self.rng.clone(),
+            events: self.events.len(),
+            diagnostics: self.diagnostics.len(),
+        });
+    }
+
+    fn restore(&mut self, checkpoint: &Checkpoint) {
+        if !self
+            .macros_snapshot
+            .as_ref()
+            .is_some_and(|macros| Arc::ptr_eq(macros, &checkpoint.macros))
+        {
+            self.macros = (*checkpoint.macros).clone();
+            self.macros_snapshot = Some(checkpoint.macros.clone());
         }
+        self.state = checkpoint.state.clone();
+        self.metadata = checkpoint.metadata.clone();
+        self.last_bar = checkpoint.last_bar.clone();
+        self.rng = checkpoint.rng.clone();
+    }
+
+    /// Keeps the item events for `recompile` and runs the passes over the whole piece.
+    fn finalize(&mut self) {
+        self.raw_events = self.events.clone();
+        self.item_diagnostics = self.diagnostics.len();
+        self.finalize_events();
+    }
+
+    fn finalize_events(&mut self) {
         self.finalize_negative_duration_notes();
+        self.finalize_ties();
         self.finalize_sustain_notes();
     }
 
+    /// Runs the passes over the first `len` events alone and takes them out of `events`.
+    fn take_finalized(&mut self, len: usize) -> Vec<CompileEvent> {
+        let rest = self.events.split_off(len);
+        self.finalize_events();
+        replace(&mut self.events, rest)
+    }
+
+    /// Length of the longest prefix of `events` that later events cannot change: no tie is
+    /// left open in it, and no sustain joins notes across the cut. Notes of later items
+    /// start at the cursor or after it.
+    fn settled_len(&self) -> usize {
+        // where each note starts and ends as written; a negative duration ends it at its start
+        fn span(event: &CompileEvent) -> Option<(&Note, Rational64, Rational64)> {
+            let EventBody::Note(note) = &event.body else {
+                return None;
+            };
+            let start = event.start_time.position;
+            let length = if note.duration > Rational64::zero() {
+                note.duration
+            } else {
+                Rational64::zero()
+            };
+            Some((note, start, start.checked_add(length)?))
+        }
+        // earliest sustain start and note end from each index on
+        let now = self.state.time.position;
+        let mut later = vec![(now, now); self.events.len() + 1];
+        for (idx, event) in self.events.iter().enumerate().rev() {
+            let (mut sustain_start, mut note_end) = later[idx + 1];
+            match span(event) {
+                Some((note, start, _)) if note.is_sustain() && start < sustain_start => {
+                    sustain_start = start
+                }
+                Some((note, _, end)) if !note.is_sustain() && end < note_end => note_end = end,
+                _ => {}
+            }
+            later[idx] = (sustain_start, note_end);
+        }
+
+        let mut open_ties: HashMap<Option<&str>, TimeStamp> = HashMap::new();
+        let (mut latest_end, mut latest_sustain) = (None, None);
+        let mut settled = 0;
+        for (idx, event) in self.events.iter().enumerate() {
+            if let Some((note, start, end)) = span(event) {
+                if latest_end.is_none_or(|latest| end > latest) {
+                    latest_end = Some(end);
+                }
+                if note.is_sustain() && latest_sustain.is_none_or(|latest| start > latest) {
+                    latest_sustain = Some(start);
+                }
+                // mirrors `finalize_ties`: a note at another start closes the open tie
+                let voice = event.voice.as_deref();
+                if !note.is_tie() {
+                    if open_ties
+                        .get(&voice)
+                        .is_some_and(|&t| t != event.start_time)
+                    {
+                        open_ties.remove(&voice);
+                    }
+                    if note.tie {
+                        open_ties.entry(voice).or_insert(event.start_time);
+                    }
+                }
+            }
+            let (sustain_start, note_end) = later[idx + 1];
+            if open_ties.is_empty()
+                && latest_end.is_none_or(|end| end < sustain_start)
+                && latest_sustain.is_none_or(|start| start < note_end)
+            {
+                settled = idx + 1;
+            }
+        }
+        settled
+    }
+
+    fn compile_items(&mut self, tree: &SyntaxNode) {
+        for child in tree.children_with_tokens() {
+            self.compile_item(child);
+        }
+    }
+
+    fn compile_item(&mut self, child: SyntaxElementRef) {
+        match child {
+            NodeOrToken::Node(node) => match node.kind() {
+                SyntaxKind::NODE_METADATA => {
+                    self.compile_metadata(&node);
+                }
+                SyntaxKind::NODE_MACRODEF_ALIAS
+                |
+                SyntaxKind::NODE_MACRODEF_SIMPLE
+                | SyntaxKind::NODE_MACRODEF_COMPLEX
+                | SyntaxKind::NODE_MACRODEF_TUNING => {
+                    self.compile_macro_def(&node);
+                }
+                SyntaxKind::NODE_NORMAL_LINE | SyntaxKind::NODE_GHOST_LINE => {
+                    self.compile_normal_line(&node);
+                }
+                SyntaxKind::NODE_CONDITIONAL => {
+                    self.compile_conditional(&node);
+                }
+                SyntaxKind::Newline => {
+                    // Ignore top-level newlines
+                }
+                _ => {
+                    self.error(
+                        DiagnosticCode::UnexpectedSyntax,
+                        format!("Unexpected node kind: {:?}", node.kind()),
+                        node.text_range(),
+                    );
+                }
+            },
+            NodeOrToken::Token(token) => {
+                if !token.kind().is_trivia()
+                    && !token.kind().is_newline()
+                    && !token.kind().is_if_directive()
+                    && !token.kind().is_endif_directive()
+                {
+                    self.error(
+                        DiagnosticCode::UnexpectedSyntax,
+                        format!("Unexpected token: {}", token.text()),
+                        token.text_range(),
+                    );
+                }
+            }
+        }
+        self.reset_ticks();
+    }
+
+    fn compile_conditional(&mut self, node: &SyntaxNode) {
+        debug_assert!(node.kind().is_node_conditional());
+        let Some(directive) = node.find_child_token_by_fn(|t| t.kind().is_if_directive()) else {
+            return;
+        };
+        let name = directive.text()["#if".len()..].trim();
+        if self.variants.contains(name) {
+            self.compile_items(node);
+        }
+    }
+
+    fn compile_metadata(&mut self, node: &SyntaxNode) {
+        debug_assert!(node.kind().is_node_metadata());
+        for field in node.find_child_tokens_by_fn(|t| t.kind().is_meta_field()) {
+            let Some((key, value)) = field.text().split_once(':') else {
+                continue;
+            };
+            let value = value.trim().to_string();
+            let slot = match key.trim() {
+                "title" => &mut self.metadata.title,
+                "composer" => &mut self.metadata.composer,
+                "copyright" => &mut self.metadata.copyright,
+                other => {
+                    self.error(
+                        DiagnosticCode::UnknownMetadataField,
+                        format!("Unknown metadata field: {}", other),
+                        field.text_range(),
+                    );
+                    continue;
+                }
+            };
+            if slot.replace(value).is_some() {
+                self.warn(
+                    DiagnosticCode::Redefinition,
+                    format!("Metadata field redefined: {}", key.trim()),
+                    field.text_range(),
+                );
+            }
+        }
+    }
+
     fn compile_normal_line(&mut self, node: &SyntaxNode) {
         debug_assert!(node.kind().is_node_normal_line() || node.kind().is_node_ghost_line());
         let is_ghost = node.kind().is_node_ghost_line();
         let start_time_stamp = if is_ghost {
-            Some(self.state.time.clone())
+            Some(self.state.time)
         } else {
             None
         };
+        let line_start = self.state.time;
+        let first_event = self.events.len();
+        let mut has_repeat = false;
+        self.compile_line_items(node, &mut has_repeat);
+        // check if current tick equals time signature (or pickup length) or zero
+        let bar_length = self.state.pickup.unwrap_or(self.state.time_signature);
+        if self.state.time.ticks > Rational64::zero() && self.state.time.ticks != bar_length {
+            self.warn(
+                DiagnosticCode::MisalignedMeasure,
+                "Line ended but current ticks do not align with time signature".to_string(),
+                node.text_range(),
+            );
+        }
+
+        if !has_repeat {
+            self.buffer_last_bar(line_start, first_event);
+        }
+
+        if let Some(ts) = start_time_stamp {
+            self.state.time = ts;
+        }
+        self.line_voice = None;
+        self.in_percussion = false;
+    }
+
+    /// Compiles the notes, settings and commas of a line or quantize block in order.
+    fn compile_line_items(&mut self, node: &SyntaxNode, has_repeat: &mut bool) {
         for child in node.children_with_tokens() {
             match child {
-                NodeOrToken::Node(n) => match n.kind().into() {
+                NodeOrToken::Node(n) => match n.kind() {
                     SyntaxKind::NODE_BPM_DEF => self.compile_bpm_def(&n),
                     SyntaxKind::NODE_TIME_SIGNATURE_DEF => self.compile_time_signature_def(&n),
+                    SyntaxKind::NODE_PICKUP_DEF => self.compile_pickup_def(&n),
+                    SyntaxKind::NODE_TRANSPOSE_DEF => self.compile_transpose_def(&n),
+                    SyntaxKind::NODE_CAPO_DEF => self.compile_capo_def(&n),
+                    SyntaxKind::NODE_INSTRUMENT_DEF => self.compile_instrument_def(&n),
+                    SyntaxKind::NODE_SECTION_DEF => self.compile_section_def(&n),
+                    SyntaxKind::NODE_CUE_DEF => self.compile_cue_def(&n),
+                    SyntaxKind::NODE_LOOP_DEF => self.compile_loop_def(&n),
+                    SyntaxKind::NODE_KEY_DEF => self.compile_key_def(&n),
+                    SyntaxKind::NODE_VOLUME_DEF | SyntaxKind::NODE_PAN_DEF => {
+                        self.compile_mix_def(&n)
+                    }
+                    SyntaxKind::NODE_PEDAL_DEF => self.compile_pedal_def(&n),
+                    SyntaxKind::NODE_TUNING_DEF => self.compile_tuning_def(&n),
+                    SyntaxKind::NODE_AT_DEF => self.compile_at_def(&n),
                     SyntaxKind::NODE_BASE_PITCH_DEF => self.compile_base_pitch_def(&n),
                     SyntaxKind::NODE_NOTE_GROUP | SyntaxKind::NODE_NOTE => {
                         self.compile_note_group(&n)
                     }
+                    SyntaxKind::NODE_ARPEGGIO => self.compile_arpeggio(&n),
+                    SyntaxKind::NODE_QUANTIZE_BLOCK => self.compile_quantize_block(&n, has_repeat),
+                    // already reported by the parser; the rest of the line still compiles
+                    SyntaxKind::NODE_ERROR => {}
                     _ => {
                         self.error(
+                            DiagnosticCode::UnexpectedSyntax,
                             format!("Unexpected node in line: {:?}", n.kind()),
                             n.text_range(),
                         );
                     }
                 },
-                NodeOrToken::Token(t) => match t.kind().into() {
+                NodeOrToken::Token(t) => match t.kind() {
                     SyntaxKind::Quantize => {
                         if let Some(dur) = self.parse_duration_fraction(&t) {
                             self.state.quantize = dur;
                             self.push_event(EventBody::QuantizeDef(dur), t.text_range());
                         }
                     }
+                    SyntaxKind::VoicePrefix => {
+                        self.line_voice = Some(SmolStr::new(t.text().trim_end_matches(':')));
+                    }
+                    SyntaxKind::PercussionPrefix => self.in_percussion = true,
+                    SyntaxKind::OctaveMode => {
+                        self.state.relative = t.text() == "(relative)";
+                        self.state.relative_anchor = None;
+                    }
+                    SyntaxKind::MultiBarRest => self.compile_multi_bar_rest(&t),
+                    SyntaxKind::BarRepeat => {
+                        *has_repeat = true;
+                        self.compile_bar_repeat(&t);
+                    }
                     SyntaxKind::Comma => {
                         // advance time by quantize
-                        self.state.time = self
-                            .state
-                            .time
-                            .add_duration(self.state.quantize, &self.state)
-                            .reduct_to_quantize(self.state.quantize);
+                        self.advance(self.state.quantize, t.text_range());
+                        self.state.time = self.state.time.reduct_to_quantize(self.state.quantize);
                     }
                     SyntaxKind::Newline
                     | SyntaxKind::Whitespace
                     | SyntaxKind::Comment
-                    | SyntaxKind::Equals => {
-                        // Ignore newlines within lines
+                    | SyntaxKind::LineContinuation
+                    | SyntaxKind::Equals
+                    | SyntaxKind::QuantizeOpen
+                    | SyntaxKind::RBrace => {
+                        // Ignore newlines within lines and quantize block delimiters
                     }
                     _ => {
                         self.error(
+                            DiagnosticCode::UnexpectedSyntax,
                             format!("Unexpected token in line: {}", t.text()),
                             t.text_range(),
                         );
@@ -144,505 +948,1021 @@ impl Compiler {
                 },
             }
         }
-        // check if current tick equals time signature or zero
-        if self.state.time.ticks > Rational32::zero()
-            && self.state.time.ticks != self.state.time_signature
-        {
+    }
+
+    /// Compiles `{8: ...}` with its own quantize, restoring the enclosing one afterwards.
+    fn compile_quantize_block(&mut self, node: &SyntaxNode, has_repeat: &mut bool) {
+        debug_assert!(node.kind().is_node_quantize_block());
+        let Some(open) = node.find_child_token_by_fn(|t| t.kind().is_quantize_open()) else {
+            return;
+        };
+        let Some(dur) = self.parse_duration_fraction(&open) else {
+            self.compile_line_items(node, has_repeat);
+            return;
+        };
+        self.quantize_stack.push(self.state.quantize);
+        self.state.quantize = dur;
+        self.push_event(EventBody::QuantizeDef(dur), open.text_range());
+        self.compile_line_items(node, has_repeat);
+        if let Some(outer) = self.quantize_stack.pop() {
+            self.state.quantize = outer;
+            let close = node
+                .find_child_token_by_fn(|t| t.kind().is_r_brace())
+                .map_or(node.text_range(), |t| t.text_range());
+            self.push_event(EventBody::QuantizeDef(outer), close);
+        }
+    }
+
+    fn buffer_last_bar(&mut self, line_start: TimeStamp, first_event: usize) {
+        if self.state.time.bars != line_start.bars {
+            return;
+        }
+        let span = self.state.time.ticks - line_start.ticks;
+        if span <= Rational64::zero() {
+            return;
+        }
+        let events = self.events[first_event..]
+            .iter()
+            .filter(|e| matches!(e.body, EventBody::Note(_)))
+            .map(|e| CompileEvent {
+                start_time: TimeStamp {
+                    ticks: e.start_time.ticks - line_start.ticks,
+                    ..TimeStamp::default()
+                },
+                ..e.clone()
+            })
+            .collect();
+        self.last_bar = Some((events, span));
+    }
+
+    fn compile_bar_repeat(&mut self, t: &SyntaxToken) {
+        debug_assert!(t.kind().is_bar_repeat());
+        let Some((events, span)) = self.last_bar.clone() else {
             self.warn(
-                "Line ended but current ticks do not align with time signature".to_string(),
-                node.text_range(),
+                DiagnosticCode::MissingPreviousNote,
+                "Bar repeat has no previous measure to repeat".to_string(),
+                t.text_range(),
             );
+            return;
+        };
+        for mut e in events {
+            if let EventBody::Note(note) = &mut e.body {
+                note.set_duration(note.duration, &self.state);
+            }
+            let start_time = self.offset_time(self.state.time, e.start_time.ticks, t.text_range());
+            self.events.push(CompileEvent {
+                start_time,
+                range_invoked: Some(t.text_range()),
+                ..e
+            });
         }
+        self.advance(span, t.text_range());
+    }
 
-        if let Some(ts) = start_time_stamp {
-            self.state.time = ts;
+    fn compile_multi_bar_rest(&mut self, t: &SyntaxToken) {
+        debug_assert!(t.kind().is_multi_bar_rest());
+        let Ok(count) = t.text()[1..].parse::<u32>() else {
+            self.error(
+                DiagnosticCode::InvalidValue,
+                format!("Invalid multi-bar rest: {}", t.text()),
+                t.text_range(),
+            );
+            return;
+        };
+        if !self.state.time.ticks.is_zero() {
+            self.warn(
+                DiagnosticCode::MisalignedMeasure,
+                "Multi-bar rest should start at the beginning of a measure".to_string(),
+                t.text_range(),
+            );
+        }
+        for i in 0..count {
+            if i > 0 {
+                self.reset_ticks();
+            }
+            if !self.within_limits(t.text_range()) {
+                break;
+            }
+            self.advance(self.state.time_signature, t.text_range());
         }
     }
 
     fn compile_macro_def(&mut self, node: &SyntaxNode) {
-        debug_assert!(
-            node.kind().is_node_macrodef_alias()
-                ||
-            node.kind().is_node_macrodef_simple()
-                || node.kind().is_node_macrodef_complex()
-        );
-        let ident_tok = node
-            .find_child_token_by_fn(|t| t.kind().is_identifier())
-            .expect("Macro definition must have an identifier token");
-        let note_kind = node.kind();
-        match note_kind {
-            SyntaxKind::NODE_MACRODEF_ALIAS => {
-                let Some(chain_node) =
-                    node.find_child_node_by_fn(|n| n.kind().is_node_pitch_chain())
-                else {
+        let Some(def) = ast::MacroDef::cast(node.clone()) else {
+            return;
+        };
+        let Some(ident_tok) = def.name() else {
+            self.error(
+                DiagnosticCode::UnexpectedSyntax,
+                "Macro definition must have a name".to_string(),
+                node.text_range(),
+            );
+            return;
+        };
+        let name = ident_tok.text();
+        let hidden = if self.macros.contains(name) {
+            let message = format!("Macro redefined: {}", name);
+            Some((DiagnosticCode::Redefinition, message, SmolStr::new(name)))
+        } else {
+            self.macros.shadowed(name).map(|outer| {
+                let message = format!("Macro {} shadows {}", name, outer);
+                (DiagnosticCode::ShadowedMacro, message, outer)
+            })
+        };
+        if let Some((code, message, hidden)) = hidden {
+            let mut warning = Diagnostic::new(
+                DiagnosticLevel::Warning,
+                code,
+                message,
+                ident_tok.text_range(),
+            );
+            if let Some(&span) = self.macros.definitions.get(&hidden) {
+                warning = warning.with_related(format!("{} is defined here", hidden), span);
+            }
+            self.report(warning);
+        }
+        self.macros
+            .definitions
+            .insert(SmolStr::new(name), ident_tok.text_range());
+        // the body of `lib.x` resolves names from inside `lib`
+        let scope = name.rsplit_once('.').map_or("", |(namespace, _)| namespace);
+        let outer_scope = std::mem::replace(&mut self.macro_scope, scope.to_string());
+        self.compile_macro_def_body(&def, &ident_tok);
+        self.macro_scope = outer_scope;
+        self.macros_snapshot = None;
+    }
+
+    fn compile_macro_def_body(&mut self, def: &ast::MacroDef, ident_tok: &SyntaxToken) {
+        let node = def.syntax();
+        match node.kind() {
+            SyntaxKind::NODE_MACRODEF_ALIAS => {
+                let Some(chain) = def.pitch_chain() else {
                     self.error(
+                        DiagnosticCode::UnexpectedSyntax,
                         "Alias macro definition must contain a pitch chain".to_string(),
                         node.text_range(),
                     );
                     return;
                 };
-                let chain_tokens: Vec<SyntaxToken> = chain_node
-                    .descendants_with_tokens()
-                    .filter_map(|nt| nt.into_token())
-                    .filter(|t| {
-                        t.kind().is_pitch()
-                            || t.kind().is_formal_pitch()
-                            || t.kind().is_identifier()
-                            || t.kind().is_at()
-                            || t.kind().is_plus()
-                    })
-                    .collect();
+                let chain_node = chain.syntax();
+                let chain_tokens = chain.atoms();
+                if let [token] = chain_tokens.as_slice() {
+                    let value = match token.kind() {
+                        SyntaxKind::PitchFrequency => {
+                            token.text().parse::<f32>().ok().map(MacroValue::Number)
+                        }
+                        SyntaxKind::PitchRatio => match Pitch::parse_ratio(token.text()) {
+                            Some(Pitch::Ratio(r)) => Some(MacroValue::Ratio(r)),
+                            _ => None,
+                        },
+                        _ => None,
+                    };
+                    if let Some(value) = value {
+                        self.macros
+                            .value_macros
+                            .insert(SmolStr::new(ident_tok.text()), value);
+                    }
+                }
                 if let Some(note) = self.parse_base_pitch_rhs_chain_tokens(
                     &chain_tokens,
                     chain_node.text_range(),
                 ) {
                     self.macros
                         .alias_macros
-                        .insert(ident_tok.text().to_string(), note.pitch_chain);
+                        .insert(SmolStr::new(ident_tok.text()), note.pitch_chain);
                 }
             }
             SyntaxKind::NODE_MACRODEF_SIMPLE => {
                 let mut pitches: Vec<Note> = Vec::new();
-                for child in node.children() {
-                    if !child.kind().is_node_note() {
-                        continue;
-                    }
+                for child in def.notes() {
                     let mut has_chain = false;
-                    for chain in child.children().filter(|n| n.kind().is_node_pitch_chain()) {
+                    for chain in child.pitch_chains() {
                         has_chain = true;
-                        let chain_tokens: Vec<SyntaxToken> = chain
-                            .descendants_with_tokens()
-                            .filter_map(|nt| nt.into_token())
-                            .filter(|t| {
-                                t.kind().is_pitch()
-                                    || t.kind().is_formal_pitch()
-                                    || t.kind().is_identifier()
-                                    || t.kind().is_at()
-                                    || t.kind().is_plus()
-                            })
-                            .collect();
+                        let range = chain.syntax().text_range();
+                        let chain_tokens = chain.atoms();
                         if chain_tokens.is_empty() {
                             self.error(
+                                DiagnosticCode::UnexpectedSyntax,
                                 "Simple macro note must contain a pitch chain".to_string(),
-                                chain.text_range(),
+                                range,
                             );
                             continue;
                         }
-                        if let Some(note) = self.parse_pitch_chain_tokens(
-                            &chain_tokens,
-                            false,
-                            chain.text_range(),
-                        ) {
+                        if let Some(note) =
+                            self.parse_pitch_chain_tokens(&chain_tokens, false, range)
+                        {
                             pitches.push(note);
                         }
                     }
                     if !has_chain {
                         self.error(
+                            DiagnosticCode::UnexpectedSyntax,
                             "Simple macro note must contain a pitch chain".to_string(),
-                            child.text_range(),
+                            child.syntax().text_range(),
                         );
                     }
                 }
                 self.macros
                     .simple_macros
-                    .insert(ident_tok.text().to_string(), pitches);
+                    .insert(SmolStr::new(ident_tok.text()), pitches);
             }
             SyntaxKind::NODE_MACRODEF_COMPLEX => {
-                let saved_state = CompileState {
-                    time: self.state.time,
-                    base_note: self.state.base_note,
-                    base_frequency: self.state.base_frequency,
-                    time_signature: self.state.time_signature,
-                    beat_duration: self.state.beat_duration,
-                    bpm: self.state.bpm,
-                    quantize: self.state.quantize,
-                    edo_def: self.state.edo_def,
-                };
+                let saved_state = self.state.clone();
                 let saved_events = take(&mut self.events);
+                let saved_last_bar = self.last_bar.take();
 
                 self.state = CompileState {
                     time: TimeStamp {
                         seconds: 0.0,
                         bars: 0,
-                        ticks: Rational32::new(0, *self.state.quantize.denom()),
+                        ticks: Rational64::new(0, *self.state.quantize.denom()),
+                        position: Rational64::zero(),
                     },
                     base_note: saved_state.base_note,
                     base_frequency: saved_state.base_frequency,
                     time_signature: saved_state.time_signature,
                     beat_duration: saved_state.beat_duration,
                     bpm: saved_state.bpm,
+                    tempo: TempoMap::new(saved_state.seconds_per_whole_note()),
                     quantize: saved_state.quantize,
-                    edo_def: saved_state.edo_def,
+                    tuning: saved_state.tuning.clone(),
+                    base_refs: saved_state.base_refs.clone(),
+                    grace_duration: saved_state.grace_duration,
+                    ornament_rate: saved_state.ornament_rate,
+                    pickup: None,
+                    transpose: saved_state.transpose,
+                    arpeggio_offset: saved_state.arpeggio_offset,
+                    fermata_factor: saved_state.fermata_factor,
+                    relative: saved_state.relative,
+                    relative_anchor: None,
+                    instruments: HashMap::new(),
                 };
 
-                let node_body = node
-                    .find_child_node_by_fn(|n| n.kind().is_node_macrodef_complex_body())
-                    .expect("Macro complex definition must have a body node");
-                for node in node_body.children() {
+                // 宏体缺失时已由解析器报告，按空宏处理
+                for node in def.body().into_iter().flat_map(|body| body.children()) {
                     self.compile_normal_line(&node);
                     self.reset_ticks();
                 }
 
                 let compiled_events = take(&mut self.events);
-                self.macros.complex_macros.insert(
-                    ident_tok.text().to_string(),
-                    compiled_events,
-                );
+                self.macros
+                    .complex_macros
+                    .insert(SmolStr::new(ident_tok.text()), compiled_events.into());
                 self.state = saved_state;
                 self.events = saved_events;
+                self.last_bar = saved_last_bar;
+            }
+            SyntaxKind::NODE_MACRODEF_TUNING => {
+                let Some(spec) = def.tuning_spec() else {
+                    return;
+                };
+                if let Some(tuning) = self.parse_tuning_spec(&spec) {
+                    self.macros
+                        .tunings
+                        .insert(SmolStr::new(ident_tok.text()), tuning);
+                }
             }
             _ => {
                 self.error(
-                    format!("Unexpected macro definition kind: {:?}", note_kind),
+                    DiagnosticCode::UnexpectedSyntax,
+                    format!("Unexpected macro definition kind: {:?}", node.kind()),
                     node.text_range(),
                 );
             }
         }
     }
 
-    fn compile_time_signature_def(&mut self, n: &SyntaxNode) {
-        debug_assert!(n.kind().is_node_time_signature_def());
-        let duration_token = n
-            .find_child_token_by_fn(|t| t.kind().is_pitch_ratio())
-            .expect("Time signature definition must have a pitch ratio token (as ./. format)");
-        let parts = duration_token.text().split('/').collect::<Vec<&str>>();
-        if parts.len() == 2 {
-            let numerator = parts[0].parse::<i32>().ok();
-            let denominator = parts[1].parse::<i32>().ok();
+    fn compile_tuning_def(&mut self, n: &SyntaxNode) {
+        debug_assert!(n.kind().is_node_tuning_def());
+        if let Some(spec) = n.find_child_token_by_fn(|t| t.kind().is_tuning_spec()) {
+            if let Some(tuning) = self.parse_tuning_spec(&spec) {
+                self.state.tuning = Some(tuning);
+            }
+        } else if let Some(name) = n.find_child_token_by_fn(|t| t.kind().is_identifier()) {
+            let key = self.macros.resolve(name.text(), &self.macro_scope);
+            match self.macros.tunings.get(&key) {
+                Some(tuning) => self.state.tuning = Some(tuning.clone()),
+                None => self.error_undefined(format!("Undefined tuning: {}", name.text()), &name),
+            }
+        }
+    }
 
-            if let (Some(n), Some(d)) = (numerator, denominator) {
-                if d == 0 {
+    /// Parses `19edo`, or loads the Scala file named by `path.scl`.
+    fn parse_tuning_spec(&mut self, t: &SyntaxToken) -> Option<Tuning> {
+        let text = t.text();
+        if let Some(edo) = text.strip_suffix("edo") {
+            return match edo.parse::<u16>() {
+                Ok(edo) if edo > 0 => Some(Tuning::Edo(edo)),
+                _ => {
                     self.error(
-                        format!("Denominator of time signature cannot be zero: {}", d),
-                        duration_token.text_range(),
-                    );
-                    return;
-                }
-                // if denominator is not pow of 2, issue warning
-                if d.reverse_bits() & (d - 1) != 0 {
-                    self.warn(
-                        format!(
-                            "Denominator of time signature is not a power of 2 but {}, which is discouraged",
-                            d
-                        ),
-                        duration_token.text_range(),
+                        DiagnosticCode::InvalidValue,
+                        format!("Invalid EDO: {}", text),
+                        t.text_range(),
                     );
+                    None
                 }
+            };
+        }
+        let path = match &self.scale_dir {
+            Some(dir) => dir.join(text),
+            None => PathBuf::from(text),
+        };
+        let Ok(contents) = fs::read_to_string(&path) else {
+            self.error(
+                DiagnosticCode::InvalidScaleFile,
+                format!("Cannot read scale file: {}", text),
+                t.text_range(),
+            );
+            return None;
+        };
+        let cents = parse_scala(&contents);
+        if cents.is_none() {
+            self.error(
+                DiagnosticCode::InvalidScaleFile,
+                format!("Invalid scale file: {}", text),
+                t.text_range(),
+            );
+        }
+        cents.map(Tuning::Scale)
+    }
 
-                let time_signature = Rational32::new(n, d);
-                self.state.time_signature = time_signature;
-                self.push_event(
-                    EventBody::TimeSignatureDef(time_signature),
-                    duration_token.text_range(),
-                );
+    fn compile_time_signature_def(&mut self, n: &SyntaxNode) {
+        debug_assert!(n.kind().is_node_time_signature_def());
+        let Some(duration_token) = n.find_child_token_by_fn(|t| t.kind().is_pitch_ratio()) else {
+            self.error(
+                DiagnosticCode::UnexpectedSyntax,
+                "Time signature definition must have a ratio (as ./. format)".to_string(),
+                n.text_range(),
+            );
+            return;
+        };
+        // beat groups before the last one in an additive time signature, e.g. `3` in `(3+2/8)`
+        let group_tokens: Vec<SyntaxToken> = n
+            .children_with_tokens()
+            .filter_map(|nt| nt.into_token())
+            .filter(|t| t.kind().is_pitch_frequency())
+            .collect();
+        let range = group_tokens
+            .first()
+            .map_or(duration_token.text_range(), |first| {
+                first.text_range().cover(duration_token.text_range())
+            });
+        let mut groups: Vec<Option<u32>> = group_tokens
+            .iter()
+            .map(|t| t.text().parse::<u32>().ok())
+            .collect();
+        let parts = duration_token.text().split('/').collect::<Vec<&str>>();
+        if parts.len() == 2 {
+            groups.push(parts[0].parse::<u32>().ok());
+            let groups = groups.into_iter().collect::<Option<Vec<u32>>>();
+            let denominator = parts[1].parse::<u32>().ok();
+
+            if let (Some(groups), Some(d)) = (groups, denominator) {
+                self.set_time_signature(TimeSignature::new(groups, d), range);
             } else {
                 self.error(
-                    format!("Invalid time signature format: {}", duration_token.text()),
-                    duration_token.text_range(),
+                    DiagnosticCode::InvalidTimeSignature,
+                    format!("Invalid time signature format: {}", n.text()),
+                    range,
                 );
             }
         } else {
             self.error(
+                DiagnosticCode::InvalidTimeSignature,
                 format!("Invalid time signature format: {}", duration_token.text()),
                 duration_token.text_range(),
             );
         }
     }
 
-    fn compile_bpm_def(&mut self, n: &SyntaxNode) {
-        debug_assert!(n.kind().is_node_bpm_def());
-        let duration_token = n.find_child_token_by_fn(|t| t.kind().is_duration_fraction());
-        let Some(bpm_token) = n.find_child_token_by_fn(|t| t.kind().is_pitch_frequency()) else {
+    fn set_time_signature(&mut self, time_signature: TimeSignature, range: TextRange) {
+        let d = time_signature.denominator;
+        if d == 0 {
             self.error(
-                "BPM definition must have a number token".to_string(),
-                n.text_range(),
+                DiagnosticCode::InvalidTimeSignature,
+                format!("Denominator of time signature cannot be zero: {}", d),
+                range,
             );
             return;
-        };
-        if let Some(bpm) = bpm_token.text().parse::<f32>().ok() {
-            if let Some(dur_tok) = duration_token {
-                if let Some(dur) = self.parse_duration_fraction(&dur_tok) {
-                    let beat_duration = dur;
-                    self.state.beat_duration = beat_duration;
-                    self.push_event(
-                        EventBody::BeatDurationDef(beat_duration),
-                        dur_tok.text_range(),
-                    );
-                }
-            }
-            self.state.bpm = bpm;
-            self.push_event(EventBody::BPMDef(bpm), bpm_token.text_range());
-        } else {
+        }
+        if time_signature.groups.contains(&0) {
             self.error(
-                format!("Invalid BPM value: {}", bpm_token.text()),
-                bpm_token.text_range(),
+                DiagnosticCode::InvalidTimeSignature,
+                format!(
+                    "Beat groups of time signature must be positive: {}",
+                    time_signature
+                ),
+                range,
             );
+            return;
         }
-    }
-
-    fn parse_duration_fraction(&mut self, t: &SyntaxToken) -> Option<Rational32> {
-        let rs = (|| {
-            debug_assert!(t.kind().is_duration_fraction() || t.kind().is_quantize());
-            let text = t.text().trim_matches(&['[', ']', '{', '}']); //also trim '{' '}'
-            let parts: Vec<&str> = text.split(':').collect();
-            let numerator: i32 = parts.get(1).and_then(|s| s.parse().ok()).unwrap_or(1);
-            let denominator: i32 = parts[0].parse().ok()?;
-            if denominator != 0 {
-                return Some(Rational32::new(numerator, denominator));
-            }
-            None
-        })();
-        if rs.is_none() {
-            self.error(
-                format!("Invalid duration format: {}", t.text()),
-                t.text_range(),
+        // if denominator is not pow of 2, issue warning
+        if !d.is_power_of_two() {
+            self.warn(
+                DiagnosticCode::DiscouragedTimeSignature,
+                format!(
+                    "Denominator of time signature is not a power of 2 but {}, which is discouraged",
+                    d
+                ),
+                range,
             );
         }
-        rs
-    }
 
-    fn parse_duration_commas(&mut self, t: &SyntaxToken) -> Option<u32> {
-        debug_assert!(t.kind().is_duration_commas());
-        let text = t.text();
-        let count = text.chars().filter(|&c| c == ',').count() as u32;
-        Some(count)
+        self.state.time_signature = time_signature.bar_length();
+        self.push_event(EventBody::TimeSignatureDef(time_signature), range);
     }
 
-    fn compile_base_pitch_def(&mut self, n: &SyntaxNode) {
-        debug_assert!(n.kind().is_node_base_pitch_def());
-        let pitch_spell = n
-            .find_child_token_by_fn(|t| {
-                t.kind().is_pitch_spell_octave() || t.kind().is_pitch_spell_simple()
-            })
-            .and_then(|t| self.parse_pitch(&t, false));
-
-        let pitch_ref = n
-            .find_child_node_by_fn(|child| child.kind().is_node_pitch_chain())
-            .and_then(|chain_node| {
-                let chain_tokens: Vec<SyntaxToken> = chain_node
-                    .descendants_with_tokens()
-                    .filter_map(|nt| nt.into_token())
-                    .filter(|t| {
-                        t.kind().is_pitch()
-                            || t.kind().is_formal_pitch()
-                            || t.kind().is_identifier()
-                            || t.kind().is_at()
-                            || t.kind().is_plus()
-                    })
-                    .collect();
-                self.parse_base_pitch_rhs_chain_tokens(&chain_tokens, chain_node.text_range())
-            });
+    fn resolve_value_macro(&mut self, t: &SyntaxToken) -> Option<MacroValue> {
+        let key = self.macros.resolve(t.text(), &self.macro_scope);
+        let value = self.macros.value_macros.get(&key).copied();
+        if value.is_none() {
+            self.error_undefined(format!("Undefined value macro: {}", t.text()), t);
+        }
+        value
+    }
 
-        if let Some(spell) = pitch_spell {
-            self.state.base_note = match spell.pitch_chain.first().copied() {
-                Some(Pitch::SpellOctave(s)) => s,
-                Some(Pitch::SpellSimple(s)) => s,
-                _ => unreachable!("Base pitch must be a spell"),
-            };
-            self.state.base_frequency = pitch_ref.map(|p| p.freq).unwrap_or(spell.freq);
-            self.push_event(EventBody::BaseNoteDef(self.state.base_note), n.text_range());
-            self.push_event(
-                EventBody::BaseFequencyDef(self.state.base_frequency),
+    fn compile_pickup_def(&mut self, n: &SyntaxNode) {
+        debug_assert!(n.kind().is_node_pickup_def());
+        let Some(duration_token) = n.find_child_token_by_fn(|t| t.kind().is_pitch_ratio()) else {
+            self.error(
+                DiagnosticCode::UnexpectedSyntax,
+                "Pickup definition must have a duration (as ./. format)".to_string(),
+                n.text_range(),
+            );
+            return;
+        };
+        let Some(Pitch::Ratio(duration)) = Pitch::parse_ratio(duration_token.text()) else {
+            self.error(
+                DiagnosticCode::InvalidValue,
+                format!("Invalid pickup duration: {}", duration_token.text()),
+                duration_token.text_range(),
+            );
+            return;
+        };
+        if self.state.time.bars != 0 || !self.state.time.ticks.is_zero() {
+            self.error(
+                DiagnosticCode::PositionBeforeCurrent,
+                "Pickup must be declared before the first measure".to_string(),
                 n.text_range(),
             );
+            return;
+        }
+        if duration >= self.state.time_signature {
+            self.warn(
+                DiagnosticCode::MisalignedMeasure,
+                "Pickup is not shorter than a full measure".to_string(),
+                duration_token.text_range(),
+            );
+        }
+        self.state.pickup = Some(duration);
+    }
+
+    /// Moves the cursor forward to `(at bar:tick)` (1-based bar) or `(at Ns)`.
+    /// Second positions are snapped to the quantize grid so that ticks and
+    /// seconds stay consistent under the current tempo.
+    fn compile_at_def(&mut self, n: &SyntaxNode) {
+        debug_assert!(n.kind().is_node_at_def());
+        let now = self.state.time;
+        let distance = if let Some(t) = n.find_child_token_by_fn(|t| t.kind().is_time_seconds()) {
+            let Ok(seconds) = t.text().trim_end_matches('s').parse::<f64>() else {
+                self.error(
+                    DiagnosticCode::InvalidValue,
+                    format!("Invalid absolute time: {}", t.text()),
+                    t.text_range(),
+                );
+                return;
+            };
+            let offset = seconds - now.seconds;
+            if offset < -1e-9 {
+                self.error(
+                    DiagnosticCode::PositionBeforeCurrent,
+                    format!("Absolute time {} is before the current position", t.text()),
+                    t.text_range(),
+                );
+                return;
+            }
+            let quantize_sec = TimeStamp::dur_in_sec(self.state.quantize, &self.state);
+            let steps = (offset / quantize_sec).round() as i64;
+            Rational64::from_integer(steps) * self.state.quantize
         } else {
-            if let Some(pitch_ref) = pitch_ref {
-                self.state.base_note = freq2spell(pitch_ref.freq, &self.state);
-                self.state.base_frequency = pitch_ref.freq;
-                self.push_event(EventBody::BaseNoteDef(self.state.base_note), n.text_range());
-                self.push_event(
-                    EventBody::BaseFequencyDef(self.state.base_frequency),
+            let Some(bar_token) = n.find_child_token_by_fn(|t| t.kind().is_pitch_frequency())
+            else {
+                self.error(
+                    DiagnosticCode::UnexpectedSyntax,
+                    "Absolute position must have a bar number or a time in seconds".to_string(),
                     n.text_range(),
                 );
-            } else {
+                return;
+            };
+            let Some(bar) = bar_token.text().parse::<u32>().ok().filter(|b| *b > 0) else {
                 self.error(
-                    "Base pitch definition must have either a pitch spell or pitch chain reference"
-                        .to_string(),
+                    DiagnosticCode::InvalidValue,
+                    format!("Invalid bar number: {}", bar_token.text()),
+                    bar_token.text_range(),
+                );
+                return;
+            };
+            let tick = match n.find_child_token_by_fn(|t| t.kind().is_pitch_ratio()) {
+                Some(t) => match Pitch::parse_ratio(t.text()) {
+                    Some(Pitch::Ratio(r)) if r < self.state.time_signature => r,
+                    _ => {
+                        self.error(
+                            DiagnosticCode::InvalidValue,
+                            format!("Invalid tick within the measure: {}", t.text()),
+                            t.text_range(),
+                        );
+                        return;
+                    }
+                },
+                None => Rational64::zero(),
+            };
+            let bar = bar - 1;
+            if bar < now.bars || (bar == now.bars && tick < now.ticks) {
+                self.error(
+                    DiagnosticCode::PositionBeforeCurrent,
+                    "Absolute position is before the current position".to_string(),
                     n.text_range(),
                 );
+                return;
             }
-        }
+            if bar == now.bars {
+                tick - now.ticks
+            } else {
+                let bar_length = self.state.pickup.unwrap_or(self.state.time_signature);
+                let rest_of_bar = if now.ticks < bar_length {
+                    bar_length - now.ticks
+                } else {
+                    Rational64::zero()
+                };
+                rest_of_bar
+                    + Rational64::from_integer((bar - now.bars - 1) as i64)
+                        * self.state.time_signature
+                    + tick
+            }
+        };
+        self.advance_across_bars(distance, n.text_range());
     }
 
-    fn parse_pitch_chain_ident_as_chain_for_base_rhs(
-        &mut self,
-        t: &SyntaxToken,
-    ) -> Option<Vec<Pitch>> {
-        debug_assert!(t.kind().is_identifier());
-        let ident = t.text().to_string();
+    /// Advances the cursor by `distance`, starting a new measure at each bar line crossed.
+    fn advance_across_bars(&mut self, mut distance: Rational64, span: TextRange) {
+        loop {
+            let bar_length = self.state.pickup.unwrap_or(self.state.time_signature);
+            let rest_of_bar = bar_length - self.state.time.ticks;
+            if distance < rest_of_bar {
+                break;
+            }
+            if rest_of_bar > Rational64::zero() {
+                self.advance(rest_of_bar, span);
+                distance = distance - rest_of_bar;
+            }
+            self.reset_ticks();
+        }
+        if distance > Rational64::zero() {
+            self.advance(distance, span);
+        }
+    }
 
-        if let Some(chain) = self.macros.alias_macros.get(ident.as_str()) {
-            return Some(chain.clone());
+    fn compile_transpose_def(&mut self, n: &SyntaxNode) {
+        debug_assert!(n.kind().is_node_transpose_def());
+        if let Some(factor) = self.parse_interval_factor(n, "Transpose") {
+            self.state.transpose = factor;
         }
+    }
 
-        if self.macros.simple_macros.contains_key(ident.as_str()) {
-            self.error(
-                format!(
-                    "Identifier in base pitch RHS must resolve to an alias macro: {}",
-                    ident
-                ),
-                t.text_range(),
-            );
-            return None;
+    fn compile_capo_def(&mut self, n: &SyntaxNode) {
+        debug_assert!(n.kind().is_node_capo_def());
+        if let Some(factor) = self.parse_interval_factor(n, "Capo") {
+            self.push_event(EventBody::CapoDef(factor), n.text_range());
         }
+    }
 
-        if self.macros.complex_macros.contains_key(ident.as_str()) {
-            self.error(
-                format!(
-                    "Identifier in base pitch RHS cannot resolve to a complex macro: {}",
-                    ident
-                ),
-                t.text_range(),
-            );
-            return None;
+    /// Sets the General MIDI program of the line's voice from an instrument name, or from a
+    /// program number counted from 1 as in the General MIDI tables.
+    fn compile_instrument_def(&mut self, n: &SyntaxNode) {
+        debug_assert!(n.kind().is_node_instrument_def());
+        // a missing argument is reported by the parser
+        let Some(t) =
+            n.find_child_token_by_fn(|t| t.kind().is_identifier() || t.kind().is_pitch_frequency())
+        else {
+            return;
+        };
+        let program = if t.kind().is_identifier() {
+            let program = self
+                .macros
+                .instruments
+                .get(&normalize_instrument_name(t.text()))
+                .copied();
+            if program.is_none() {
+                self.error(
+                    DiagnosticCode::UndefinedName,
+                    format!("Unknown instrument: {}", t.text()),
+                    t.text_range(),
+                );
+            }
+            program
+        } else {
+            let program = t
+                .text()
+                .parse::<u8>()
+                .ok()
+                .filter(|p| (1..=128).contains(p));
+            if program.is_none() {
+                self.error(
+                    DiagnosticCode::InvalidValue,
+                    format!("Instrument program must be between 1 and 128: {}", t.text()),
+                    t.text_range(),
+                );
+            }
+            program.map(|p| p - 1)
+        };
+        if let Some(program) = program {
+            self.state
+                .instruments
+                .insert(self.line_voice.clone(), program);
         }
+    }
 
-        self.error(
-            format!("Undefined identifier in base pitch RHS: {}", ident),
-            t.text_range(),
-        );
-        None
+    /// Marks the start of a section at the cursor.
+    fn compile_section_def(&mut self, n: &SyntaxNode) {
+        debug_assert!(n.kind().is_node_section_def());
+        // a missing name is reported by the parser
+        if let Some(t) = n.find_child_token_by_fn(|t| t.kind().is_lyric()) {
+            let name = t.text().trim_matches('"').trim().to_string();
+            self.push_event(EventBody::Marker(name), n.text_range());
+        }
     }
 
-    fn parse_base_pitch_rhs_chain_tokens(
-        &mut self,
-        tokens: &[SyntaxToken],
-        range: TextRange,
-    ) -> Option<Note> {
-        if tokens.is_empty() {
-            return None;
+    /// Places a cue point at the cursor.
+    fn compile_cue_def(&mut self, n: &SyntaxNode) {
+        debug_assert!(n.kind().is_node_cue_def());
+        // a missing name is reported by the parser
+        if let Some(t) = n.find_child_token_by_fn(|t| t.kind().is_lyric()) {
+            let name = t.text().trim_matches('"').trim().to_string();
+            self.push_event(EventBody::CuePoint(name), n.text_range());
         }
+    }
 
-        let mut pitch_atoms: Vec<Pitch> = Vec::new();
-        let mut expect_pitch = true;
+    /// Starts or ends a loop region at the cursor.
+    fn compile_loop_def(&mut self, n: &SyntaxNode) {
+        debug_assert!(n.kind().is_node_loop_def());
+        // a missing boundary is reported by the parser
+        let Some(boundary) = n.find_child_token_by_fn(|t| t.kind().is_identifier()) else {
+            return;
+        };
+        let body = match boundary.text() {
+            "start" => EventBody::LoopStart,
+            "end" => EventBody::LoopEnd,
+            other => {
+                self.error(
+                    DiagnosticCode::InvalidValue,
+                    format!("Loop boundary must be start or end: {}", other),
+                    boundary.text_range(),
+                );
+                return;
+            }
+        };
+        self.push_event(body, n.text_range());
+    }
 
-        for token in tokens {
-            if expect_pitch {
-                if token.kind().is_pitch() || token.kind().is_formal_pitch() {
-                    let pitch = self.parse_pitch_atom(token, false)?;
-                    pitch_atoms.push(pitch);
-                    expect_pitch = false;
-                } else if token.kind().is_identifier() {
-                    let chain = self.parse_pitch_chain_ident_as_chain_for_base_rhs(token)?;
-                    if chain.is_empty() {
-                        self.error(
-                            "Identifier in base pitch RHS cannot resolve to an empty pitch chain"
-                                .to_string(),
-                            token.text_range(),
-                        );
-                        return None;
-                    }
-                    pitch_atoms.extend(chain);
-                    expect_pitch = false;
-                } else {
-                    self.error(
-                        format!("Expected pitch token, got: {}", token.text()),
-                        token.text_range(),
-                    );
-                    return None;
-                }
-            } else if token.kind().is_at() {
-                expect_pitch = true;
-            } else if token.kind().is_plus() {
-                pitch_atoms.push(Pitch::Ratio(Rational32::new(2, 1)));
-            } else if token.kind().is_pitch_sustain() {
-                pitch_atoms.push(Pitch::Ratio(Rational32::new(1, 2)));
-            } else {
+    /// Sets the volume (0 to 1) or pan (-1 to 1) of the line's voice; a `~` after the level
+    /// glides to the next setting of the same kind.
+    fn compile_mix_def(&mut self, n: &SyntaxNode) {
+        let pan = n.kind().is_node_pan_def();
+        // a missing level is reported by the parser
+        let Some(t) = n.find_child_token_by_fn(|t| t.kind().is_pitch_frequency()) else {
+            return;
+        };
+        let (name, min) = if pan { ("Pan", -1.0) } else { ("Volume", 0.0) };
+        let Some(value) = t
+            .text()
+            .parse::<f32>()
+            .ok()
+            .filter(|v| (min..=1.0).contains(v))
+        else {
+            self.error(
+                DiagnosticCode::InvalidValue,
+                format!("{} must be between {} and 1: {}", name, min, t.text()),
+                t.text_range(),
+            );
+            return;
+        };
+        let level = MixLevel {
+            value,
+            ramp: n
+                .find_child_token_by_fn(|t| t.kind().is_portamento())
+                .is_some(),
+        };
+        let body = if pan {
+            EventBody::PanDef(level)
+        } else {
+            EventBody::VolumeDef(level)
+        };
+        self.push_event(body, n.text_range());
+    }
+
+    /// Presses or releases the sustain pedal of the line's voice.
+    fn compile_pedal_def(&mut self, n: &SyntaxNode) {
+        debug_assert!(n.kind().is_node_pedal_def());
+        // a missing state is reported by the parser
+        let Some(state) = n.find_child_token_by_fn(|t| t.kind().is_identifier()) else {
+            return;
+        };
+        let down = match state.text() {
+            "down" => true,
+            "up" => false,
+            other => {
                 self.error(
-                    format!("Expected '@' in pitch chain, got: {}", token.text()),
-                    token.text_range(),
+                    DiagnosticCode::InvalidValue,
+                    format!("Pedal must be down or up: {}", other),
+                    state.text_range(),
                 );
-                return None;
+                return;
+            }
+        };
+        self.push_event(EventBody::SustainPedal(down), n.text_range());
+    }
+
+    /// Sets the key signature from the tonic and optional mode of `(key ...)`.
+    fn compile_key_def(&mut self, n: &SyntaxNode) {
+        debug_assert!(n.kind().is_node_key_def());
+        // a missing tonic is reported by the parser
+        let Some(tonic) = n.find_child_token_by_fn(|t| t.kind().is_pitch_spell_simple()) else {
+            return;
+        };
+        let minor = match n.find_child_token_by_fn(|t| t.kind().is_identifier()) {
+            None => false,
+            Some(mode) if mode.text() == "major" => false,
+            Some(mode) if mode.text() == "minor" => true,
+            Some(mode) => {
+                self.error(
+                    DiagnosticCode::InvalidValue,
+                    format!("Key mode must be major or minor: {}", mode.text()),
+                    mode.text_range(),
+                );
+                return;
             }
+        };
+        match KeySignature::from_tonic(tonic.text(), minor) {
+            Some(key) => self.push_event(EventBody::KeySignatureDef(key), n.text_range()),
+            None => self.error(
+                DiagnosticCode::InvalidValue,
+                format!("No key signature for tonic: {}", tonic.text()),
+                tonic.text_range(),
+            ),
         }
+    }
 
-        if expect_pitch {
-            self.error("Pitch chain cannot end with '@'".to_string(), range);
+    /// General MIDI program of the voice of the line being compiled.
+    fn instrument(&self) -> Option<u8> {
+        self.state.instruments.get(&self.line_voice).copied()
+    }
+
+    /// Frequency factor of the ratio, cents or EDO interval of `(transpose ...)` or `(capo ...)`.
+    fn parse_interval_factor(&mut self, n: &SyntaxNode, name: &str) -> Option<f64> {
+        let Some(interval_token) = n.find_child_token_by_fn(|t| {
+            t.kind().is_pitch_ratio() || t.kind().is_pitch_cents() || t.kind().is_pitch_edo()
+        }) else {
+            self.error(
+                DiagnosticCode::UnexpectedSyntax,
+                format!("{} definition must have an interval", name),
+                n.text_range(),
+            );
             return None;
+        };
+        let text = interval_token.text();
+        let factor = match interval_token.kind() {
+            SyntaxKind::PitchRatio => Pitch::parse_ratio(text),
+            SyntaxKind::PitchCents => Pitch::parse_cents(text),
+            _ => Pitch::parse_edo(text),
         }
+        .and_then(|pitch| match pitch {
+            Pitch::Ratio(r) => Some(r.to_f64()),
+            Pitch::Cents(c) => Some(2f64.powf(c / 1200.0)),
+            Pitch::Edo(r) => Some(2f64.powf(r.to_f64())),
+            _ => None,
+        })
+        .filter(|f| f.is_finite() && *f > 0.0);
+        if factor.is_none() {
+            self.error(
+                DiagnosticCode::InvalidValue,
+                format!("Invalid {} interval: {}", name.to_lowercase(), text),
+                interval_token.text_range(),
+            );
+        }
+        factor
+    }
 
-        self.eval_pitch_chain_pitches(&pitch_atoms, range)
+    fn compile_bpm_def(&mut self, n: &SyntaxNode) {
+        debug_assert!(n.kind().is_node_bpm_def());
+        let duration_token = n.find_child_token_by_fn(|t| t.kind().is_duration_fraction());
+        let Some(bpm_token) =
+            n.find_child_token_by_fn(|t| t.kind().is_pitch_frequency() || t.kind().is_identifier())
+        else {
+            self.error(
+                DiagnosticCode::UnexpectedSyntax,
+                "BPM definition must have a number token".to_string(),
+                n.text_range(),
+            );
+            return;
+        };
+        let bpm = if bpm_token.kind().is_identifier() {
+            match self.resolve_value_macro(&bpm_token) {
+                Some(MacroValue::Number(bpm)) => Ok(bpm),
+                Some(MacroValue::Ratio(r)) if duration_token.is_none() => {
+                    // `(name)` holding a ratio is a time signature
+                    match (u32::try_from(*r.numer()), u32::try_from(*r.denom())) {
+                        (Ok(n), Ok(d)) => self.set_time_signature(
+                            TimeSignature::simple(n, d),
+                            bpm_token.text_range(),
+                        ),
+                        _ => self.error(
+                            DiagnosticCode::InvalidTimeSignature,
+                            format!("Invalid time signature: {}/{}", r.numer(), r.denom()),
+                            bpm_token.text_range(),
+                        ),
+                    }
+                    return;
+                }
+                Some(MacroValue::Ratio(_)) => {
+                    self.error(
+                        DiagnosticCode::MacroKindMismatch,
+                        format!("Value macro is not a number: {}", bpm_token.text()),
+                        bpm_token.text_range(),
+                    );
+                    return;
+                }
+                None => return,
+            }
+        } else {
+            bpm_token.text().parse::<f32>()
+        };
+        if let Ok(bpm) = bpm {
+            let mut beat_duration = self.state.beat_duration;
+            if let Some(dur_tok) = duration_token
+                && let Some(dur) = self.parse_duration_fraction(&dur_tok)
+            {
+                beat_duration = dur;
+                self.push_event(
+                    EventBody::BeatDurationDef(beat_duration),
+                    dur_tok.text_range(),
+                );
+            }
+            self.state.set_tempo(bpm, beat_duration);
+            self.push_event(EventBody::BPMDef(bpm), bpm_token.text_range());
+        } else {
+            self.error(
+                DiagnosticCode::InvalidValue,
+                format!("Invalid BPM value: {}", bpm_token.text()),
+                bpm_token.text_range(),
+            );
+        }
     }
 
-    fn parse_pitch_atom(&mut self, t: &SyntaxToken, allow_formal: bool) -> Option<Pitch> {
-        debug_assert!(t.kind().is_pitch() || t.kind().is_formal_pitch());
-        if !allow_formal {
-            if t.kind().is_formal_pitch() {
+    fn parse_duration_fraction(&mut self, t: &SyntaxToken) -> Option<Rational64> {
+        let name = t.text().trim_matches(['[', ']', '{', '}', ':']);
+        if (t.kind().is_quantize() || t.kind().is_quantize_open())
+            && name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        {
+            let key = self.macros.resolve(name, &self.macro_scope);
+            let Some(value) = self.macros.value_macros.get(&key).copied() else {
                 self.error(
-                    format!("Formal pitch not allowed here: {}", t.text()),
+                    DiagnosticCode::UndefinedName,
+                    format!("Undefined value macro: {}", name),
                     t.text_range(),
                 );
                 return None;
-            }
-        }
-        let text = t.text();
-        match t.kind() {
-            SyntaxKind::PitchSpellOctave => Pitch::parse_spell_octave(text),
-            SyntaxKind::PitchSpellSimple => Pitch::parse_spell_simple(text),
-            SyntaxKind::PitchFrequency => {
-                // handle edo grammar sugar: if edo_def is set and the token text is an integer, parse it as edo and convert to frequency
-                if self.state.edo_def == 0 || text.contains('.') {
-                    if text
-                        .parse::<f32>()
-                        .ok()
-                        .filter(|&f| f >= 1.0 && f < 1e8)
-                        .is_some()
-                    {
-                        self.state.edo_def = 0;
-                        Pitch::parse_fequency(text)
-                    } else {
-                        self.error(format!("Invalid frequency value: {}", text), t.text_range());
-                        None
-                    }
-                } else {
-                    Pitch::parse_edo(format!("{}\\{}", text, self.state.edo_def).as_str())
+            };
+            return match value {
+                MacroValue::Number(d) if d >= 1.0 && d.fract() == 0.0 => {
+                    Some(Rational64::new(1, d as i64))
                 }
-            }
-            SyntaxKind::PitchRatio => Pitch::parse_ratio(text),
-            SyntaxKind::PitchEdo => {
-                let p = Pitch::parse_edo(text);
-                if let Some(Pitch::Edo(r)) = p {
-                    self.state.edo_def = *r.denom() as u16;
+                MacroValue::Ratio(r) if *r.numer() > 0 => Some(r),
+                _ => {
+                    self.error(
+                        DiagnosticCode::InvalidValue,
+                        format!("Invalid quantize value: {}", name),
+                        t.text_range(),
+                    );
+                    None
                 }
-                p
-            }
-            SyntaxKind::PitchCents => Pitch::parse_cents(text),
-            SyntaxKind::PitchRest => Some(Pitch::Rest),
-            SyntaxKind::PitchSustain => Some(Pitch::Sustain),
-            _ => {
-                self.error(format!("Invalid pitch token: {}", text), t.text_range());
-                None
+            };
+        }
+        let rs = (|| {
+            debug_assert!(
+                t.kind().is_duration_fraction()
+                    || t.kind().is_quantize()
+                    || t.kind().is_quantize_open()
+            );
+            let text = t.text().trim_matches(['[', ']', '{', '}', ':']); //also trim '{' '}' ':'
+            let parts: Vec<&str> = text.split(':').collect();
+            let numerator: i64 = parts.get(1).and_then(|s| s.parse().ok()).unwrap_or(1);
+            let denominator: i64 = parts[0].parse().ok()?;
+            if denominator != 0 {
+                return Some(Rational64::new(numerator, denominator));
             }
+            None
+        })();
+        if rs.is_none() {
+            self.error(
+                DiagnosticCode::InvalidValue,
+                format!("Invalid duration format: {}", t.text()),
+                t.text_range(),
+            );
         }
+        rs
     }
 
-    fn parse_pitch(&mut self, t: &SyntaxToken, allow_formal: bool) -> Option<Note> {
-        self.parse_pitch_atom(t, allow_formal)
-            .map(|pitch| Note::from_pitch(pitch, &self.state))
+    fn parse_duration_commas(&mut self, t: &SyntaxToken) -> Option<u32> {
+        debug_assert!(t.kind().is_duration_commas());
+        let text = t.text();
+        let count = text.chars().filter(|&c| c == ',').count() as u32;
+        Some(count)
     }
 
-    fn parse_pitch_chain_ident_as_chain(&mut self, t: &SyntaxToken) -> Option<Vec<Pitch>> {
-        debug_assert!(t.kind().is_identifier());
-        let ident = t.text().to_string();
+    fn compile_base_pitch_def(&mut self, n: &SyntaxNode) {
+        debug_assert!(n.kind().is_node_base_pitch_def());
+        // base pitch is defined in notation space; transposition and relative entry apply to notes only
+        let transpose = std::mem::replace(&mut self.state.transpose, 1.0);
+        let relative = std::mem::replace(&mut self.state.relative, false);
+        let pitch_spell = n
+            .find_child_token_by_fn(|t| {
+                t.kind().is_pitch_spell_octave() || t.kind().is_pitch_spell_simple()
+            })
+            .and_then(|t| self.parse_pitch(&t, false));
 
-        if let Some(chain) = self.macros.alias_macros.get(ident.as_str()) {
-            if chain.is_empty() {
+        let pitch_ref = n
+            .children()
+            .find_map(ast::PitchChain::cast)
+            .and_then(|chain| {
+                self.parse_base_pitch_rhs_chain_tokens(&chain.atoms(), chain.syntax().text_range())
+            });
+
+        if let Some(name) = n.find_child_token_by_fn(|t| t.kind().is_identifier()) {
+            if let Some(pitch_ref) = pitch_ref {
+                let base_note = match pitch_ref.pitch_chain.as_slice() {
+                    [Pitch::SpellOctave(s)] | [Pitch::SpellSimple(s)] => *s,
+                    _ => freq2spell(pitch_ref.freq, &self.state),
+                };
+                self.state
+                    .base_refs
+                    .insert(SmolStr::new(name.text()), (base_note, pitch_ref.freq));
+            } else {
                 self.error(
-                    format!(
-                        "Identifier in pitch chain cannot resolve to an empty alias macro: {}",
-                        ident
-                    ),
-                    t.text_range(),
+                    DiagnosticCode::UnexpectedSyntax,
+                    format!("Base reference {} must have a pitch chain", name.text()),
+                    n.text_range(),
                 );
-                return None;
             }
+        } else if let Some(spell) = pitch_spell {
+            self.state.base_note = match spell.pitch_chain.first().copied() {
+                Some(Pitch::SpellOctave(s)) => s,
+                Some(Pitch::SpellSimple(s)) => s,
+                _ => freq2spell(spell.freq, &self.state),
+            };
+            self.state.base_frequency = pitch_ref.map(|p| p.freq).unwrap_or(spell.freq);
+            self.push_event(EventBody::BaseNoteDef(self.state.base_note), n.text_range());
+            self.push_event(
+                EventBody::BaseFequencyDef(self.state.base_frequency),
+                n.text_range(),
+            );
+        } else {
+            if let Some(pitch_ref) = pitch_ref {
+                self.state.base_note = freq2spell(pitch_ref.freq, &self.state);
+                self.state.base_frequency = pitch_ref.freq;
+                self.push_event(EventBody::BaseNoteDef(self.state.base_note), n.text_range());
+                self.push_event(
+                    EventBody::BaseFequencyDef(self.state.base_frequency),
+                    n.text_range(),
+                );
+            } else {
+                self.error(
+                    DiagnosticCode::UnexpectedSyntax,
+                    "Base pitch definition must have either a pitch spell or pitch chain reference"
+                        .to_string(),
+                    n.text_range(),
+                );
+            }
+        }
+        self.state.transpose = transpose;
+        self.state.relative = relative;
+    }
+
+    fn parse_pitch_chain_ident_as_chain_for_base_rhs(
+        &mut self,
+        t: &SyntaxToken,
+    ) -> Option<Vec<Pitch>> {
+        debug_assert!(t.kind().is_identifier());
+        let ident = self.macros.resolve(t.text(), &self.macro_scope);
+
+        if let Some(chain) = self.macros.alias_macros.get(ident.as_str()) {
             return Some(chain.clone());
         }
 
         if self.macros.simple_macros.contains_key(ident.as_str()) {
             self.error(
+                DiagnosticCode::MacroKindMismatch,
                 format!(
-                    "Identifier in pitch chain must resolve to an alias macro: {}",
+                    "Identifier in base pitch RHS must resolve to an alias macro: {}",
                     ident
                 ),
                 t.text_range(),
@@ -652,8 +1972,9 @@ impl Compiler {
 
         if self.macros.complex_macros.contains_key(ident.as_str()) {
             self.error(
+                DiagnosticCode::MacroKindMismatch,
                 format!(
-                    "Identifier in pitch chain cannot resolve to a complex macro: {}",
+                    "Identifier in base pitch RHS cannot resolve to a complex macro: {}",
                     ident
                 ),
                 t.text_range(),
@@ -661,57 +1982,60 @@ impl Compiler {
             return None;
         }
 
-        self.error(
-            format!("Undefined identifier in pitch chain: {}", ident),
-            t.text_range(),
+        self.error_undefined(
+            format!("Undefined identifier in base pitch RHS: {}", ident),
+            t,
         );
         None
     }
 
-    fn parse_pitch_chain_tokens(
+    fn parse_base_pitch_rhs_chain_tokens(
         &mut self,
         tokens: &[SyntaxToken],
-        allow_formal_single: bool,
         range: TextRange,
     ) -> Option<Note> {
         if tokens.is_empty() {
             return None;
         }
 
-        let mut pitch_atoms: Vec<(Pitch, TextRange)> = Vec::new();
+        let mut pitch_atoms: Vec<Pitch> = Vec::new();
         let mut expect_pitch = true;
-        let mut has_chain = false;
 
         for token in tokens {
             if expect_pitch {
                 if token.kind().is_pitch() || token.kind().is_formal_pitch() {
-                    let pitch = self.parse_pitch_atom(token, allow_formal_single)?;
-                    pitch_atoms.push((pitch, token.text_range()));
+                    pitch_atoms.extend(self.parse_pitch_atoms(token, false)?);
                     expect_pitch = false;
                 } else if token.kind().is_identifier() {
-                    let chain = self.parse_pitch_chain_ident_as_chain(token)?;
-                    for pitch in chain {
-                        pitch_atoms.push((pitch, token.text_range()));
+                    let chain = self.parse_pitch_chain_ident_as_chain_for_base_rhs(token)?;
+                    if chain.is_empty() {
+                        self.error(
+                            DiagnosticCode::MacroKindMismatch,
+                            "Identifier in base pitch RHS cannot resolve to an empty pitch chain"
+                                .to_string(),
+                            token.text_range(),
+                        );
+                        return None;
                     }
+                    pitch_atoms.extend(chain);
                     expect_pitch = false;
                 } else {
                     self.error(
+                        DiagnosticCode::InvalidPitchChain,
                         format!("Expected pitch token, got: {}", token.text()),
                         token.text_range(),
                     );
                     return None;
                 }
             } else if token.kind().is_at() {
-                has_chain = true;
                 expect_pitch = true;
             } else if token.kind().is_plus() {
-                has_chain = true;
-                pitch_atoms.push((Pitch::Ratio(Rational32::new(2, 1)), token.text_range()));
+                pitch_atoms.push(Pitch::Ratio(Rational64::new(2, 1)));
             } else if token.kind().is_pitch_sustain() {
-                has_chain = true;
-                pitch_atoms.push((Pitch::Ratio(Rational32::new(1, 2)), token.text_range()));
+                pitch_atoms.push(Pitch::Ratio(Rational64::new(1, 2)));
             } else {
                 self.error(
+                    DiagnosticCode::InvalidPitchChain,
                     format!("Expected '@' in pitch chain, got: {}", token.text()),
                     token.text_range(),
                 );
@@ -720,16 +2044,277 @@ impl Compiler {
         }
 
         if expect_pitch {
-            self.error("Pitch chain cannot end with '@'".to_string(), range);
-            return None;
+            self.error(
+                DiagnosticCode::InvalidPitchChain,
+                "Pitch chain cannot end with '@'".to_string(),
+                range,
+            );
+            return None;
+        }
+
+        self.eval_pitch_chain_pitches(&pitch_atoms, range)
+    }
+
+    fn parse_pitch_atom(&mut self, t: &SyntaxToken, allow_formal: bool) -> Option<Pitch> {
+        debug_assert!(t.kind().is_pitch() || t.kind().is_formal_pitch());
+        if !allow_formal && t.kind().is_formal_pitch() {
+            self.error(
+                DiagnosticCode::InvalidPitchChain,
+                format!("Formal pitch not allowed here: {}", t.text()),
+                t.text_range(),
+            );
+            return None;
+        }
+        let text = t.text();
+        match t.kind() {
+            SyntaxKind::PitchSpellOctave => Pitch::parse_spell_octave(text),
+            SyntaxKind::PitchSpellSimple => Pitch::parse_spell_simple(text),
+            SyntaxKind::PitchFrequency => {
+                // step sugar: under an active tuning, an integer token is a step of that tuning
+                if let Some(tuning) = self.state.tuning.as_ref().filter(|_| !text.contains('.')) {
+                    match tuning {
+                        Tuning::Edo(edo) => Pitch::parse_edo(format!("{}\\{}", text, edo).as_str()),
+                        Tuning::Scale(_) => text
+                            .parse::<i32>()
+                            .ok()
+                            .and_then(|step| tuning.step_pitch(step)),
+                    }
+                } else {
+                    if text
+                        .parse::<f64>()
+                        .ok()
+                        .filter(|&f| (1.0..1e8).contains(&f))
+                        .is_some()
+                    {
+                        self.state.tuning = None;
+                        Pitch::parse_fequency(text)
+                    } else {
+                        self.error(
+                            DiagnosticCode::InvalidValue,
+                            format!("Invalid frequency value: {}", text),
+                            t.text_range(),
+                        );
+                        None
+                    }
+                }
+            }
+            SyntaxKind::PitchRatio if text.contains('^') => {
+                self.error(
+                    DiagnosticCode::InvalidPitchChain,
+                    format!("Ratio power is only allowed in pitch chains: {}", text),
+                    t.text_range(),
+                );
+                None
+            }
+            SyntaxKind::PitchRatio => Pitch::parse_ratio(text),
+            SyntaxKind::PitchEdo => {
+                let p = Pitch::parse_edo(text);
+                if let Some(Pitch::Edo(r)) = p {
+                    self.state.tuning = Some(Tuning::Edo(*r.denom() as u16));
+                }
+                p
+            }
+            SyntaxKind::PitchCents => Pitch::parse_cents(text),
+            SyntaxKind::PitchSubharmonic => Pitch::parse_subharmonic(text),
+            SyntaxKind::PitchRest => Some(Pitch::Rest),
+            SyntaxKind::PitchSustain => Some(Pitch::Sustain),
+            SyntaxKind::PitchTie => Some(Pitch::Tie),
+            _ => {
+                self.error(
+                    DiagnosticCode::InvalidPitchChain,
+                    format!("Invalid pitch token: {}", text),
+                    t.text_range(),
+                );
+                None
+            }
+        }
+    }
+
+    /// Parses a pitch token, expanding half accidentals into a leading cents offset
+    /// (`Cd4` becomes `-50c@C4`), comma accidentals into a leading ratio (`E\4` becomes
+    /// `80/81@E4`) and up/down arrows into EDO steps of the active EDO (`^E4` becomes
+    /// `1\31@E4` under 31-EDO).
+    fn parse_pitch_atoms(&mut self, t: &SyntaxToken, allow_formal: bool) -> Option<Vec<Pitch>> {
+        // `3/2^2` stacks the ratio, i.e. `3/2@3/2`
+        if t.kind().is_pitch_ratio()
+            && let Some((ratio, exponent)) = Pitch::parse_ratio_power(t.text())
+        {
+            if exponent == 0 {
+                return Some(vec![Pitch::Ratio(Rational64::from_integer(1))]);
+            }
+            let step = if exponent < 0 {
+                Rational64::new(*ratio.denom(), *ratio.numer())
+            } else {
+                ratio
+            };
+            return Some(vec![Pitch::Ratio(step); exponent.unsigned_abs() as usize]);
+        }
+        let pitch = self.parse_pitch_atom(t, allow_formal)?;
+        let (cents, comma, steps) = match pitch {
+            Pitch::SpellOctave(_) | Pitch::SpellSimple(_) => (
+                Pitch::spell_half_accidental_cents(t.text()),
+                Pitch::spell_comma_ratio(t.text()),
+                Pitch::spell_arrow_steps(t.text()),
+            ),
+            _ => (0, Rational64::from_integer(1), 0),
+        };
+        let mut atoms = vec![pitch];
+        if comma != Rational64::from_integer(1) {
+            atoms.insert(0, Pitch::Ratio(comma));
+        }
+        if cents != 0 {
+            atoms.insert(0, Pitch::Cents(f64::from(cents)));
+        }
+        if steps != 0 {
+            let Some(&Tuning::Edo(edo)) = self.state.tuning.as_ref() else {
+                self.error(
+                    DiagnosticCode::InvalidPitchChain,
+                    format!("Up/down arrows require an active EDO: {}", t.text()),
+                    t.text_range(),
+                );
+                return None;
+            };
+            let step = Rational64::new(i64::from(steps), i64::from(edo));
+            atoms.insert(0, Pitch::Edo(step));
+        }
+        Some(atoms)
+    }
+
+    fn parse_pitch(&mut self, t: &SyntaxToken, allow_formal: bool) -> Option<Note> {
+        self.parse_pitch_atom(t, allow_formal)
+            .map(|pitch| Note::from_pitch(pitch, &self.state))
+    }
+
+    fn parse_pitch_chain_ident_as_chain(&mut self, t: &SyntaxToken) -> Option<Vec<Pitch>> {
+        debug_assert!(t.kind().is_identifier());
+        let ident = self.macros.resolve(t.text(), &self.macro_scope);
+
+        if let Some(chain) = self.macros.alias_macros.get(ident.as_str()) {
+            if chain.is_empty() {
+                self.error(
+                    DiagnosticCode::MacroKindMismatch,
+                    format!(
+                        "Identifier in pitch chain cannot resolve to an empty alias macro: {}",
+                        ident
+                    ),
+                    t.text_range(),
+                );
+                return None;
+            }
+            return Some(chain.clone());
+        }
+
+        if self.macros.simple_macros.contains_key(ident.as_str()) {
+            self.error(
+                DiagnosticCode::MacroKindMismatch,
+                format!(
+                    "Identifier in pitch chain must resolve to an alias macro: {}",
+                    ident
+                ),
+                t.text_range(),
+            );
+            return None;
+        }
+
+        if self.macros.complex_macros.contains_key(ident.as_str()) {
+            self.error(
+                DiagnosticCode::MacroKindMismatch,
+                format!(
+                    "Identifier in pitch chain cannot resolve to a complex macro: {}",
+                    ident
+                ),
+                t.text_range(),
+            );
+            return None;
+        }
+
+        self.error_undefined(format!("Undefined identifier in pitch chain: {}", ident), t);
+        None
+    }
+
+    fn parse_pitch_chain_tokens(
+        &mut self,
+        tokens: &[SyntaxToken],
+        allow_formal_single: bool,
+        range: TextRange,
+    ) -> Option<Note> {
+        if tokens.is_empty() {
+            return None;
+        }
+
+        // `3/2@root` resolves the rest of the chain against the named base reference `root`
+        if let [rest @ .., at, name] = tokens
+            && at.kind().is_at()
+            && name.kind().is_identifier()
+            && let Some(&base) = self.state.base_refs.get(name.text())
+        {
+            let saved = (self.state.base_note, self.state.base_frequency);
+            (self.state.base_note, self.state.base_frequency) = base;
+            let note = self.parse_pitch_chain_tokens(rest, allow_formal_single, range);
+            (self.state.base_note, self.state.base_frequency) = saved;
+            return note;
+        }
+
+        let mut pitch_atoms: Vec<(Pitch, TextRange)> = Vec::new();
+        let mut expect_pitch = true;
+        let mut has_chain = false;
+
+        for token in tokens {
+            if expect_pitch {
+                if token.kind().is_pitch() || token.kind().is_formal_pitch() {
+                    for pitch in self.parse_pitch_atoms(token, allow_formal_single)? {
+                        pitch_atoms.push((pitch, token.text_range()));
+                    }
+                    expect_pitch = false;
+                } else if token.kind().is_identifier() {
+                    let chain = self.parse_pitch_chain_ident_as_chain(token)?;
+                    for pitch in chain {
+                        pitch_atoms.push((pitch, token.text_range()));
+                    }
+                    expect_pitch = false;
+                } else {
+                    self.error(
+                        DiagnosticCode::InvalidPitchChain,
+                        format!("Expected pitch token, got: {}", token.text()),
+                        token.text_range(),
+                    );
+                    return None;
+                }
+            } else if token.kind().is_at() {
+                has_chain = true;
+                expect_pitch = true;
+            } else if token.kind().is_plus() {
+                has_chain = true;
+                pitch_atoms.push((Pitch::Ratio(Rational64::new(2, 1)), token.text_range()));
+            } else if token.kind().is_pitch_sustain() {
+                has_chain = true;
+                pitch_atoms.push((Pitch::Ratio(Rational64::new(1, 2)), token.text_range()));
+            } else {
+                self.error(
+                    DiagnosticCode::InvalidPitchChain,
+                    format!("Expected '@' in pitch chain, got: {}", token.text()),
+                    token.text_range(),
+                );
+                return None;
+            }
+        }
+
+        if expect_pitch {
+            self.error(
+                DiagnosticCode::InvalidPitchChain,
+                "Pitch chain cannot end with '@'".to_string(),
+                range,
+            );
+            return None;
         }
 
         if has_chain
             && pitch_atoms
                 .iter()
-                .any(|(p, _)| matches!(p, Pitch::Rest | Pitch::Sustain))
+                .any(|(p, _)| matches!(p, Pitch::Rest | Pitch::Sustain | Pitch::Tie))
         {
             self.error(
+                DiagnosticCode::InvalidPitchChain,
                 "rest/sustain cannot be used inside pitch chain".to_string(),
                 range,
             );
@@ -742,7 +2327,7 @@ impl Compiler {
             ]));
         }
 
-        let right = *pitch_atoms.last().expect("non-empty pitch atoms");
+        let &right = pitch_atoms.last()?;
         let mut current_note = Note::from_pitch(right.0, &self.state);
         let mut current_base = (
             Note::base_note_from_pitch(
@@ -781,8 +2366,7 @@ impl Compiler {
         for token in tokens {
             if expect_pitch {
                 if token.kind().is_pitch() || token.kind().is_formal_pitch() {
-                    let pitch = self.parse_pitch_atom(token, false)?;
-                    pitch_atoms.push(pitch);
+                    pitch_atoms.extend(self.parse_pitch_atoms(token, false)?);
                     expect_pitch = false;
                 } else if token.kind().is_identifier() {
                     let chain = self.parse_pitch_chain_ident_as_chain(token)?;
@@ -790,25 +2374,26 @@ impl Compiler {
                     expect_pitch = false;
                 } else {
                     self.error(
+                        DiagnosticCode::InvalidPitchChain,
                         format!("Expected pitch token after '@', got: {}", token.text()),
                         token.text_range(),
                     );
                     return None;
                 }
             } else if pitch_atoms.is_empty() && token.kind().is_pitch() {
-                let pitch = self.parse_pitch_atom(token, false)?;
-                pitch_atoms.push(pitch);
+                pitch_atoms.extend(self.parse_pitch_atoms(token, false)?);
             } else if pitch_atoms.is_empty() && token.kind().is_identifier() {
                 let chain = self.parse_pitch_chain_ident_as_chain(token)?;
                 pitch_atoms.extend(chain);
             } else if token.kind().is_at() {
                 expect_pitch = true;
             } else if token.kind().is_plus() {
-                pitch_atoms.push(Pitch::Ratio(Rational32::new(2, 1)));
+                pitch_atoms.push(Pitch::Ratio(Rational64::new(2, 1)));
             } else if token.kind().is_pitch_sustain() {
-                pitch_atoms.push(Pitch::Ratio(Rational32::new(1, 2)));
+                pitch_atoms.push(Pitch::Ratio(Rational64::new(1, 2)));
             } else {
                 self.error(
+                    DiagnosticCode::InvalidPitchChain,
                     format!(
                         "Expected '+', '-', or '@' after macro invoke, got: {}",
                         token.text()
@@ -820,7 +2405,11 @@ impl Compiler {
         }
 
         if expect_pitch {
-            self.error("Pitch chain cannot end with '@'".to_string(), range);
+            self.error(
+                DiagnosticCode::InvalidPitchChain,
+                "Pitch chain cannot end with '@'".to_string(),
+                range,
+            );
             return None;
         }
 
@@ -838,9 +2427,10 @@ impl Compiler {
         if pitch_atoms.len() > 1
             && pitch_atoms
                 .iter()
-                .any(|p| matches!(p, Pitch::Rest | Pitch::Sustain))
+                .any(|p| matches!(p, Pitch::Rest | Pitch::Sustain | Pitch::Tie))
         {
             self.error(
+                DiagnosticCode::InvalidPitchChain,
                 "rest/sustain cannot be used inside pitch chain".to_string(),
                 range,
             );
@@ -852,7 +2442,7 @@ impl Compiler {
             ]));
         }
 
-        let right = *pitch_atoms.last().expect("non-empty pitch atoms");
+        let &right = pitch_atoms.last()?;
         let mut current_note = Note::from_pitch(right, &self.state);
         let mut current_base = (
             Note::base_note_from_pitch(
@@ -881,45 +2471,90 @@ impl Compiler {
         } else {
             vec![NodeOrToken::Node(n.clone())]
         };
-        // Count sub-groups separated by semicolons
-        let sub_group_count = tokens
-            .iter()
-            .filter(|nt| nt.as_token().is_some_and(|t| t.kind().is_semicolon()))
-            .count()
-            + 1;
-        let mut cur_sub_group: Vec<CompileEvent> = Vec::new();
+        // Count sub-groups separated by semicolons or portamento connectors
+        let sub_group_count = ast::NoteGroup::cast(n.clone()).map_or(1, |g| g.sub_group_count());
+        let mut cur_sub_group = PendingSubGroup::default();
+        let group_range = n.text_range();
         // temporarily set quantize to sub-group duration
-        self.state.quantize =
-            self.state.quantize / Rational32::from_integer(sub_group_count as i32);
+        let Some(sub_quantize) = self
+            .state
+            .quantize
+            .checked_div(Rational64::from_integer(sub_group_count as i64))
+        else {
+            self.report_duration_overflow(group_range);
+            return;
+        };
+        self.state.quantize = sub_quantize;
 
         for nt in tokens {
             match nt {
                 NodeOrToken::Node(n) => match n.kind() {
                     SyntaxKind::NODE_NOTE => {
-                        if let Some(notes) = self.parse_note(&n) {
-                            for note in notes.into_iter() {
-                                cur_sub_group.push(CompileEvent {
-                                    body: EventBody::Note(note),
-                                    start_time: self.state.time.clone(),
-                                    range: n.text_range(),
-                                    range_invoked: None,
-                                });
+                        // `m*N` repeats the note back-to-back: one slot per repetition, or the
+                        // whole body of a complex macro, whose last copy then takes one slot
+                        // like a single invocation
+                        let repeat = self.parse_repeat_count(&n);
+                        let body_rest = self
+                            .complex_macro_length(&n)
+                            .map(|length| length - self.state.quantize)
+                            .filter(|rest| *rest > Rational64::zero());
+                        for r in 0..repeat {
+                            if r > 0 {
+                                self.submit_note_sub_group(&mut cur_sub_group, group_range);
+                                if let Some(rest) = body_rest {
+                                    self.advance(rest, n.text_range());
+                                }
+                                if !self.within_limits(n.text_range()) {
+                                    break;
+                                }
                             }
+                            self.compile_note(&n, &mut cur_sub_group, r == 0);
                         }
                     }
+                    SyntaxKind::NODE_NOTE_GROUP => {
+                        // nested group subdivides the current sub-group slot
+                        self.compile_note_group(&n);
+                    }
+                    SyntaxKind::NODE_ERROR => {}
                     _ => {
                         self.error(
+                            DiagnosticCode::UnexpectedSyntax,
                             format!("Unexpected node in note group: {:?}", n.kind()),
                             n.text_range(),
                         );
                     }
                 },
                 NodeOrToken::Token(t) => match t.kind() {
-                    SyntaxKind::Semicolon => self.submit_note_sub_group(&mut cur_sub_group),
-                    SyntaxKind::Colon => {
+                    SyntaxKind::Semicolon => {
+                        self.submit_note_sub_group(&mut cur_sub_group, group_range)
+                    }
+                    SyntaxKind::Portamento => {
+                        // glide from the last pitched note before `~` into the next sub-group
+                        let from = cur_sub_group
+                            .events
+                            .iter()
+                            .rev()
+                            .find_map(|e| match &e.body {
+                                EventBody::Note(n) if n.freq > 0.0 && n.drum_key.is_none() => {
+                                    Some(n.freq)
+                                }
+                                _ => None,
+                            });
+                        if from.is_none() {
+                            self.warn(
+                                DiagnosticCode::MissingPreviousNote,
+                                "Portamento requires a pitched note before `~`".to_string(),
+                                t.text_range(),
+                            );
+                        }
+                        self.submit_note_sub_group(&mut cur_sub_group, group_range);
+                        cur_sub_group.portamento_from = from;
+                    }
+                    SyntaxKind::Colon | SyntaxKind::LParen | SyntaxKind::RParen => {
                         // do nothing, just a separator
                     }
                     _ => self.error(
+                        DiagnosticCode::UnexpectedSyntax,
                         format!("Unexpected token in note group: {}", t.text()),
                         t.text_range(),
                     ),
@@ -927,81 +2562,447 @@ impl Compiler {
             }
         }
         // submit last sub-group
-        self.submit_note_sub_group(&mut cur_sub_group);
+        self.submit_note_sub_group(&mut cur_sub_group, group_range);
         // restore quantize and timestamp
         self.state.quantize =
-            self.state.quantize * Rational32::from_integer(sub_group_count as i32);
-        self.state.time = self
-            .state
-            .time
-            .add_duration(self.state.quantize.neg(), &self.state);
+            self.state.quantize * Rational64::from_integer(sub_group_count as i64);
+        self.advance(self.state.quantize.neg(), group_range);
         // advance time if the last token is a commas duration
         if let Some(last_token) = n
             .descendants_with_tokens()
             .last()
             .and_then(|nt| nt.into_token())
+            && last_token.kind().is_duration_commas()
         {
-            if last_token.kind().is_duration_commas() {
-                let count = self.parse_duration_commas(&last_token).unwrap_or(0);
-                let advance_dur = self.state.quantize * Rational32::from_integer(count as i32);
-                self.state.time = self.state.time.add_duration(advance_dur, &self.state);
-            }
+            let count = self.parse_duration_commas(&last_token).unwrap_or(0);
+            let advance_dur = self.state.quantize * Rational64::from_integer(count as i64);
+            self.advance(advance_dur, group_range);
         }
     }
 
-    fn submit_note_sub_group(&mut self, cur_sub_group: &mut Vec<CompileEvent>) {
-        let mut cur_dur = self.state.quantize;
-        for note in cur_sub_group.iter_mut().rev() {
-            if let EventBody::Note(n) = &mut note.body {
-                if n.duration.is_zero() {
-                    n.set_duration(cur_dur, &self.state);
-                } else {
-                    cur_dur = n.duration;
+    fn compile_arpeggio(&mut self, n: &SyntaxNode) {
+        debug_assert!(n.kind().is_node_arpeggio());
+        let Some(group) =
+            n.find_child_node_by_fn(|c| c.kind().is_node_note_group() || c.kind().is_node_note())
+        else {
+            self.error(
+                DiagnosticCode::UnexpectedSyntax,
+                "Arpeggio must contain notes".to_string(),
+                n.text_range(),
+            );
+            return;
+        };
+        self.in_arpeggio = true;
+        self.compile_note_group(&group);
+        self.in_arpeggio = false;
+    }
+
+    fn compile_note(&mut self, n: &SyntaxNode, sub_group: &mut PendingSubGroup, with_lyric: bool) {
+        let Some(note_node) = ast::Note::cast(n.clone()) else {
+            return;
+        };
+        let grace_notes = note_node
+            .grace()
+            .map(|g| (self.parse_grace(&g), g.text_range()));
+        self.chord_root = sub_group.events.iter().rev().find_map(|e| match &e.body {
+            EventBody::Note(note) if note.freq > 0.0 && note.drum_key.is_none() => {
+                Some(note.pitch_chain.clone())
+            }
+            _ => None,
+        });
+        if let Some(notes) = self.parse_note(n) {
+            // 与 LilyPond 一致：下一个和弦相对于上一个和弦的第一个音
+            if self.state.relative
+                && let Some(first) = sub_group
+                    .events
+                    .iter()
+                    .filter_map(|e| match &e.body {
+                        EventBody::Note(note) => Some(note),
+                        _ => None,
+                    })
+                    .chain(&notes)
+                    .find(|note| note.freq > 0.0 && note.drum_key.is_none())
+            {
+                self.state.relative_anchor = Some(first.freq);
+            }
+            let first = sub_group.events.len();
+            if let Some((grace_notes, range)) = grace_notes {
+                sub_group.graces.push(GraceAttachment {
+                    target: first..first + notes.len(),
+                    notes: grace_notes,
+                    range,
+                });
+            }
+            let mut vibrato = false;
+            if let Some(t) = note_node.ornament() {
+                let kind = match t.text() {
+                    "~tr" => Some(Ornament::Trill),
+                    "~trem" => Some(Ornament::Tremolo),
+                    // vibrato marks the notes instead of expanding them
+                    _ => None,
+                };
+                match kind {
+                    Some(kind) => sub_group.ornaments.push(OrnamentAttachment {
+                        target: first..first + notes.len(),
+                        kind,
+                        range: t.text_range(),
+                    }),
+                    None => vibrato = true,
+                }
+            }
+            if let Some(t) = note_node.fermata() {
+                sub_group.fermata = Some(t.text_range());
+            }
+            let bend_envelope = note_node
+                .bend_envelope()
+                .and_then(|t| self.parse_bend_envelope(&t));
+            let volume = note_node.volume().map(|t| self.parse_note_volume(&t));
+            let tie = note_node.is_tied();
+            let macro_trace = self.note_macro_call(&note_node);
+            for mut note in notes.into_iter() {
+                if let Some(volume) = volume {
+                    note.volume = volume;
                 }
+                note.tie = tie && !note.is_rest() && !note.is_sustain();
+                if note.freq > 0.0 && note.drum_key.is_none() {
+                    note.portamento_from = sub_group.portamento_from;
+                    note.bend_envelope = bend_envelope.clone();
+                    note.vibrato = vibrato;
+                }
+                sub_group.events.push(CompileEvent {
+                    body: EventBody::Note(note),
+                    start_time: self.state.time,
+                    range: n.text_range(),
+                    range_invoked: None,
+                    macro_trace: macro_trace.clone(),
+                    voice: self.line_voice.clone(),
+                    channel: None,
+                    instrument: self.instrument(),
+                });
             }
         }
+        if with_lyric && let Some(lyric) = note_node.lyric() {
+            sub_group.events.push(CompileEvent {
+                body: EventBody::Lyric(lyric.text().trim_matches('"').to_string()),
+                start_time: self.state.time,
+                range: lyric.text_range(),
+                range_invoked: None,
+                macro_trace: Vec::new(),
+                voice: self.line_voice.clone(),
+                channel: None,
+                instrument: None,
+            });
+        }
+    }
 
-        for note in cur_sub_group.drain(..) {
-            self.push_event(note.body, note.range);
+    /// Parses `{bend 0c..+50c}` into its cent breakpoints.
+    fn parse_bend_envelope(&mut self, t: &SyntaxToken) -> Option<BendEnvelope> {
+        let body = t.text().trim_start_matches("{bend").trim_end_matches('}');
+        let cents: Result<Vec<i32>, _> = body
+            .split("..")
+            .map(|point| point.trim().trim_end_matches('c').parse::<i32>())
+            .collect();
+        match cents {
+            Ok(cents) => Some(BendEnvelope { cents }),
+            Err(_) => {
+                self.error(
+                    DiagnosticCode::InvalidValue,
+                    format!("Invalid bend envelope: {}", t.text()),
+                    t.text_range(),
+                );
+                None
+            }
         }
+    }
 
-        self.state.time = self
-            .state
-            .time
-            .add_duration(self.state.quantize, &self.state);
+    /// Parses a `&0.6` volume suffix, clamping it into `0.0..=1.0`.
+    fn parse_note_volume(&mut self, t: &SyntaxToken) -> f32 {
+        let volume = t.text()[1..].parse::<f32>().unwrap_or(1.0);
+        if volume > 1.0 {
+            let warning = Diagnostic::new(
+                DiagnosticLevel::Warning,
+                DiagnosticCode::ClampedVolume,
+                format!("Note volume {} exceeds 1.0 and is clamped", &t.text()[1..]),
+                t.text_range(),
+            );
+            self.report(warning.with_fix(
+                "Use the full volume".to_string(),
+                t.text_range(),
+                "&1".to_string(),
+            ));
+        }
+        volume.clamp(0.0, 1.0)
     }
 
-    fn parse_note(&mut self, n: &SyntaxNode) -> Option<Vec<Note>> {
-        debug_assert!(n.kind().is_node_note());
-        let duration_token = n.find_child_token_by_fn(|t| {
-            t.kind().is_duration_commas() || t.kind().is_duration_fraction()
-        });
-        let duration = duration_token
-            .and_then(|t| {
-                if t.kind().is_duration_commas() {
-                    self.parse_duration_commas(&t)
-                        .map(|c| self.state.quantize * Rational32::from_integer((c + 1) as i32))
-                } else if t.kind().is_duration_fraction() {
-                    self.parse_duration_fraction(&t)
-                } else {
-                    None
-                }
+    /// Length of the body of the complex macro a note invokes, up to the end of its last note.
+    fn complex_macro_length(&self, n: &SyntaxNode) -> Option<Rational64> {
+        let name = ast::Note::cast(n.clone())?.macro_invoke()?.name()?;
+        if self.in_percussion && drum_key(name.text()).is_some() {
+            return None;
+        }
+        let key = self.macros.resolve(name.text(), &self.macro_scope);
+        let body = self.macros.complex_macros.get(key.as_str())?;
+        body.iter()
+            .filter_map(|e| match &e.body {
+                EventBody::Note(note) => Some(e.start_time.position + note.duration),
+                _ => None,
             })
-            .unwrap_or(Rational32::zero());
-        let mut notes: Vec<Note> = Vec::new();
+            .reduce(|a, b| if b > a { b } else { a })
+    }
 
-        if let Some(node) = n
-            .descendants()
-            .find(|child| child.kind().is_node_macro_invoke())
-        {
+    fn parse_repeat_count(&mut self, n: &SyntaxNode) -> u32 {
+        let Some(t) = n
+            .descendants_with_tokens()
+            .filter_map(|nt| nt.into_token())
+            .find(|t| t.kind().is_repeat_count())
+        else {
+            return 1;
+        };
+        match t.text()[1..].parse::<u32>() {
+            Ok(count) if count > 0 => count,
+            _ => {
+                self.error(
+                    DiagnosticCode::InvalidValue,
+                    format!("Invalid repetition count: {}", t.text()),
+                    t.text_range(),
+                );
+                1
+            }
+        }
+    }
+
+    fn submit_note_sub_group(&mut self, sub_group: &mut PendingSubGroup, span: TextRange) {
+        let PendingSubGroup {
+            events: cur_sub_group,
+            graces,
+            ornaments,
+            fermata,
+            portamento_from,
+        } = sub_group;
+        *portamento_from = None;
+        // a fermata slows the tempo for this slot, so seconds stretch while ticks stay put
+        let fermata = take(fermata).map(|range| {
+            let bpm = self.state.bpm;
+            self.state
+                .set_tempo(bpm / self.state.fermata_factor, self.state.beat_duration);
+            self.push_event(EventBody::BPMDef(self.state.bpm), range);
+            (bpm, range)
+        });
+        let mut cur_dur = self.state.quantize;
+        for note in cur_sub_group.iter_mut().rev() {
+            if let EventBody::Note(n) = &mut note.body {
+                if n.duration.is_zero() {
+                    n.set_duration(cur_dur, &self.state);
+                } else {
+                    cur_dur = n.duration;
+                    if fermata.is_some() {
+                        n.set_duration(n.duration, &self.state);
+                    }
+                }
+            }
+        }
+
+        // grace notes steal their duration from the start of the decorated note
+        for grace in take(graces) {
+            let steal =
+                self.state.grace_duration * Rational64::from_integer(grace.notes.len() as i64);
+            let fits = cur_sub_group[grace.target.clone()]
+                .iter()
+                .all(|e| match &e.body {
+                    EventBody::Note(n) => n.duration > steal,
+                    _ => true,
+                });
+            if !fits {
+                self.warn(
+                    DiagnosticCode::OverlongDecoration,
+                    "Grace notes are longer than the note they decorate".to_string(),
+                    grace.range,
+                );
+                continue;
+            }
+            for (i, mut note) in grace.notes.into_iter().enumerate() {
+                note.set_duration(self.state.grace_duration, &self.state);
+                let offset = self.state.grace_duration * Rational64::from_integer(i as i64);
+                let start_time = self.offset_time(self.state.time, offset, grace.range);
+                self.events.push(CompileEvent {
+                    body: EventBody::Note(note),
+                    start_time,
+                    range: grace.range,
+                    range_invoked: None,
+                    macro_trace: Vec::new(),
+                    voice: self.line_voice.clone(),
+                    channel: None,
+                    instrument: self.instrument(),
+                });
+            }
+            for event in cur_sub_group[grace.target].iter_mut() {
+                if let EventBody::Note(n) = &mut event.body {
+                    n.set_duration(n.duration - steal, &self.state);
+                    event.start_time = self.offset_time(event.start_time, steal, grace.range);
+                }
+            }
+        }
+
+        if self.in_arpeggio {
+            self.spread_arpeggio(cur_sub_group);
+        }
+
+        // expand ornaments back to front so earlier target ranges stay valid
+        for ornament in take(ornaments).into_iter().rev() {
+            for idx in ornament.target.rev() {
+                let Some(expanded) = self.expand_ornament(&cur_sub_group[idx], ornament.kind)
+                else {
+                    self.warn(
+                        DiagnosticCode::OverlongDecoration,
+                        "Ornament requires a pitched note longer than the ornament rate"
+                            .to_string(),
+                        ornament.range,
+                    );
+                    continue;
+                };
+                cur_sub_group.splice(idx..idx + 1, expanded);
+            }
+        }
+
+        self.events.append(cur_sub_group);
+
+        self.advance(self.state.quantize, span);
+
+        if let Some((bpm, range)) = fermata {
+            self.state.set_tempo(bpm, self.state.beat_duration);
+            self.push_event(EventBody::BPMDef(bpm), range);
+        }
+    }
+
+    /// Delays each successive note of the sub-group; all notes still end together.
+    fn spread_arpeggio(&mut self, cur_sub_group: &mut [CompileEvent]) {
+        let offset = self.state.arpeggio_offset;
+        let mut delay = Rational64::zero();
+        for event in cur_sub_group.iter_mut() {
+            let EventBody::Note(note) = &mut event.body else {
+                continue;
+            };
+            if note.is_rest() || note.is_sustain() || note.is_tie() {
+                continue;
+            }
+            if delay >= note.duration {
+                self.warn(
+                    DiagnosticCode::OverlongDecoration,
+                    "Arpeggio spread is longer than the chord".to_string(),
+                    event.range,
+                );
+                return;
+            }
+            note.set_duration(note.duration - delay, &self.state);
+            event.start_time = self.offset_time(event.start_time, delay, event.range);
+            delay += offset;
+        }
+    }
+
+    fn expand_ornament(
+        &mut self,
+        event: &CompileEvent,
+        kind: Ornament,
+    ) -> Option<Vec<CompileEvent>> {
+        let EventBody::Note(note) = &event.body else {
+            return None;
+        };
+        let rate = self.state.ornament_rate;
+        if note.is_rest() || note.is_sustain() || note.is_tie() || note.duration <= rate {
+            return None;
+        }
+        let steps = note.duration / rate;
+        let count = steps.numer() / steps.denom();
+        let ratio = 2f64.powf(TRILL_INTERVAL_CENTS / 1200.0);
+
+        let mut expanded = Vec::new();
+        for i in 0..count {
+            let offset = rate * Rational64::from_integer(i);
+            let mut sub_note = note.clone();
+            if i == count - 1 {
+                sub_note.set_duration(note.duration - offset, &self.state);
+            } else {
+                sub_note.set_duration(rate, &self.state);
+            }
+            if matches!(kind, Ornament::Trill) && i % 2 == 1 {
+                sub_note.freq *= ratio;
+                sub_note.pitch_ratio *= ratio;
+                sub_note
+                    .pitch_chain
+                    .insert(0, Pitch::Cents(TRILL_INTERVAL_CENTS));
+            }
+            expanded.push(CompileEvent {
+                body: EventBody::Note(sub_note),
+                start_time: self.offset_time(event.start_time, offset, event.range),
+                ..event.clone()
+            });
+        }
+        Some(expanded)
+    }
+
+    fn parse_grace(&mut self, n: &SyntaxNode) -> Vec<Note> {
+        debug_assert!(n.kind().is_node_grace());
+        let mut notes = Vec::new();
+        for chain in n.children().filter_map(ast::PitchChain::cast) {
+            if let Some(note) =
+                self.parse_pitch_chain_tokens(&chain.atoms(), false, chain.syntax().text_range())
+            {
+                notes.push(note);
+            }
+        }
+        notes
+    }
+
+    fn parse_note(&mut self, n: &SyntaxNode) -> Option<Vec<Note>> {
+        let note_node = ast::Note::cast(n.clone())?;
+        let duration = note_node
+            .duration()
+            .and_then(|t| {
+                if t.kind().is_duration_commas() {
+                    let commas = self.parse_duration_commas(&t)?;
+                    let duration = self
+                        .state
+                        .quantize
+                        .checked_mul(Rational64::from_integer(commas as i64 + 1));
+                    if duration.is_none() {
+                        self.report_duration_overflow(t.text_range());
+                    }
+                    duration
+                } else if t.kind().is_duration_fraction() {
+                    self.parse_duration_fraction(&t)
+                } else {
+                    None
+                }
+            })
+            .unwrap_or(Rational64::zero());
+        let mut notes: Vec<Note> = Vec::new();
+
+        if let Some(t) = note_node.ji_chord() {
+            let Some(ratios) = ji_chord_ratios(t.text()) else {
+                self.error(
+                    DiagnosticCode::InvalidValue,
+                    format!("Invalid JI chord: {}", t.text()),
+                    t.text_range(),
+                );
+                return None;
+            };
+            let intervals = ratios.into_iter().map(Pitch::Ratio).collect();
+            return Some(self.chord_notes(intervals, Vec::new(), duration, t.text_range()));
+        }
+        if let Some(invoke) = note_node.macro_invoke() {
+            let node = invoke.syntax();
             match node.kind() {
                 // Compile macro invoke
                 SyntaxKind::NODE_MACRO_INVOKE => {
-                    let ident = node
-                        .find_child_token_by_fn(|t| t.kind().is_identifier())
-                        .expect("Macro invoke node must have an identifier token")
-                        .text()
-                        .to_string();
+                    let Some(ident_tok) = invoke.name() else {
+                        self.error(
+                            DiagnosticCode::UnexpectedSyntax,
+                            "Macro invocation must have a name".to_string(),
+                            node.text_range(),
+                        );
+                        return None;
+                    };
+                    let ident = SmolStr::new(ident_tok.text());
                     let mut arg_chain_tokens: Vec<SyntaxToken> = node
                         .children_with_tokens()
                         .filter_map(|nt| nt.into_token())
@@ -1027,15 +3028,25 @@ impl Compiler {
                     }
                     let anchor_pitch_chain =
                         self.parse_macro_invoke_tail_tokens(&arg_chain_tokens, node.text_range());
-                    if let Some(macro_notes) =
-                        self.macros.simple_macros.get(ident.as_str()).cloned()
+                    let key = self.macros.resolve(&ident, &self.macro_scope);
+                    if self.in_percussion
+                        && let Some(key) = drum_key(&ident)
+                    {
+                        // drum names take precedence over macros on a `drums:` line
+                        let mut note = Note::from_drum(key, &self.state);
+                        note.set_duration(duration, &self.state);
+                        notes.push(note);
+                    } else if let Some(macro_notes) =
+                        self.macros.simple_macros.get(key.as_str()).cloned()
                     {
                         // !!!Simple macro invoke!!!
                         for mut note in macro_notes {
-                            if let Some(anchor_chain) = &anchor_pitch_chain {
-                                if !note.is_rest() && !note.is_sustain() {
-                                    note.pitch_chain.extend(anchor_chain.iter().copied());
-                                }
+                            if let Some(anchor_chain) = &anchor_pitch_chain
+                                && !note.is_rest()
+                                && !note.is_sustain()
+                                && !note.is_tie()
+                            {
+                                note.pitch_chain.extend(anchor_chain.iter().copied());
                             }
                             if let Some(note_live) =
                                 self.eval_pitch_chain_pitches(&note.pitch_chain, node.text_range())
@@ -1048,15 +3059,17 @@ impl Compiler {
                             notes.push(note);
                         }
                     } else if let Some(alias_chain) =
-                        self.macros.alias_macros.get(ident.as_str()).cloned()
+                        self.macros.alias_macros.get(key.as_str()).cloned()
                     {
                         if let Some(mut note) =
                             self.eval_pitch_chain_pitches(alias_chain.as_slice(), node.text_range())
                         {
-                            if let Some(anchor_chain) = &anchor_pitch_chain {
-                                if !note.is_rest() && !note.is_sustain() {
-                                    note.pitch_chain.extend(anchor_chain.iter().copied());
-                                }
+                            if let Some(anchor_chain) = &anchor_pitch_chain
+                                && !note.is_rest()
+                                && !note.is_sustain()
+                                && !note.is_tie()
+                            {
+                                note.pitch_chain.extend(anchor_chain.iter().copied());
                             }
                             if let Some(note_live) =
                                 self.eval_pitch_chain_pitches(&note.pitch_chain, node.text_range())
@@ -1069,75 +3082,122 @@ impl Compiler {
                             notes.push(note);
                         }
                     } else if let Some(macro_events) =
-                        self.macros.complex_macros.get(ident.as_str()).cloned()
+                        self.macros.complex_macros.get(key.as_str()).cloned()
                     {
                         // !!!Complex macro invoke!!!
                         // Directly push events and return empty notes
-                        for e in macro_events {
-                            if let EventBody::Note(mut note) = e.body {
-                                if let Some(anchor_chain) = &anchor_pitch_chain {
-                                    if !note.is_rest() && !note.is_sustain() {
+                        let depth = macro_events.iter().map(|e| e.macro_trace.len() + 1).max();
+                        if depth.is_some_and(|depth| depth > self.limits.max_macro_depth) {
+                            self.error(
+                                DiagnosticCode::LimitExceeded,
+                                format!(
+                                    "Macro {} nests more than {} macro invocations",
+                                    ident, self.limits.max_macro_depth
+                                ),
+                                n.text_range(),
+                            );
+                            return None;
+                        }
+                        if !self.within_limits(n.text_range()) {
+                            return None;
+                        }
+                        let call = MacroCall {
+                            name: ident.clone(),
+                            range: node.text_range(),
+                        };
+                        // the body stays shared; only the events placed here are built
+                        for e in macro_events.iter() {
+                            let start_time = TimeStamp {
+                                seconds: self.state.time.seconds + e.start_time.seconds,
+                                bars: self.state.time.bars + e.start_time.bars,
+                                ticks: self.state.time.ticks + e.start_time.ticks,
+                                position: self.state.time.position + e.start_time.position,
+                            };
+                            let body = match &e.body {
+                                EventBody::Note(note) => {
+                                    let mut note = note.clone();
+                                    if let Some(anchor_chain) = &anchor_pitch_chain
+                                        && !note.is_rest()
+                                        && !note.is_sustain()
+                                        && !note.is_tie()
+                                    {
                                         note.pitch_chain.extend(anchor_chain.iter().copied());
                                     }
+                                    if let Some(note_live) = self
+                                        .eval_pitch_chain_pitches(&note.pitch_chain, n.text_range())
+                                    {
+                                        note.freq = note_live.freq;
+                                        note.pitch_ratio = note_live.pitch_ratio;
+                                    }
+                                    EventBody::Note(note)
                                 }
-                                if let Some(note_live) =
-                                    self.eval_pitch_chain_pitches(&note.pitch_chain, n.text_range())
-                                {
-                                    note.freq = note_live.freq;
-                                    note.pitch_ratio = note_live.pitch_ratio;
-                                }
-                                let start_time = TimeStamp {
-                                    seconds: self.state.time.seconds + e.start_time.seconds,
-                                    bars: self.state.time.bars + e.start_time.bars,
-                                    ticks: self.state.time.ticks + e.start_time.ticks,
-                                };
-                                let ev = CompileEvent {
-                                    body: EventBody::Note(note),
-                                    start_time: start_time,
-                                    range_invoked: Some(n.text_range()),
-                                    ..e
-                                };
-                                self.events.push(ev);
-                            }
+                                EventBody::Lyric(text) => EventBody::Lyric(text.clone()),
+                                EventBody::Marker(name) => EventBody::Marker(name.clone()),
+                                EventBody::CuePoint(name) => EventBody::CuePoint(name.clone()),
+                                EventBody::LoopStart => EventBody::LoopStart,
+                                EventBody::LoopEnd => EventBody::LoopEnd,
+                                _ => continue,
+                            };
+                            let macro_trace = iter::once(call.clone())
+                                .chain(e.macro_trace.iter().cloned())
+                                .collect();
+                            self.events.push(CompileEvent {
+                                body,
+                                start_time,
+                                range: e.range,
+                                range_invoked: Some(n.text_range()),
+                                macro_trace,
+                                voice: self.line_voice.clone(),
+                                channel: None,
+                                // an instrument set inside the macro body wins
+                                instrument: e.instrument.or(self.instrument()),
+                            });
                         }
+                    } else if let Some((root, offsets)) =
+                        split_chord_symbol(&ident, &self.macros.chord_qualities)
+                    {
+                        // an `@` anchor relates the root (or the whole chord) to another pitch
+                        let root: PitchChain = root
+                            .into_iter()
+                            .chain(anchor_pitch_chain.unwrap_or_default())
+                            .collect();
+                        let intervals = offsets
+                            .into_iter()
+                            .map(|c| Pitch::Cents(f64::from(c)))
+                            .collect();
+                        notes = self.chord_notes(intervals, root, duration, node.text_range());
                     } else {
-                        self.error(
+                        self.error_undefined(
                             format!("Undefined macro invoked: {}", ident),
-                            node.text_range(),
+                            &ident_tok,
                         );
                     }
                 }
                 _ => {
                     self.error(
+                        DiagnosticCode::UnexpectedSyntax,
                         format!("Unexpected node in note: {:?}", node.kind()),
                         node.text_range(),
                     );
                 }
             }
         } else {
-            let Some(chain_node) = n
-                .children()
-                .find(|child| child.kind().is_node_pitch_chain())
+            let Some(chain) = self
+                .pick_choice(&note_node)
+                .or_else(|| note_node.pitch_chains().next())
             else {
                 self.error(
+                    DiagnosticCode::UnexpectedSyntax,
                     "Note must have a pitch chain node".to_string(),
                     n.text_range(),
                 );
                 return None;
             };
-            let chain_tokens: Vec<SyntaxToken> = chain_node
-                .descendants_with_tokens()
-                .filter_map(|nt| nt.into_token())
-                .filter(|t| {
-                    t.kind().is_pitch()
-                        || t.kind().is_formal_pitch()
-                        || t.kind().is_identifier()
-                        || t.kind().is_at()
-                        || t.kind().is_plus()
-                })
-                .collect();
+            let chain_node = chain.syntax();
+            let chain_tokens = chain.atoms();
             if chain_tokens.is_empty() {
                 self.error(
+                    DiagnosticCode::UnexpectedSyntax,
                     "Note must have a pitch token or macro invoke node".to_string(),
                     chain_node.text_range(),
                 );
@@ -1153,88 +3213,172 @@ impl Compiler {
         Some(notes)
     }
 
+    /// Builds the notes of a chord symbol. Without an explicit root the chord stacks on the
+    /// previous note of the chord (skipping the unison), or on the base pitch.
+    fn chord_notes(
+        &mut self,
+        intervals: Vec<Pitch>,
+        root: PitchChain,
+        duration: Rational64,
+        range: TextRange,
+    ) -> Vec<Note> {
+        let (root, skip_unison) = match self.chord_root.clone() {
+            Some(chain) if root.is_empty() => (chain, true),
+            _ => (root, false),
+        };
+        let mut notes = Vec::new();
+        for interval in intervals {
+            let unison = match interval {
+                Pitch::Cents(c) => c == 0.0,
+                Pitch::Ratio(r) => r.numer() == r.denom(),
+                _ => false,
+            };
+            if skip_unison && unison {
+                continue;
+            }
+            let chain: PitchChain = iter::once(interval).chain(root.iter().copied()).collect();
+            if let Some(mut note) = self.eval_pitch_chain_pitches(&chain, range) {
+                note.set_duration(duration, &self.state);
+                notes.push(note);
+            }
+        }
+        notes
+    }
+
+    /// Picks one alternative of the note's `?{...}` choice, if any.
+    fn pick_choice(&mut self, note: &ast::Note) -> Option<ast::PitchChain> {
+        let choice = note.choice()?;
+        let alternatives: Vec<_> = choice.children().filter_map(ast::PitchChain::cast).collect();
+        if alternatives.is_empty() {
+            return None;
+        }
+        let idx = self.rng.below(alternatives.len());
+        alternatives.into_iter().nth(idx)
+    }
+
     fn finalize_negative_duration_notes(&mut self) {
-        for event in self.events.iter_mut() {
-            if let EventBody::Note(note) = &mut event.body {
-                if note.duration.numer() < &0 {
-                    let dur = note.duration.neg();
-                    note.set_duration(dur, &self.state);
-                    // adjust start time
-                    event.start_time = event.start_time.add_duration(dur.neg(), &self.state);
+        for idx in 0..self.events.len() {
+            let event = &mut self.events[idx];
+            if let EventBody::Note(note) = &mut event.body
+                && note.duration.numer() < &0
+            {
+                let dur = note.duration.neg();
+                note.set_duration(dur, &self.state);
+                // adjust start time
+                let (start_time, range) = (event.start_time, event.range);
+                self.events[idx].start_time = self.offset_time(start_time, dur.neg(), range);
+            }
+        }
+    }
+
+    /// Extends each note marked with a `_` tie by the `_` slots that follow it in the same
+    /// voice, adding their rational durations exactly, and drops the continuation slots.
+    fn finalize_ties(&mut self) {
+        let mut open: HashMap<Option<SmolStr>, Vec<usize>> = HashMap::new();
+        let mut unmatched = Vec::new();
+        for idx in 0..self.events.len() {
+            let (is_tie, tie, duration, seconds) = match &self.events[idx].body {
+                EventBody::Note(note) => (
+                    note.is_tie(),
+                    note.tie,
+                    note.duration,
+                    note.duration_seconds,
+                ),
+                _ => continue,
+            };
+            let tied = open.entry(self.events[idx].voice.clone()).or_default();
+            if is_tie {
+                if tied.is_empty() {
+                    unmatched.push(self.events[idx].range);
+                }
+                let mut overflow = false;
+                for &i in tied.iter() {
+                    if let EventBody::Note(note) = &mut self.events[i].body {
+                        match note.duration.checked_add(duration) {
+                            Some(total) => note.duration = total,
+                            None => overflow = true,
+                        }
+                        note.duration_seconds += seconds;
+                    }
+                }
+                if overflow {
+                    self.report_duration_overflow(self.events[idx].range);
                 }
+                continue;
+            }
+            // notes of the same chord join the open tie; any later note closes it
+            let start = self.events[idx].start_time;
+            if tied
+                .first()
+                .is_some_and(|&i| self.events[i].start_time != start)
+            {
+                tied.clear();
             }
+            if tie {
+                tied.push(idx);
+            }
+        }
+        for range in unmatched {
+            self.warn(
+                DiagnosticCode::MissingPreviousNote,
+                "Tie has no preceding tied note".to_string(),
+                range,
+            );
         }
+        self.events.retain(|e| {
+            if let EventBody::Note(n) = &e.body {
+                !n.is_tie()
+            } else {
+                true
+            }
+        });
     }
 
     fn finalize_sustain_notes(&mut self) {
-        let tolerance = 1e-4;
-        let bucket_size = tolerance;
-        let to_bucket = |sec: f64| -> i64 { (sec / bucket_size).round() as i64 };
+        // positions are exact, so a sustain continues the notes ending exactly where it starts
+        let key = |position: Rational64| -> (i64, i64) { position.reduce().into() };
 
         let mut sustain_infos = Vec::new();
-        let mut end_buckets: HashMap<i64, Vec<usize>> = HashMap::new();
+        let mut note_ends: HashMap<(i64, i64), Vec<usize>> = HashMap::new();
 
         for (idx, event) in self.events.iter().enumerate() {
             if let EventBody::Note(note) = &event.body {
                 if note.is_sustain() {
                     sustain_infos.push((
-                        event.start_time.seconds,
+                        event.start_time.position,
                         note.duration_seconds,
                         note.duration,
                         event.range,
                     ));
-                } else {
-                    let end = event.start_time.seconds + note.duration_seconds;
-                    end_buckets.entry(to_bucket(end)).or_default().push(idx);
+                } else if let Some(end) = event.start_time.position.checked_add(note.duration) {
+                    // an end past the overflow already reported cannot be sustained anyway
+                    note_ends.entry(key(end)).or_default().push(idx);
                 }
             }
         }
 
         for (sustain_start, sustain_dur_sec, sustain_dur, sustain_range) in sustain_infos {
-            let mut matched = false;
-
-            let center_bucket = to_bucket(sustain_start);
-            let mut candidate_indices = Vec::new();
-            for key in [center_bucket - 1, center_bucket, center_bucket + 1] {
-                if let Some(indices) = end_buckets.get(&key) {
-                    candidate_indices.extend(indices.iter().copied());
-                }
-            }
-
-            for idx in candidate_indices {
-                let old_end = match &self.events[idx].body {
-                    EventBody::Note(note) if !note.is_sustain() => {
-                        self.events[idx].start_time.seconds + note.duration_seconds
-                    }
-                    _ => continue,
-                };
-
-                if (old_end - sustain_start).abs() < tolerance {
-                    if let EventBody::Note(note) = &mut self.events[idx].body {
-                        note.duration += sustain_dur;
-                        note.duration_seconds += sustain_dur_sec;
-                    }
-
-                    let new_end = old_end + sustain_dur_sec;
-                    let old_bucket = to_bucket(old_end);
-                    let new_bucket = to_bucket(new_end);
-                    if old_bucket != new_bucket {
-                        if let Some(indices) = end_buckets.get_mut(&old_bucket) {
-                            if let Some(pos) = indices.iter().position(|&i| i == idx) {
-                                indices.swap_remove(pos);
-                            }
-                        }
-                        end_buckets.entry(new_bucket).or_default().push(idx);
-                    }
-                    matched = true;
-                }
-            }
-
-            if !matched {
+            let Some(indices) = note_ends.remove(&key(sustain_start)) else {
                 self.warn(
+                    DiagnosticCode::MissingPreviousNote,
                     "Sustain note has no matching preceding note".to_string(),
                     sustain_range,
                 );
+                continue;
+            };
+            let mut overflow = false;
+            for &idx in &indices {
+                if let EventBody::Note(note) = &mut self.events[idx].body {
+                    match note.duration.checked_add(sustain_dur) {
+                        Some(total) => note.duration = total,
+                        None => overflow = true,
+                    }
+                    note.duration_seconds += sustain_dur_sec;
+                }
+            }
+            match sustain_start.checked_add(sustain_dur) {
+                Some(end) if !overflow => note_ends.entry(key(end)).or_default().extend(indices),
+                _ => self.report_duration_overflow(sustain_range),
             }
         }
         self.events.retain(|e| {
@@ -1246,20 +3390,112 @@ impl Compiler {
         });
     }
 
-    fn error(&mut self, message: String, span: TextRange) {
-        self.diagnostics.push(Diagnostic {
-            message,
-            level: DiagnosticLevel::Error,
+    /// Whether the piece so far stays within the configured limits. Only the first limit
+    /// exceeded is reported, at `span`; callers skip the rest of the piece from there.
+    fn within_limits(&mut self, span: TextRange) -> bool {
+        let exceeded = if self.events.len() >= self.limits.max_events {
+            format!("Piece has more than {} events", self.limits.max_events)
+        } else if self.state.time.seconds > self.limits.max_seconds {
+            format!("Piece is longer than {} seconds", self.limits.max_seconds)
+        } else {
+            return true;
+        };
+        if !self
+            .diagnostics
+            .iter()
+            .any(|d| d.code == DiagnosticCode::LimitExceeded)
+        {
+            self.error(DiagnosticCode::LimitExceeded, exceeded, span);
+        }
+        false
+    }
+
+    /// Moves the cursor by `duration`; see [`Self::offset_time`].
+    fn advance(&mut self, duration: Rational64, span: TextRange) {
+        self.state.time = self.offset_time(self.state.time, duration, span);
+    }
+
+    /// `time` moved by `duration`. When the exact position no longer fits, the overflow is
+    /// reported at `span` and `time` is kept, so the rest of the piece still compiles.
+    fn offset_time(&mut self, time: TimeStamp, duration: Rational64, span: TextRange) -> TimeStamp {
+        time.checked_add_duration(duration, &self.state)
+            .unwrap_or_else(|| {
+                self.report_duration_overflow(span);
+                time
+            })
+    }
+
+    /// Reports the first duration overflow only: once positions stop fitting, every later
+    /// step overflows from the same place.
+    fn report_duration_overflow(&mut self, span: TextRange) {
+        if self
+            .diagnostics
+            .iter()
+            .any(|d| d.code == DiagnosticCode::DurationOverflow)
+        {
+            return;
+        }
+        self.error(
+            DiagnosticCode::DurationOverflow,
+            "Duration is too fine-grained to be represented exactly".to_string(),
             span,
-        });
+        );
+    }
+
+    fn error(&mut self, code: DiagnosticCode, message: String, span: TextRange) {
+        self.report(Diagnostic::new(DiagnosticLevel::Error, code, message, span));
     }
 
-    fn warn(&mut self, message: String, span: TextRange) {
-        self.diagnostics.push(Diagnostic {
+    fn warn(&mut self, code: DiagnosticCode, message: String, span: TextRange) {
+        self.report(Diagnostic::new(
+            DiagnosticLevel::Warning,
+            code,
             message,
-            level: DiagnosticLevel::Warning,
             span,
-        });
+        ));
+    }
+
+    fn report(&mut self, diagnostic: Diagnostic) {
+        self.diagnostics.push(diagnostic);
+    }
+
+    /// Reports an undefined name, suggesting the closest defined macro as a fix.
+    fn error_undefined(&mut self, message: String, name: &SyntaxToken) {
+        let mut diagnostic = Diagnostic::new(
+            DiagnosticLevel::Error,
+            DiagnosticCode::UndefinedName,
+            message,
+            name.text_range(),
+        );
+        if let Some(candidate) = self.macros.suggest(name.text()) {
+            diagnostic = diagnostic.with_fix(
+                format!("Did you mean {}?", candidate),
+                name.text_range(),
+                candidate.to_string(),
+            );
+        }
+        self.report(diagnostic);
+    }
+
+    /// The simple or alias macro call supplying the pitches of a note, as an expansion trace.
+    fn note_macro_call(&self, note: &ast::Note) -> Vec<MacroCall> {
+        let Some(invoke) = note.macro_invoke() else {
+            return Vec::new();
+        };
+        let Some(name) = invoke.name() else {
+            return Vec::new();
+        };
+        let key = self.macros.resolve(name.text(), &self.macro_scope);
+        let is_drum = self.in_percussion && drum_key(name.text()).is_some();
+        let is_macro = self.macros.simple_macros.contains_key(key.as_str())
+            || self.macros.alias_macros.contains_key(key.as_str());
+        if is_drum || !is_macro {
+            return Vec::new();
+        }
+        vec![MacroCall {
+            name: SmolStr::new(name.text()),
+            range: invoke.syntax().text_range(),
+        }]
     }
 
     fn push_event(&mut self, body: EventBody, range: TextRange) {
@@ -1267,8 +3503,42 @@ impl Compiler {
             body,
             range,
             range_invoked: None,
-            start_time: self.state.time.clone(),
+            macro_trace: Vec::new(),
+            start_time: self.state.time,
+            voice: self.line_voice.clone(),
+            channel: None,
+            instrument: None,
+        });
+    }
+}
+
+impl FromStr for Note {
+    type Err = ParsePitchError;
+
+    /// Compiles a single note such as `3/2@C4[8]` as it would sound at the start of a piece
+    /// with the default settings; a note without a duration lasts one quarter.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parsed = parse_source(format!("{s},\n").into());
+        if let Some(error) = parsed.errors().first() {
+            return Err(ParsePitchError(error.message.clone()));
+        }
+        let mut compiler = Compiler::new();
+        compiler.compile(&parsed.syntax_node());
+        if let Some(error) = compiler
+            .diagnostics
+            .iter()
+            .find(|d| d.level == DiagnosticLevel::Error)
+        {
+            return Err(ParsePitchError(error.message.clone()));
+        }
+        let mut notes = compiler.events.into_iter().filter_map(|e| match e.body {
+            EventBody::Note(note) => Some(note),
+            _ => None,
         });
+        match (notes.next(), notes.next()) {
+            (Some(note), None) => Ok(note),
+            _ => Err(ParsePitchError(format!("Expected a single note: {s}"))),
+        }
     }
 }
 
@@ -1276,7 +3546,13 @@ impl Compiler {
 mod tests {
     use std::{fs, path::Path, sync::Arc};
 
-    use crate::rowan::parse_fn::parse_source;
+    use crate::{
+        compiler::timeline::Timeline,
+        rowan::{
+            parse_fn::parse_source,
+            parser::{TextEdit, reparse},
+        },
+    };
 
     use super::*;
 
@@ -1301,7 +3577,7 @@ mod tests {
             .any(|d| matches!(d.level, DiagnosticLevel::Error))
     }
 
-    fn first_note_freq(compiler: &Compiler) -> f32 {
+    fn first_note_freq(compiler: &Compiler) -> f64 {
         compiler
             .events
             .iter()
@@ -1326,11 +3602,20 @@ mod tests {
             })
             .expect("expected one note event");
 
-        let right_freq = 261.63f32 * 1.5;
-        let expected = right_freq * 2f32.powf((60f32 - 67f32) / 12.0);
+        let right_freq = 261.63f64 * 1.5;
+        let expected = right_freq * 2f64.powf((60f64 - 67f64) / 12.0);
         assert!((note.freq - expected).abs() < 0.2);
     }
 
+    #[test]
+    fn compile_long_pitch_chain_keeps_frequency_precision() {
+        let chained = compile_source(&format!("{}A4,\n", "81/80@80/81@".repeat(20)));
+        let direct = compile_source("A4,\n");
+        assert!(!has_error_diagnostics(&chained));
+        let ratio = first_note_freq(&chained) / first_note_freq(&direct);
+        assert!((ratio - 1.0).abs() < 1e-12);
+    }
+
     #[test]
     fn compile_pitch_chain_rejects_rest_or_sustain() {
         let compiler = compile_source(".@C4,\n");
@@ -1354,8 +3639,8 @@ mod tests {
             })
             .expect("expected one note event");
 
-        let right_freq = 261.63f32 * 1.5;
-        let expected = right_freq * 2f32.powf((60f32 - 67f32) / 12.0);
+        let right_freq = 261.63f64 * 1.5;
+        let expected = right_freq * 2f64.powf((60f64 - 67f64) / 12.0);
         assert!((note.freq - expected).abs() < 0.3);
     }
 
@@ -1378,248 +3663,1774 @@ mod tests {
     }
 
     #[test]
-    fn compile_macro_invoke_head_unrestricted_tail_identifier_restricted() {
-        let compiler = compile_source("m = C4:D4\nb = 3/2\nm@b,\n");
-        assert!(!has_error_diagnostics(&compiler));
-
-        let note_count = compiler
+    fn compile_macro_invoke_head_unrestricted_tail_identifier_restricted() {
+        let compiler = compile_source("m = C4:D4\nb = 3/2\nm@b,\n");
+        assert!(!has_error_diagnostics(&compiler));
+
+        let note_count = compiler
+            .events
+            .iter()
+            .filter(|e| matches!(e.body, EventBody::Note(_)))
+            .count();
+        assert_eq!(note_count, 2);
+    }
+
+    #[test]
+    fn compile_simple_macro_def_allows_pitch_chain_with_identifier() {
+        let compiler = compile_source("a = 3/2\nm = C4@a\nm,\n");
+        assert!(!has_error_diagnostics(&compiler));
+
+        let note = compiler
+            .events
+            .iter()
+            .find_map(|e| match &e.body {
+                EventBody::Note(n) => Some(n.clone()),
+                _ => None,
+            })
+            .expect("expected one note event");
+
+        let right_freq = 261.63f64 * 1.5;
+        let expected = right_freq * 2f64.powf((60f64 - 67f64) / 12.0);
+        assert!((note.freq - expected).abs() < 0.3);
+    }
+
+    #[test]
+    fn compile_simple_macro_anchor_pitch_chain() {
+        let compiler = compile_source("m = 3/2\nm@D4,\n");
+        assert!(!has_error_diagnostics(&compiler));
+
+        let note = compiler
+            .events
+            .iter()
+            .find_map(|e| match &e.body {
+                EventBody::Note(n) => Some(n.clone()),
+                _ => None,
+            })
+            .expect("expected one note event");
+
+        let expected = 293.66f64 * 1.5;
+        assert!((note.freq - expected).abs() < 0.3);
+    }
+
+    #[test]
+    fn compile_complex_macro_anchor_pitch_chain() {
+        let compiler = compile_source("m =\n3/2,\n\nm@D4,\n");
+        assert!(!has_error_diagnostics(&compiler));
+
+        let note = compiler
+            .events
+            .iter()
+            .find_map(|e| match &e.body {
+                EventBody::Note(n) => Some(n.clone()),
+                _ => None,
+            })
+            .expect("expected one note event");
+
+        let expected = 293.66f64 * 1.5;
+        assert!((note.freq - expected).abs() < 0.3);
+    }
+
+    #[test]
+    fn compile_simple_macro_anchor_appends_pitch_chain() {
+        let direct = compile_source("3/2@D4,\n");
+        let from_macro = compile_source("m = 3/2\nm@D4,\n");
+        assert!(!has_error_diagnostics(&direct));
+        assert!(!has_error_diagnostics(&from_macro));
+
+        let direct_freq = first_note_freq(&direct);
+        let macro_freq = first_note_freq(&from_macro);
+        assert!((direct_freq - macro_freq).abs() < 1e-3);
+    }
+
+    #[test]
+    fn compile_complex_macro_anchor_appends_pitch_chain() {
+        let direct = compile_source("3/2@D4,\n");
+        let from_macro = compile_source("m =\n3/2,\n\nm@D4,\n");
+        assert!(!has_error_diagnostics(&direct));
+        assert!(!has_error_diagnostics(&from_macro));
+
+        let direct_freq = first_note_freq(&direct);
+        let macro_freq = first_note_freq(&from_macro);
+        assert!((direct_freq - macro_freq).abs() < 1e-3);
+    }
+
+    #[test]
+    fn compile_pitch_chain_plus_suffix_equivalent_to_ratio_up() {
+        let with_suffix = compile_source("3/2+,\n");
+        let with_ratio = compile_source("3/2@2/1,\n");
+        assert!(!has_error_diagnostics(&with_suffix));
+        assert!(!has_error_diagnostics(&with_ratio));
+
+        let suffix_freq = first_note_freq(&with_suffix);
+        let ratio_freq = first_note_freq(&with_ratio);
+        assert!((suffix_freq - ratio_freq).abs() < 1e-3);
+    }
+
+    #[test]
+    fn compile_pitch_chain_minus_suffix_equivalent_to_ratio_down() {
+        let with_suffix = compile_source("3/2-,\n");
+        let with_ratio = compile_source("3/2@1/2,\n");
+        assert!(!has_error_diagnostics(&with_suffix));
+        assert!(!has_error_diagnostics(&with_ratio));
+
+        let suffix_freq = first_note_freq(&with_suffix);
+        let ratio_freq = first_note_freq(&with_ratio);
+        assert!((suffix_freq - ratio_freq).abs() < 1e-3);
+    }
+
+    #[test]
+    fn compile_macro_invoke_plus_suffix_equivalent_to_ratio_up() {
+        let with_suffix = compile_source("m = 3/2\nm+,\n");
+        let with_ratio = compile_source("m = 3/2\nm@2/1,\n");
+        assert!(!has_error_diagnostics(&with_suffix));
+        assert!(!has_error_diagnostics(&with_ratio));
+
+        let suffix_freq = first_note_freq(&with_suffix);
+        let ratio_freq = first_note_freq(&with_ratio);
+        assert!((suffix_freq - ratio_freq).abs() < 1e-3);
+    }
+
+    #[test]
+    fn compile_macro_invoke_minus_suffix_equivalent_to_ratio_down() {
+        let with_suffix = compile_source("m = 3/2\nm-,\n");
+        let with_ratio = compile_source("m = 3/2\nm@1/2,\n");
+        assert!(!has_error_diagnostics(&with_suffix));
+        assert!(!has_error_diagnostics(&with_ratio));
+
+        let suffix_freq = first_note_freq(&with_suffix);
+        let ratio_freq = first_note_freq(&with_ratio);
+        assert!((suffix_freq - ratio_freq).abs() < 1e-3);
+    }
+
+    #[test]
+    fn compile_simple_macro_preserves_pitch_chain_semantics() {
+        let direct = compile_source("4/5@3/2,\n");
+        let from_macro = compile_source("m = 4/5@3/2\nm,\n");
+        assert!(!has_error_diagnostics(&direct));
+        assert!(!has_error_diagnostics(&from_macro));
+
+        let direct_freq = first_note_freq(&direct);
+        let macro_freq = first_note_freq(&from_macro);
+        assert!((direct_freq - macro_freq).abs() < 1e-3);
+    }
+
+    #[test]
+    fn compile_complex_macro_preserves_pitch_chain_semantics() {
+        let direct = compile_source("4/5@3/2,\n");
+        let from_macro = compile_source("m =\n4/5@3/2,\n\nm,\n");
+        assert!(!has_error_diagnostics(&direct));
+        assert!(!has_error_diagnostics(&from_macro));
+
+        let direct_freq = first_note_freq(&direct);
+        let macro_freq = first_note_freq(&from_macro);
+        assert!((direct_freq - macro_freq).abs() < 1e-3);
+    }
+
+    #[test]
+    fn compile_base_pitch_accepts_non_frequency_reference() {
+        let compiler = compile_source("<C4=3/2>\n");
+        assert!(!has_error_diagnostics(&compiler));
+        assert!(
+            compiler
+                .events
+                .iter()
+                .any(|e| matches!(e.body, EventBody::BaseNoteDef(_)))
+        );
+        assert!(
+            compiler
+                .events
+                .iter()
+                .any(|e| matches!(e.body, EventBody::BaseFequencyDef(_)))
+        );
+    }
+
+    #[test]
+    fn compile_base_pitch_rhs_identifier_chain_alias_macro_ok() {
+        let direct = compile_source("<C4=3/2@5/4>\n");
+        let from_ident = compile_source("a = 3/2@5/4\n<C4=a>\n");
+        assert!(!has_error_diagnostics(&direct));
+        assert!(!has_error_diagnostics(&from_ident));
+
+        let direct_freq = direct
+            .events
+            .iter()
+            .find_map(|e| match e.body {
+                EventBody::BaseFequencyDef(f) => Some(f),
+                _ => None,
+            })
+            .expect("expected base frequency event");
+        let ident_freq = from_ident
+            .events
+            .iter()
+            .find_map(|e| match e.body {
+                EventBody::BaseFequencyDef(f) => Some(f),
+                _ => None,
+            })
+            .expect("expected base frequency event");
+
+        assert!((direct_freq - ident_freq).abs() < 1e-3);
+    }
+
+    #[test]
+    fn compile_base_pitch_rhs_identifier_chain_rejects_multi_note_simple_macro() {
+        let compiler = compile_source("a = C4:D4\n<C4=a>\n");
+        assert!(compiler.diagnostics.iter().any(|d| {
+            d.message
+                .contains("Identifier in base pitch RHS must resolve to an alias macro")
+        }));
+    }
+
+    #[test]
+    fn compile_base_pitch_spell_without_rhs_infers_frequency() {
+        let shorthand = compile_source("<D4>\n");
+        let explicit = compile_source("<D4=D4>\n");
+        assert!(!has_error_diagnostics(&shorthand));
+        assert!(!has_error_diagnostics(&explicit));
+
+        let shorthand_freq = shorthand
+            .events
+            .iter()
+            .find_map(|e| match e.body {
+                EventBody::BaseFequencyDef(f) => Some(f),
+                _ => None,
+            })
+            .expect("expected base frequency event");
+        let explicit_freq = explicit
+            .events
+            .iter()
+            .find_map(|e| match e.body {
+                EventBody::BaseFequencyDef(f) => Some(f),
+                _ => None,
+            })
+            .expect("expected base frequency event");
+
+        assert!((shorthand_freq - explicit_freq).abs() < 1e-3);
+    }
+
+    #[test]
+    fn compile_lyric_aligned_to_note() {
+        let compiler = compile_source("C4,D4\"la\";E4\"di\",\n");
+        assert!(!has_error_diagnostics(&compiler));
+
+        let lyrics: Vec<(String, f64)> = compiler
+            .events
+            .iter()
+            .filter_map(|e| match &e.body {
+                EventBody::Lyric(text) => Some((text.clone(), e.start_time.seconds)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(lyrics.len(), 2);
+        assert_eq!(lyrics[0].0, "la");
+        assert!((lyrics[0].1 - 0.5).abs() < 1e-6);
+        assert_eq!(lyrics[1].0, "di");
+        assert!((lyrics[1].1 - 0.75).abs() < 1e-6);
+    }
+
+    #[test]
+    fn compile_complex_macro_body_is_shared_between_invocations() {
+        let compiler = compile_source("m =\nC4,D4,\n\nm,\nm,\n");
+        assert!(!has_error_diagnostics(&compiler));
+        let starts: Vec<Rational64> = compiler
+            .events
+            .iter()
+            .filter(|e| matches!(e.body, EventBody::Note(_)))
+            .map(|e| e.start_time.position)
+            .collect();
+        assert_eq!(starts.len(), 4);
+        assert_eq!(starts[2] - starts[0], Rational64::new(1, 4));
+
+        // invocations re-time their own copies and leave the stored body at the macro start
+        let body = &compiler.macros.complex_macros["m"];
+        assert!(body[0].start_time.position.is_zero());
+        let snapshot = &compiler.checkpoints.last().expect("end checkpoint").macros;
+        assert!(Arc::ptr_eq(body, &snapshot.complex_macros["m"]));
+    }
+
+    #[test]
+    fn compile_macro_events_record_full_invocation_chain() {
+        let source = "s = E4\ninner =\nC4,s,\n\nouter =\ninner,\n\nD4,outer,\n";
+        let compiler = compile_source(source);
+        assert!(!has_error_diagnostics(&compiler));
+
+        let traces: Vec<Vec<(&str, &str)>> = compiler
+            .events
+            .iter()
+            .filter(|e| matches!(e.body, EventBody::Note(_)))
+            .map(|e| {
+                e.macro_trace
+                    .iter()
+                    .map(|call| (call.name.as_str(), &source[call.range]))
+                    .collect()
+            })
+            .collect();
+        assert_eq!(
+            traces,
+            vec![
+                vec![],
+                vec![("outer", "outer"), ("inner", "inner")],
+                vec![("outer", "outer"), ("inner", "inner"), ("s", "s")],
+            ]
+        );
+        let outer_call = compiler
+            .events
+            .iter()
+            .find_map(|e| e.macro_trace.first())
+            .expect("expected a macro expansion");
+        assert_eq!(
+            usize::from(outer_call.range.start()),
+            source.rfind("outer").unwrap()
+        );
+    }
+
+    #[test]
+    fn compile_complex_macro_keeps_lyrics() {
+        let compiler = compile_source("m =\nC4\"la\",\n\nD4,m,\n");
+        assert!(!has_error_diagnostics(&compiler));
+
+        let lyric = compiler
+            .events
+            .iter()
+            .find(|e| matches!(e.body, EventBody::Lyric(_)))
+            .expect("expected lyric event from macro");
+        assert!((lyric.start_time.seconds - 0.5).abs() < 1e-6);
+        assert!(lyric.range_invoked.is_some());
+    }
+
+    #[test]
+    fn compile_metadata_block() {
+        let compiler = compile_source("title: Prelude\ncomposer:  Someone \n\nC4,\n");
+        assert!(!has_error_diagnostics(&compiler));
+        assert_eq!(compiler.metadata.title.as_deref(), Some("Prelude"));
+        assert_eq!(compiler.metadata.composer.as_deref(), Some("Someone"));
+        assert_eq!(compiler.metadata.copyright, None);
+    }
+
+    #[test]
+    fn compile_metadata_redefinition_warns() {
+        let compiler = compile_source("title: A\ntitle: B\n");
+        assert_eq!(compiler.metadata.title.as_deref(), Some("B"));
+        assert!(
+            compiler
+                .diagnostics
+                .iter()
+                .any(|d| d.message.contains("Metadata field redefined"))
+        );
+    }
+
+    #[test]
+    fn compile_unknown_metadata_field_of_a_built_tree_reports_error() {
+        let field = GreenToken::new(SyntaxKind::MetaField.into(), "tempo: fast");
+        let metadata = GreenNode::new(SyntaxKind::NODE_METADATA.into(), [field.into()]);
+        let root = GreenNode::new(SyntaxKind::NODE_ROOT.into(), [metadata.into()]);
+        let mut compiler = Compiler::new();
+        compiler.compile(&SyntaxNode::new_root(root));
+        let codes: Vec<_> = compiler.diagnostics.iter().map(|d| d.code).collect();
+        assert_eq!(codes, vec![DiagnosticCode::UnknownMetadataField]);
+    }
+
+    #[test]
+    fn compile_grace_note_steals_from_following_note() {
+        let compiler = compile_source("(g D4)C4,E4,\n");
+        assert!(!has_error_diagnostics(&compiler));
+        let notes: Vec<(f64, Rational64, Rational64)> = compiler
+            .events
+            .iter()
+            .filter_map(|e| match &e.body {
+                EventBody::Note(n) => Some((e.start_time.seconds, e.start_time.ticks, n.duration)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(notes.len(), 3);
+        let grace = Rational64::new(1, 32);
+        assert_eq!(notes[0].1, Rational64::zero());
+        assert_eq!(notes[0].2, grace);
+        assert_eq!(notes[1].1, grace);
+        assert_eq!(notes[1].2, Rational64::new(1, 4) - grace);
+        // bar ticks of the following note are unaffected
+        assert_eq!(notes[2].1, Rational64::new(1, 4));
+        assert!((notes[2].0 - 0.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn compile_grace_note_too_long_warns() {
+        let mut compiler = Compiler::new();
+        compiler.state.grace_duration = Rational64::new(1, 2);
+        let parsed = parse_source(Arc::from("(g D4)C4,\n"));
+        compiler.compile(&parsed.syntax_node());
+        assert!(
+            compiler
+                .diagnostics
+                .iter()
+                .any(|d| d.message.contains("Grace notes are longer"))
+        );
+    }
+
+    #[test]
+    fn compile_trill_alternates_with_upper_neighbour() {
+        let compiler = compile_source("C4~tr,\n");
+        assert!(!has_error_diagnostics(&compiler));
+        let notes: Vec<(Rational64, f64)> = compiler
+            .events
+            .iter()
+            .filter_map(|e| match &e.body {
+                EventBody::Note(n) => Some((e.start_time.ticks, n.freq)),
+                _ => None,
+            })
+            .collect();
+        // a quarter note at the default rate of 1/32 yields 8 sub-notes
+        assert_eq!(notes.len(), 8);
+        assert_eq!(notes[1].0, Rational64::new(1, 32));
+        assert!((notes[0].1 - 261.63).abs() < 0.1);
+        assert!((notes[1].1 - 293.67).abs() < 0.1);
+        assert!((notes[2].1 - 261.63).abs() < 0.1);
+    }
+
+    #[test]
+    fn compile_tremolo_repeats_note_and_keeps_timing() {
+        let mut compiler = Compiler::new();
+        compiler.state.ornament_rate = Rational64::new(1, 12);
+        let parsed = parse_source(Arc::from("C4~trem,D4,\n"));
+        compiler.compile(&parsed.syntax_node());
+        let notes: Vec<(Rational64, Rational64, f64)> = compiler
+            .events
+            .iter()
+            .filter_map(|e| match &e.body {
+                EventBody::Note(n) => Some((e.start_time.ticks, n.duration, n.freq)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(notes.len(), 4);
+        assert!(notes[..3].iter().all(|n| (n.2 - 261.63).abs() < 0.1));
+        assert_eq!(notes[2].1, Rational64::new(1, 12));
+        assert_eq!(notes[3].0, Rational64::new(1, 4));
+    }
+
+    #[test]
+    fn compile_multi_bar_rest_advances_measures() {
+        let compiler = compile_source("C4,,,,\nR3\nD4,,,,\n");
+        assert!(compiler.diagnostics.is_empty());
+        let measures: Vec<u32> = compiler
+            .events
+            .iter()
+            .filter_map(|e| match e.body {
+                EventBody::NewMeasure(bar) => Some(bar),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(measures, vec![1, 2, 3, 4, 5]);
+        let last = compiler
+            .events
+            .iter()
+            .rev()
+            .find(|e| matches!(e.body, EventBody::Note(_)))
+            .expect("expected note after rest");
+        assert_eq!(last.start_time.bars, 4);
+        assert!((last.start_time.seconds - 8.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn compile_bar_repeat_replays_previous_measure() {
+        let compiler = compile_source("C4,D4,E4,F4,\n%\n");
+        assert!(compiler.diagnostics.is_empty());
+        let notes: Vec<(u32, Rational64, f64, bool)> = compiler
+            .events
+            .iter()
+            .filter_map(|e| match &e.body {
+                EventBody::Note(n) => Some((
+                    e.start_time.bars,
+                    e.start_time.ticks,
+                    n.freq,
+                    e.range_invoked.is_some(),
+                )),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(notes.len(), 8);
+        for (orig, repeated) in notes[..4].iter().zip(&notes[4..]) {
+            assert_eq!(repeated.0, 1);
+            assert_eq!(orig.1, repeated.1);
+            assert_eq!(orig.2, repeated.2);
+            assert!(repeated.3);
+        }
+    }
+
+    #[test]
+    fn compile_bar_repeat_without_previous_measure_warns() {
+        let compiler = compile_source("%\n");
+        assert!(
+            compiler
+                .diagnostics
+                .iter()
+                .any(|d| d.message.contains("no previous measure"))
+        );
+    }
+
+    #[test]
+    fn compile_at_bar_and_tick_positions_note() {
+        let compiler = compile_source("C4,,,,\n(at 3:1/4) D4,,,\n");
+        assert!(compiler.diagnostics.is_empty());
+        let measures: Vec<u32> = compiler
+            .events
+            .iter()
+            .filter_map(|e| match e.body {
+                EventBody::NewMeasure(bar) => Some(bar),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(measures, vec![1, 2, 3]);
+        let last = compiler
+            .events
+            .iter()
+            .rev()
+            .find(|e| matches!(e.body, EventBody::Note(_)))
+            .expect("expected positioned note");
+        assert_eq!(last.start_time.bars, 2);
+        assert_eq!(last.start_time.ticks, Rational64::new(1, 4));
+        assert!((last.start_time.seconds - 4.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn compile_at_seconds_snaps_to_quantize_grid() {
+        let compiler = compile_source("(at 3s) C4,,\n");
+        let note = compiler
+            .events
+            .iter()
+            .find(|e| matches!(e.body, EventBody::Note(_)))
+            .expect("expected positioned note");
+        assert_eq!(note.start_time.bars, 1);
+        assert_eq!(note.start_time.ticks, Rational64::new(1, 2));
+        assert!((note.start_time.seconds - 3.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn compile_at_backwards_reports_error() {
+        let compiler = compile_source("C4,,,,\nD4,,,,\n(at 1:1/4) E4,,,\n");
+        assert!(has_error_diagnostics(&compiler));
+    }
+
+    #[test]
+    fn compile_pickup_bar_does_not_warn() {
+        let compiler = compile_source("(pickup 1/4)\nG4,\nC4,D4,E4,F4,\nG4,\n");
+        let misaligned = compiler
+            .diagnostics
+            .iter()
+            .filter(|d| d.message.contains("do not align with time signature"))
+            .count();
+        // only the last, incomplete bar is reported
+        assert_eq!(misaligned, 1);
+        let c4 = compiler
+            .events
+            .iter()
+            .filter(|e| matches!(e.body, EventBody::Note(_)))
+            .nth(1)
+            .expect("expected note in first full bar");
+        assert_eq!(c4.start_time.bars, 1);
+        assert!(c4.start_time.ticks.is_zero());
+    }
+
+    #[test]
+    fn compile_pickup_after_first_bar_reports_error() {
+        let compiler = compile_source("C4,D4,E4,F4,\n(pickup 1/4)\n");
+        assert!(has_error_diagnostics(&compiler));
+    }
+
+    #[test]
+    fn compile_macro_repeat_suffix_matches_explicit_repetition() {
+        let repeated = compile_source("m = C4:E4\nm*3,G4,\n");
+        let explicit = compile_source("m = C4:E4\nm,m,m,G4,\n");
+        assert!(!has_error_diagnostics(&repeated));
+        let notes = |c: &Compiler| -> Vec<(Rational64, f64)> {
+            c.events
+                .iter()
+                .filter_map(|e| match &e.body {
+                    EventBody::Note(n) => Some((e.start_time.ticks, n.freq)),
+                    _ => None,
+                })
+                .collect()
+        };
+        assert_eq!(notes(&repeated), notes(&explicit));
+        assert_eq!(notes(&repeated).len(), 7);
+    }
+
+    #[test]
+    fn compile_complex_macro_repeat_suffix() {
+        let compiler = compile_source("m =\nC4,D4,\n\nm*2,,\n");
+        let starts: Vec<Rational64> = compiler
+            .events
+            .iter()
+            .filter(|e| matches!(e.body, EventBody::Note(_)))
+            .map(|e| e.start_time.ticks)
+            .collect();
+        assert_eq!(
+            starts,
+            vec![
+                Rational64::new(0, 4),
+                Rational64::new(1, 4),
+                Rational64::new(2, 4),
+                Rational64::new(3, 4),
+            ]
+        );
+    }
+
+    #[test]
+    fn compile_choice_is_reproducible_with_seed() {
+        let source = "?{C4|E4|G4},?{C4|E4|G4},?{C4|E4|G4},?{C4|E4|G4},\n";
+        let freqs = |seed: u64| -> Vec<f64> {
+            let parsed = parse_source(Arc::from(source));
+            let mut compiler = Compiler::with_config(CompilerConfig {
+                seed,
+                ..Default::default()
+            });
+            compiler.compile(&parsed.syntax_node());
+            assert!(!has_error_diagnostics(&compiler));
+            compiler
+                .events
+                .iter()
+                .filter_map(|e| match &e.body {
+                    EventBody::Note(n) => Some(n.freq),
+                    _ => None,
+                })
+                .collect()
+        };
+        let first = freqs(3);
+        assert_eq!(first.len(), 4);
+        assert_eq!(first, freqs(3));
+        let candidates = [261.63f64, 329.63, 392.0];
+        assert!(
+            first
+                .iter()
+                .all(|f| candidates.iter().any(|c| (f - c).abs() < 0.1))
+        );
+    }
+
+    #[test]
+    fn compile_transpose_scales_following_notes() {
+        let compiler =
+            compile_source("C4,\n(transpose 3/2)\nC4,\n(transpose +1200c)\n<C4=261.63>\nC4,\n");
+        assert!(!has_error_diagnostics(&compiler));
+        let notes: Vec<&Note> = compiler
+            .events
+            .iter()
+            .filter_map(|e| match &e.body {
+                EventBody::Note(n) => Some(n),
+                _ => None,
+            })
+            .collect();
+        assert!((notes[0].freq - 261.63).abs() < 0.01);
+        assert!((notes[1].freq - 261.63 * 1.5).abs() < 0.01);
+        assert!((notes[2].freq - 261.63 * 2.0).abs() < 0.01);
+        // notation is unchanged
+        assert_eq!(notes[1].pitch_chain, notes[0].pitch_chain);
+        assert!((notes[1].pitch_ratio - notes[0].pitch_ratio).abs() < 1e-6);
+    }
+
+    #[test]
+    fn compile_ratio_powers_stack_in_chain() {
+        let compiler = compile_source("C4,(3/2)^2@C4,3/2^-1@C4,\n");
+        assert!(!has_error_diagnostics(&compiler));
+        let notes: Vec<(f64, usize)> = compiler
+            .events
+            .iter()
+            .filter_map(|e| match &e.body {
+                EventBody::Note(n) => Some((n.freq, n.pitch_chain.len())),
+                _ => None,
+            })
+            .collect();
+        assert!((notes[1].0 / notes[0].0 - 2.25).abs() < 1e-5);
+        assert_eq!(notes[1].1, 3);
+        assert!((notes[2].0 / notes[0].0 - 2.0 / 3.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn compile_subharmonics_divide_base_frequency() {
+        let compiler = compile_source("1/7,u7,u4@C5,\n");
+        assert!(!has_error_diagnostics(&compiler));
+        let notes: Vec<&Note> = compiler
+            .events
+            .iter()
+            .filter_map(|e| match &e.body {
+                EventBody::Note(n) => Some(n),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(notes[1].pitch_chain, vec![Pitch::Subharmonic(7)]);
+        assert!((notes[1].freq - notes[0].freq).abs() < 1e-3);
+        assert!((notes[1].pitch_ratio - 1.0 / 7.0).abs() < 1e-6);
+        assert_eq!(notes[2].pitch_chain.len(), 2);
+    }
+
+    #[test]
+    fn compile_fractional_cents() {
+        let compiler = compile_source("C4,+14.7c@C4,-0.5c@C4,\n");
+        assert!(!has_error_diagnostics(&compiler));
+        let freqs: Vec<f64> = compiler
+            .events
+            .iter()
+            .filter_map(|e| match &e.body {
+                EventBody::Note(n) => Some(n.freq),
+                _ => None,
+            })
+            .collect();
+        let cents = |a: f64, b: f64| 1200.0 * (b / a).log2();
+        assert!((cents(freqs[0], freqs[1]) - 14.7).abs() < 1e-3);
+        assert!((cents(freqs[0], freqs[2]) + 0.5).abs() < 1e-3);
+    }
+
+    #[test]
+    fn compile_arpeggio_spreads_chord() {
+        let compiler = compile_source("^{C4:E4:G4},C5,\n");
+        assert!(!has_error_diagnostics(&compiler));
+        let notes: Vec<(Rational64, Rational64)> = compiler
+            .events
+            .iter()
+            .filter_map(|e| match &e.body {
+                EventBody::Note(n) => Some((e.start_time.ticks, n.duration)),
+                _ => None,
+            })
+            .collect();
+        let offset = Rational64::new(1, 64);
+        assert_eq!(notes[0], (Rational64::zero(), Rational64::new(1, 4)));
+        assert_eq!(notes[1].0, offset);
+        assert_eq!(notes[2].0, offset * 2);
+        // all chord notes end together and the next note is unaffected
+        assert_eq!(notes[2].0 + notes[2].1, Rational64::new(1, 4));
+        assert_eq!(notes[3].0, Rational64::new(1, 4));
+    }
+
+    #[test]
+    fn compile_arpeggio_offset_from_config() {
+        let parsed = parse_source(Arc::from("^{C4:E4},\n"));
+        let mut compiler = Compiler::with_config(CompilerConfig {
+            arpeggio_offset: Rational64::new(1, 16),
+            ..Default::default()
+        });
+        compiler.compile(&parsed.syntax_node());
+        let second = compiler
+            .events
+            .iter()
+            .filter(|e| matches!(e.body, EventBody::Note(_)))
+            .nth(1)
+            .expect("expected two notes");
+        assert_eq!(second.start_time.ticks, Rational64::new(1, 16));
+    }
+
+    #[test]
+    fn compile_config_defines_its_tempo_and_meter_at_the_start() {
+        let parsed = parse_source(Arc::from("C4,D4,E4,\nF4,\n"));
+        let mut compiler = Compiler::with_config(CompilerConfig {
+            bpm: 60.0,
+            time_signature: Rational64::new(3, 4),
+            ..Default::default()
+        });
+        compiler.compile(&parsed.syntax_node());
+        let defs: Vec<&EventBody> = compiler
+            .events
+            .iter()
+            .filter(|e| e.start_time.position.is_zero())
+            .map(|e| &e.body)
+            .filter(|body| matches!(body, EventBody::BPMDef(_) | EventBody::TimeSignatureDef(_)))
+            .collect();
+        assert_eq!(
+            defs,
+            vec![
+                &EventBody::BPMDef(60.0),
+                &EventBody::TimeSignatureDef(TimeSignature::simple(3, 4)),
+            ]
+        );
+        let timeline = Timeline::new(&compiler.events);
+        assert_eq!(
+            timeline.bar_tick_to_seconds(1, Rational64::zero()),
+            Some(3.0)
+        );
+    }
+
+    #[test]
+    fn compile_coprime_quantizes_share_a_wide_denominator() {
+        let compiler = compile_source("{65521}C4,{65519}D4,E4,\n");
+        let last = compiler
+            .events
+            .iter()
+            .rev()
+            .find(|e| matches!(e.body, EventBody::Note(_)))
+            .expect("expected the last note");
+        assert_eq!(
+            last.start_time.position,
+            Rational64::new(1, 65521) + Rational64::new(1, 65519)
+        );
+    }
+
+    #[test]
+    fn compile_iter_streams_the_same_events_as_compile() {
+        let source = "C4_,_,D4,-,\n=,-,E4,,,\nC4,F4_,\n_,-2G4,A4,-,\nv1: C4_:E4_,\nv1: _,D4,\n";
+        let compiler = compile_source(source);
+
+        let parsed = parse_source(Arc::from(source));
+        let mut streaming = Compiler::new();
+        let events: Vec<CompileEvent> = streaming.compile_iter(&parsed.syntax_node()).collect();
+        assert_eq!(events, compiler.events);
+        assert_eq!(streaming.diagnostics, compiler.diagnostics);
+        assert!(streaming.events.is_empty());
+    }
+
+    #[test]
+    fn compile_parallel_appends_sections() {
+        let source = "(60)\nC4,D4,,\nE4,F4,\nG4,A4,B4,C5,\nx\n";
+        let split = source.find("G4").expect("second section");
+        let sections: Vec<(TextSize, GreenNode)> =
+            [(0, &source[..split]), (split, &source[split..])]
+                .into_iter()
+                .map(|(offset, text)| {
+                    let green = parse_source(Arc::from(text)).green_node;
+                    (TextSize::from(offset as u32), green)
+                })
+                .collect();
+        let compiler = Compiler::compile_parallel(&CompilerConfig::default(), &sections);
+
+        let notes: Vec<(f64, String)> = compiler
+            .events
+            .iter()
+            .filter(|e| matches!(e.body, EventBody::Note(_)))
+            .map(|e| (e.start_time.seconds, get_span_text(&e.range, source).2))
+            .collect();
+        // the second section starts where the first ends, back at the configured tempo
+        let expected = [
+            (0.0, "C4"),
+            (1.0, "D4"),
+            (3.0, "E4"),
+            (4.0, "F4"),
+            (5.0, "G4"),
+            (5.5, "A4"),
+            (6.0, "B4"),
+            (6.5, "C5"),
+        ];
+        assert_eq!(
+            notes,
+            expected.map(|(seconds, text)| (seconds, text.to_string()))
+        );
+        let bars: Vec<u32> = compiler
+            .events
+            .iter()
+            .filter_map(|e| match e.body {
+                EventBody::NewMeasure(bar) => Some(bar),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(bars, vec![1, 2, 3]);
+        assert!(
+            compiler
+                .events
+                .iter()
+                .any(|e| e.body == EventBody::BPMDef(120.0)
+                    && e.start_time.seconds == 5.0
+                    && e.start_time.bars == 2)
+        );
+        let errors: Vec<String> = compiler
+            .diagnostics
+            .iter()
+            .filter(|d| d.level == DiagnosticLevel::Error)
+            .map(|d| get_span_text(&d.span, source).2)
+            .collect();
+        assert_eq!(errors, vec!["x"]);
+    }
+
+    #[test]
+    fn compile_parallel_reports_overflow_and_limits_of_the_merged_piece() {
+        let compile_sections = |limits: CompileLimits, texts: &[&str]| {
+            let mut offset = 0;
+            let sections: Vec<(TextSize, GreenNode)> = texts
+                .iter()
+                .map(|text| {
+                    let green = parse_source(Arc::from(*text)).green_node;
+                    let section = (TextSize::from(offset as u32), green);
+                    offset += text.len();
+                    section
+                })
+                .collect();
+            let config = CompilerConfig {
+                limits,
+                ..CompilerConfig::default()
+            };
+            Compiler::compile_parallel(&config, &sections)
+        };
+        let count = |compiler: &Compiler, code: DiagnosticCode| {
+            compiler
+                .diagnostics
+                .iter()
+                .filter(|d| d.code == code)
+                .count()
+        };
+
+        let compiler = compile_sections(
+            CompileLimits::default(),
+            &["{65521}C4,{65519}D4,{65497}E4,\n", "{65479}C4,{65449}D4,\n"],
+        );
+        assert_eq!(count(&compiler, DiagnosticCode::DurationOverflow), 1);
+
+        let few = CompileLimits {
+            max_events: 8,
+            ..CompileLimits::default()
+        };
+        let compiler = compile_sections(few, &["C4,D4,E4,\n", "F4,G4,A4,\n", "B4,C5,D5,\n"]);
+        assert_eq!(count(&compiler, DiagnosticCode::LimitExceeded), 1);
+        assert!(compiler.events.len() <= 8);
+    }
+
+    #[test]
+    fn compile_skips_error_nodes_and_keeps_the_rest_of_the_line() {
+        let source = "(60)\nC4,= = D4,E4:=G4,.,\n";
+        let compiler = compile_source(source);
+        // the parser reports the broken regions; the compiler adds nothing on top
+        assert!(compiler.diagnostics.is_empty());
+        let notes: Vec<(f64, String)> = compiler
+            .events
+            .iter()
+            .filter(|e| matches!(e.body, EventBody::Note(_)))
+            .map(|e| {
+                let text = get_span_text(&e.range, source).2;
+                (e.start_time.seconds, text.trim().to_string())
+            })
+            .collect();
+        assert_eq!(
+            notes,
+            vec![
+                (0.0, "C4".to_string()),
+                (1.0, "D4".to_string()),
+                (2.0, "E4".to_string()),
+                (2.0, "G4".to_string()),
+                (3.0, ".".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn compile_with_state_continues_from_a_prelude() {
+        let prelude = compile_source("(60)\nm = A4\nC4,D4,\n");
+        let source = "m,B4,\n";
+        assert!(has_error_diagnostics(&compile_source(source)));
+
+        let parsed = parse_source(Arc::from(source));
+        let mut compiler = Compiler::new();
+        compiler.compile_with_state(
+            &parsed.syntax_node(),
+            prelude.state.clone(),
+            prelude.macros.clone(),
+        );
+        assert!(!has_error_diagnostics(&compiler));
+        let notes: Vec<(f64, String)> = compiler
+            .events
+            .iter()
+            .filter(|e| matches!(e.body, EventBody::Note(_)))
+            .map(|e| {
+                (
+                    e.start_time.seconds,
+                    get_span_text(&e.range_invoked.unwrap_or(e.range), source).2,
+                )
+            })
+            .collect();
+        assert_eq!(notes, vec![(0.0, "m".to_string()), (1.0, "B4".to_string())]);
+        assert!((first_note_freq(&compiler) - 440.0).abs() < 0.01);
+        let first_note = compiler
+            .events
+            .iter()
+            .find(|e| matches!(e.body, EventBody::Note(_)))
+            .expect("expected a note");
+        assert!(first_note.start_time.position.is_zero());
+        assert!(
+            compiler
+                .events
+                .iter()
+                .any(|e| e.body == EventBody::BPMDef(60.0) && e.start_time.position.is_zero())
+        );
+        let bars: Vec<u32> = compiler
+            .events
+            .iter()
+            .filter_map(|e| match e.body {
+                EventBody::NewMeasure(bar) => Some(bar),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(bars, vec![1]);
+    }
+
+    #[test]
+    fn compile_reports_duration_overflow_instead_of_panicking() {
+        let compiler = compile_source("{65521}C4,{65519}D4,{65497}E4,{65479}F4,{65449}G4,\n");
+        let overflows: Vec<_> = compiler
+            .diagnostics
+            .iter()
+            .filter(|d| d.code == DiagnosticCode::DurationOverflow)
+            .collect();
+        assert_eq!(overflows.len(), 1);
+        assert_eq!(overflows[0].level, DiagnosticLevel::Error);
+    }
+
+    #[test]
+    fn compile_seconds_do_not_drift_over_long_pieces() {
+        let bar = format!("{}\n", "C4,".repeat(12));
+        let source = format!("(97)\n{{12}}\n{}D4,-,\n", bar.repeat(30));
+        let compiler = compile_source(&source);
+        assert!(!has_error_diagnostics(&compiler));
+
+        let (start, duration) = compiler
+            .events
+            .iter()
+            .rev()
+            .find_map(|e| match &e.body {
+                EventBody::Note(n) => Some((e.start_time, n.duration)),
+                _ => None,
+            })
+            .expect("expected the last note");
+        assert_eq!(start.position, Rational64::from_integer(30));
+        assert_eq!(start.bars, 30);
+        let seconds_per_whole_note = 60.0 / (97.0 * 0.25);
+        assert_eq!(start.seconds, 30.0 * seconds_per_whole_note);
+        // the sustain still finds the note it continues
+        assert_eq!(duration, Rational64::new(1, 6));
+    }
+
+    #[test]
+    fn compile_fermata_stretches_seconds_not_ticks() {
+        let compiler = compile_source("C4,D4~fermata,E4,F4,\nG4,,,,\n");
+        assert!(compiler.diagnostics.is_empty());
+        let notes: Vec<(f64, Rational64, f64)> = compiler
+            .events
+            .iter()
+            .filter_map(|e| match &e.body {
+                EventBody::Note(n) => {
+                    Some((e.start_time.seconds, e.start_time.ticks, n.duration_seconds))
+                }
+                _ => None,
+            })
+            .collect();
+        // a quarter lasts 0.5s at 120 BPM; the fermata doubles the second one
+        assert!((notes[1].2 - 1.0).abs() < 1e-9);
+        assert_eq!(notes[2].1, Rational64::new(2, 4));
+        assert!((notes[2].0 - 1.5).abs() < 1e-9);
+        assert!((notes[4].0 - 2.5).abs() < 1e-9);
+        assert_eq!(compiler.state.bpm, 120.0);
+    }
+
+    #[test]
+    fn compile_fermata_factor_from_config() {
+        let parsed = parse_source(Arc::from("C4𝄐,\n"));
+        let mut compiler = Compiler::with_config(CompilerConfig {
+            fermata_factor: 3.0,
+            ..Default::default()
+        });
+        compiler.compile(&parsed.syntax_node());
+        let note = compiler
+            .events
+            .iter()
+            .find_map(|e| match &e.body {
+                EventBody::Note(n) => Some(n.duration_seconds),
+                _ => None,
+            })
+            .expect("expected a note");
+        assert!((note - 1.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn compile_portamento_glides_into_next_note() {
+        let compiler = compile_source("C4~D4,E4,\n");
+        assert!(!has_error_diagnostics(&compiler));
+        let notes: Vec<(Rational64, f64, Option<f64>)> = compiler
+            .events
+            .iter()
+            .filter_map(|e| match &e.body {
+                EventBody::Note(n) => Some((e.start_time.ticks, n.freq, n.portamento_from)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(notes.len(), 3);
+        assert_eq!(notes[0].2, None);
+        assert_eq!(notes[1].0, Rational64::new(1, 8));
+        assert_eq!(notes[1].2, Some(notes[0].1));
+        assert_eq!(notes[2].2, None);
+    }
+
+    #[test]
+    fn compile_chord_symbols_expand_to_chords() {
+        let compiler = compile_source("C4maj,D4:min7,o:4-5-6,\n");
+        assert!(!has_error_diagnostics(&compiler));
+        let notes: Vec<(Rational64, f64)> = compiler
+            .events
+            .iter()
+            .filter_map(|e| match &e.body {
+                EventBody::Note(n) => Some((e.start_time.ticks, n.freq)),
+                _ => None,
+            })
+            .collect();
+        let slot = |i: i64| -> Vec<f64> {
+            notes
+                .iter()
+                .filter(|(t, _)| *t == Rational64::new(i, 4))
+                .map(|&(_, f)| f)
+                .collect()
+        };
+        let cents = |a: f64, b: f64| (1200.0 * (b / a).log2()).round() as i32;
+        let cmaj = slot(0);
+        assert_eq!(cmaj.len(), 3);
+        assert_eq!(cents(cmaj[0], cmaj[2]), 700);
+        let dmin7 = slot(1);
+        assert_eq!(dmin7.len(), 4);
+        assert_eq!(cents(dmin7[0], dmin7[1]), 300);
+        assert_eq!(cents(dmin7[0], dmin7[3]), 1000);
+        let ji = slot(2);
+        assert_eq!(ji.len(), 3);
+        assert!((ji[1] / ji[0] - 1.25).abs() < 1e-4);
+    }
+
+    #[test]
+    fn compile_chord_qualities_from_config() {
+        let parsed = parse_source(Arc::from("Cneu,\n"));
+        let mut compiler = Compiler::with_config(CompilerConfig {
+            chord_qualities: vec![("neu".to_string(), vec![0, 350, 700])],
+            ..Default::default()
+        });
+        compiler.compile(&parsed.syntax_node());
+        assert!(!has_error_diagnostics(&compiler));
+        let count = compiler
+            .events
+            .iter()
+            .filter(|e| matches!(e.body, EventBody::Note(_)))
+            .count();
+        assert_eq!(count, 3);
+    }
+
+    #[test]
+    fn compile_bend_envelope_attaches_to_note() {
+        let compiler = compile_source("C4{bend 0c..+50c..-20c},.{bend 0c..10c},\n");
+        assert!(!has_error_diagnostics(&compiler));
+        let envelopes: Vec<Option<BendEnvelope>> = compiler
+            .events
+            .iter()
+            .filter_map(|e| match &e.body {
+                EventBody::Note(n) => Some(n.bend_envelope.clone()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            envelopes,
+            vec![
+                Some(BendEnvelope {
+                    cents: vec![0, 50, -20]
+                }),
+                None
+            ]
+        );
+    }
+
+    #[test]
+    fn compile_nested_note_group_subdivides_slot() {
+        let compiler = compile_source("C4;(D4;E4:G4);A4,B4,\n");
+        assert!(!has_error_diagnostics(&compiler));
+        let notes: Vec<(Rational64, Rational64)> = compiler
+            .events
+            .iter()
+            .filter_map(|e| match &e.body {
+                EventBody::Note(n) => Some((e.start_time.ticks, n.duration)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            notes,
+            vec![
+                (Rational64::new(0, 1), Rational64::new(1, 12)),
+                (Rational64::new(1, 12), Rational64::new(1, 24)),
+                (Rational64::new(1, 8), Rational64::new(1, 24)),
+                (Rational64::new(1, 8), Rational64::new(1, 24)),
+                (Rational64::new(1, 6), Rational64::new(1, 12)),
+                (Rational64::new(1, 4), Rational64::new(1, 4)),
+            ]
+        );
+    }
+
+    #[test]
+    fn compile_conditional_sections_follow_variants() {
+        let source = "#if intro\nC4,,,,\n#endif\nD4,,,,\n";
+        let compile_with = |variants: Vec<String>| {
+            let parsed = parse_source(Arc::from(source));
+            let mut compiler = Compiler::with_config(CompilerConfig {
+                variants,
+                ..Default::default()
+            });
+            compiler.compile(&parsed.syntax_node());
+            assert!(compiler.diagnostics.is_empty());
+            compiler
+                .events
+                .iter()
+                .filter(|e| matches!(e.body, EventBody::Note(_)))
+                .map(|e| e.start_time.bars)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(compile_with(vec![]), vec![0]);
+        assert_eq!(compile_with(vec!["intro".to_string()]), vec![0, 1]);
+    }
+
+    #[test]
+    fn compile_quantize_block_restores_quantize() {
+        let compiler = compile_source("(2/4)\nC4,{8: D4,E4,}\n{8: F4,{16: G4,G4,}}A4,\n");
+        assert!(!has_error_diagnostics(&compiler));
+        let ticks: Vec<Rational64> = compiler
+            .events
+            .iter()
+            .filter(|e| matches!(e.body, EventBody::Note(_)))
+            .map(|e| e.start_time.ticks)
+            .collect();
+        assert_eq!(
+            ticks,
+            vec![
+                Rational64::new(0, 4),
+                Rational64::new(1, 4),
+                Rational64::new(3, 8),
+                Rational64::new(0, 8),
+                Rational64::new(1, 8),
+                Rational64::new(3, 16),
+                Rational64::new(1, 4),
+            ]
+        );
+        assert_eq!(compiler.state.quantize, Rational64::new(1, 4));
+    }
+
+    #[test]
+    fn compile_value_macros_in_settings() {
+        let compiler = compile_source("t = 90\nts = 3/4\nq = 8\n(t)\n(ts)\n{q}C4,\n");
+        assert!(!has_error_diagnostics(&compiler));
+        assert_eq!(compiler.state.bpm, 90.0);
+        assert_eq!(compiler.state.time_signature, Rational64::new(3, 4));
+        assert_eq!(*compiler.state.time_signature.denom(), 4);
+        assert_eq!(compiler.state.quantize, Rational64::new(1, 8));
+    }
+
+    #[test]
+    fn compile_additive_time_signature() {
+        let compiler = compile_source("(3+2/8)\n{8}C4,,,D4,,\nE4,,,F4,,\n");
+        assert!(compiler.diagnostics.is_empty());
+        let sig = compiler
+            .events
+            .iter()
+            .find_map(|e| match &e.body {
+                EventBody::TimeSignatureDef(sig) => Some(sig.clone()),
+                _ => None,
+            })
+            .expect("expected time signature event");
+        assert_eq!(sig.groups, vec![3, 2]);
+        assert_eq!(sig.to_string(), "3+2/8");
+        assert_eq!(compiler.state.time_signature, Rational64::new(5, 8));
+        let e4 = compiler
+            .events
+            .iter()
+            .filter(|e| matches!(e.body, EventBody::Note(_)))
+            .nth(2)
+            .expect("expected note in second bar");
+        assert_eq!(e4.start_time.bars, 1);
+        assert_eq!(e4.start_time.position, Rational64::new(5, 8));
+    }
+
+    #[test]
+    fn compile_undefined_value_macro_reports_error() {
+        let compiler = compile_source("(nope)\n");
+        assert!(
+            compiler
+                .diagnostics
+                .iter()
+                .any(|d| d.message.contains("Undefined value macro"))
+        );
+    }
+
+    #[test]
+    fn compile_voice_prefix_tags_line_events() {
+        let compiler = compile_source("v2: C4,,D4,,\nE4,,F4,,\n");
+        assert!(compiler.diagnostics.is_empty());
+        let voices: Vec<Option<&str>> = compiler
+            .events
+            .iter()
+            .filter(|e| matches!(e.body, EventBody::Note(_)))
+            .map(|e| e.voice.as_deref())
+            .collect();
+        assert_eq!(voices, vec![Some("v2"), Some("v2"), None, None]);
+    }
+
+    #[test]
+    fn compile_instrument_directive_per_voice() {
+        let compiler = compile_source(
+            "(instrument violin)C4,\nv1: (instrument 43)D4,\nE4,\nv1: F4,\n(instrument kazoo)G4,\n",
+        );
+        let instruments: Vec<Option<u8>> = compiler
             .events
             .iter()
             .filter(|e| matches!(e.body, EventBody::Note(_)))
-            .count();
-        assert_eq!(note_count, 2);
+            .map(|e| e.instrument)
+            .collect();
+        assert_eq!(
+            instruments,
+            vec![Some(40), Some(42), Some(40), Some(42), Some(40)]
+        );
+        let unknown: Vec<_> = compiler
+            .diagnostics
+            .iter()
+            .filter(|d| d.code == DiagnosticCode::UndefinedName)
+            .map(|d| d.message.as_str())
+            .collect();
+        assert_eq!(unknown, vec!["Unknown instrument: kazoo"]);
     }
 
     #[test]
-    fn compile_simple_macro_def_allows_pitch_chain_with_identifier() {
-        let compiler = compile_source("a = 3/2\nm = C4@a\nm,\n");
+    fn compile_percussion_line_maps_drum_names() {
+        let compiler = compile_source("kick = C4\ndrums: kick,snare:hihat,,,\nkick,,,,\n");
         assert!(!has_error_diagnostics(&compiler));
-
-        let note = compiler
+        let keys: Vec<Option<u8>> = compiler
             .events
             .iter()
-            .find_map(|e| match &e.body {
-                EventBody::Note(n) => Some(n.clone()),
+            .filter_map(|e| match &e.body {
+                EventBody::Note(note) => Some(note.drum_key),
                 _ => None,
             })
-            .expect("expected one note event");
-
-        let right_freq = 261.63f32 * 1.5;
-        let expected = right_freq * 2f32.powf((60f32 - 67f32) / 12.0);
-        assert!((note.freq - expected).abs() < 0.3);
+            .collect();
+        assert_eq!(keys, vec![Some(36), Some(38), Some(42), None]);
     }
 
     #[test]
-    fn compile_simple_macro_anchor_pitch_chain() {
-        let compiler = compile_source("m = 3/2\nm@D4,\n");
-        assert!(!has_error_diagnostics(&compiler));
-
-        let note = compiler
+    fn compile_ties_merge_across_lines() {
+        let compiler = compile_source("C4,D4,E4,F4_:A4_,\n_,_,G4,C4_,\nD4,\n");
+        let notes: Vec<(Pitch, Rational64)> = compiler
             .events
             .iter()
-            .find_map(|e| match &e.body {
-                EventBody::Note(n) => Some(n.clone()),
+            .filter_map(|e| match &e.body {
+                EventBody::Note(n) => Some((n.pitch_chain[0], n.duration)),
                 _ => None,
             })
-            .expect("expected one note event");
+            .collect();
+        let spell = |s: &str| Pitch::parse_spell_octave(s).expect("valid spelling");
+        assert_eq!(notes.len(), 8);
+        assert_eq!(notes[3], (spell("F4"), Rational64::new(3, 4)));
+        assert_eq!(notes[4], (spell("A4"), Rational64::new(3, 4)));
+        // the trailing `C4_` has nothing to tie into, as `D4` is not a `_` slot
+        assert_eq!(notes[6], (spell("C4"), Rational64::new(1, 4)));
+        assert_eq!(notes[7], (spell("D4"), Rational64::new(1, 4)));
+        assert!(
+            compiler
+                .diagnostics
+                .iter()
+                .all(|d| d.level != DiagnosticLevel::Error)
+        );
 
-        let expected = 293.66f32 * 1.5;
-        assert!((note.freq - expected).abs() < 0.3);
+        let compiler = compile_source("_,C4,\n");
+        assert!(
+            compiler
+                .diagnostics
+                .iter()
+                .any(|d| d.message.contains("Tie has no preceding tied note"))
+        );
     }
 
     #[test]
-    fn compile_complex_macro_anchor_pitch_chain() {
-        let compiler = compile_source("m =\n3/2,\n\nm@D4,\n");
+    fn compile_note_volume_suffix() {
+        let compiler = compile_source("C4&0.6,E4:G4&0.25,D4,\n");
         assert!(!has_error_diagnostics(&compiler));
-
-        let note = compiler
+        let volumes: Vec<f32> = compiler
             .events
             .iter()
-            .find_map(|e| match &e.body {
-                EventBody::Note(n) => Some(n.clone()),
+            .filter_map(|e| match &e.body {
+                EventBody::Note(n) => Some(n.volume),
                 _ => None,
             })
-            .expect("expected one note event");
-
-        let expected = 293.66f32 * 1.5;
-        assert!((note.freq - expected).abs() < 0.3);
-    }
-
-    #[test]
-    fn compile_simple_macro_anchor_appends_pitch_chain() {
-        let direct = compile_source("3/2@D4,\n");
-        let from_macro = compile_source("m = 3/2\nm@D4,\n");
-        assert!(!has_error_diagnostics(&direct));
-        assert!(!has_error_diagnostics(&from_macro));
-
-        let direct_freq = first_note_freq(&direct);
-        let macro_freq = first_note_freq(&from_macro);
-        assert!((direct_freq - macro_freq).abs() < 1e-3);
+            .collect();
+        assert_eq!(volumes, vec![0.6, 1.0, 0.25, 1.0]);
     }
 
     #[test]
-    fn compile_complex_macro_anchor_appends_pitch_chain() {
-        let direct = compile_source("3/2@D4,\n");
-        let from_macro = compile_source("m =\n3/2,\n\nm@D4,\n");
-        assert!(!has_error_diagnostics(&direct));
-        assert!(!has_error_diagnostics(&from_macro));
-
-        let direct_freq = first_note_freq(&direct);
-        let macro_freq = first_note_freq(&from_macro);
-        assert!((direct_freq - macro_freq).abs() < 1e-3);
+    fn compile_heji_comma_accidentals() {
+        let compiler = compile_source("E4,E\\4,B!4,\n");
+        assert!(!has_error_diagnostics(&compiler));
+        let notes: Vec<(f64, PitchChain)> = compiler
+            .events
+            .iter()
+            .filter_map(|e| match &e.body {
+                EventBody::Note(n) => Some((n.freq, n.pitch_chain.clone())),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(notes[1].1[0], Pitch::Ratio(Rational64::new(80, 81)));
+        assert!((notes[1].0 / notes[0].0 - 80.0 / 81.0).abs() < 1e-5);
+        assert_eq!(notes[2].1[0], Pitch::Ratio(Rational64::new(63, 64)));
     }
 
     #[test]
-    fn compile_pitch_chain_plus_suffix_equivalent_to_ratio_up() {
-        let with_suffix = compile_source("3/2+,\n");
-        let with_ratio = compile_source("3/2@2/1,\n");
-        assert!(!has_error_diagnostics(&with_suffix));
-        assert!(!has_error_diagnostics(&with_ratio));
-
-        let suffix_freq = first_note_freq(&with_suffix);
-        let ratio_freq = first_note_freq(&with_ratio);
-        assert!((suffix_freq - ratio_freq).abs() < 1e-3);
+    fn compile_named_base_references() {
+        let compiler = compile_source("<drone=196><root=D4>\n3/2@drone,3/2@root,3/2,E4@root,\n");
+        assert!(!has_error_diagnostics(&compiler));
+        let freqs: Vec<f64> = compiler
+            .events
+            .iter()
+            .filter_map(|e| match &e.body {
+                EventBody::Note(n) => Some(n.freq),
+                _ => None,
+            })
+            .collect();
+        let d4 = 261.63 * 2f64.powf(2.0 / 12.0);
+        assert!((freqs[0] - 294.0).abs() < 1e-3);
+        assert!((freqs[1] - d4 * 1.5).abs() < 1e-2);
+        // the active base pitch is unchanged
+        assert!((freqs[2] - 261.63 * 1.5).abs() < 1e-2);
+        assert!((freqs[3] - d4 * 2f64.powf(2.0 / 12.0)).abs() < 1e-2);
     }
 
     #[test]
-    fn compile_pitch_chain_minus_suffix_equivalent_to_ratio_down() {
-        let with_suffix = compile_source("3/2-,\n");
-        let with_ratio = compile_source("3/2@1/2,\n");
-        assert!(!has_error_diagnostics(&with_suffix));
-        assert!(!has_error_diagnostics(&with_ratio));
-
-        let suffix_freq = first_note_freq(&with_suffix);
-        let ratio_freq = first_note_freq(&with_ratio);
-        assert!((suffix_freq - ratio_freq).abs() < 1e-3);
+    fn compile_names_are_kept_inline() {
+        let compiler = compile_source("lib.tonic = D4\nlib.fifth = 3/2@tonic\nv1: lib.fifth,\n");
+        assert!(!has_error_diagnostics(&compiler));
+        assert_eq!(compiler.macros.resolve("tonic", "lib"), "lib.tonic");
+        assert!(
+            compiler
+                .macros
+                .definitions
+                .keys()
+                .all(|name| !name.is_heap_allocated())
+        );
+        let voice = compiler
+            .events
+            .iter()
+            .find_map(|e| e.voice.as_ref())
+            .expect("voiced note");
+        assert_eq!(voice, "v1");
+        assert!(!voice.is_heap_allocated());
     }
 
     #[test]
-    fn compile_macro_invoke_plus_suffix_equivalent_to_ratio_up() {
-        let with_suffix = compile_source("m = 3/2\nm+,\n");
-        let with_ratio = compile_source("m = 3/2\nm@2/1,\n");
-        assert!(!has_error_diagnostics(&with_suffix));
-        assert!(!has_error_diagnostics(&with_ratio));
-
-        let suffix_freq = first_note_freq(&with_suffix);
-        let ratio_freq = first_note_freq(&with_ratio);
-        assert!((suffix_freq - ratio_freq).abs() < 1e-3);
+    fn compile_macro_namespaces() {
+        let compiler = compile_source(
+            "tonic = E4\nlib.tonic = D4\nlib.fifth = 3/2@tonic\nlib.fifth,tonic,\ntonic = G4\n",
+        );
+        assert!(!has_error_diagnostics(&compiler));
+        let d4 = 261.63 * 2f64.powf(2.0 / 12.0);
+        assert!((first_note_freq(&compiler) - d4 * 1.5).abs() < 1e-2);
+        let messages: Vec<_> = compiler
+            .diagnostics
+            .iter()
+            .map(|d| d.message.as_str())
+            .collect();
+        assert!(messages.contains(&"Macro lib.tonic shadows tonic"));
+        assert!(messages.contains(&"Macro redefined: tonic"));
     }
 
     #[test]
-    fn compile_macro_invoke_minus_suffix_equivalent_to_ratio_down() {
-        let with_suffix = compile_source("m = 3/2\nm-,\n");
-        let with_ratio = compile_source("m = 3/2\nm@1/2,\n");
-        assert!(!has_error_diagnostics(&with_suffix));
-        assert!(!has_error_diagnostics(&with_ratio));
-
-        let suffix_freq = first_note_freq(&with_suffix);
-        let ratio_freq = first_note_freq(&with_ratio);
-        assert!((suffix_freq - ratio_freq).abs() < 1e-3);
+    fn recompile_splices_events_after_the_edit() {
+        let old_text = "(120)\nC4,D4,\nE4,F4,\nG4,A4,\n%\n";
+        let new_text = "(120)\nC4,Eb4,\nE4,F4,\nG4,A4,\n%\n";
+        let old = parse_source(Arc::from(old_text));
+        let mut compiler = Compiler::new();
+        compiler.compile(&old.syntax_node());
+        // mark the last note so that it survives only if it is spliced rather than recompiled
+        compiler
+            .raw_events
+            .iter_mut()
+            .rev()
+            .find(|e| matches!(e.body, EventBody::Note(_)))
+            .expect("note event")
+            .voice = Some("spliced".into());
+
+        let edit = TextEdit::between(old_text, new_text);
+        let dirty = edit.delete;
+        let new = reparse(&old, edit);
+        compiler.recompile(&new.syntax_node(), dirty);
+        let spliced = compiler
+            .events
+            .iter_mut()
+            .find(|e| e.voice.as_deref() == Some("spliced"))
+            .expect("spliced note");
+        assert_eq!(&new_text[spliced.range], "A4");
+        spliced.voice = None;
+
+        let mut full = Compiler::new();
+        full.compile(&new.syntax_node());
+        assert_eq!(compiler.events, full.events);
+        assert_eq!(compiler.diagnostics.len(), full.diagnostics.len());
     }
 
     #[test]
-    fn compile_simple_macro_preserves_pitch_chain_semantics() {
-        let direct = compile_source("4/5@3/2,\n");
-        let from_macro = compile_source("m = 4/5@3/2\nm,\n");
-        assert!(!has_error_diagnostics(&direct));
-        assert!(!has_error_diagnostics(&from_macro));
+    fn compile_limits_turn_runaway_pieces_into_errors() {
+        let compile_limited = |limits: CompileLimits, source: &str| {
+            let parsed = parse_source(Arc::from(source));
+            let mut compiler = Compiler::with_config(CompilerConfig {
+                limits,
+                ..CompilerConfig::default()
+            });
+            compiler.compile(&parsed.syntax_node());
+            compiler
+        };
+        let limit_errors = |compiler: &Compiler| {
+            compiler
+                .diagnostics
+                .iter()
+                .filter(|d| d.code == DiagnosticCode::LimitExceeded)
+                .count()
+        };
 
-        let direct_freq = first_note_freq(&direct);
-        let macro_freq = first_note_freq(&from_macro);
-        assert!((direct_freq - macro_freq).abs() < 1e-3);
-    }
+        let many = CompileLimits {
+            max_events: 8,
+            ..CompileLimits::default()
+        };
+        let compiler = compile_limited(many, "m = C4\nm*1000000,\nD4,\n");
+        assert_eq!(limit_errors(&compiler), 1);
+        assert!(compiler.events.len() <= 9);
 
-    #[test]
-    fn compile_complex_macro_preserves_pitch_chain_semantics() {
-        let direct = compile_source("4/5@3/2,\n");
-        let from_macro = compile_source("m =\n4/5@3/2,\n\nm,\n");
-        assert!(!has_error_diagnostics(&direct));
-        assert!(!has_error_diagnostics(&from_macro));
+        let long = CompileLimits {
+            max_seconds: 60.0,
+            ..CompileLimits::default()
+        };
+        let compiler = compile_limited(long, "C4,,,,\nR1000000\nD4,\n");
+        assert_eq!(limit_errors(&compiler), 1);
+        assert!(compiler.state.time.seconds < 70.0);
 
-        let direct_freq = first_note_freq(&direct);
-        let macro_freq = first_note_freq(&from_macro);
-        assert!((direct_freq - macro_freq).abs() < 1e-3);
+        let shallow = CompileLimits {
+            max_macro_depth: 1,
+            ..CompileLimits::default()
+        };
+        let compiler = compile_limited(shallow, "inner =\nC4,\n\nouter =\ninner,\n\nouter,\n");
+        assert_eq!(limit_errors(&compiler), 1);
     }
 
     #[test]
-    fn compile_base_pitch_accepts_non_frequency_reference() {
-        let compiler = compile_source("<C4=3/2>\n");
-        assert!(!has_error_diagnostics(&compiler));
-        assert!(
+    fn cancelled_recompile_keeps_earlier_lines_and_resumes_later() {
+        let texts = [
+            "(90)\nC4,D4,\nE4,F4,\nG4,A4,\n",
+            "(90)\nC4,D4,\nE4,F#4,\nG4,A4,\n",
+            "(90)\nC4,D4,\nE4,F#4,\nG4,B4,\n",
+        ];
+        let mut parse = parse_source(Arc::from(texts[0]));
+        let mut compiler = Compiler::new();
+        compiler.compile(&parse.syntax_node());
+
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+        let edit = TextEdit::between(texts[0], texts[1]);
+        let dirty = edit.delete;
+        parse = reparse(&parse, edit);
+        compiler.recompile_cancellable(&parse.syntax_node(), dirty, &cancel);
+        assert!(compiler.cancelled);
+        let notes = |compiler: &Compiler| {
             compiler
                 .events
                 .iter()
-                .any(|e| matches!(e.body, EventBody::BaseNoteDef(_)))
-        );
-        assert!(
+                .filter(|e| matches!(e.body, EventBody::Note(_)))
+                .count()
+        };
+        assert_eq!(notes(&compiler), 2);
+
+        let edit = TextEdit::between(texts[1], texts[2]);
+        let dirty = edit.delete;
+        parse = reparse(&parse, edit);
+        compiler.recompile(&parse.syntax_node(), dirty);
+        assert!(!compiler.cancelled);
+        let mut full = Compiler::new();
+        full.compile(&parse.syntax_node());
+        assert_eq!(compiler.events, full.events);
+    }
+
+    #[test]
+    fn diagnostics_carry_codes_related_spans_and_fixes() {
+        let compiler = compile_source("chord1 = C4:E4\nchord1 = D4:F4\nchrod1,\nC4&1.5,\n");
+        let find = |code: DiagnosticCode| {
             compiler
-                .events
+                .diagnostics
                 .iter()
-                .any(|e| matches!(e.body, EventBody::BaseFequencyDef(_)))
+                .find(|d| d.code == code)
+                .expect("expected diagnostic")
+        };
+        let redefined = find(DiagnosticCode::Redefinition);
+        assert_eq!(redefined.code.to_string(), "W0001");
+        assert_eq!(redefined.span, TextRange::new(15.into(), 21.into()));
+        assert_eq!(redefined.related.len(), 1);
+        assert_eq!(
+            redefined.related[0].span,
+            TextRange::new(0.into(), 6.into())
         );
+
+        let undefined = find(DiagnosticCode::UndefinedName);
+        assert_eq!(undefined.code.to_string(), "E0005");
+        assert_eq!(undefined.fixes.len(), 1);
+        assert_eq!(undefined.fixes[0].replacement, "chord1");
+        assert_eq!(undefined.fixes[0].span, undefined.span);
+
+        let clamped = find(DiagnosticCode::ClampedVolume);
+        assert_eq!(clamped.fixes[0].replacement, "&1");
     }
 
     #[test]
-    fn compile_base_pitch_rhs_identifier_chain_alias_macro_ok() {
-        let direct = compile_source("<C4=3/2@5/4>\n");
-        let from_ident = compile_source("a = 3/2@5/4\n<C4=a>\n");
-        assert!(!has_error_diagnostics(&direct));
-        assert!(!has_error_diagnostics(&from_ident));
-
-        let direct_freq = direct
+    fn compile_applies_config_defaults() {
+        let parsed = parse_source(Arc::from("0,19,\n"));
+        let mut compiler = Compiler::with_config(CompilerConfig {
+            base_frequency: 440.0,
+            bpm: 60.0,
+            edo: Some(19),
+            ..Default::default()
+        });
+        compiler.compile(&parsed.syntax_node());
+        assert!(!has_error_diagnostics(&compiler));
+        let notes: Vec<(f64, f64)> = compiler
             .events
             .iter()
-            .find_map(|e| match e.body {
-                EventBody::BaseFequencyDef(f) => Some(f),
+            .filter_map(|e| match &e.body {
+                EventBody::Note(n) => Some((n.freq, e.start_time.seconds)),
                 _ => None,
             })
-            .expect("expected base frequency event");
-        let ident_freq = from_ident
+            .collect();
+        assert_eq!(notes.len(), 2);
+        assert!((notes[0].0 - 440.0).abs() < 1e-3);
+        assert!((notes[1].0 - 880.0).abs() < 1e-2);
+        assert!((notes[1].1 - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn compile_output_round_trips_through_json() {
+        let compiler = compile_source("(90)\nm = C4:E4\nm@D4,3/2@A4&0.5,u7,.,\nundefined,\n");
+        let json = serde_json::to_string(&compiler.events).expect("serialize events");
+        let events: Vec<CompileEvent> = serde_json::from_str(&json).expect("deserialize events");
+        assert_eq!(events, compiler.events);
+        let json = serde_json::to_string(&compiler.diagnostics).expect("serialize diagnostics");
+        let diagnostics: Vec<Diagnostic> =
+            serde_json::from_str(&json).expect("deserialize diagnostics");
+        assert_eq!(diagnostics, compiler.diagnostics);
+    }
+
+    #[test]
+    fn compile_switches_named_tunings() {
+        let dir = std::env::temp_dir().join("symi_compile_switches_named_tunings");
+        fs::create_dir_all(&dir).expect("create scale dir");
+        fs::write(dir.join("tri.scl"), "three steps\n3\n300.0\n5/4\n2/1\n")
+            .expect("write scale file");
+        let parsed = parse_source(Arc::from(
+            "tun_a = 19edo\ntun_b = tri.scl\n(tuning tun_a) 0,19,(tuning tun_b) 2,4,\n",
+        ));
+        let mut compiler = Compiler::with_config(CompilerConfig {
+            scale_dir: Some(dir),
+            ..Default::default()
+        });
+        compiler.compile(&parsed.syntax_node());
+        assert!(!has_error_diagnostics(&compiler));
+        let chains: Vec<Pitch> = compiler
             .events
             .iter()
-            .find_map(|e| match e.body {
-                EventBody::BaseFequencyDef(f) => Some(f),
+            .filter_map(|e| match &e.body {
+                EventBody::Note(n) => Some(n.pitch_chain[0]),
                 _ => None,
             })
-            .expect("expected base frequency event");
+            .collect();
+        assert_eq!(chains[1], Pitch::Edo(Rational64::new(19, 19)));
+        match (chains[2], chains[3]) {
+            (Pitch::Cents(a), Pitch::Cents(b)) => {
+                assert!((a - 1200.0 * 1.25f64.log2()).abs() < 1e-9);
+                assert!((b - 1500.0).abs() < 1e-9);
+            }
+            other => panic!("expected scale steps in cents, got {other:?}"),
+        }
 
-        assert!((direct_freq - ident_freq).abs() < 1e-3);
+        let compiler = compile_source("(tuning missing) 3,\n");
+        assert!(has_error_diagnostics(&compiler));
     }
 
     #[test]
-    fn compile_base_pitch_rhs_identifier_chain_rejects_multi_note_simple_macro() {
-        let compiler = compile_source("a = C4:D4\n<C4=a>\n");
-        assert!(compiler.diagnostics.iter().any(|d| {
-            d.message
-                .contains("Identifier in base pitch RHS must resolve to an alias macro")
-        }));
+    fn compile_ups_and_downs_use_active_edo() {
+        let compiler = compile_source("0\\31,^C4,vvC4,C4,\n");
+        assert!(!has_error_diagnostics(&compiler));
+        let freqs: Vec<f64> = compiler
+            .events
+            .iter()
+            .filter_map(|e| match &e.body {
+                EventBody::Note(n) => Some(n.freq),
+                _ => None,
+            })
+            .collect();
+        let cents = |a: f64, b: f64| 1200.0 * (b / a).log2();
+        assert!((cents(freqs[3], freqs[1]) - 1200.0 / 31.0).abs() < 0.01);
+        assert!((cents(freqs[3], freqs[2]) + 2400.0 / 31.0).abs() < 0.01);
+
+        let compiler = compile_source("^C4,\n");
+        assert!(has_error_diagnostics(&compiler));
     }
 
     #[test]
-    fn compile_base_pitch_spell_without_rhs_infers_frequency() {
-        let shorthand = compile_source("<D4>\n");
-        let explicit = compile_source("<D4=D4>\n");
-        assert!(!has_error_diagnostics(&shorthand));
-        assert!(!has_error_diagnostics(&explicit));
-
-        let shorthand_freq = shorthand
+    fn compile_relative_mode_picks_nearest_octave() {
+        let compiler = compile_source("<A4=440>\n(relative) A,C,G,E,\n(absolute) G,,,,\n");
+        assert!(!has_error_diagnostics(&compiler));
+        let freqs: Vec<f64> = compiler
             .events
             .iter()
-            .find_map(|e| match e.body {
-                EventBody::BaseFequencyDef(f) => Some(f),
+            .filter_map(|e| match &e.body {
+                EventBody::Note(note) => Some(note.freq),
                 _ => None,
             })
-            .expect("expected base frequency event");
-        let explicit_freq = explicit
+            .collect();
+        let expected = [440.0, 523.25, 392.0, 329.63, 783.99];
+        assert_eq!(freqs.len(), expected.len());
+        for (freq, expected) in freqs.iter().zip(expected) {
+            assert!((freq - expected).abs() < 0.05, "{freq} != {expected}");
+        }
+    }
+
+    #[test]
+    fn compile_relative_mode_keeps_repeated_chords_in_place() {
+        for source in [
+            "(relative) C:E:G,C:E:G,C:E:G,C:E:G,\n",
+            "(relative) Cmaj,Cmaj,Cmaj,Cmaj,\n",
+        ] {
+            let compiler = compile_source(source);
+            assert!(!has_error_diagnostics(&compiler));
+            let freqs: Vec<f64> = compiler
+                .events
+                .iter()
+                .filter_map(|e| match &e.body {
+                    EventBody::Note(note) => Some(note.freq),
+                    _ => None,
+                })
+                .collect();
+            assert_eq!(freqs.len(), 12, "{source}");
+            for chord in freqs.chunks(3) {
+                assert_eq!(chord, &freqs[..3], "{source}");
+            }
+        }
+    }
+
+    #[test]
+    fn compile_unicode_and_half_accidentals() {
+        let compiler = compile_source("<A4=440>\nA♯4,B♭4,A𝄲4,Ad4,\n");
+        assert!(!has_error_diagnostics(&compiler));
+        let freqs: Vec<f64> = compiler
             .events
             .iter()
-            .find_map(|e| match e.body {
-                EventBody::BaseFequencyDef(f) => Some(f),
+            .filter_map(|e| match &e.body {
+                EventBody::Note(note) => Some(note.freq),
                 _ => None,
             })
-            .expect("expected base frequency event");
+            .collect();
+        let semitone = 2f64.powf(1.0 / 12.0);
+        let quarter = 2f64.powf(1.0 / 24.0);
+        let expected = [
+            440.0 * semitone,
+            440.0 * semitone,
+            440.0 * quarter,
+            440.0 / quarter,
+        ];
+        assert_eq!(freqs.len(), expected.len());
+        for (freq, expected) in freqs.iter().zip(expected) {
+            assert!((freq - expected).abs() < 0.01, "{freq} != {expected}");
+        }
+    }
 
-        assert!((shorthand_freq - explicit_freq).abs() < 1e-3);
+    #[test]
+    fn compile_line_continuation_stays_in_one_measure() {
+        let compiler = compile_source("C4,D4,\\\nE4,F4,\nG4,,,,\n");
+        assert!(compiler.diagnostics.is_empty());
+        let starts: Vec<(u32, Rational64)> = compiler
+            .events
+            .iter()
+            .filter(|e| matches!(e.body, EventBody::Note(_)))
+            .map(|e| (e.start_time.bars, e.start_time.ticks))
+            .collect();
+        assert_eq!(starts[2], (0, Rational64::new(2, 4)));
+        assert_eq!(starts[4], (1, Rational64::zero()));
     }
 
     #[test]
@@ -1642,6 +5453,7 @@ mod tests {
         );
         for (idx, event) in compiler.events.iter().enumerate() {
             let (start, end, text) = get_span_text(&event.range, &source);
+            let source_cell = format!("\"[{}, {}] {}\"", start, end, text.replace('\n', "\\n"));
             match event {
                 CompileEvent {
                     body: EventBody::Note(note),
@@ -1651,7 +5463,7 @@ mod tests {
                         "{},{},{},{:.3},{:.3},{},{},{:.3},{}\n",
                         idx,
                         "Note",
-                        format!("\"[{}, {}] {}\"", start, end, text.replace('\n', "\\n")),
+                        source_cell,
                         note.freq,
                         event.start_time.seconds,
                         event.start_time.bars,
@@ -1666,19 +5478,11 @@ mod tests {
                 } => {
                     output.push_str(&format!(
                         "{},{},{},\"{:?}\",,,,\n",
-                        idx,
-                        "BaseNoteDef",
-                        format!("\"[{}, {}] {}\"", start, end, text.replace('\n', "\\n")),
-                        pitch_spell,
+                        idx, "BaseNoteDef", source_cell, pitch_spell,
                     ));
                 }
                 _ => {
-                    output.push_str(&format!(
-                        "{},{},{},,,,\n",
-                        idx,
-                        "OtherEvent",
-                        format!("\"[{}, {}] {}\"", start, end, text.replace('\n', "\\n")),
-                    ));
+                    output.push_str(&format!("{},{},{},,,,\n", idx, "OtherEvent", source_cell,));
                 }
             }
         }
@@ -0,0 +1,37 @@
+/// Drum names usable on a `drums:` line and their General MIDI percussion keys.
+pub const DRUM_MAP: &[(&str, u8)] = &[
+    ("kick", 36),
+    ("rimshot", 37),
+    ("snare", 38),
+    ("clap", 39),
+    ("tom_low", 45),
+    ("tom_mid", 47),
+    ("tom_high", 50),
+    ("hihat", 42),
+    ("pedal_hihat", 44),
+    ("open_hihat", 46),
+    ("crash", 49),
+    ("ride", 51),
+    ("tambourine", 54),
+    ("cowbell", 56),
+];
+
+/// Looks up the General MIDI percussion key of a drum name.
+pub fn drum_key(name: &str) -> Option<u8> {
+    DRUM_MAP
+        .iter()
+        .find(|(drum, _)| *drum == name)
+        .map(|&(_, key)| key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drum_names_map_to_gm_keys() {
+        assert_eq!(drum_key("kick"), Some(36));
+        assert_eq!(drum_key("snare"), Some(38));
+        assert_eq!(drum_key("C4"), None);
+    }
+}
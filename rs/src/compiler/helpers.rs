@@ -23,8 +23,8 @@ impl NodeOrTokenAsKind for NodeOrToken<SyntaxNode, SyntaxToken> {
     /// ```
     fn kind(&self) -> SyntaxKind {
         match self {
-            NodeOrToken::Node(n) => n.kind().into(),
-            NodeOrToken::Token(t) => t.kind().into(),
+            NodeOrToken::Node(n) => n.kind(),
+            NodeOrToken::Token(t) => t.kind(),
         }
     }
 }
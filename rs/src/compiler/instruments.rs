@@ -0,0 +1,183 @@
+/// General MIDI instrument names usable in `(instrument ...)`, indexed by program (0-127).
+pub const GM_PROGRAMS: [&str; 128] = [
+    "acoustic_grand_piano",
+    "bright_acoustic_piano",
+    "electric_grand_piano",
+    "honky_tonk_piano",
+    "electric_piano_1",
+    "electric_piano_2",
+    "harpsichord",
+    "clavinet",
+    "celesta",
+    "glockenspiel",
+    "music_box",
+    "vibraphone",
+    "marimba",
+    "xylophone",
+    "tubular_bells",
+    "dulcimer",
+    "drawbar_organ",
+    "percussive_organ",
+    "rock_organ",
+    "church_organ",
+    "reed_organ",
+    "accordion",
+    "harmonica",
+    "tango_accordion",
+    "acoustic_guitar_nylon",
+    "acoustic_guitar_steel",
+    "electric_guitar_jazz",
+    "electric_guitar_clean",
+    "electric_guitar_muted",
+    "overdriven_guitar",
+    "distortion_guitar",
+    "guitar_harmonics",
+    "acoustic_bass",
+    "electric_bass_finger",
+    "electric_bass_pick",
+    "fretless_bass",
+    "slap_bass_1",
+    "slap_bass_2",
+    "synth_bass_1",
+    "synth_bass_2",
+    "violin",
+    "viola",
+    "cello",
+    "contrabass",
+    "tremolo_strings",
+    "pizzicato_strings",
+    "orchestral_harp",
+    "timpani",
+    "string_ensemble_1",
+    "string_ensemble_2",
+    "synth_strings_1",
+    "synth_strings_2",
+    "choir_aahs",
+    "voice_oohs",
+    "synth_voice",
+    "orchestra_hit",
+    "trumpet",
+    "trombone",
+    "tuba",
+    "muted_trumpet",
+    "french_horn",
+    "brass_section",
+    "synth_brass_1",
+    "synth_brass_2",
+    "soprano_sax",
+    "alto_sax",
+    "tenor_sax",
+    "baritone_sax",
+    "oboe",
+    "english_horn",
+    "bassoon",
+    "clarinet",
+    "piccolo",
+    "flute",
+    "recorder",
+    "pan_flute",
+    "blown_bottle",
+    "shakuhachi",
+    "whistle",
+    "ocarina",
+    "lead_1_square",
+    "lead_2_sawtooth",
+    "lead_3_calliope",
+    "lead_4_chiff",
+    "lead_5_charang",
+    "lead_6_voice",
+    "lead_7_fifths",
+    "lead_8_bass_lead",
+    "pad_1_new_age",
+    "pad_2_warm",
+    "pad_3_polysynth",
+    "pad_4_choir",
+    "pad_5_bowed",
+    "pad_6_metallic",
+    "pad_7_halo",
+    "pad_8_sweep",
+    "fx_1_rain",
+    "fx_2_soundtrack",
+    "fx_3_crystal",
+    "fx_4_atmosphere",
+    "fx_5_brightness",
+    "fx_6_goblins",
+    "fx_7_echoes",
+    "fx_8_sci_fi",
+    "sitar",
+    "banjo",
+    "shamisen",
+    "koto",
+    "kalimba",
+    "bagpipe",
+    "fiddle",
+    "shanai",
+    "tinkle_bell",
+    "agogo",
+    "steel_drums",
+    "woodblock",
+    "taiko_drum",
+    "melodic_tom",
+    "synth_drum",
+    "reverse_cymbal",
+    "guitar_fret_noise",
+    "breath_noise",
+    "seashore",
+    "bird_tweet",
+    "telephone_ring",
+    "helicopter",
+    "applause",
+    "gunshot",
+];
+
+/// Short names for the most common General MIDI programs.
+pub const GM_ALIASES: &[(&str, u8)] = &[
+    ("piano", 0),
+    ("organ", 19),
+    ("guitar", 24),
+    ("bass", 32),
+    ("harp", 46),
+    ("strings", 48),
+    ("choir", 52),
+    ("horn", 60),
+    ("sax", 65),
+];
+
+/// Instrument names and their General MIDI programs, aliases included.
+pub fn gm_instruments() -> impl Iterator<Item = (&'static str, u8)> {
+    GM_PROGRAMS
+        .iter()
+        .zip(0u8..)
+        .map(|(&name, program)| (name, program))
+        .chain(GM_ALIASES.iter().copied())
+}
+
+/// Folds case and separators so `French Horn`, `french-horn` and `french_horn` match.
+pub fn normalize_instrument_name(name: &str) -> String {
+    name.trim()
+        .chars()
+        .map(|c| match c {
+            ' ' | '-' => '_',
+            c => c.to_ascii_lowercase(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn instrument_names_map_to_gm_programs() {
+        let program = |name: &str| {
+            let name = normalize_instrument_name(name);
+            gm_instruments().find(|&(n, _)| n == name).map(|(_, p)| p)
+        };
+        assert_eq!(program("acoustic_grand_piano"), Some(0));
+        assert_eq!(program("Violin"), Some(40));
+        assert_eq!(program("French Horn"), Some(60));
+        assert_eq!(program("gunshot"), Some(127));
+        assert_eq!(program("strings"), Some(48));
+        assert_eq!(program("kazoo"), None);
+    }
+}
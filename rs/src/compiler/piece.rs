@@ -0,0 +1,284 @@
+use std::{cmp::Ordering, ops::Range};
+
+use rowan::{TextRange, TextSize};
+
+use crate::compiler::types::{CompileEvent, EventBody};
+
+/// Compiled events with sorted indexes for repeated lookups by time and by source offset,
+/// as needed by hover, the playback cursor and selection playback.
+pub struct CompiledPiece {
+    pub events: Vec<CompileEvent>,
+    /// Event indices ordered by start time in seconds, then by kind
+    by_seconds: Vec<usize>,
+    /// Event indices ordered by start bar and tick, then by kind
+    by_bar: Vec<usize>,
+    /// Event indices ordered by the start of their source span
+    by_offset: Vec<usize>,
+    /// Longest note, bounding how early a note sounding at a given time can start
+    max_duration: f64,
+    /// Longest source span, bounding how early a span containing an offset can start
+    max_span: TextSize,
+}
+
+/// Part of the source an event stands for: the macro invocation for events a macro produced.
+pub fn source_span(event: &CompileEvent) -> TextRange {
+    event.range_invoked.unwrap_or(event.range)
+}
+
+/// Order of events starting at the same time: a measure begins before the definitions made
+/// at its start, and those apply before the lyrics and notes there.
+fn kind_rank(event: &CompileEvent) -> u8 {
+    match event.body {
+        EventBody::NewMeasure(_) => 0,
+        EventBody::BaseNoteDef(_)
+        | EventBody::BaseFequencyDef(_)
+        | EventBody::TimeSignatureDef(_)
+        | EventBody::KeySignatureDef(_)
+        | EventBody::VolumeDef(_)
+        | EventBody::PanDef(_)
+        | EventBody::SustainPedal(_)
+        | EventBody::BeatDurationDef(_)
+        | EventBody::BPMDef(_)
+        | EventBody::QuantizeDef(_)
+        | EventBody::CapoDef(_)
+        | EventBody::Marker(_)
+        | EventBody::CuePoint(_)
+        | EventBody::LoopStart
+        | EventBody::LoopEnd => 1,
+        EventBody::Lyric(_) => 2,
+        EventBody::Note(_) => 3,
+    }
+}
+
+fn duration_seconds(event: &CompileEvent) -> f64 {
+    match &event.body {
+        EventBody::Note(note) if note.duration_seconds.is_finite() => {
+            note.duration_seconds.max(0.0)
+        }
+        _ => 0.0,
+    }
+}
+
+impl CompiledPiece {
+    /// Indexes `events`; events that start together and are of the same kind keep their order.
+    pub fn new(events: Vec<CompileEvent>) -> Self {
+        let mut by_seconds: Vec<usize> = (0..events.len()).collect();
+        by_seconds.sort_by(|&a, &b| {
+            let (a, b) = (&events[a], &events[b]);
+            a.start_time
+                .seconds
+                .total_cmp(&b.start_time.seconds)
+                .then(kind_rank(a).cmp(&kind_rank(b)))
+        });
+        let mut by_bar: Vec<usize> = (0..events.len()).collect();
+        by_bar.sort_by(|&a, &b| {
+            let (a, b) = (&events[a], &events[b]);
+            let ticks = a.start_time.ticks.partial_cmp(&b.start_time.ticks);
+            a.start_time
+                .bars
+                .cmp(&b.start_time.bars)
+                .then(ticks.unwrap_or(Ordering::Equal))
+                .then(kind_rank(a).cmp(&kind_rank(b)))
+        });
+        let mut by_offset: Vec<usize> = (0..events.len()).collect();
+        by_offset.sort_by_key(|&i| source_span(&events[i]).start());
+        let max_duration = events.iter().map(duration_seconds).fold(0.0, f64::max);
+        let max_span = events
+            .iter()
+            .map(|e| source_span(e).len())
+            .max()
+            .unwrap_or_default();
+        Self {
+            events,
+            by_seconds,
+            by_bar,
+            by_offset,
+            max_duration,
+            max_span,
+        }
+    }
+
+    /// All events in start time order.
+    pub fn iter_sorted(&self) -> impl Iterator<Item = &CompileEvent> {
+        self.by_seconds.iter().map(|&i| &self.events[i])
+    }
+
+    /// Events grouped by the bar they start in, each group in tick order.
+    pub fn iter_by_bar(&self) -> impl Iterator<Item = (u32, impl Iterator<Item = &CompileEvent>)> {
+        self.by_bar
+            .chunk_by(|&a, &b| self.events[a].start_time.bars == self.events[b].start_time.bars)
+            .map(|chunk| {
+                let bar = self.events[chunk[0]].start_time.bars;
+                (bar, chunk.iter().map(|&i| &self.events[i]))
+            })
+    }
+
+    /// Events sounding at some point of the half-open `range` of seconds, in start time order.
+    /// Events without duration count when they start inside the range.
+    pub fn events_in_seconds(&self, range: Range<f64>) -> impl Iterator<Item = &CompileEvent> {
+        let earliest = range.start - self.max_duration;
+        let first = self
+            .by_seconds
+            .partition_point(|&i| self.events[i].start_time.seconds < earliest);
+        self.by_seconds[first..]
+            .iter()
+            .map(|&i| &self.events[i])
+            .take_while(move |e| e.start_time.seconds < range.end)
+            .filter(move |e| {
+                let start = e.start_time.seconds;
+                start >= range.start || start + duration_seconds(e) > range.start
+            })
+    }
+
+    /// Events starting in the half-open `range` of bars, in bar and tick order.
+    pub fn events_in_bars(&self, range: Range<u32>) -> impl Iterator<Item = &CompileEvent> {
+        let bar = |i: usize| self.events[i].start_time.bars;
+        let first = self.by_bar.partition_point(|&i| bar(i) < range.start);
+        let last = self.by_bar.partition_point(|&i| bar(i) < range.end);
+        self.by_bar[first..last.max(first)]
+            .iter()
+            .map(|&i| &self.events[i])
+    }
+
+    /// Part of the source the event at `index` stands for, as [`source_span`].
+    pub fn span_of(&self, index: usize) -> TextRange {
+        source_span(&self.events[index])
+    }
+
+    /// Indices of the events whose source span contains the byte `offset`, ends included,
+    /// in source order.
+    pub fn event_indices_at(&self, offset: TextSize) -> impl Iterator<Item = usize> {
+        self.event_indices_in_span(TextRange::empty(offset))
+    }
+
+    /// Indices of the events whose source span overlaps `range`, ends included, in source
+    /// order.
+    pub fn event_indices_in_span(&self, range: TextRange) -> impl Iterator<Item = usize> {
+        let start = |i: usize| self.span_of(i).start();
+        let earliest = range.start().checked_sub(self.max_span).unwrap_or_default();
+        let first = self.by_offset.partition_point(|&i| start(i) < earliest);
+        let last = self.by_offset.partition_point(|&i| start(i) <= range.end());
+        self.by_offset[first..last]
+            .iter()
+            .copied()
+            .filter(move |&i| self.span_of(i).end() >= range.start())
+    }
+
+    /// Index of the event the byte `offset` points at: the one with the narrowest source
+    /// span containing it, the earliest of those.
+    pub fn event_at(&self, offset: TextSize) -> Option<usize> {
+        self.event_indices_at(offset).min_by(|&a, &b| {
+            let (a_start, b_start) = (&self.events[a].start_time, &self.events[b].start_time);
+            self.span_of(a)
+                .len()
+                .cmp(&self.span_of(b).len())
+                .then(a_start.seconds.total_cmp(&b_start.seconds))
+        })
+    }
+
+    /// Events whose source span contains the byte `offset`, ends included.
+    pub fn events_at_offset(&self, offset: TextSize) -> impl Iterator<Item = &CompileEvent> {
+        self.event_indices_at(offset).map(|i| &self.events[i])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{compiler::compile::Compiler, rowan::parse_fn::parse_source};
+
+    fn piece(source: &str) -> CompiledPiece {
+        let parsed = parse_source(Arc::from(source));
+        let mut compiler = Compiler::new();
+        compiler.compile(&parsed.syntax_node());
+        CompiledPiece::new(compiler.events)
+    }
+
+    fn note_texts<'a>(source: &str, events: impl Iterator<Item = &'a CompileEvent>) -> Vec<String> {
+        events
+            .filter(|e| matches!(e.body, EventBody::Note(_)))
+            .map(|e| source[source_span(e)].to_string())
+            .collect()
+    }
+
+    #[test]
+    fn sorted_iteration_orders_simultaneous_events_by_kind() {
+        let piece = piece("C4,,,,\n(90)\nE4\"la\",\n");
+        let kinds: Vec<&str> = piece.iter_sorted().map(|e| (&e.body).into()).collect();
+        assert_eq!(
+            kinds,
+            [
+                "Note",
+                "NewMeasure",
+                "BPMDef",
+                "Lyric",
+                "Note",
+                "NewMeasure"
+            ]
+        );
+
+        let bars: Vec<(u32, usize)> = piece
+            .iter_by_bar()
+            .map(|(bar, events)| (bar, events.count()))
+            .collect();
+        assert_eq!(bars, vec![(0, 1), (1, 4), (2, 1)]);
+    }
+
+    #[test]
+    fn events_are_found_by_time_bar_and_offset() {
+        // at 120 BPM every quarter note lasts half a second
+        let source = "C4,,,,\nD4,E4,F4,G4,\nm = A4\nm,B4,\n";
+        let piece = piece(source);
+
+        assert_eq!(
+            note_texts(source, piece.events_in_seconds(2.3..2.6)),
+            vec!["D4", "E4"]
+        );
+        assert_eq!(
+            note_texts(source, piece.events_in_seconds(2.5..2.6)),
+            vec!["E4"]
+        );
+        assert_eq!(
+            note_texts(source, piece.events_in_bars(1..2)),
+            vec!["D4", "E4", "F4", "G4"]
+        );
+        assert!(piece.events_in_bars(5..9).next().is_none());
+
+        let at = |text: &str| TextSize::from(source.find(text).expect("in source") as u32);
+        assert_eq!(
+            note_texts(source, piece.events_at_offset(at("E4"))),
+            vec!["E4"]
+        );
+        assert_eq!(
+            note_texts(source, piece.events_at_offset(at("m,B4"))),
+            vec!["m"]
+        );
+    }
+
+    #[test]
+    fn offsets_map_to_events_and_back() {
+        let source = "m = C4:E4\nm,D4,\n";
+        let piece = piece(source);
+        let at = |text: &str| TextSize::from(source.find(text).expect("in source") as u32);
+
+        let index = piece.event_at(at("D4")).expect("D4 is an event");
+        assert_eq!(&source[piece.span_of(index)], "D4");
+        // both notes of the macro point back to its invocation
+        let invoked: Vec<&str> = piece
+            .event_indices_at(at("m,D4"))
+            .map(|i| &source[piece.span_of(i)])
+            .collect();
+        assert_eq!(invoked, vec!["m", "m"]);
+        assert!(piece.event_at(at("C4")).is_none());
+
+        let line = TextRange::new(at("m,D4"), at("D4"));
+        let mut found: Vec<usize> = piece.event_indices_in_span(line).collect();
+        found.sort_unstable();
+        assert_eq!(
+            note_texts(source, found.iter().map(|&i| &piece.events[i])),
+            vec!["m", "m", "D4"]
+        );
+    }
+}
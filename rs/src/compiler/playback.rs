@@ -0,0 +1,54 @@
+use crate::compiler::types::{CompileEvent, EventBody};
+
+/// Post-compile transform shared by audio playback and MIDI export: applies `(capo ...)` to
+/// sounding frequencies in compile order, leaving spellings and pitch ratios untouched.
+pub fn playback_events(events: &[CompileEvent]) -> Vec<CompileEvent> {
+    let mut capo = 1.0;
+    events
+        .iter()
+        .map(|event| {
+            let mut event = event.clone();
+            match &mut event.body {
+                EventBody::CapoDef(factor) => capo = *factor,
+                EventBody::Note(note) if note.drum_key.is_none() => {
+                    note.freq *= capo;
+                    note.portamento_from = note.portamento_from.map(|f| f * capo);
+                }
+                _ => {}
+            }
+            event
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{compiler::compile::Compiler, rowan::parse_fn::parse_source};
+
+    #[test]
+    fn capo_scales_playback_not_notation() {
+        let parsed = parse_source(Arc::from("C4,(capo +2\\12)C4,\ndrums: kick,\n"));
+        let mut compiler = Compiler::new();
+        compiler.compile(&parsed.syntax_node());
+        let notes = |events: &[CompileEvent]| -> Vec<(f64, f64)> {
+            events
+                .iter()
+                .filter_map(|e| match &e.body {
+                    EventBody::Note(n) => Some((n.freq, n.pitch_ratio)),
+                    _ => None,
+                })
+                .collect()
+        };
+        let compiled = notes(&compiler.events);
+        let played = notes(&playback_events(&compiler.events));
+        assert_eq!(compiled[0], compiled[1]);
+        assert_eq!(played[0], compiled[0]);
+        assert!((played[1].0 / compiled[1].0 - 2f64.powf(2.0 / 12.0)).abs() < 1e-5);
+        assert_eq!(played[1].1, compiled[1].1);
+        // drum hits keep their General MIDI keys
+        assert_eq!(played[2], compiled[2]);
+    }
+}
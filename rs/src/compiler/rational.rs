@@ -1,34 +1,40 @@
-use std::ops::{Add, AddAssign, Div, Mul, Neg};
+use std::ops::{Add, AddAssign, Div, Mul, Neg, Sub};
 
-#[derive(Debug, Clone, Copy)]
-pub struct Rational32(pub i32, pub i32);
+/// Exact fraction used for durations and positions. Arithmetic is carried out in `i128`,
+/// so only results that do not fit in `i64` overflow.
+#[derive(Debug, Clone, Copy, serde::Serialize, serde::Deserialize)]
+pub struct Rational64(pub i64, pub i64);
 
-fn gcd(a: i32, b: i32) -> i32 {
+fn gcd(a: i128, b: i128) -> i128 {
     if b == 0 { a.abs() } else { gcd(b, a % b) }
 }
 
-fn lcm(a: i32, b: i32) -> i32 {
-    let a64 = i64::from(a);
-    let b64 = i64::from(b);
-    let g64 = i64::from(gcd(a, b));
-    let l = (a64 / g64).checked_mul(b64).expect("LCM overflow").abs();
-    i32::try_from(l).expect("LCM overflow")
+fn lcm(a: i128, b: i128) -> i128 {
+    (a / gcd(a, b) * b).abs()
 }
 
-impl Rational32 {
+/// Narrows a widened fraction back to `i64`, if it fits.
+fn narrow(num: i128, den: i128) -> Option<Rational64> {
+    Some(Rational64(
+        i64::try_from(num).ok()?,
+        i64::try_from(den).ok()?,
+    ))
+}
+
+impl Rational64 {
     pub fn zero() -> Self {
         Self(0, 1)
     }
 
-    pub fn numer(&self) -> &i32 {
+    pub fn numer(&self) -> &i64 {
         &self.0
     }
 
-    pub fn denom(&self) -> &i32 {
+    pub fn denom(&self) -> &i64 {
         &self.1
     }
 
-    pub fn new(num: i32, denom: i32) -> Self {
+    pub fn new(num: i64, denom: i64) -> Self {
         if denom == 0 {
             panic!("Denominator cannot be zero");
         }
@@ -41,7 +47,7 @@ impl Rational32 {
 
     pub fn from_int<T>(num: T) -> Self
     where
-        T: Into<i32>,
+        T: Into<i64>,
     {
         Self(num.into(), 1)
     }
@@ -54,249 +60,305 @@ impl Rational32 {
         }
     }
 
+    pub fn to_f64(&self) -> f64 {
+        self.0 as f64 / self.1 as f64
+    }
+
     pub fn is_zero(&self) -> bool {
         self.0 == 0
     }
 
     pub fn reduce(self) -> Self {
-        let g = gcd(self.0, self.1);
-        let mut num = self.0 / g;
-        let mut den = self.1 / g;
+        let g = gcd(i128::from(self.0), i128::from(self.1));
+        let mut num = i128::from(self.0) / g;
+        let mut den = i128::from(self.1) / g;
         if den < 0 {
             num = -num;
             den = -den;
         }
-        Self(num, den)
+        narrow(num, den).expect("Rational reduction overflow")
     }
 
-    pub fn reduct_to(self, denom: i32) -> Self {
+    /// Rewrites the fraction over a multiple of `denom`. A fraction whose common
+    /// denominator with `denom` does not fit in `i64` is only reduced.
+    pub fn reduct_to(self, denom: i64) -> Self {
         if denom == 0 {
             panic!("Denominator cannot be zero");
         }
+        self.try_reduct_to(denom).unwrap_or_else(|| self.reduce())
+    }
+
+    /// Rewrites the fraction over a multiple of `denom`, if that denominator fits in `i64`.
+    pub fn try_reduct_to(self, denom: i64) -> Option<Self> {
+        if denom == 0 {
+            return None;
+        }
         let reduced = self.reduce();
-        let target_denom = lcm(reduced.1.abs(), denom.abs());
-        let factor = target_denom / reduced.1.abs();
-        let sign = if reduced.1 < 0 { -1 } else { 1 };
-        Self(reduced.0 * factor * sign, target_denom)
+        let target_denom = lcm(i128::from(reduced.1), i128::from(denom));
+        let factor = target_denom / i128::from(reduced.1);
+        narrow(i128::from(reduced.0) * factor, target_denom)
+    }
+
+    pub fn checked_add(self, rhs: Self) -> Option<Self> {
+        let (lhs_num, rhs_num, common_denom) = self.widen_common(rhs);
+        narrow(lhs_num + rhs_num, common_denom)
     }
 
-    pub fn from_integer(s: i32) -> Self {
+    pub fn checked_sub(self, rhs: Self) -> Option<Self> {
+        let (lhs_num, rhs_num, common_denom) = self.widen_common(rhs);
+        narrow(lhs_num - rhs_num, common_denom)
+    }
+
+    pub fn checked_mul(self, rhs: Self) -> Option<Self> {
+        let lhs = self.reduce();
+        let rhs = rhs.reduce();
+
+        let g1 = gcd(i128::from(lhs.0), i128::from(rhs.1)).max(1);
+        let g2 = gcd(i128::from(rhs.0), i128::from(lhs.1)).max(1);
+
+        let num = (i128::from(lhs.0) / g1) * (i128::from(rhs.0) / g2);
+        let den = (i128::from(lhs.1) / g2) * (i128::from(rhs.1) / g1);
+        narrow(num, den)
+    }
+
+    /// Quotient of two fractions; `None` when dividing by zero or overflowing.
+    pub fn checked_div(self, rhs: Self) -> Option<Self> {
+        if rhs.0 == 0 {
+            return None;
+        }
+        let num = i128::from(self.0) * i128::from(rhs.1);
+        let den = i128::from(self.1) * i128::from(rhs.0);
+        narrow(num, den)
+    }
+
+    pub fn from_integer(s: i64) -> Self {
         Self(s, 1)
     }
+
+    /// Numerators of both fractions over their least common denominator.
+    fn widen_common(self, rhs: Self) -> (i128, i128, i128) {
+        let lhs = self.reduce();
+        let rhs = rhs.reduce();
+        let common_denom = lcm(i128::from(lhs.1), i128::from(rhs.1));
+        (
+            i128::from(lhs.0) * (common_denom / i128::from(lhs.1)),
+            i128::from(rhs.0) * (common_denom / i128::from(rhs.1)),
+            common_denom,
+        )
+    }
 }
 
-impl Default for Rational32 {
+impl Default for Rational64 {
     fn default() -> Self {
         Self::zero()
     }
 }
 
-impl Neg for Rational32 {
+impl Neg for Rational64 {
     type Output = Self;
 
     fn neg(self) -> Self::Output {
-        Rational32(-self.0, self.1)
+        Rational64(-self.0, self.1)
     }
 }
 
-impl Add<Rational32> for Rational32 {
+impl Add<Rational64> for Rational64 {
     type Output = Self;
 
     fn add(self, rhs: Self) -> Self::Output {
-        let lhs = self.reduce();
-        let rhs = rhs.reduce();
-
-        let common_denom = lcm(lhs.1, rhs.1);
-        let lhs_factor = common_denom / lhs.1;
-        let rhs_factor = common_denom / rhs.1;
-
-        let lhs_num = i64::from(lhs.0) * i64::from(lhs_factor);
-        let rhs_num = i64::from(rhs.0) * i64::from(rhs_factor);
-        let num = lhs_num
-            .checked_add(rhs_num)
-            .and_then(|v| i32::try_from(v).ok())
-            .expect("Rational addition overflow");
-
-        Rational32(num, common_denom)
+        self.checked_add(rhs).expect("Rational addition overflow")
     }
 }
 
-impl AddAssign<Rational32> for Rational32 {
-    fn add_assign(&mut self, rhs: Rational32) {
+impl AddAssign<Rational64> for Rational64 {
+    fn add_assign(&mut self, rhs: Rational64) {
         *self = *self + rhs;
     }
 }
 
-impl Mul<Rational32> for Rational32 {
+impl Sub<Rational64> for Rational64 {
     type Output = Self;
 
-    fn mul(self, rhs: Self) -> Self::Output {
-        let lhs = self.reduce();
-        let rhs = rhs.reduce();
-
-        let g1 = gcd(lhs.0, rhs.1);
-        let g2 = gcd(rhs.0, lhs.1);
-
-        let lhs_num = lhs.0 / g1;
-        let lhs_den = lhs.1 / g2;
-        let rhs_num = rhs.0 / g2;
-        let rhs_den = rhs.1 / g1;
+    fn sub(self, rhs: Self) -> Self::Output {
+        self.checked_sub(rhs)
+            .expect("Rational subtraction overflow")
+    }
+}
 
-        let num = i64::from(lhs_num)
-            .checked_mul(i64::from(rhs_num))
-            .and_then(|v| i32::try_from(v).ok())
-            .expect("Rational multiplication overflow");
-        let den = i64::from(lhs_den)
-            .checked_mul(i64::from(rhs_den))
-            .and_then(|v| i32::try_from(v).ok())
-            .expect("Rational multiplication overflow");
+impl Mul<Rational64> for Rational64 {
+    type Output = Self;
 
-        Rational32(num, den)
+    fn mul(self, rhs: Self) -> Self::Output {
+        self.checked_mul(rhs)
+            .expect("Rational multiplication overflow")
     }
 }
 
-impl Div<Rational32> for Rational32 {
+impl Div<Rational64> for Rational64 {
     type Output = Self;
 
     fn div(self, rhs: Self) -> Self::Output {
         if rhs.0 == 0 {
             panic!("Cannot divide by zero");
         }
-        Rational32(self.0 * rhs.1, self.1 * rhs.0)
+        self.checked_div(rhs).expect("Rational division overflow")
     }
 }
 
-impl<T> Mul<T> for Rational32
+impl<T> Mul<T> for Rational64
 where
-    T: Into<i32>,
+    T: Into<i64>,
 {
     type Output = Self;
 
     fn mul(self, rhs: T) -> Self::Output {
-        let rhs = rhs.into();
-        let num = i64::from(self.0)
-            .checked_mul(i64::from(rhs))
-            .and_then(|v| i32::try_from(v).ok())
-            .expect("Rational multiplication overflow");
-        Rational32(num, self.1)
+        let num = i128::from(self.0) * i128::from(rhs.into());
+        narrow(num, i128::from(self.1)).expect("Rational multiplication overflow")
     }
 }
 
-impl From<i32> for Rational32 {
-    fn from(value: i32) -> Self {
-        Rational32(value, 1)
+impl From<i64> for Rational64 {
+    fn from(value: i64) -> Self {
+        Rational64(value, 1)
     }
 }
 
-impl From<Rational32> for (i32, i32) {
-    fn from(value: Rational32) -> Self {
+impl From<Rational64> for (i64, i64) {
+    fn from(value: Rational64) -> Self {
         (value.0, value.1)
     }
 }
 
-impl std::fmt::Display for Rational32 {
+impl std::fmt::Display for Rational64 {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}/{}", self.0, self.1)
     }
 }
 
-impl PartialEq<Rational32> for Rational32 {
-    fn eq(&self, other: &Rational32) -> bool {
+impl PartialEq<Rational64> for Rational64 {
+    fn eq(&self, other: &Rational64) -> bool {
         let reduced_self = self.reduce();
         let reduced_other = other.reduce();
         reduced_self.0 == reduced_other.0 && reduced_self.1 == reduced_other.1
     }
 }
 
-impl PartialOrd<Rational32> for Rational32 {
-    fn partial_cmp(&self, other: &Rational32) -> Option<std::cmp::Ordering> {
+impl PartialOrd<Rational64> for Rational64 {
+    fn partial_cmp(&self, other: &Rational64) -> Option<std::cmp::Ordering> {
         let reduced_self = self.reduce();
         let reduced_other = other.reduce();
-        (reduced_self.0 * reduced_other.1).partial_cmp(&(reduced_other.0 * reduced_self.1))
+        (i128::from(reduced_self.0) * i128::from(reduced_other.1))
+            .partial_cmp(&(i128::from(reduced_other.0) * i128::from(reduced_self.1)))
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::Rational32;
+    use super::Rational64;
 
     #[test]
     fn reduce_normalizes_fraction_and_sign() {
-        assert_eq!(Rational32(2, 4).reduce(), Rational32(1, 2));
-        assert_eq!(Rational32(2, -4).reduce(), Rational32(-1, 2));
-        assert_eq!(Rational32(-2, -4).reduce(), Rational32(1, 2));
+        assert_eq!(Rational64(2, 4).reduce(), Rational64(1, 2));
+        assert_eq!(Rational64(2, -4).reduce(), Rational64(-1, 2));
+        assert_eq!(Rational64(-2, -4).reduce(), Rational64(1, 2));
     }
 
     #[test]
     fn add_and_add_assign_work() {
-        let sum = Rational32(1, 2) + Rational32(1, 3);
-        assert_eq!(sum.reduce(), Rational32(5, 6));
-        assert_eq!(sum, Rational32(5, 6));
+        let sum = Rational64(1, 2) + Rational64(1, 3);
+        assert_eq!(sum.reduce(), Rational64(5, 6));
+        assert_eq!(sum, Rational64(5, 6));
 
-        let mut acc = Rational32(1, 2);
-        acc += Rational32(1, 3);
-        assert_eq!(acc.reduce(), Rational32(5, 6));
+        let mut acc = Rational64(1, 2);
+        acc += Rational64(1, 3);
+        assert_eq!(acc.reduce(), Rational64(5, 6));
+    }
+
+    #[test]
+    fn sub_works() {
+        assert_eq!(Rational64(1, 2) - Rational64(1, 3), Rational64(1, 6));
+        assert_eq!(Rational64(1, 4) - Rational64(1, 2), Rational64(-1, 4));
     }
 
     #[test]
     fn add_uses_lcm_denominator() {
-        let sum = Rational32(1, 6) + Rational32(1, 4);
-        assert_eq!(sum, Rational32(5, 12));
+        let sum = Rational64(1, 6) + Rational64(1, 4);
+        assert_eq!(sum, Rational64(5, 12));
     }
 
     #[test]
     fn mul_and_div_produce_expected_results() {
-        let product = Rational32(1, 2) * Rational32(1, 3);
-        assert_eq!(product.reduce(), Rational32(1, 6));
+        let product = Rational64(1, 2) * Rational64(1, 3);
+        assert_eq!(product.reduce(), Rational64(1, 6));
 
-        let quotient = Rational32(1, 2) / Rational32(2, 3);
-        assert_eq!(quotient.reduce(), Rational32(3, 4));
+        let quotient = Rational64(1, 2) / Rational64(2, 3);
+        assert_eq!(quotient.reduce(), Rational64(3, 4));
     }
 
     #[test]
     fn mul_by_integer_and_negation_work() {
-        assert_eq!((Rational32(3, 5) * 2).reduce(), Rational32(6, 5));
-        assert_eq!((-Rational32(3, 5)).reduce(), Rational32(-3, 5));
+        assert_eq!((Rational64(3, 5) * 2).reduce(), Rational64(6, 5));
+        assert_eq!((-Rational64(3, 5)).reduce(), Rational64(-3, 5));
     }
 
     #[test]
     fn ordering_and_equality_use_reduced_form() {
-        assert_eq!(Rational32(1, 2), Rational32(2, 4));
-        assert!(Rational32(1, 3) < Rational32(1, 2));
-        assert!(Rational32(-1, 2) < Rational32(1, 3));
+        assert_eq!(Rational64(1, 2), Rational64(2, 4));
+        assert!(Rational64(1, 3) < Rational64(1, 2));
+        assert!(Rational64(-1, 2) < Rational64(1, 3));
     }
 
     #[test]
     fn reduct_to_converts_to_compatible_denominator() {
-        let converted = Rational32(1, 2).reduct_to(6);
-        assert_eq!(converted, Rational32(3, 6));
+        let converted = Rational64(1, 2).reduct_to(6);
+        assert_eq!(converted, Rational64(3, 6));
     }
 
     #[test]
     fn to_f32_and_zero_helpers_work() {
-        assert_eq!(Rational32::zero(), Rational32(0, 1));
-        assert!(Rational32::zero().is_zero());
+        assert_eq!(Rational64::zero(), Rational64(0, 1));
+        assert!(Rational64::zero().is_zero());
 
-        let value = Rational32(1, 4).to_f32().expect("expected valid f32 value");
+        let value = Rational64(1, 4).to_f32().expect("expected valid f32 value");
         assert!((value - 0.25).abs() < f32::EPSILON);
     }
 
     #[test]
     #[should_panic(expected = "Denominator cannot be zero")]
     fn new_panics_on_zero_denominator() {
-        let _ = Rational32::new(1, 0);
+        let _ = Rational64::new(1, 0);
     }
 
     #[test]
     #[should_panic(expected = "Cannot divide by zero")]
     fn div_panics_on_zero_numerator_rhs() {
-        let _ = Rational32(1, 2) / Rational32(0, 3);
+        let _ = Rational64(1, 2) / Rational64(0, 3);
+    }
+
+    #[test]
+    fn arithmetic_beyond_32_bits_does_not_overflow() {
+        let sum = Rational64(1, 65521) + Rational64(1, 65519);
+        assert_eq!(sum, Rational64(131040, 65521 * 65519));
+        assert!(sum < Rational64(1, 32000));
+        // no common denominator fits, so the fraction is only reduced
+        assert_eq!(Rational64(2, 6).reduct_to(i64::MAX), Rational64(1, 3));
+    }
+
+    #[test]
+    fn checked_arithmetic_reports_overflow() {
+        let tiny = Rational64(1, i64::MAX);
+        assert_eq!(tiny.checked_add(tiny), Some(Rational64(2, i64::MAX)));
+        assert_eq!(tiny.checked_add(Rational64(1, i64::MAX - 1)), None);
+        assert_eq!(Rational64(i64::MAX, 1).checked_mul(Rational64(2, 1)), None);
+        assert_eq!(Rational64(1, 2).checked_div(Rational64(0, 1)), None);
+        assert_eq!(Rational64(1, 3).try_reduct_to(0), None);
+        assert_eq!(Rational64(1, 3).try_reduct_to(i64::MAX), None);
     }
 
     #[test]
     fn reduct_to() {
-        assert_eq!(Rational32(1, 2).reduct_to(4), Rational32(2, 4));
-        assert_eq!(Rational32(1, 3).reduct_to(6), Rational32(2, 6));
-        assert_eq!(Rational32(2, 8).reduct_to(4), Rational32(1, 4));
+        assert_eq!(Rational64(1, 2).reduct_to(4), Rational64(2, 4));
+        assert_eq!(Rational64(1, 3).reduct_to(6), Rational64(2, 6));
+        assert_eq!(Rational64(2, 8).reduct_to(4), Rational64(1, 4));
     }
 }
@@ -0,0 +1,40 @@
+/// Parses the contents of a Scala `.scl` file into the cents of its degrees 1..=n; the last
+/// degree is the period (usually `2/1`).
+pub fn parse_scala(text: &str) -> Option<Vec<f64>> {
+    let mut lines = text.lines().map(str::trim).filter(|l| !l.starts_with('!'));
+    lines.next()?; // description
+    let count: usize = lines.next()?.split_whitespace().next()?.parse().ok()?;
+    let cents: Vec<f64> = lines
+        .take(count)
+        .map(|l| parse_scala_pitch(l.split_whitespace().next()?))
+        .collect::<Option<_>>()?;
+    (count > 0 && cents.len() == count).then_some(cents)
+}
+
+/// A Scala pitch is in cents when it contains a period, otherwise a ratio such as `5/4` or `2`.
+fn parse_scala_pitch(s: &str) -> Option<f64> {
+    if s.contains('.') {
+        return s.parse().ok();
+    }
+    let (numer, denom) = s.split_once('/').unwrap_or((s, "1"));
+    let numer: f64 = numer.parse().ok()?;
+    let denom: f64 = denom.parse().ok()?;
+    (numer > 0.0 && denom > 0.0).then(|| 1200.0 * (numer / denom).log2())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scala_files_parse_into_cents() {
+        let text = "! meantone.scl\n!\nQuarter-comma meantone (partial)\n 3\n!\n 193.157 \n 5/4 major third\n 2/1\n";
+        let cents = parse_scala(text).expect("valid scala file");
+        assert_eq!(cents.len(), 3);
+        assert!((cents[0] - 193.157).abs() < 1e-9);
+        assert!((cents[1] - 386.3137).abs() < 1e-3);
+        assert!((cents[2] - 1200.0).abs() < 1e-9);
+        assert!(parse_scala("short\n 2\n 100.0\n").is_none());
+        assert!(parse_scala("bad\n 1\n 0/1\n").is_none());
+    }
+}
@@ -0,0 +1,193 @@
+use std::cmp::Ordering;
+
+use crate::compiler::{
+    rational::Rational64,
+    types::{CompileEvent, CompileState, EventBody, TempoMap},
+};
+
+/// Start of a bar on both time axes.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize)]
+pub struct BarBoundary {
+    pub bar: u32,
+    /// Whole notes from the start of the piece
+    pub position: Rational64,
+    pub seconds: f64,
+}
+
+/// Bar, tick and seconds conversions of a compiled piece, shared by the MIDI writer and the
+/// editor's ruler. Ticks are whole notes from the start of a bar, as in `TimeStamp`.
+///
+/// Bars follow the lines of the source rather than the time signature, so their starts are
+/// read from the `NewMeasure` events. Past the last bar, bars of the last time signature
+/// follow one another.
+#[derive(Debug, Clone)]
+pub struct Timeline {
+    tempo: TempoMap,
+    bars: Vec<BarBoundary>,
+    /// Time signature in effect at the end of the piece
+    time_signature: Rational64,
+}
+
+impl Timeline {
+    /// Reads tempo changes, time signatures and bar starts from `events`, in any order. The
+    /// piece starts from the default state; a compiler configured otherwise defines its tempo
+    /// and meter at the start.
+    pub fn new(events: &[CompileEvent]) -> Self {
+        Self::with_start(events, &CompileState::new())
+    }
+
+    /// [`Timeline::new`] for a piece starting from the tempo and meter of `start`.
+    pub fn with_start(events: &[CompileEvent], start: &CompileState) -> Self {
+        let mut sorted: Vec<&CompileEvent> = events.iter().collect();
+        sorted.sort_by(|a, b| {
+            a.start_time
+                .position
+                .partial_cmp(&b.start_time.position)
+                .unwrap_or(Ordering::Equal)
+        });
+
+        let mut bpm = f64::from(start.bpm);
+        let mut beat_duration = start.beat_duration;
+        let seconds_per_whole_note = |bpm: f64, beat: Rational64| 60.0 / (bpm * beat.to_f64());
+        let mut tempo = TempoMap::new(start.seconds_per_whole_note());
+        let mut time_signature = start.time_signature;
+        let mut bars = vec![BarBoundary {
+            bar: 0,
+            position: Rational64::zero(),
+            seconds: 0.0,
+        }];
+        for event in sorted {
+            let position = event.start_time.position;
+            match event.body {
+                EventBody::BPMDef(next) => {
+                    bpm = f64::from(next);
+                    tempo.set_tempo(position, seconds_per_whole_note(bpm, beat_duration));
+                }
+                EventBody::BeatDurationDef(next) => {
+                    beat_duration = next;
+                    tempo.set_tempo(position, seconds_per_whole_note(bpm, beat_duration));
+                }
+                EventBody::TimeSignatureDef(ref next) => time_signature = next.bar_length(),
+                EventBody::NewMeasure(bar) => bars.push(BarBoundary {
+                    bar,
+                    position,
+                    seconds: event.start_time.seconds,
+                }),
+                _ => {}
+            }
+        }
+        bars.sort_by_key(|b| b.bar);
+        bars.dedup_by_key(|b| b.bar);
+
+        Self {
+            tempo,
+            bars,
+            time_signature,
+        }
+    }
+
+    /// Start in seconds and seconds per whole note of each tempo of the piece, in order.
+    pub fn tempo_changes(&self) -> impl Iterator<Item = (f64, f64)> + '_ {
+        self.tempo.changes()
+    }
+
+    /// Starts of the bars of the piece, in bar order.
+    pub fn bar_boundaries(&self) -> &[BarBoundary] {
+        &self.bars
+    }
+
+    /// Position in whole notes where `bar` starts, or `None` when it does not fit.
+    pub fn bar_position(&self, bar: u32) -> Option<Rational64> {
+        let index = self.bars.partition_point(|b| b.bar <= bar).checked_sub(1)?;
+        let start = self.bars[index];
+        let extra = self
+            .time_signature
+            .checked_mul(Rational64::from_integer(i64::from(bar - start.bar)))?;
+        start.position.checked_add(extra)
+    }
+
+    /// Seconds at `tick` whole notes into `bar`, or `None` when the position does not fit.
+    pub fn bar_tick_to_seconds(&self, bar: u32, tick: Rational64) -> Option<f64> {
+        let position = self.bar_position(bar)?.checked_add(tick)?;
+        Some(self.tempo.seconds_at(position))
+    }
+
+    /// Bar sounding at `seconds`, and the whole notes elapsed in it.
+    pub fn seconds_to_bar_tick(&self, seconds: f64) -> (u32, f64) {
+        let seconds = seconds.max(0.0);
+        let position = self.tempo.position_at(seconds);
+        let index = self
+            .bars
+            .partition_point(|b| b.seconds <= seconds)
+            .saturating_sub(1);
+        let start = self.bars[index];
+        let mut bar = start.bar;
+        let mut tick = (position - start.position.to_f64()).max(0.0);
+        let length = self.time_signature.to_f64();
+        if index + 1 == self.bars.len() && length > 0.0 {
+            let extra = (tick / length).floor();
+            bar = bar.saturating_add(extra as u32);
+            tick -= extra * length;
+        }
+        (bar, tick)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{compiler::compile::Compiler, rowan::parse_fn::parse_source};
+
+    fn timeline(source: &str) -> Timeline {
+        let parse = parse_source(Arc::from(source));
+        let mut compiler = Compiler::new();
+        compiler.compile(&parse.syntax_node());
+        Timeline::new(&compiler.events)
+    }
+
+    #[test]
+    fn converts_between_bars_and_seconds() {
+        // 4/4 at 120 bpm: a bar lasts two seconds, then one second at 240 bpm
+        let timeline = timeline("C4,D4,E4,F4,\n(240)\nC4,D4,E4,F4,\n");
+        let starts: Vec<_> = timeline
+            .bar_boundaries()
+            .iter()
+            .map(|b| (b.bar, b.seconds))
+            .collect();
+        assert_eq!(starts, vec![(0, 0.0), (1, 2.0), (2, 3.0)]);
+
+        assert_eq!(
+            timeline.bar_tick_to_seconds(1, Rational64::new(1, 2)),
+            Some(2.5)
+        );
+        assert_eq!(
+            timeline.bar_tick_to_seconds(3, Rational64::zero()),
+            Some(4.0)
+        );
+
+        let (bar, tick) = timeline.seconds_to_bar_tick(0.5);
+        assert_eq!(bar, 0);
+        assert!((tick - 0.25).abs() < 1e-9);
+        let (bar, tick) = timeline.seconds_to_bar_tick(4.5);
+        assert_eq!(bar, 3);
+        assert!((tick - 0.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn starts_from_the_given_state() {
+        let mut start = CompileState::new();
+        start.set_tempo(60.0, start.beat_duration);
+        start.time_signature = Rational64::new(3, 4);
+        let timeline = Timeline::with_start(&[], &start);
+        assert_eq!(
+            timeline.bar_tick_to_seconds(2, Rational64::zero()),
+            Some(6.0)
+        );
+        assert_eq!(
+            timeline.tempo_changes().collect::<Vec<_>>(),
+            vec![(0.0, 4.0)]
+        );
+    }
+}
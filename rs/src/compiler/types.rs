@@ -1,23 +1,39 @@
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    fmt,
+    path::PathBuf,
+    str::FromStr,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+};
 
-use super::rational::Rational32;
-use regex::Regex;
+use super::rational::Rational64;
+use crate::rowan::lexer::SyntaxKind;
+use itertools::Itertools;
+use logos::Logos;
 use rowan::TextRange;
+pub use smol_str::SmolStr;
 use strum::Display;
 
 pub type PitchSpell = i16; // note: 0=C-1, 1=C#-1, ..., 60=C4, ... 
 pub type PitchChain = Vec<Pitch>;
 
-#[derive(Debug, Display, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
 pub enum Pitch {
     SpellOctave(PitchSpell),
     SpellSimple(PitchSpell),
-    Frequency(f32),
-    Ratio(Rational32),
-    Edo(Rational32),
-    Cents(i32),
+    Frequency(f64),
+    Ratio(Rational64),
+    Edo(Rational64),
+    Cents(f64),
+    /// Nth subharmonic (undertone) of the base frequency, e.g. `u7`
+    Subharmonic(u16),
     Rest,
     Sustain,
+    /// Slot continuing the previous note tied with a `_` suffix
+    Tie,
 }
 fn char_to_semitone(c: char) -> Option<i16> {
     match c {
@@ -32,86 +48,283 @@ fn char_to_semitone(c: char) -> Option<i16> {
     }
 }
 
+/// Helmholtz-Ellis comma accidentals and the ratio each applies to the spelled pitch:
+/// syntonic comma down/up (`\` `/`) and septimal comma down/up (`!` `¡`).
+pub const COMMA_ACCIDENTALS: &[(char, i32, i32)] =
+    &[('\\', 80, 81), ('/', 81, 80), ('!', 63, 64), ('¡', 64, 63)];
+
+/// Semitone and cents offsets of an accidental; half accidentals only carry cents and
+/// comma accidentals neither.
+fn accidental_offset(c: char) -> Option<(i16, i32)> {
+    match c {
+        '#' | '♯' => Some((1, 0)),
+        'b' | '♭' => Some((-1, 0)),
+        '𝄪' => Some((2, 0)),
+        '𝄫' => Some((-2, 0)),
+        '+' | '𝄲' => Some((0, 50)),
+        'd' | '𝄳' => Some((0, -50)),
+        _ if COMMA_ACCIDENTALS.iter().any(|&(comma, _, _)| comma == c) => Some((0, 0)),
+        _ => None,
+    }
+}
+
+/// Semitone of the letter and accidentals that start a spelling such as `^Eb4`, skipping
+/// up/down arrows, and the text after them where an octave would follow.
+fn parse_spell_letter(s: &str) -> Option<(i16, &str)> {
+    let mut chars = s.trim_start_matches(['^', 'v']).chars();
+    let mut semitone = char_to_semitone(chars.next()?)?;
+    let rest = chars.as_str();
+    let octave_at = rest
+        .find(|c: char| c.is_ascii_digit() || c == '-')
+        .unwrap_or(rest.len());
+    for acc in rest[..octave_at].chars() {
+        semitone += accidental_offset(acc)?.0;
+    }
+    Some((semitone, &rest[octave_at..]))
+}
+
 impl Pitch {
     pub fn parse_spell_octave(s: &str) -> Option<Self> {
-        let regex = Regex::new(r"^([A-G])([#b]*)(-?\d+)$").unwrap();
-        if let Some(caps) = regex.captures(s) {
-            let base_char = caps.get(1)?.as_str().chars().next()?;
-            let accidentals = caps.get(2)?.as_str();
-            let octave_str = caps.get(3)?.as_str();
-
-            let mut semitone = char_to_semitone(base_char)?;
-            for acc in accidentals.chars() {
-                match acc {
-                    '#' => semitone += 1,
-                    'b' => semitone -= 1,
-                    _ => return None,
-                }
-            }
-            let octave: i16 = octave_str.parse().ok()?;
-            let pitch_spell = semitone + (octave + 1) * 12;
-            Some(Pitch::SpellOctave(pitch_spell))
-        } else {
-            None
-        }
+        let (semitone, octave) = parse_spell_letter(s)?;
+        let octave: i16 = octave.parse().ok()?;
+        let pitch_spell = octave
+            .checked_add(1)?
+            .checked_mul(12)?
+            .checked_add(semitone)?;
+        Some(Pitch::SpellOctave(pitch_spell))
     }
 
     pub fn parse_spell_simple(s: &str) -> Option<Self> {
-        let regex = Regex::new(r"^([A-G])([#b]*)$").unwrap();
-        if let Some(caps) = regex.captures(s) {
-            let base_char = caps.get(1)?.as_str().chars().next()?;
-            let accidentals = caps.get(2)?.as_str();
-
-            let mut semitone = char_to_semitone(base_char)?;
-            for acc in accidentals.chars() {
-                match acc {
-                    '#' => semitone += 1,
-                    'b' => semitone -= 1,
-                    _ => return None,
-                }
-            }
-            Some(Pitch::SpellSimple(semitone))
-        } else {
-            None
-        }
+        let (semitone, rest) = parse_spell_letter(s)?;
+        rest.is_empty().then_some(Pitch::SpellSimple(semitone))
+    }
+
+    /// Cents added by the half accidentals of a spelling (e.g. `C𝄲4` gives 50).
+    pub fn spell_half_accidental_cents(s: &str) -> i32 {
+        s.trim_start_matches(['^', 'v'])
+            .chars()
+            .skip(1)
+            .filter_map(accidental_offset)
+            .map(|(_, cents)| cents)
+            .sum()
+    }
+
+    /// Product of the comma accidentals of a spelling (e.g. `E\4` gives 80/81).
+    pub fn spell_comma_ratio(s: &str) -> Rational64 {
+        s.chars()
+            .filter_map(|c| COMMA_ACCIDENTALS.iter().find(|&&(comma, _, _)| comma == c))
+            .fold(Rational64::from_integer(1), |ratio, &(_, numer, denom)| {
+                (ratio * Rational64::new(i64::from(numer), i64::from(denom))).reduce()
+            })
+    }
+
+    /// EDO steps added by the up/down arrows of a spelling (e.g. `^^E4` gives 2).
+    pub fn spell_arrow_steps(s: &str) -> i32 {
+        s.chars()
+            .map_while(|c| match c {
+                '^' => Some(1),
+                'v' => Some(-1),
+                _ => None,
+            })
+            .sum()
     }
 
     pub fn parse_fequency(s: &str) -> Option<Self> {
-        s.parse::<f32>().ok().map(Pitch::Frequency)
+        s.parse::<f64>().ok().map(Pitch::Frequency)
     }
 
     pub fn parse_ratio(s: &str) -> Option<Self> {
         let parts: Vec<&str> = s.split('/').collect();
         if parts.len() == 2 {
-            let numerator = parts[0].parse::<i32>().ok()?;
-            let denominator = parts[1].parse::<i32>().ok()?;
-            Some(Pitch::Ratio(Rational32::new(numerator, denominator)))
+            let numerator = parts[0].parse::<i64>().ok()?;
+            let denominator = parts[1].parse::<i64>().ok()?;
+            Some(Pitch::Ratio(Rational64::new(numerator, denominator)))
         } else {
             None
         }
     }
 
+    /// Splits a ratio power such as `3/2^2` or `(3/2)^-1` into its base ratio and exponent.
+    pub fn parse_ratio_power(s: &str) -> Option<(Rational64, i32)> {
+        let (base, exponent) = s.split_once('^')?;
+        let base = base.trim_start_matches('(').trim_end_matches(')');
+        let Pitch::Ratio(ratio) = Pitch::parse_ratio(base)? else {
+            return None;
+        };
+        Some((ratio, exponent.parse().ok()?))
+    }
+
+    pub fn parse_subharmonic(s: &str) -> Option<Self> {
+        s[1..]
+            .parse::<u16>()
+            .ok()
+            .filter(|&n| n > 0)
+            .map(Pitch::Subharmonic)
+    }
+
     pub fn parse_edo(s: &str) -> Option<Self> {
         let parts: Vec<&str> = s.split('\\').collect();
         if parts.len() == 2 {
-            let numerator = parts[0].parse::<i32>().ok()?;
-            let denominator = parts[1].parse::<i32>().ok()?;
-            Some(Pitch::Edo(Rational32::new(numerator, denominator)))
+            let numerator = parts[0].parse::<i64>().ok()?;
+            let denominator = parts[1].parse::<i64>().ok()?;
+            Some(Pitch::Edo(Rational64::new(numerator, denominator)))
         } else {
             None
         }
     }
 
     pub fn parse_cents(s: &str) -> Option<Self> {
-        s[..s.len() - 1].parse::<i32>().ok().map(Pitch::Cents)
+        s[..s.len() - 1].parse::<f64>().ok().map(Pitch::Cents)
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+/// Names of the semitones above C, spelled with sharps.
+const SHARP_NAMES: [&str; 12] = [
+    "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B",
+];
+
+impl fmt::Display for Pitch {
+    /// Writes the pitch as symi source, spelling semitones with sharps.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Pitch::SpellOctave(spell) => {
+                let (octave, semitone) = (spell.div_euclid(12) - 1, spell.rem_euclid(12));
+                write!(f, "{}{}", SHARP_NAMES[semitone as usize], octave)
+            }
+            Pitch::SpellSimple(semitone @ 0..12) => f.write_str(SHARP_NAMES[semitone as usize]),
+            // `Cb` and `B#` spell semitones that leave the octave
+            Pitch::SpellSimple(semitone) if semitone < 0 => {
+                write!(f, "C{}", "b".repeat(semitone.unsigned_abs() as usize))
+            }
+            Pitch::SpellSimple(semitone) => write!(f, "B{}", "#".repeat((semitone - 11) as usize)),
+            Pitch::Frequency(freq) => write!(f, "{freq}"),
+            Pitch::Ratio(ratio) => write!(f, "{}/{}", ratio.numer(), ratio.denom()),
+            Pitch::Edo(step) => write!(f, "{}\\{}", step.numer(), step.denom()),
+            Pitch::Cents(cents) => write!(f, "{cents}c"),
+            Pitch::Subharmonic(n) => write!(f, "u{n}"),
+            Pitch::Rest => f.write_str("."),
+            Pitch::Sustain => f.write_str("-"),
+            Pitch::Tie => f.write_str("_"),
+        }
+    }
+}
+
+/// Error of reading a pitch, pitch chain or note from symi source.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParsePitchError(pub String);
+
+impl fmt::Display for ParsePitchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for ParsePitchError {}
+
+impl FromStr for Pitch {
+    type Err = ParsePitchError;
+
+    /// Parses a single pitch such as `C#4`, `3/2`, `7\12`, `-50c` or `.`. Numbers stay
+    /// frequencies, as no tuning is in effect, and spellings whose half, comma or arrow
+    /// accidentals would add pitches to a chain are rejected like ratio powers.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ParsePitchError(format!("Invalid pitch: {s}"));
+        let mut lexer = SyntaxKind::lexer(s);
+        let (Some(Ok(kind)), None) = (lexer.next(), lexer.next()) else {
+            return Err(invalid());
+        };
+        let pitch = match kind {
+            SyntaxKind::PitchSpellOctave | SyntaxKind::PitchSpellSimple
+                if Pitch::spell_half_accidental_cents(s) != 0
+                    || Pitch::spell_comma_ratio(s) != Rational64::from_integer(1)
+                    || Pitch::spell_arrow_steps(s) != 0 =>
+            {
+                None
+            }
+            SyntaxKind::PitchSpellOctave => Pitch::parse_spell_octave(s),
+            SyntaxKind::PitchSpellSimple => Pitch::parse_spell_simple(s),
+            SyntaxKind::PitchFrequency => Pitch::parse_fequency(s),
+            SyntaxKind::PitchRatio if !s.contains('^') => Pitch::parse_ratio(s),
+            SyntaxKind::PitchEdo => Pitch::parse_edo(s),
+            SyntaxKind::PitchCents => Pitch::parse_cents(s),
+            SyntaxKind::PitchSubharmonic => Pitch::parse_subharmonic(s),
+            SyntaxKind::PitchRest => Some(Pitch::Rest),
+            SyntaxKind::PitchSustain => Some(Pitch::Sustain),
+            SyntaxKind::PitchTie => Some(Pitch::Tie),
+            _ => None,
+        };
+        pitch.ok_or_else(invalid)
+    }
+}
+
+/// Writes a pitch chain as symi source, e.g. `3/2@C4`; see [`display_chain`].
+pub struct ChainDisplay<'a>(&'a [Pitch]);
+
+/// Displays a pitch chain as symi source.
+pub fn display_chain(chain: &[Pitch]) -> ChainDisplay<'_> {
+    ChainDisplay(chain)
+}
+
+impl fmt::Display for ChainDisplay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0.iter().format("@"))
+    }
+}
+
+/// Parses a pitch chain written like `3/2@C4` or `E4+`, the inverse of [`display_chain`].
+/// Octave suffixes become `2/1` and `1/2` ratios as in the compiler, and rests, sustains and
+/// ties stand only alone.
+pub fn parse_pitch_chain(s: &str) -> Result<PitchChain, ParsePitchError> {
+    let mut chain = Vec::new();
+    let mut expect_pitch = true;
+    let mut lexer = SyntaxKind::lexer(s);
+    while let Some(kind) = lexer.next() {
+        match kind {
+            Ok(SyntaxKind::Whitespace) => {}
+            Ok(SyntaxKind::At) if !expect_pitch => expect_pitch = true,
+            Ok(SyntaxKind::Plus) if !expect_pitch => {
+                chain.push(Pitch::Ratio(Rational64::new(2, 1)))
+            }
+            Ok(SyntaxKind::PitchSustain) if !expect_pitch => {
+                chain.push(Pitch::Ratio(Rational64::new(1, 2)))
+            }
+            Ok(_) if expect_pitch => {
+                chain.push(lexer.slice().parse()?);
+                expect_pitch = false;
+            }
+            _ => {
+                return Err(ParsePitchError(format!(
+                    "Unexpected '{}' in pitch chain: {s}",
+                    lexer.slice()
+                )));
+            }
+        }
+    }
+    if expect_pitch {
+        return Err(ParsePitchError(format!(
+            "Pitch chain cannot end with '@': {s}"
+        )));
+    }
+    if chain.len() > 1
+        && chain
+            .iter()
+            .any(|p| matches!(p, Pitch::Rest | Pitch::Sustain | Pitch::Tie))
+    {
+        return Err(ParsePitchError(format!(
+            "rest/sustain cannot be used inside pitch chain: {s}"
+        )));
+    }
+    Ok(chain)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct TimeStamp {
+    /// Seconds from the start, derived from `position` through the tempo map
     pub seconds: f64,
     pub bars: u32,
-    pub ticks: Rational32,
+    pub ticks: Rational64,
+    /// Exact whole notes from the start
+    pub position: Rational64,
 }
 
 impl Default for TimeStamp {
@@ -119,63 +332,303 @@ impl Default for TimeStamp {
         Self {
             seconds: 0.0,
             bars: 0,
-            ticks: Rational32::new(0, 4),
+            ticks: Rational64::new(0, 4),
+            position: Rational64::zero(),
         }
     }
 }
 
 impl TimeStamp {
-    pub fn dur_in_sec(duration: Rational32, state: &CompileState) -> f64 {
-        let full_notes = duration.to_f32().unwrap();
-        let full_note_per_minute = state.bpm
-            * (state.beat_duration)
-                .to_f32()
-                .expect("Rational32 to f32 conversion failed");
-        (full_notes / full_note_per_minute as f32 * 60.0) as f64
-    }
-
-    pub fn add_duration(&self, duration: Rational32, state: &CompileState) -> Self {
-        let mut _self = self.clone();
-        // Update ticks
-        _self.ticks += duration;
-        // Update seconds
-        _self.seconds += TimeStamp::dur_in_sec(duration, state);
-        _self
+    pub fn dur_in_sec(duration: Rational64, state: &CompileState) -> f64 {
+        duration.to_f64() * state.seconds_per_whole_note()
+    }
+
+    pub fn add_duration(&self, duration: Rational64, state: &CompileState) -> Self {
+        self.checked_add_duration(duration, state)
+            .expect("Rational addition overflow")
     }
 
-    pub fn reduct_to_quantize(&self, quantize: Rational32) -> Self {
-        let mut _self = self.clone();
+    /// Moves the time stamp by `duration`, or `None` when ticks or position overflow.
+    pub fn checked_add_duration(&self, duration: Rational64, state: &CompileState) -> Option<Self> {
+        let mut _self = *self;
+        _self.ticks = _self.ticks.checked_add(duration)?;
+        _self.position = _self.position.checked_add(duration)?;
+        // seconds are looked up rather than accumulated, so they never drift
+        _self.seconds = state.tempo.seconds_at(_self.position);
+        Some(_self)
+    }
+
+    pub fn reduct_to_quantize(&self, quantize: Rational64) -> Self {
+        let mut _self = *self;
         _self.ticks = _self.ticks.reduct_to(*quantize.denom());
         _self
     }
 
-    pub fn next_bar(&self, time_signature: Rational32) -> Self {
-        let mut next = self.clone();
+    pub fn next_bar(&self, time_signature: Rational64) -> Self {
+        let mut next = *self;
         next.bars += 1;
-        next.ticks = Rational32::new(0, *time_signature.denom());
+        next.ticks = Rational64::new(0, *time_signature.denom());
         next
     }
 
     pub fn is_zero(&self) -> bool {
-        self.seconds == 0.0
+        self.position.is_zero()
+    }
+
+    /// This time stamp in a piece appended at `start`, the start of a bar, instead of
+    /// starting on its own. Ticks stay as they are within their bar.
+    pub fn after(&self, start: TimeStamp) -> Self {
+        self.checked_after(start)
+            .expect("Rational addition overflow")
     }
+
+    /// [`Self::after`], or `None` when the position overflows.
+    pub fn checked_after(&self, start: TimeStamp) -> Option<Self> {
+        Some(Self {
+            seconds: start.seconds + self.seconds,
+            bars: start.bars + self.bars,
+            ticks: self.ticks,
+            position: start.position.checked_add(self.position)?,
+        })
+    }
+}
+
+/// A tempo in effect from an exact position on.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct TempoSegment {
+    start: Rational64,
+    start_seconds: f64,
+    seconds_per_whole_note: f64,
 }
 
+/// Tempo changes by exact position, mapping positions to seconds.
 #[derive(Debug, Clone, PartialEq)]
+pub struct TempoMap {
+    segments: Vec<TempoSegment>,
+}
+
+impl TempoMap {
+    pub fn new(seconds_per_whole_note: f64) -> Self {
+        Self {
+            segments: vec![TempoSegment {
+                start: Rational64::zero(),
+                start_seconds: 0.0,
+                seconds_per_whole_note,
+            }],
+        }
+    }
+
+    /// Seconds at `position`, under the tempo in effect there.
+    pub fn seconds_at(&self, position: Rational64) -> f64 {
+        let index = self
+            .segments
+            .partition_point(|s| s.start <= position)
+            .saturating_sub(1);
+        let segment = &self.segments[index];
+        let elapsed = position.checked_sub(segment.start).map_or_else(
+            || position.to_f64() - segment.start.to_f64(),
+            |d| d.to_f64(),
+        );
+        segment.start_seconds + elapsed * segment.seconds_per_whole_note
+    }
+
+    /// Position in whole notes at `seconds`, the inverse of [`TempoMap::seconds_at`].
+    pub fn position_at(&self, seconds: f64) -> f64 {
+        let index = self
+            .segments
+            .partition_point(|s| s.start_seconds <= seconds)
+            .saturating_sub(1);
+        let segment = &self.segments[index];
+        segment.start.to_f64() + (seconds - segment.start_seconds) / segment.seconds_per_whole_note
+    }
+
+    /// Start in seconds and seconds per whole note of each tempo, in order.
+    pub fn changes(&self) -> impl Iterator<Item = (f64, f64)> + '_ {
+        self.segments
+            .iter()
+            .map(|s| (s.start_seconds, s.seconds_per_whole_note))
+    }
+
+    /// Starts a new tempo at `position`, replacing any tempo set at or after it.
+    pub fn set_tempo(&mut self, position: Rational64, seconds_per_whole_note: f64) {
+        let start_seconds = self.seconds_at(position);
+        self.segments.retain(|s| s.start < position);
+        self.segments.push(TempoSegment {
+            start: position,
+            start_seconds,
+            seconds_per_whole_note,
+        });
+    }
+}
+
+/// Time signature as beat groups over a denominator: `(3/4)` is one group of three
+/// quarters, the additive `(3+2/8)` a group of three eighths followed by one of two.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct TimeSignature {
+    pub groups: Vec<u32>,
+    pub denominator: u32,
+}
+
+impl TimeSignature {
+    pub fn new(groups: Vec<u32>, denominator: u32) -> Self {
+        Self {
+            groups,
+            denominator,
+        }
+    }
+
+    /// A time signature of a single beat group, like `(3/4)`.
+    pub fn simple(numerator: u32, denominator: u32) -> Self {
+        Self::new(vec![numerator], denominator)
+    }
+
+    /// Beats in a bar, the sum of the beat groups.
+    pub fn numerator(&self) -> u32 {
+        self.groups.iter().sum()
+    }
+
+    /// Length of a bar in whole notes, kept over the denominator: `(6/8)` stays 6/8.
+    pub fn bar_length(&self) -> Rational64 {
+        Rational64::new(i64::from(self.numerator()), i64::from(self.denominator))
+    }
+
+    /// Whether the bar is split into more than one beat group.
+    pub fn is_additive(&self) -> bool {
+        self.groups.len() > 1
+    }
+}
+
+impl fmt::Display for TimeSignature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (idx, group) in self.groups.iter().enumerate() {
+            if idx > 0 {
+                write!(f, "+")?;
+            }
+            write!(f, "{}", group)?;
+        }
+        write!(f, "/{}", self.denominator)
+    }
+}
+
+/// Level of a volume or pan setting.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct MixLevel {
+    pub value: f32,
+    /// Whether the level glides to the next setting of the same kind (`~`)
+    pub ramp: bool,
+}
+
+/// Key signature set by `(key ...)`, in sharps (negative for flats) as MIDI writes it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct KeySignature {
+    pub sharps: i8,
+    pub minor: bool,
+}
+
+/// Major tonics from seven flats to seven sharps.
+const MAJOR_TONICS: [&str; 15] = [
+    "Cb", "Gb", "Db", "Ab", "Eb", "Bb", "F", "C", "G", "D", "A", "E", "B", "F#", "C#",
+];
+/// Minor tonics from seven flats to seven sharps.
+const MINOR_TONICS: [&str; 15] = [
+    "Ab", "Eb", "Bb", "F", "C", "G", "D", "A", "E", "B", "F#", "C#", "G#", "D#", "A#",
+];
+
+impl KeySignature {
+    /// Key of the tonic spelled `s` (e.g. `Eb`, `F#`), or `None` when the spelling has
+    /// microtonal accidentals or its key needs more than seven sharps or flats.
+    pub fn from_tonic(s: &str, minor: bool) -> Option<Self> {
+        let mut chars = s.chars();
+        let mut fifths: i32 = match chars.next()? {
+            'F' => -1,
+            'C' => 0,
+            'G' => 1,
+            'D' => 2,
+            'A' => 3,
+            'E' => 4,
+            'B' => 5,
+            _ => return None,
+        };
+        for acc in chars {
+            fifths += match acc {
+                '#' | '♯' => 7,
+                'b' | '♭' => -7,
+                '𝄪' => 14,
+                '𝄫' => -14,
+                _ => return None,
+            };
+        }
+        if minor {
+            fifths -= 3;
+        }
+        let sharps = i8::try_from(fifths).ok().filter(|f| (-7..=7).contains(f))?;
+        Some(Self { sharps, minor })
+    }
+
+    /// Major key with the fewest accidentals on the tonic `semitone` above C, for pieces that
+    /// name no key but their base note.
+    pub fn major_on_semitone(semitone: PitchSpell) -> Self {
+        // flats for Db, Eb, Ab and Bb, sharps otherwise
+        const SHARPS: [i8; 12] = [0, -5, 2, -3, 4, -1, 6, 1, -4, 3, -2, 5];
+        Self {
+            sharps: SHARPS[semitone.rem_euclid(12) as usize],
+            minor: false,
+        }
+    }
+}
+
+impl fmt::Display for KeySignature {
+    /// Writes the argument of `(key ...)`, e.g. `Eb` or `F# minor`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let index = (self.sharps.clamp(-7, 7) + 7) as usize;
+        if self.minor {
+            write!(f, "{} minor", MINOR_TONICS[index])
+        } else {
+            f.write_str(MAJOR_TONICS[index])
+        }
+    }
+}
+
+/// Velocity of a note at full volume when none is given.
+pub const DEFAULT_VELOCITY: u8 = 100;
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct Note {
     pub pitch_chain: PitchChain,
-    pub freq: f32,
-    pub duration: Rational32,
+    pub freq: f64,
+    pub duration: Rational64,
     pub duration_seconds: f64,
-    pub pitch_ratio: f32,
+    pub pitch_ratio: f64,
+    /// General MIDI percussion key of an unpitched drum hit
+    pub drum_key: Option<u8>,
+    /// Frequency this note glides from, set by the `~` portamento connector
+    pub portamento_from: Option<f64>,
+    /// Pitch-bend envelope attached with `{bend ..}`
+    pub bend_envelope: Option<BendEnvelope>,
+    /// Volume factor of this note alone, set with a `&0.6` suffix
+    pub volume: f32,
+    /// MIDI velocity at full volume, [`DEFAULT_VELOCITY`] when unset
+    pub velocity: Option<u8>,
+    /// MIDI release velocity of the NoteOff, the note-off velocity of the export when unset
+    pub release: Option<u8>,
+    /// Whether a `_` suffix ties this note into the following `_` slots
+    pub tie: bool,
+    /// Whether a `~vib` suffix marks this note with vibrato
+    pub vibrato: bool,
+}
+
+/// Cent offsets of a bend envelope, spread evenly from the start to the end of the note.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct BendEnvelope {
+    pub cents: Vec<i32>,
 }
 #[allow(unused)]
-pub(crate) fn spell2freq(spell: i16, state: &CompileState) -> f32 {
+pub(crate) fn spell2freq(spell: i16, state: &CompileState) -> f64 {
     let semitone_diff = spell - state.base_note;
-    state.base_frequency * 2f32.powf(semitone_diff as f32 / 12.0)
+    state.base_frequency * 2f64.powf(semitone_diff as f64 / 12.0)
 }
 
-pub(crate) fn freq2spell(freq: f32, state: &CompileState) -> i16 {
+pub(crate) fn freq2spell(freq: f64, state: &CompileState) -> i16 {
     let semitone_diff = 12.0 * (freq / state.base_frequency).log2();
     (semitone_diff.round() as i16) + state.base_note
 }
@@ -187,63 +640,104 @@ impl Note {
         let freq = match pitch {
             Pitch::SpellOctave(spell) => {
                 let semitone_diff = spell - base_note;
-                base_frequency * 2f32.powf(semitone_diff as f32 / 12.0)
+                base_frequency * 2f64.powf(semitone_diff as f64 / 12.0)
             }
             Pitch::SpellSimple(spell) => {
                 let semitone_diff = spell.div_euclid(12) * 12 + (spell - base_note).rem_euclid(12);
-                base_frequency * 2f32.powf(semitone_diff as f32 / 12.0)
+                let freq = base_frequency * 2f64.powf(semitone_diff as f64 / 12.0);
+                match state.relative_anchor {
+                    // nearest octave to the previous sounding pitch
+                    Some(anchor) if state.relative => {
+                        freq * 2f64.powf((anchor / (freq * state.transpose)).log2().round())
+                    }
+                    _ => freq,
+                }
             }
             Pitch::Frequency(f) => f,
-            Pitch::Ratio(r) => {
-                base_frequency * r.to_f32().expect("Rational32 to f32 conversion failed")
-            }
+            Pitch::Ratio(r) => base_frequency * r.to_f64(),
             Pitch::Edo(r) => {
-                let semitone_diff = r.to_f32().expect("Rational32 to f32 conversion failed");
-                base_frequency * 2f32.powf(semitone_diff)
+                let semitone_diff = r.to_f64();
+                base_frequency * 2f64.powf(semitone_diff)
             }
-            Pitch::Cents(c) => base_frequency * 2f32.powf(c as f32 / 1200.0),
-            Pitch::Rest | Pitch::Sustain => 0.0,
+            Pitch::Cents(c) => base_frequency * 2f64.powf(c / 1200.0),
+            Pitch::Subharmonic(n) => base_frequency / n as f64,
+            Pitch::Rest | Pitch::Sustain | Pitch::Tie => 0.0,
         };
         Self {
             pitch_chain: vec![pitch],
-            freq,
-            duration: Rational32::new(0, 4),
+            freq: freq * state.transpose,
+            duration: Rational64::new(0, 4),
             duration_seconds: 0.0,
             pitch_ratio: freq / base_frequency,
+            drum_key: None,
+            portamento_from: None,
+            bend_envelope: None,
+            volume: 1.0,
+            velocity: None,
+            release: None,
+            tie: false,
+            vibrato: false,
         }
     }
 
-    pub fn note_from_pitch_with_base(pitch: Pitch, base_note: i16, base_frequency: f32) -> Note {
+    /// Unpitched hit on a General MIDI percussion key. The frequency is only nominal.
+    pub fn from_drum(key: u8, state: &CompileState) -> Self {
+        let freq = 440.0 * 2f64.powf((key as f64 - 69.0) / 12.0);
+        Self {
+            pitch_chain: vec![Pitch::Frequency(freq)],
+            freq,
+            duration: Rational64::new(0, 4),
+            duration_seconds: 0.0,
+            pitch_ratio: freq / state.base_frequency,
+            drum_key: Some(key),
+            portamento_from: None,
+            bend_envelope: None,
+            volume: 1.0,
+            velocity: None,
+            release: None,
+            tie: false,
+            vibrato: false,
+        }
+    }
+
+    pub fn note_from_pitch_with_base(pitch: Pitch, base_note: i16, base_frequency: f64) -> Note {
         let freq = match pitch {
             Pitch::SpellOctave(spell) => {
                 let semitone_diff = spell - base_note;
-                base_frequency * 2f32.powf(semitone_diff as f32 / 12.0)
+                base_frequency * 2f64.powf(semitone_diff as f64 / 12.0)
             }
             Pitch::SpellSimple(spell) => {
                 let semitone_diff = spell - (base_note % 12);
-                base_frequency * 2f32.powf(semitone_diff as f32 / 12.0)
+                base_frequency * 2f64.powf(semitone_diff as f64 / 12.0)
             }
             Pitch::Frequency(f) => f,
-            Pitch::Ratio(r) => {
-                base_frequency * r.to_f32().expect("Rational32 to f32 conversion failed")
-            }
+            Pitch::Ratio(r) => base_frequency * r.to_f64(),
             Pitch::Edo(r) => {
-                let semitone_diff = r.to_f32().expect("Rational32 to f32 conversion failed");
-                base_frequency * 2f32.powf(semitone_diff)
+                let semitone_diff = r.to_f64();
+                base_frequency * 2f64.powf(semitone_diff)
             }
-            Pitch::Cents(c) => base_frequency * 2f32.powf(c as f32 / 1200.0),
-            Pitch::Rest | Pitch::Sustain => 0.0,
+            Pitch::Cents(c) => base_frequency * 2f64.powf(c / 1200.0),
+            Pitch::Subharmonic(n) => base_frequency / n as f64,
+            Pitch::Rest | Pitch::Sustain | Pitch::Tie => 0.0,
         };
         Note {
             pitch_chain: vec![pitch],
             freq,
-            duration: Rational32::new(0, 4),
+            duration: Rational64::new(0, 4),
             duration_seconds: 0.0,
             pitch_ratio: freq / base_frequency,
+            drum_key: None,
+            portamento_from: None,
+            bend_envelope: None,
+            volume: 1.0,
+            velocity: None,
+            release: None,
+            tie: false,
+            vibrato: false,
         }
     }
 
-    pub fn base_note_from_pitch(pitch: Pitch, freq: f32, current_base: (i16, f32)) -> i16 {
+    pub fn base_note_from_pitch(pitch: Pitch, freq: f64, current_base: (i16, f64)) -> i16 {
         match pitch {
             Pitch::SpellOctave(s) | Pitch::SpellSimple(s) => s,
             _ => {
@@ -255,7 +749,7 @@ impl Note {
         }
     }
 
-    pub fn set_duration(&mut self, duration: Rational32, state: &CompileState) {
+    pub fn set_duration(&mut self, duration: Rational64, state: &CompileState) {
         self.duration = duration;
         self.duration_seconds = TimeStamp::dur_in_sec(duration, state);
     }
@@ -272,42 +766,369 @@ impl Note {
     pub fn is_sustain(&self) -> bool {
         self.pitch_chain.len() == 1 && matches!(self.pitch_chain[0], Pitch::Sustain)
     }
+
+    pub fn is_tie(&self) -> bool {
+        self.pitch_chain.len() == 1 && matches!(self.pitch_chain[0], Pitch::Tie)
+    }
+
+    /// Loudness of the note relative to a note at [`DEFAULT_VELOCITY`] and full volume.
+    pub fn gain(&self) -> f32 {
+        let velocity = self.velocity.unwrap_or(DEFAULT_VELOCITY);
+        self.volume * f32::from(velocity) / f32::from(DEFAULT_VELOCITY)
+    }
 }
 
-#[derive(Debug, Clone, strum::EnumTryAs, strum::IntoStaticStr)]
+impl fmt::Display for Note {
+    /// Writes the note as symi source: its pitch chain, then the tie, vibrato, bend, volume
+    /// and duration suffixes, e.g. `3/2@C4_&0.5[8:3]`. Drum keys and portamento come from the
+    /// line and the group around a note and are not written.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", display_chain(&self.pitch_chain))?;
+        if self.tie {
+            f.write_str("_")?;
+        }
+        if self.vibrato {
+            f.write_str("~vib")?;
+        }
+        if let Some(bend) = &self.bend_envelope {
+            write!(
+                f,
+                "{{bend {}}}",
+                bend.cents
+                    .iter()
+                    .format_with("..", |cents, f| { f(&format_args!("{cents}c")) })
+            )?;
+        }
+        if self.volume != 1.0 {
+            write!(f, "&{}", self.volume)?;
+        }
+        let (numer, denom) = (*self.duration.numer(), *self.duration.denom());
+        let sign = if numer < 0 { "-" } else { "" };
+        match numer.unsigned_abs() {
+            0 => Ok(()),
+            1 => write!(f, "[{sign}{denom}]"),
+            numer => write!(f, "[{sign}{denom}:{numer}]"),
+        }
+    }
+}
+
+#[derive(
+    Debug,
+    Clone,
+    PartialEq,
+    strum::EnumTryAs,
+    strum::IntoStaticStr,
+    serde::Serialize,
+    serde::Deserialize,
+)]
 pub enum EventBody {
     Note(Note),
     BaseNoteDef(PitchSpell),
-    BaseFequencyDef(f32),
-    TimeSignatureDef(Rational32),
-    BeatDurationDef(Rational32),
+    BaseFequencyDef(f64),
+    TimeSignatureDef(TimeSignature),
+    KeySignatureDef(KeySignature),
+    /// Volume of the voice from 0 to 1, set by `(volume ...)`
+    VolumeDef(MixLevel),
+    /// Pan of the voice from -1 (left) to 1 (right), set by `(pan ...)`
+    PanDef(MixLevel),
+    /// Sustain pedal of the voice pressed (`true`) or released, set by `(pedal down)` / `(pedal up)`
+    SustainPedal(bool),
+    BeatDurationDef(Rational64),
     BPMDef(f32),
-    QuantizeDef(Rational32),
+    QuantizeDef(Rational64),
+    /// Playback-only frequency factor set by `(capo ...)`, applied after compilation
+    CapoDef(f64),
     NewMeasure(u32),
+    Lyric(String),
+    /// Start of a section of the piece named by `(section "...")`
+    Marker(String),
+    /// Cue point for samplers and DAWs named by `(cue "...")`
+    CuePoint(String),
+    /// Start of a loop region, set by `(loop start)`
+    LoopStart,
+    /// End of a loop region, set by `(loop end)`
+    LoopEnd,
 }
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct CompileEvent {
     pub body: EventBody,
     pub start_time: TimeStamp,
     pub range: TextRange,
     pub range_invoked: Option<TextRange>,
+    /// Macro calls the event was expanded from, outermost first
+    pub macro_trace: Vec<MacroCall>,
+    /// Voice named by the line prefix (e.g. `v2:`), overriding automatic MIDI track assignment
+    pub voice: Option<SmolStr>,
+    /// MIDI channel index (0-15) the event is played on, allocated by the writer when unset
+    pub channel: Option<u8>,
+    /// General MIDI program the event is played with
+    pub instrument: Option<u8>,
+}
+
+/// One macro invocation in the expansion chain of an event.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct MacroCall {
+    pub name: SmolStr,
+    pub range: TextRange,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize)]
+pub struct PieceMetadata {
+    pub title: Option<String>,
+    pub composer: Option<String>,
+    pub copyright: Option<String>,
+}
+
+/// Flag shared with a compilation running elsewhere, such as the editor's background
+/// compile, to stop it once a newer edit makes its result stale. Clones share the flag.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Bounds that stop a runaway piece with an error before it exhausts memory or time.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CompileLimits {
+    /// Most events a piece may produce
+    pub max_events: usize,
+    /// Deepest nesting of complex macro invocations within one event
+    pub max_macro_depth: usize,
+    /// Longest a piece may play, in seconds
+    pub max_seconds: f64,
+}
+
+impl Default for CompileLimits {
+    fn default() -> Self {
+        Self {
+            max_events: 1_000_000,
+            max_macro_depth: 64,
+            max_seconds: 24.0 * 3600.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CompilerConfig {
+    /// Seed for aleatoric choices; the same seed reproduces the same piece
+    pub seed: u64,
+    /// Base note before any `<...>` definition
+    pub base_note: PitchSpell,
+    /// Frequency of the base note before any `<...>` definition
+    pub base_frequency: f64,
+    /// Tempo before any `(120)` definition
+    pub bpm: f32,
+    /// Time signature before any `(3/4)` definition
+    pub time_signature: Rational64,
+    /// Grid before any `{8}` definition
+    pub quantize: Rational64,
+    /// Equal division of the octave that bare step numbers resolve against, if any
+    pub edo: Option<u16>,
+    /// Delay between successive notes of an arpeggiated chord
+    pub arpeggio_offset: Rational64,
+    /// Names of `#if` sections to compile; all other sections are skipped
+    pub variants: Vec<String>,
+    /// Real-time stretch applied to a slot marked with a fermata
+    pub fermata_factor: f32,
+    /// Extra chord qualities as cent offsets from the root, overriding built-in ones
+    pub chord_qualities: Vec<(String, Vec<i32>)>,
+    /// Extra instrument names and their General MIDI programs (0-127), overriding
+    /// built-in ones
+    pub instruments: Vec<(String, u8)>,
+    /// Directory relative `.scl` tuning paths are resolved against; the working directory
+    /// when unset
+    pub scale_dir: Option<PathBuf>,
+    pub limits: CompileLimits,
+}
+
+impl Default for CompilerConfig {
+    fn default() -> Self {
+        let state = CompileState::new();
+        Self {
+            seed: 0,
+            base_note: state.base_note,
+            base_frequency: state.base_frequency,
+            bpm: state.bpm,
+            time_signature: state.time_signature,
+            quantize: state.quantize,
+            edo: None,
+            arpeggio_offset: Rational64::new(1, 64),
+            variants: Vec::new(),
+            fermata_factor: 2.0,
+            chord_qualities: Vec::new(),
+            instruments: Vec::new(),
+            scale_dir: None,
+            limits: CompileLimits::default(),
+        }
+    }
+}
+
+impl MacroRegistry {
+    /// Whether a macro of any kind is defined under this full name.
+    pub fn contains(&self, name: &str) -> bool {
+        self.value_macros.contains_key(name)
+            || self.alias_macros.contains_key(name)
+            || self.simple_macros.contains_key(name)
+            || self.complex_macros.contains_key(name)
+            || self.tunings.contains_key(name)
+    }
+
+    /// Full name that `name` refers to from namespace `scope` (e.g. `lib.sub`): the innermost
+    /// defined one of `lib.sub.name`, `lib.name` and `name`, or `name` itself if none is.
+    pub fn resolve(&self, name: &str, scope: &str) -> SmolStr {
+        let mut scope = scope;
+        while !scope.is_empty() {
+            let full = format!("{}.{}", scope, name);
+            if self.contains(&full) {
+                return full.into();
+            }
+            scope = scope.rsplit_once('.').map_or("", |(parent, _)| parent);
+        }
+        SmolStr::new(name)
+    }
+
+    /// Defined macro that a definition of `name` hides inside its namespace, e.g. `chord1`
+    /// for `lib.chord1`.
+    pub fn shadowed(&self, name: &str) -> Option<SmolStr> {
+        let (namespace, leaf) = name.rsplit_once('.')?;
+        let parent = namespace.rsplit_once('.').map_or("", |(parent, _)| parent);
+        let outer = self.resolve(leaf, parent);
+        self.contains(&outer).then_some(outer)
+    }
+
+    /// Defined macro whose name is closest to the undefined `name`, if any is a likely typo.
+    pub fn suggest(&self, name: &str) -> Option<&str> {
+        let max_distance = (name.chars().count() / 3).clamp(1, 2);
+        self.definitions
+            .keys()
+            .map(|candidate| (edit_distance(name, candidate), candidate))
+            .filter(|&(distance, _)| distance <= max_distance)
+            .min()
+            .map(|(_, candidate)| candidate.as_str())
+    }
+}
+
+/// Levenshtein distance between two strings, counted in chars.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let substitution = diagonal + usize::from(ca != cb);
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// Tuning consulted when a bare step number (e.g. `7`) is resolved to a pitch.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Tuning {
+    /// Equal division of the octave, e.g. `19edo`
+    Edo(u16),
+    /// Scala scale as cents of degrees 1..=n; the last degree is the period
+    Scale(Vec<f64>),
+}
+
+impl Tuning {
+    /// Pitch of a scale step above the base pitch, wrapping around the period.
+    pub fn step_pitch(&self, step: i32) -> Option<Pitch> {
+        match self {
+            Tuning::Edo(0) => None,
+            Tuning::Edo(edo) => Some(Pitch::Edo(Rational64::new(
+                i64::from(step),
+                i64::from(*edo),
+            ))),
+            Tuning::Scale(cents) => {
+                let period = *cents.last()?;
+                let n = cents.len() as i32;
+                let degree = step.rem_euclid(n) as usize;
+                let offset = if degree == 0 { 0.0 } else { cents[degree - 1] };
+                Some(Pitch::Cents(f64::from(step.div_euclid(n)) * period + offset))
+            }
+        }
+    }
 }
 
+/// Numeric constant defined by a macro such as `tempo1 = 132` or `ts = 3/4`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MacroValue {
+    Number(f32),
+    Ratio(Rational64),
+}
+
+/// Macros keyed by their full name; dots nest namespaces, so `lib.chord1` lives in `lib`.
+#[derive(Clone, Default)]
 pub struct MacroRegistry {
-    pub alias_macros: HashMap<String, Vec<Pitch>>,
-    pub simple_macros: HashMap<String, Vec<Note>>,
-    pub complex_macros: HashMap<String, Vec<CompileEvent>>,
+    pub value_macros: HashMap<SmolStr, MacroValue>,
+    pub alias_macros: HashMap<SmolStr, Vec<Pitch>>,
+    pub simple_macros: HashMap<SmolStr, Vec<Note>>,
+    /// Events of each complex macro body, shared by every invocation
+    pub complex_macros: HashMap<SmolStr, Arc<[CompileEvent]>>,
+    /// Chord quality table used by chord symbols such as `Cmaj`
+    pub chord_qualities: HashMap<String, Vec<i32>>,
+    /// Instrument names `(instrument ...)` looks up, with their General MIDI programs
+    pub instruments: HashMap<String, u8>,
+    /// Named tunings defined by `tun_a = 19edo` or `tun_b = path.scl`
+    pub tunings: HashMap<SmolStr, Tuning>,
+    /// Source range of the latest definition of each macro, for diagnostics
+    pub definitions: HashMap<SmolStr, TextRange>,
 }
 
+#[derive(Clone, PartialEq)]
 pub struct CompileState {
     pub time: TimeStamp,
     pub base_note: PitchSpell,
-    pub base_frequency: f32,
-    pub time_signature: Rational32,
-    pub beat_duration: Rational32,
+    pub base_frequency: f64,
+    pub time_signature: Rational64,
+    pub beat_duration: Rational64,
     pub bpm: f32,
-    pub quantize: Rational32,
-    pub edo_def: u16,
+    /// Tempo changes so far, set through `set_tempo`
+    pub tempo: TempoMap,
+    pub quantize: Rational64,
+    /// Tuning that bare step numbers resolve against, set by `1\19` or `(tuning ...)`
+    pub tuning: Option<Tuning>,
+    /// Named base references (`<root=C4>`) that a chain ending in `@root` resolves against
+    pub base_refs: HashMap<SmolStr, (PitchSpell, f64)>,
+    /// Duration stolen from the following note by each grace note
+    pub grace_duration: Rational64,
+    /// Length of each sub-note produced by trill/tremolo expansion
+    pub ornament_rate: Rational64,
+    /// Length of the anacrusis bar, cleared once the first bar ends
+    pub pickup: Option<Rational64>,
+    /// Frequency factor applied to every note, set by `(transpose ...)`
+    pub transpose: f64,
+    /// Delay between successive notes of an arpeggiated chord
+    pub arpeggio_offset: Rational64,
+    /// Real-time stretch applied to a slot marked with a fermata
+    pub fermata_factor: f32,
+    /// Whether `(relative)` octave entry is active
+    pub relative: bool,
+    /// Previous sounding frequency, used to pick the octave of `PitchSpellSimple` in relative mode
+    pub relative_anchor: Option<f64>,
+    /// General MIDI program of each voice set by `(instrument ...)`, `None` for lines
+    /// without a voice prefix
+    pub instruments: HashMap<Option<SmolStr>, u8>,
+}
+
+impl Default for CompileState {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl CompileState {
@@ -316,34 +1137,264 @@ impl CompileState {
             time: TimeStamp::default(),
             base_note: 60,          // C4
             base_frequency: 261.63, // Frequency of C4
-            time_signature: Rational32::new(4, 4),
-            beat_duration: Rational32::new(1, 4),
+            time_signature: Rational64::new(4, 4),
+            beat_duration: Rational64::new(1, 4),
             bpm: 120.0,
-            quantize: Rational32::new(1, 4),
-            edo_def: 0,
+            tempo: TempoMap::new(2.0),
+            quantize: Rational64::new(1, 4),
+            tuning: None,
+            base_refs: HashMap::new(),
+            grace_duration: Rational64::new(1, 32),
+            ornament_rate: Rational64::new(1, 32),
+            pickup: None,
+            transpose: 1.0,
+            arpeggio_offset: Rational64::new(1, 64),
+            fermata_factor: 2.0,
+            relative: false,
+            relative_anchor: None,
+            instruments: HashMap::new(),
         }
     }
-}
 
-impl Default for MacroRegistry {
-    fn default() -> Self {
-        Self {
-            alias_macros: HashMap::new(),
-            simple_macros: HashMap::new(),
-            complex_macros: HashMap::new(),
-        }
+    pub fn seconds_per_whole_note(&self) -> f64 {
+        60.0 / (f64::from(self.bpm) * self.beat_duration.to_f64())
+    }
+
+    /// Changes the tempo from the current position on.
+    pub fn set_tempo(&mut self, bpm: f32, beat_duration: Rational64) {
+        self.bpm = bpm;
+        self.beat_duration = beat_duration;
+        self.tempo
+            .set_tempo(self.time.position, self.seconds_per_whole_note());
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
 pub enum DiagnosticLevel {
     Warning,
     Error,
 }
 
-#[derive(Debug, Clone)]
+/// Stable identifier of a kind of diagnostic, displayed as `E0005` or `W0002`.
+#[derive(Debug, Display, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum DiagnosticCode {
+    /// A node or token the compiler does not expect at this place
+    #[strum(serialize = "E0001")]
+    UnexpectedSyntax,
+    /// A metadata field other than `title`, `composer` or `copyright`. The lexer only
+    /// recognizes those, so only syntax trees built by hand still report it
+    #[strum(serialize = "E0002")]
+    UnknownMetadataField,
+    /// A number, duration or interval that cannot be parsed or is out of range
+    #[strum(serialize = "E0003")]
+    InvalidValue,
+    #[strum(serialize = "E0004")]
+    InvalidTimeSignature,
+    /// A macro, value macro or tuning that is not defined
+    #[strum(serialize = "E0005")]
+    UndefinedName,
+    /// A macro used where a macro of another kind is required
+    #[strum(serialize = "E0006")]
+    MacroKindMismatch,
+    #[strum(serialize = "E0007")]
+    InvalidPitchChain,
+    #[strum(serialize = "E0008")]
+    InvalidScaleFile,
+    /// Positioning that would move backwards in time
+    #[strum(serialize = "E0009")]
+    PositionBeforeCurrent,
+    /// A duration or position too fine-grained to be represented exactly
+    #[strum(serialize = "E0010")]
+    DurationOverflow,
+    /// A piece growing past one of the configured compile limits
+    #[strum(serialize = "E0011")]
+    LimitExceeded,
+    #[strum(serialize = "W0001")]
+    Redefinition,
+    #[strum(serialize = "W0002")]
+    ShadowedMacro,
+    /// Content that does not line up with the measures of the time signature
+    #[strum(serialize = "W0003")]
+    MisalignedMeasure,
+    #[strum(serialize = "W0004")]
+    DiscouragedTimeSignature,
+    /// A repeat, tie, sustain or glide without the note it refers to
+    #[strum(serialize = "W0005")]
+    MissingPreviousNote,
+    #[strum(serialize = "W0006")]
+    ClampedVolume,
+    /// Grace notes, ornaments or arpeggios that do not fit into their note
+    #[strum(serialize = "W0007")]
+    OverlongDecoration,
+    /// A macro defined but never referred to
+    #[strum(serialize = "W0008")]
+    UnusedMacro,
+    /// Summary of every use of an undefined name
+    #[strum(serialize = "W0009")]
+    UndefinedNames,
+    /// A note starting on the pitch of a note of the same voice that still sounds
+    #[strum(serialize = "W0010")]
+    NoteOverlap,
+    /// Notes starting together at nearly the same pitch
+    #[strum(serialize = "W0011")]
+    NearUnison,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct Diagnostic {
     pub level: DiagnosticLevel,
+    pub code: DiagnosticCode,
+    pub message: String,
+    pub span: TextRange,
+    /// Other places that explain the diagnostic, such as the previous definition of a macro
+    pub related: Vec<RelatedSpan>,
+    /// Edits that would resolve the diagnostic
+    pub fixes: Vec<SuggestedFix>,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct RelatedSpan {
+    pub message: String,
+    pub span: TextRange,
+}
+
+/// Replacement of `span` with `replacement`, described by `message`.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct SuggestedFix {
     pub message: String,
     pub span: TextRange,
+    pub replacement: String,
+}
+
+impl Diagnostic {
+    pub fn new(
+        level: DiagnosticLevel,
+        code: DiagnosticCode,
+        message: String,
+        span: TextRange,
+    ) -> Self {
+        Diagnostic {
+            level,
+            code,
+            message,
+            span,
+            related: Vec::new(),
+            fixes: Vec::new(),
+        }
+    }
+
+    pub fn with_related(mut self, message: String, span: TextRange) -> Self {
+        self.related.push(RelatedSpan { message, span });
+        self
+    }
+
+    pub fn with_fix(mut self, message: String, span: TextRange, replacement: String) -> Self {
+        self.fixes.push(SuggestedFix {
+            message,
+            span,
+            replacement,
+        });
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spellings_parse_with_every_accidental_form() {
+        assert_eq!(
+            Pitch::parse_spell_octave("C4"),
+            Some(Pitch::SpellOctave(60))
+        );
+        assert_eq!(
+            Pitch::parse_spell_octave("^^Eb4"),
+            Some(Pitch::SpellOctave(63))
+        );
+        assert_eq!(
+            Pitch::parse_spell_octave("F♯𝄲-1"),
+            Some(Pitch::SpellOctave(6))
+        );
+        assert_eq!(
+            Pitch::parse_spell_octave("B𝄪\\3"),
+            Some(Pitch::SpellOctave(61))
+        );
+        assert_eq!(
+            Pitch::parse_spell_simple("G𝄫¡"),
+            Some(Pitch::SpellSimple(5))
+        );
+        assert_eq!(
+            Pitch::parse_spell_simple("vAd"),
+            Some(Pitch::SpellSimple(9))
+        );
+
+        assert_eq!(Pitch::parse_spell_octave("C"), None);
+        assert_eq!(Pitch::parse_spell_octave("Cx4"), None);
+        assert_eq!(Pitch::parse_spell_octave("C--1"), None);
+        assert_eq!(Pitch::parse_spell_octave("C9999"), None);
+        assert_eq!(Pitch::parse_spell_simple("C4"), None);
+        assert_eq!(Pitch::parse_spell_simple("H"), None);
+    }
+
+    #[test]
+    fn key_signatures_from_tonics() {
+        let key = |s: &str, minor: bool| KeySignature::from_tonic(s, minor).map(|k| k.sharps);
+        assert_eq!(key("C", false), Some(0));
+        assert_eq!(key("Eb", false), Some(-3));
+        assert_eq!(key("F#", true), Some(3));
+        assert_eq!(key("C♭", false), Some(-7));
+        assert_eq!(key("G#", false), None);
+        assert_eq!(key("Ed", false), None);
+        assert_eq!(KeySignature::major_on_semitone(10).to_string(), "Bb");
+        assert_eq!(
+            KeySignature {
+                sharps: -2,
+                minor: true
+            }
+            .to_string(),
+            "G minor"
+        );
+    }
+
+    #[test]
+    fn pitches_and_notes_round_trip_through_source() {
+        let pitches = [
+            (Pitch::SpellOctave(61), "C#4"),
+            (Pitch::SpellOctave(0), "C-1"),
+            (Pitch::SpellSimple(-1), "Cb"),
+            (Pitch::SpellSimple(13), "B##"),
+            (Pitch::Frequency(261.63), "261.63"),
+            (Pitch::Ratio(Rational64::new(3, 2)), "3/2"),
+            (Pitch::Edo(Rational64::new(-1, 12)), "-1\\12"),
+            (Pitch::Cents(-14.7), "-14.7c"),
+            (Pitch::Subharmonic(7), "u7"),
+            (Pitch::Rest, "."),
+            (Pitch::Sustain, "-"),
+            (Pitch::Tie, "_"),
+        ];
+        for (pitch, text) in pitches {
+            assert_eq!(pitch.to_string(), text);
+            assert_eq!(text.parse::<Pitch>(), Ok(pitch));
+        }
+        assert!("C𝄲4".parse::<Pitch>().is_err());
+        assert!("3/2^2".parse::<Pitch>().is_err());
+        assert!("C4 D4".parse::<Pitch>().is_err());
+
+        let chain = parse_pitch_chain("3/2@C4+").expect("valid chain");
+        assert_eq!(display_chain(&chain).to_string(), "3/2@C4@2/1");
+        assert_eq!(parse_pitch_chain("3/2@C4@2/1"), Ok(chain));
+        assert!(parse_pitch_chain("C4@").is_err());
+        assert!(parse_pitch_chain("3/2@.").is_err());
+
+        let note: Note = "C4".parse().expect("valid note");
+        assert_eq!(note.to_string(), "C4[4]");
+        for source in ["3/2@E4_&0.5[8:3]", "D4{bend 50c..-20c}[2]", "u7[8]"] {
+            let note: Note = source.parse().expect("valid note");
+            assert_eq!(note.to_string(), source);
+            assert_eq!(note.to_string().parse(), Ok(note));
+        }
+        assert!("C4:E4".parse::<Note>().is_err());
+        assert!("undefined".parse::<Note>().is_err());
+    }
 }
@@ -0,0 +1,452 @@
+//! Writes compiled events back as symi source, for importing MIDI, refactoring and
+//! flattening macros.
+//!
+//! Every bar becomes one line per voice, all but the last starting with `=` so that they
+//! sound together. A bar is quantized to the finest grid its onsets need, starting from the
+//! beat of its time signature, and each note carries its duration explicitly.
+
+use std::{cmp::Ordering, collections::BTreeMap, fmt::Write};
+
+use itertools::Itertools;
+
+use crate::compiler::{
+    drums::DRUM_MAP,
+    rational::Rational64,
+    types::{CompileEvent, CompileState, EventBody, Note, Pitch},
+};
+
+/// Finest quantize chosen for a bar; onsets off this grid are rounded onto it.
+const MAX_QUANTIZE: i64 = 192;
+
+/// How far in cents a note may sound from its written pitch chain for the chain to be kept.
+const PITCH_TOLERANCE_CENTS: f64 = 0.01;
+
+/// Line of a bar: its voice and whether it holds drum hits.
+type LineKey<'a> = (Option<&'a str>, bool);
+
+fn gcd(a: i64, b: i64) -> i64 {
+    if b == 0 { a.abs() } else { gcd(b, a % b) }
+}
+
+fn lcm(a: i64, b: i64) -> i64 {
+    a / gcd(a, b) * b
+}
+
+fn by_position(a: &&CompileEvent, b: &&CompileEvent) -> Ordering {
+    a.start_time
+        .position
+        .partial_cmp(&b.start_time.position)
+        .unwrap_or(Ordering::Equal)
+}
+
+/// Nearest slot of a `grid` per whole note to `offset`.
+fn slot_at(offset: Rational64, grid: i64) -> i64 {
+    let (numer, denom) = (i128::from(*offset.numer()), i128::from(*offset.denom()));
+    (2 * numer * i128::from(grid) + denom).div_euclid(2 * denom) as i64
+}
+
+/// `[8]` or `[8:3]` for a duration in whole notes.
+fn fraction_marker(duration: Rational64) -> String {
+    let duration = duration.reduce();
+    match *duration.numer() {
+        1 => format!("[{}]", duration.denom()),
+        numer => format!("[{}:{}]", duration.denom(), numer),
+    }
+}
+
+/// Number of whole slots of a `grid` that `duration` spans, if it spans whole slots.
+fn whole_slots(duration: Rational64, grid: i64) -> Option<i64> {
+    let slots = (duration * Rational64::from_integer(grid)).reduce();
+    (*slots.denom() == 1).then_some(*slots.numer())
+}
+
+/// Frequency the compiler gives `chain` under the base note `base`, evaluated from right to
+/// left like a compiled pitch chain.
+fn chain_freq(chain: &[Pitch], base: (i16, f64)) -> Option<f64> {
+    let (&root, rest) = chain.split_last()?;
+    let mut state = CompileState::new();
+    (state.base_note, state.base_frequency) = base;
+    let mut note = Note::from_pitch(root, &state);
+    let mut current = (Note::base_note_from_pitch(root, note.freq, base), note.freq);
+    for &pitch in rest.iter().rev() {
+        note = Note::note_from_pitch_with_base(pitch, current.0, current.1);
+        current = (
+            Note::base_note_from_pitch(pitch, note.freq, current),
+            note.freq,
+        );
+    }
+    Some(note.freq)
+}
+
+/// Source of a note without its duration. A pitch chain that would not sound the same under
+/// the current base note, such as one written under a transposition, is replaced with the
+/// nearest spelled pitch and a cents offset.
+fn note_text(note: &Note, base: (i16, f64)) -> String {
+    if let Some(&(name, _)) = note
+        .drum_key
+        .and_then(|key| DRUM_MAP.iter().find(|&&(_, k)| k == key))
+    {
+        return name.to_string();
+    }
+    let cents = |freq: f64| 1200.0 * (note.freq / freq).log2();
+    let pitch_chain = match chain_freq(&note.pitch_chain, base) {
+        Some(freq) if cents(freq).abs() < PITCH_TOLERANCE_CENTS => note.pitch_chain.clone(),
+        _ => {
+            let spell = (12.0 * (note.freq / base.1).log2()).round() as i16 + base.0;
+            let spelled = Pitch::SpellOctave(spell);
+            let offset = (cents(chain_freq(&[spelled], base).unwrap_or(base.1)) * 1000.0).round();
+            if offset == 0.0 {
+                vec![spelled]
+            } else {
+                vec![Pitch::Cents(offset / 1000.0), spelled]
+            }
+        }
+    };
+    Note {
+        pitch_chain,
+        duration: Rational64::zero(),
+        tie: false,
+        ..note.clone()
+    }
+    .to_string()
+}
+
+/// Whether an event is written as a setting before the notes of its slot.
+fn is_setting(event: &CompileEvent) -> bool {
+    matches!(
+        event.body,
+        EventBody::BaseNoteDef(_)
+            | EventBody::BaseFequencyDef(_)
+            | EventBody::TimeSignatureDef(_)
+            | EventBody::KeySignatureDef(_)
+            | EventBody::BeatDurationDef(_)
+            | EventBody::BPMDef(_)
+            | EventBody::CapoDef(_)
+            | EventBody::Marker(_)
+            | EventBody::CuePoint(_)
+            | EventBody::LoopStart
+            | EventBody::LoopEnd
+    )
+}
+
+fn is_sounding(event: &CompileEvent) -> bool {
+    matches!(&event.body, EventBody::Note(note)
+        if !note.is_rest() && !note.is_sustain() && !note.is_tie())
+}
+
+/// Where each bar starts: at the measures the compiler started, or every time signature
+/// from the start when the events have none, as after importing MIDI.
+fn bar_starts(events: &[&CompileEvent]) -> Vec<Rational64> {
+    let mut starts = vec![Rational64::zero()];
+    let measures = events.iter().filter_map(|e| match e.body {
+        EventBody::NewMeasure(_) => Some(e.start_time.position),
+        _ => None,
+    });
+    starts.extend(measures);
+    if starts.len() == 1 {
+        let end =
+            events
+                .iter()
+                .map(|e| e.start_time.position)
+                .fold(
+                    Rational64::zero(),
+                    |end, at| if at > end { at } else { end },
+                );
+        let mut signature = CompileState::new().time_signature;
+        let mut signatures = events
+            .iter()
+            .filter_map(|e| match &e.body {
+                EventBody::TimeSignatureDef(sig) => Some((e.start_time.position, sig.bar_length())),
+                _ => None,
+            })
+            .peekable();
+        let mut at = Rational64::zero();
+        loop {
+            while let Some(&(position, sig)) = signatures.peek()
+                && position <= at
+            {
+                signature = sig;
+                signatures.next();
+            }
+            at += signature;
+            if at > end {
+                break;
+            }
+            starts.push(at);
+        }
+    }
+    starts.dedup_by(|a, b| a == b);
+    starts
+}
+
+/// Converts `events` back into symi source that compiles to the same notes at the same
+/// times. Measures, tempo, time and key signatures, base note and capo changes, sections,
+/// cue points, loops and lyrics are kept; macros, ornaments and grace notes come out
+/// expanded into the notes they produced.
+pub fn decompile(events: &[CompileEvent]) -> String {
+    let mut events: Vec<&CompileEvent> = events.iter().collect();
+    events.sort_by(by_position);
+    let starts = bar_starts(&events);
+
+    let initial = CompileState::new();
+    let mut signature = initial.time_signature;
+    let mut base = (initial.base_note, initial.base_frequency);
+    let mut quantize = *initial.quantize.denom();
+    let mut lines: Vec<String> = Vec::new();
+    let mut first = 0;
+    for (idx, &start) in starts.iter().enumerate() {
+        let next = starts.get(idx + 1).copied();
+        let last = first
+            + events[first..]
+                .partition_point(|e| next.is_none_or(|next| e.start_time.position < next));
+        let bar = &events[first..last];
+        first = last;
+        let content: Vec<&CompileEvent> = bar
+            .iter()
+            .copied()
+            .filter(|e| is_setting(e) || is_sounding(e))
+            .collect();
+        if content.is_empty() && next.is_none() {
+            break;
+        }
+        for event in &content {
+            if let EventBody::TimeSignatureDef(sig) = &event.body
+                && event.start_time.position == start
+            {
+                signature = sig.bar_length();
+            }
+        }
+
+        // the grid must hold the bar length, the beat and, within limits, every onset
+        let length = next.map(|next| next - start);
+        let mut grid = lcm(
+            *signature.denom(),
+            length.map_or(1, |l| *l.reduce().denom()),
+        );
+        let onsets = content
+            .iter()
+            .map(|e| *(e.start_time.position - start).reduce().denom())
+            .fold(grid, lcm);
+        if onsets <= MAX_QUANTIZE {
+            grid = onsets;
+        } else if grid < MAX_QUANTIZE {
+            grid *= MAX_QUANTIZE / grid;
+        }
+        let slot = |event: &CompileEvent| slot_at(event.start_time.position - start, grid);
+        let slots = match length {
+            Some(length) => slot_at(length, grid),
+            None => content
+                .iter()
+                .map(|e| slot(e) + 1)
+                .fold(slot_at(signature, grid), i64::max),
+        };
+
+        let mut voices: BTreeMap<LineKey, Vec<&CompileEvent>> = BTreeMap::new();
+        for &event in &content {
+            if let EventBody::Note(note) = &event.body
+                && is_sounding(event)
+            {
+                let key = (event.voice.as_deref(), note.drum_key.is_some());
+                voices.entry(key).or_default().push(event);
+            }
+        }
+        if voices.is_empty() {
+            voices.insert((None, false), Vec::new());
+        }
+        let settings: Vec<&CompileEvent> = content.into_iter().filter(|e| is_setting(e)).collect();
+        let lyrics: Vec<&CompileEvent> = bar
+            .iter()
+            .copied()
+            .filter(|e| matches!(e.body, EventBody::Lyric(_)))
+            .collect();
+
+        let count = voices.len();
+        for (line_idx, ((voice, drums), notes)) in voices.into_iter().enumerate() {
+            let mut line = String::new();
+            if line_idx + 1 < count {
+                line.push('=');
+            }
+            if let Some(voice) = voice {
+                let _ = write!(line, "{voice}: ");
+            }
+            if drums {
+                line.push_str("drums: ");
+            }
+            if quantize != grid {
+                let _ = write!(line, "{{{grid}}}");
+                quantize = grid;
+            }
+            let (mut beat, mut base_note) = (None, None);
+            let occupied = |at: i64| {
+                notes.iter().any(|e| slot(e) == at)
+                    || line_idx == 0 && settings.iter().any(|e| slot(e) == at)
+            };
+            let mut at = 0;
+            while at < slots {
+                // settings go into the first line, applying before the notes of its slot
+                for setting in settings.iter().filter(|e| line_idx == 0 && slot(e) == at) {
+                    match setting.body {
+                        EventBody::BaseNoteDef(spell) => base_note = Some(spell),
+                        EventBody::BaseFequencyDef(freq) => {
+                            let spell = base_note.take().unwrap_or(base.0);
+                            let pitch = Pitch::SpellOctave(spell);
+                            // a base note tuned like the previous one needs no frequency
+                            match chain_freq(&[pitch], base) {
+                                Some(implied)
+                                    if (1200.0 * (freq / implied).log2()).abs()
+                                        < PITCH_TOLERANCE_CENTS =>
+                                {
+                                    let _ = write!(line, "<{pitch}>");
+                                }
+                                _ => {
+                                    let _ = write!(line, "<{pitch}={freq:?}>");
+                                }
+                            }
+                            base = (spell, freq);
+                        }
+                        EventBody::TimeSignatureDef(ref sig) => {
+                            signature = sig.bar_length();
+                            let _ = write!(line, "({sig})");
+                        }
+                        EventBody::BeatDurationDef(duration) => beat = Some(duration),
+                        EventBody::BPMDef(bpm) => match beat.take() {
+                            Some(duration) => {
+                                let _ = write!(line, "({}={bpm})", fraction_marker(duration));
+                            }
+                            None => {
+                                let _ = write!(line, "({bpm})");
+                            }
+                        },
+                        EventBody::CapoDef(factor) => {
+                            let cents = (1200.0 * factor.log2() * 100.0).round() / 100.0;
+                            let _ = write!(line, "(capo {cents}c)");
+                        }
+                        EventBody::KeySignatureDef(key) => {
+                            let _ = write!(line, "(key {key})");
+                        }
+                        EventBody::Marker(ref name) => {
+                            let _ = write!(line, "(section \"{name}\")");
+                        }
+                        EventBody::CuePoint(ref name) => {
+                            let _ = write!(line, "(cue \"{name}\")");
+                        }
+                        EventBody::LoopStart => line.push_str("(loop start)"),
+                        EventBody::LoopEnd => line.push_str("(loop end)"),
+                        _ => {}
+                    }
+                }
+                let group: Vec<(&CompileEvent, &Note)> = notes
+                    .iter()
+                    .filter(|e| slot(e) == at)
+                    .filter_map(|e| e.body.try_as_note_ref().map(|note| (*e, note)))
+                    .collect();
+                let shared = group.iter().map(|(_, n)| n.duration).all_equal_value().ok();
+                for (idx, (event, note)) in group.iter().enumerate() {
+                    if idx > 0 {
+                        line.push(':');
+                    }
+                    line.push_str(&note_text(note, base));
+                    for lyric in lyrics.iter().filter(|l| {
+                        idx + 1 == group.len() && slot(l) == at && l.voice == event.voice
+                    }) {
+                        if let EventBody::Lyric(text) = &lyric.body {
+                            let _ = write!(line, "\"{text}\"");
+                        }
+                    }
+                    if idx + 1 < group.len() {
+                        // the others of the group take the duration of the next marked note
+                        if shared.is_none() {
+                            line.push_str(&fraction_marker(note.duration));
+                        }
+                        continue;
+                    }
+                    // commas in the marker of the last note also advance past the slots
+                    // they stand for, so they only replace empty slots
+                    match whole_slots(note.duration, grid) {
+                        Some(1) if shared.is_some() => {}
+                        Some(held)
+                            if held > 1
+                                && at + held <= slots
+                                && !(at + 1..at + held).any(occupied) =>
+                        {
+                            let _ = write!(line, "[{}]", ",".repeat(held as usize - 1));
+                            at += held - 1;
+                        }
+                        _ => line.push_str(&fraction_marker(note.duration)),
+                    }
+                }
+                line.push(',');
+                at += 1;
+            }
+            lines.push(line);
+        }
+    }
+    lines.into_iter().map(|line| line + "\n").collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{compiler::compile::Compiler, rowan::parse_fn::parse_source};
+
+    fn compile(source: &str) -> Vec<CompileEvent> {
+        let parsed = parse_source(Arc::from(source));
+        let mut compiler = Compiler::new();
+        compiler.compile(&parsed.syntax_node());
+        compiler.events
+    }
+
+    /// Start, duration, voice and frequency of every note, in a stable order.
+    fn notes(events: &[CompileEvent]) -> Vec<(Rational64, Rational64, Option<String>, f64)> {
+        let mut notes: Vec<_> = events
+            .iter()
+            .filter_map(|e| match &e.body {
+                EventBody::Note(note) => Some((
+                    e.start_time.position.reduce(),
+                    note.duration.reduce(),
+                    e.voice.as_ref().map(|v| v.to_string()),
+                    (note.freq * 1000.0).round() / 1000.0,
+                )),
+                _ => None,
+            })
+            .collect();
+        notes.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+        notes
+    }
+
+    #[test]
+    fn decompile_writes_one_line_per_voice_and_bar() {
+        let events = compile("(3/4)\nC4,E4:G4[,],\nv1: D4;E4,,F4[8],\n(90)\nm = A4\n{8}m,,,,,-,\n");
+        assert_eq!(
+            decompile(&events),
+            "(3/4)C4,E4:G4[,],\n\
+             v1: {8}D4,E4,,,F4,,\n\
+             {4}(90)A4[8],,,\n"
+        );
+
+        // without measures, as from MIDI, bars follow the time signature
+        let unmeasured: Vec<CompileEvent> = events
+            .iter()
+            .filter(|e| !matches!(e.body, EventBody::NewMeasure(_)))
+            .cloned()
+            .collect();
+        assert_eq!(decompile(&unmeasured), decompile(&events));
+    }
+
+    #[test]
+    fn decompiled_source_compiles_to_the_same_notes() {
+        for source in [
+            include_str!("tests/jingle_bell.symi"),
+            "(60)\n<D4>\n{12}C;D;E,{4}F[8:3],G[-16],\n3/2@A4&0.5,u7[2],\n(transpose 3/2)\nC4,,,,\n",
+            "(3+2/8)\n{8}C4,,,D4,,\nE4,,,F4,,\n",
+            "(section \"Verse\")C4,D4,E4,F4,\n(section \"Chorus\")(key D minor)G4,,,,\n",
+            "(loop start)(cue \"Hit\")C4,D4,E4,F4,\nG4,,,,\n(loop end)\n",
+        ] {
+            let events = compile(source);
+            let decompiled = decompile(&events);
+            assert_eq!(notes(&compile(&decompiled)), notes(&events), "{decompiled}");
+        }
+    }
+}
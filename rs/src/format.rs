@@ -0,0 +1,311 @@
+//! Formats symi source without changing what it means: whitespace is normalized, the slots
+//! of lines sounding together (a line and the `=` lines after it) are aligned, and duration
+//! fractions are written in lowest terms. Comments and line breaks are kept as written.
+//!
+//! Sources with parse errors are returned unchanged, and so is any source the formatted
+//! text would not lex back to.
+
+use std::{borrow::Cow, sync::Arc};
+
+use logos::Logos;
+
+use crate::rowan::{lexer::SyntaxKind, parse_fn::parse_source, parser::SyntaxNode};
+
+/// One token of the source with the context its formatting depends on.
+struct Token {
+    kind: SyntaxKind,
+    text: String,
+    /// Kind of the node holding the token
+    parent: SyntaxKind,
+    /// Kind of the line the token is part of, for a note or ghost line
+    line: Option<SyntaxKind>,
+}
+
+impl Token {
+    /// Whether the token is the `=` of a macro definition, written with a space on each side.
+    fn is_definition_equals(&self) -> bool {
+        self.kind == SyntaxKind::Equals
+            && matches!(
+                self.parent,
+                SyntaxKind::NODE_MACRODEF_ALIAS
+                    | SyntaxKind::NODE_MACRODEF_SIMPLE
+                    | SyntaxKind::NODE_MACRODEF_COMPLEX
+                    | SyntaxKind::NODE_MACRODEF_TUNING
+            )
+    }
+
+    /// Whether no space is written next to the token.
+    fn is_tight(&self) -> bool {
+        match self.kind {
+            SyntaxKind::Comma | SyntaxKind::Colon | SyntaxKind::Semicolon | SyntaxKind::At => true,
+            SyntaxKind::Equals => self.parent == SyntaxKind::NODE_GHOST_LINE,
+            _ => false,
+        }
+    }
+
+    /// Whether the token separates two slots of its line, rather than of a nested block.
+    fn is_slot_comma(&self) -> bool {
+        self.kind == SyntaxKind::Comma
+            && matches!(
+                self.parent,
+                SyntaxKind::NODE_NORMAL_LINE | SyntaxKind::NODE_GHOST_LINE
+            )
+    }
+
+    fn ends_line(&self) -> bool {
+        matches!(
+            self.kind,
+            SyntaxKind::Newline | SyntaxKind::LineContinuation
+        ) || self.text.ends_with('\n')
+    }
+}
+
+/// One physical line of the output, split after each slot comma.
+#[derive(Default)]
+struct Line {
+    segments: Vec<String>,
+    /// Kind of the note or ghost line that starts on this line
+    line: Option<SyntaxKind>,
+    /// Whether a quantize change or a `[,,]` marker moves later slots off the shared grid
+    off_grid: bool,
+    ending: String,
+}
+
+/// Formats `source`; see the module documentation.
+pub fn format(source: &str) -> String {
+    let parsed = parse_source(Arc::from(source));
+    if !parsed.errors().is_empty() {
+        return source.to_string();
+    }
+    let tokens = collect_tokens(&parsed.syntax_node());
+    let mut lines = split_lines(&tokens);
+    align_ghost_blocks(&mut lines);
+    let mut formatted: String = lines
+        .iter()
+        .map(|line| format!("{}{}", line.segments.concat().trim_end(), line.ending))
+        .collect();
+    if !formatted.is_empty() && !formatted.ends_with('\n') {
+        formatted.push('\n');
+    }
+    if lex_normalized(&formatted) == lex_normalized(source)
+        && parse_source(Arc::from(formatted.as_str()))
+            .errors()
+            .is_empty()
+    {
+        formatted
+    } else {
+        source.to_string()
+    }
+}
+
+fn collect_tokens(root: &SyntaxNode) -> Vec<Token> {
+    root.descendants_with_tokens()
+        .filter_map(|element| element.into_token())
+        .map(|token| {
+            let line = token.parent_ancestors().map(|n| n.kind()).find(|kind| {
+                matches!(
+                    kind,
+                    SyntaxKind::NODE_NORMAL_LINE | SyntaxKind::NODE_GHOST_LINE
+                )
+            });
+            Token {
+                kind: token.kind(),
+                text: token.text().to_string(),
+                parent: token.parent().map_or(SyntaxKind::NODE_ROOT, |p| p.kind()),
+                line,
+            }
+        })
+        .collect()
+}
+
+/// Text written for the whitespace token at `index`.
+fn whitespace(tokens: &[Token], index: usize) -> &str {
+    let prev = index.checked_sub(1).map(|i| &tokens[i]);
+    let next = tokens.get(index + 1);
+    // indentation is kept as written
+    if prev.is_none_or(Token::ends_line) {
+        return &tokens[index].text;
+    }
+    let Some(next) = next.filter(|n| n.kind != SyntaxKind::Newline) else {
+        return "";
+    };
+    let prev = prev.expect("checked above");
+    if next.kind == SyntaxKind::Comment
+        || prev.is_definition_equals()
+        || next.is_definition_equals()
+    {
+        " "
+    } else if prev.is_tight() || next.is_tight() {
+        ""
+    } else {
+        " "
+    }
+}
+
+/// `[4]` for `[8:2]`; `None` for a fraction that is not a valid duration.
+fn canonical_duration(text: &str) -> Option<String> {
+    let body = text.strip_prefix('[')?.strip_suffix(']')?;
+    let (sign, body) = match body.strip_prefix('-') {
+        Some(rest) => ("-", rest),
+        None => ("", body),
+    };
+    let (denom, numer) = body.split_once(':').unwrap_or((body, "1"));
+    let (denom, numer): (u64, u64) = (denom.parse().ok()?, numer.parse().ok()?);
+    if denom == 0 || numer == 0 {
+        return None;
+    }
+    let g = gcd(denom, numer);
+    Some(match (denom / g, numer / g) {
+        (denom, 1) => format!("[{sign}{denom}]"),
+        (denom, numer) => format!("[{sign}{denom}:{numer}]"),
+    })
+}
+
+fn gcd(a: u64, b: u64) -> u64 {
+    if b == 0 { a } else { gcd(b, a % b) }
+}
+
+fn split_lines(tokens: &[Token]) -> Vec<Line> {
+    let mut lines = Vec::new();
+    let mut line = Line {
+        segments: vec![String::new()],
+        ..Line::default()
+    };
+    for (index, token) in tokens.iter().enumerate() {
+        if token.kind == SyntaxKind::Newline {
+            line.ending = token.text.clone();
+            lines.push(std::mem::replace(
+                &mut line,
+                Line {
+                    segments: vec![String::new()],
+                    ..Line::default()
+                },
+            ));
+            continue;
+        }
+        if line.line.is_none() && !token.kind.is_trivia() {
+            line.line = token.line;
+        }
+        line.off_grid |= matches!(
+            token.kind,
+            SyntaxKind::Quantize | SyntaxKind::QuantizeOpen | SyntaxKind::DurationCommas
+        );
+        let text: Cow<str> = match token.kind {
+            SyntaxKind::Whitespace => Cow::Borrowed(whitespace(tokens, index)),
+            SyntaxKind::DurationFraction => canonical_duration(&token.text)
+                .map_or(Cow::Borrowed(token.text.as_str()), Cow::Owned),
+            // trailing spaces inside a line comment go, its line break stays
+            SyntaxKind::Comment => {
+                let body = token.text.trim_end_matches(['\r', '\n']);
+                let ending = &token.text[body.len()..];
+                Cow::Owned(format!("{}{}", body.trim_end(), ending))
+            }
+            _ if token.is_definition_equals() => {
+                let before = index
+                    .checked_sub(1)
+                    .is_some_and(|i| tokens[i].kind != SyntaxKind::Whitespace);
+                let after = tokens.get(index + 1).is_some_and(|t| {
+                    !matches!(t.kind, SyntaxKind::Whitespace | SyntaxKind::Newline)
+                });
+                Cow::Owned(format!(
+                    "{}={}",
+                    if before { " " } else { "" },
+                    if after { " " } else { "" }
+                ))
+            }
+            _ => Cow::Borrowed(token.text.as_str()),
+        };
+        let segment = line.segments.last_mut().expect("a line has a segment");
+        segment.push_str(&text);
+        if token.is_slot_comma() {
+            line.segments.push(String::new());
+        }
+    }
+    if line.segments.iter().any(|s| !s.is_empty()) {
+        lines.push(line);
+    }
+    lines
+}
+
+/// Pads the slots of each note line and the ghost lines right after it to equal widths, so
+/// that notes sounding together start in the same column. Blocks where a line leaves the
+/// quantize grid they start on are left as written.
+fn align_ghost_blocks(lines: &mut [Line]) {
+    let mut start = 0;
+    while start < lines.len() {
+        let end = start
+            + 1
+            + lines[start + 1..]
+                .iter()
+                .take_while(|l| l.line == Some(SyntaxKind::NODE_GHOST_LINE))
+                .count();
+        let block = &mut lines[start..end];
+        start = end;
+        if block.len() < 2
+            || block[0].line != Some(SyntaxKind::NODE_NORMAL_LINE)
+            || block.iter().any(|l| l.off_grid)
+            || block
+                .iter()
+                .any(|l| l.segments.iter().any(|s| s.contains('\n')))
+        {
+            continue;
+        }
+        let columns = block
+            .iter()
+            .map(|l| l.segments.len() - 1)
+            .max()
+            .unwrap_or(0);
+        for column in 0..columns {
+            let width = block
+                .iter()
+                .filter(|l| column + 1 < l.segments.len())
+                .map(|l| l.segments[column].chars().count())
+                .max()
+                .unwrap_or(0);
+            for line in block.iter_mut().filter(|l| column + 1 < l.segments.len()) {
+                let padding = width - line.segments[column].chars().count();
+                line.segments[column].extend(std::iter::repeat_n(' ', padding));
+            }
+        }
+    }
+}
+
+/// Tokens of `source` apart from whitespace and line breaks, with comments and durations
+/// in the form the formatter writes them.
+fn lex_normalized(source: &str) -> Vec<(Result<SyntaxKind, ()>, String)> {
+    let mut lexer = SyntaxKind::lexer(source);
+    let mut tokens = Vec::new();
+    while let Some(kind) = lexer.next() {
+        let text = lexer.slice();
+        let text = match kind {
+            Ok(SyntaxKind::Whitespace | SyntaxKind::Newline) => continue,
+            Ok(SyntaxKind::Comment) => text.trim_end().to_string(),
+            Ok(SyntaxKind::DurationFraction) => {
+                canonical_duration(text).unwrap_or_else(|| text.to_string())
+            }
+            _ => text.to_string(),
+        };
+        tokens.push((kind, text));
+    }
+    tokens
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_normalizes_aligns_and_keeps_comments() {
+        let source = "// head  \nm=C4:E4\nC4, D4 ,E4[8:2],  // c\n=E4:G4,F4[4:1],\n\n\
+                      v1: C4 ,\\\n D4[-6:3],";
+        let formatted = format(source);
+        assert_eq!(
+            formatted,
+            "// head\nm = C4:E4\nC4,    D4,   E4[4], // c\n=E4:G4,F4[4],\n\n\
+             v1: C4,\\\n D4[-2],\n"
+        );
+        assert_eq!(format(&formatted), formatted);
+        // broken sources are left alone
+        assert_eq!(format("C4 (\n"), "C4 (\n");
+    }
+}
@@ -0,0 +1,88 @@
+//! Entry points for fuzz harnesses. Any input, valid UTF-8 or not, must parse back to its
+//! own text and compile without panicking; a panic here is a bug to fix in the parser or
+//! compiler, never an answer to bad input.
+//!
+//! A `cargo fuzz` target only needs to forward its bytes:
+//!
+//! ```rust,ignore
+//! libfuzzer_sys::fuzz_target!(|data: &[u8]| symi::fuzz::parse_and_compile(data));
+//! ```
+
+use std::sync::Arc;
+
+use crate::{compiler::compile::Compiler, rowan::parse_fn::parse_source};
+
+/// Parses `data` (decoded lossily as UTF-8) and compiles the tree, asserting the tree is
+/// lossless. Panics are left to propagate so the harness records them.
+pub fn parse_and_compile(data: &[u8]) {
+    let source: Arc<str> = Arc::from(String::from_utf8_lossy(data).as_ref());
+    let parse = parse_source(source.clone());
+    let tree = parse.syntax_node();
+    assert_eq!(
+        tree.text().to_string(),
+        *source,
+        "syntax tree must be lossless"
+    );
+    Compiler::new().compile(&tree);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use super::*;
+    use crate::rowan::parse_fn::parse_lossy;
+
+    #[test]
+    fn malformed_sources_do_not_panic() {
+        let cases = [
+            "=",
+            "= C4",
+            "(",
+            "()",
+            "(/)",
+            "<>",
+            "<=",
+            "<root=>",
+            "m =\n\n",
+            "m = \n m,",
+            "?{",
+            "^{}",
+            "(g",
+            "(at)",
+            "(pickup)",
+            "(transpose)",
+            "(tuning)",
+            "{8:",
+            "#if\n#endif",
+            "#endif",
+            "C4@@",
+            "@C4",
+            "3/2@",
+            "\u{FEFF}\r\r\\",
+            "C4;(D4;(E4",
+            "v1:",
+            "drums:",
+        ];
+        for case in cases {
+            parse_and_compile(case.as_bytes());
+            assert_eq!(parse_lossy(Arc::from(case)).syntax_node().text(), case);
+        }
+        parse_and_compile(&[0xff, 0xfe, b'C', b'4', 0x80]);
+    }
+
+    #[test]
+    fn truncated_samples_do_not_panic() {
+        for name in ["jingle_bell.symi", "silhouette_dance.symi"] {
+            let source = fs::read_to_string(Path::new("src/tests").join(name)).unwrap();
+            let mut end = 0;
+            while end < source.len() {
+                end += 7;
+                while !source.is_char_boundary(end.min(source.len())) {
+                    end += 1;
+                }
+                parse_and_compile(&source.as_bytes()[..end.min(source.len())]);
+            }
+        }
+    }
+}
@@ -1,11 +1,20 @@
-use std::{iter, sync::Arc, time::Duration};
+use std::{
+    iter,
+    mem::take,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use cpal::{
     BufferSize, Stream,
     traits::{DeviceTrait, HostTrait, StreamTrait},
 };
 use glicol_synth::{
-    AudioContext, AudioContextBuilder, Message, Sum, oscillator::TriOsc, signal::ConstSig,
+    AudioContext, AudioContextBuilder, Message, Sum,
+    envelope::Adsr,
+    operator::Mul,
+    oscillator::{SinOsc, TriOsc},
+    signal::{ConstSig, Noise},
 };
 use parking_lot::Mutex;
 use petgraph::graph::NodeIndex;
@@ -18,11 +27,26 @@ use ringbuf::{
 use tap::Tap;
 use tokio::time::sleep;
 
+use crate::compiler::{
+    playback::playback_events,
+    types::{CancellationToken, CompileEvent, EventBody, Note},
+};
+
 pub type AudioProducer = Caching<Arc<SharedRb<Heap<f32>>>, true, false>;
 pub type AudioConsumer = Caching<Arc<SharedRb<Heap<f32>>>, false, true>;
 pub type AudioContextPtr = Arc<Mutex<AudioContext<AUDIO_CONTEXT_BUFFER_SIZE>>>;
 const AUDIO_CONTEXT_BUFFER_SIZE: usize = 128;
 const AUDIO_RB_SIZE: usize = AUDIO_CONTEXT_BUFFER_SIZE * 4;
+/// 滑音时长上限（秒）与频率更新间隔
+const GLIDE_MAX_SEC: f32 = 0.12;
+const GLIDE_STEP_SEC: f32 = 0.005;
+/// 音符松开后保留节点的时长（秒），供包络释放
+const TONE_TAIL_SEC: f32 = 0.5;
+/// 打击乐音符的最长发声时长与松开后的余音（秒）
+const DRUM_HOLD_SEC: f32 = 0.15;
+const DRUM_TAIL_SEC: f32 = 0.2;
+/// 播放整首曲子时检查是否取消的间隔（秒）
+const CANCEL_POLL_SEC: f32 = 0.05;
 pub struct AudioHandle {
     pub context: AudioContextPtr,
     pub stream: Stream,
@@ -82,8 +106,8 @@ impl AudioHandle {
                             #[coroutine]
                             || {
                                 for frame in 0..buf[0].len() {
-                                    for channel in 0..output_channels {
-                                        yield buf[channel][frame];
+                                    for channel in buf.iter().take(output_channels) {
+                                        yield channel[frame];
                                     }
                                 }
                             },
@@ -114,35 +138,209 @@ impl AudioHandle {
         *self.volume.lock()
     }
 
-    pub async fn play_note(&self, freq: f32, duration_sec: f32) {
-        let volume = self.volume();
-        let nodes = with_context_lock!(self.context, ctx, {
+    pub async fn play_note(&self, freq: f32, duration_sec: f32, note_volume: f32) {
+        self.play_glide(freq, freq, duration_sec, note_volume).await;
+    }
+
+    /// 播放滑音音符：振荡器频率从 `from` 按指数曲线滑向 `to`，随后保持至音符结束；
+    /// `note_volume` 为单个音符的音量系数，与全局音量相乘
+    pub async fn play_glide(&self, from: f32, to: f32, duration_sec: f32, note_volume: f32) {
+        let nodes = self.start_tone(from, note_volume);
+        let glide = glide_steps(from, to, duration_sec);
+        for &(_, freq) in &glide {
+            sleep(Duration::from_secs_f32(GLIDE_STEP_SEC)).await;
+            self.set_frequency(&nodes, freq);
+        }
+        sleep(Duration::from_secs_f32(
+            (duration_sec - glide.len() as f32 * GLIDE_STEP_SEC).max(0.0),
+        ))
+        .await;
+        self.release(&nodes);
+        sleep(Duration::from_secs_f32(TONE_TAIL_SEC)).await;
+        self.remove(&nodes);
+    }
+
+    /// 播放打击乐音符：底鼓与嗵鼓使用低频正弦，其余使用噪声，均为短促包络
+    pub async fn play_drum(&self, key: u8, duration_sec: f32, note_volume: f32) {
+        let nodes = self.start_drum(key, note_volume);
+        sleep(Duration::from_secs_f32(duration_sec.min(DRUM_HOLD_SEC))).await;
+        self.release(&nodes);
+        sleep(Duration::from_secs_f32(DRUM_TAIL_SEC)).await;
+        self.remove(&nodes);
+    }
+
+    /// 播放整首曲子：每个音符（经 playback_events 应用capo）在其开始时间发声、持续其时长，
+    /// 滑音、打击乐与音符音量同单个音符的试听；全部音符由同一个调度循环按时间依次开始、滑动、松开并移除，
+    /// 最后一个音符的余音结束后返回；`cancel` 被取消时松开并移除所有仍在发声的音符后立即返回
+    pub async fn play_events(&self, events: &[CompileEvent], cancel: &CancellationToken) {
+        let notes: Vec<(f32, Note)> = playback_events(events)
+            .into_iter()
+            .filter_map(|event| match event.body {
+                EventBody::Note(note) if !note.is_rest() && note.freq > 0.0 => {
+                    Some((event.start_time.seconds as f32, note))
+                }
+                _ => None,
+            })
+            .collect();
+
+        let mut steps = Vec::new();
+        for (idx, (start, note)) in notes.iter().enumerate() {
+            let duration = note.duration_seconds.max(0.0) as f32;
+            steps.push((*start, SequencerStep::Start(idx)));
+            if note.drum_key.is_some() {
+                let end = start + duration.min(DRUM_HOLD_SEC);
+                steps.push((end, SequencerStep::Release(idx)));
+                steps.push((end + DRUM_TAIL_SEC, SequencerStep::Remove(idx)));
+                continue;
+            }
+            if let Some(from) = note.portamento_from {
+                steps.extend(
+                    glide_steps(from as f32, note.freq as f32, duration)
+                        .into_iter()
+                        .map(|(at, freq)| (start + at, SequencerStep::Glide(idx, freq))),
+                );
+            }
+            steps.push((start + duration, SequencerStep::Release(idx)));
+            steps.push((start + duration + TONE_TAIL_SEC, SequencerStep::Remove(idx)));
+        }
+        // 稳定排序：同一时刻按加入顺序执行，音符总是先开始再滑动
+        steps.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+        let begin = Instant::now();
+        let mut voices: Vec<Vec<NodeIndex>> = vec![Vec::new(); notes.len()];
+        for (at, step) in steps {
+            // 按开始播放以来的实际时间等待，误差不随音符数累积；分段等待以便及时响应取消
+            loop {
+                if cancel.is_cancelled() {
+                    for nodes in voices.iter().filter(|nodes| !nodes.is_empty()) {
+                        self.release(nodes);
+                        self.remove(nodes);
+                    }
+                    return;
+                }
+                let wait = at - begin.elapsed().as_secs_f32();
+                if wait <= 0.0 {
+                    break;
+                }
+                sleep(Duration::from_secs_f32(wait.min(CANCEL_POLL_SEC))).await;
+            }
+            match step {
+                SequencerStep::Start(idx) => {
+                    let note = &notes[idx].1;
+                    // 音量与力度一同决定响度，与编辑器单音试听一致
+                    let gain = note.gain();
+                    voices[idx] = match note.drum_key {
+                        Some(key) => self.start_drum(key, gain),
+                        None => {
+                            let from = note.portamento_from.unwrap_or(note.freq);
+                            self.start_tone(from as f32, gain)
+                        }
+                    };
+                }
+                SequencerStep::Glide(idx, freq) => self.set_frequency(&voices[idx], freq),
+                SequencerStep::Release(idx) => self.release(&voices[idx]),
+                SequencerStep::Remove(idx) => self.remove(&take(&mut voices[idx])),
+            }
+        }
+    }
+
+    /// 开始一个三角波音符，返回其节点
+    fn start_tone(&self, freq: f32, note_volume: f32) -> Vec<NodeIndex> {
+        with_context_lock!(self.context, ctx, {
             let osc = ctx.add_mono_node(TriOsc::new().freq(freq).sr(self.sample_rate));
-            let gate = ctx.add_mono_node(ConstSig::new(1.0));
-            let asdr = ctx.add_mono_node(glicol_synth::envelope::Adsr::new());
-            let apply_gate = ctx.add_mono_node(glicol_synth::operator::Mul::new(1.0));
-            ctx.connect(gate, asdr);
-            ctx.connect(osc, apply_gate);
-            ctx.connect(asdr, apply_gate);
-            let final_mul = ctx.add_mono_node(glicol_synth::operator::Mul::new(volume));
-            ctx.connect(apply_gate, final_mul);
-            ctx.connect(final_mul, self.sum_node);
-            vec![osc, gate, asdr, apply_gate, final_mul]
+            self.connect_voice(&mut ctx, osc, Adsr::new(), note_volume)
+        })
+    }
+
+    /// 开始一个打击乐音符，返回其节点
+    fn start_drum(&self, key: u8, note_volume: f32) -> Vec<NodeIndex> {
+        let tonal = matches!(key, 35 | 36 | 41 | 43 | 45 | 47 | 48 | 50);
+        with_context_lock!(self.context, ctx, {
+            let source = if tonal {
+                let freq = 440.0 * 2f32.powf((key as f32 - 69.0) / 12.0);
+                ctx.add_mono_node(SinOsc::new().freq(freq).sr(self.sample_rate))
+            } else {
+                ctx.add_mono_node(Noise::new(key as usize))
+            };
+            let envelope = Adsr::new()
+                .attack(0.001)
+                .decay(0.12)
+                .sustain(0.0)
+                .release(0.05)
+                .sr(self.sample_rate);
+            self.connect_voice(&mut ctx, source, envelope, note_volume)
+        })
+    }
+
+    /// 将音源经门限控制的包络与音量接入混音节点，返回的节点依次为音源、门限、包络、门控与音量
+    fn connect_voice(
+        &self,
+        ctx: &mut AudioContext<AUDIO_CONTEXT_BUFFER_SIZE>,
+        source: NodeIndex,
+        envelope: Adsr,
+        note_volume: f32,
+    ) -> Vec<NodeIndex> {
+        let volume = self.volume() * note_volume.clamp(0.0, 1.0);
+        let gate = ctx.add_mono_node(ConstSig::new(1.0));
+        let asdr = ctx.add_mono_node(envelope);
+        let apply_gate = ctx.add_mono_node(Mul::new(1.0));
+        ctx.connect(gate, asdr);
+        ctx.connect(source, apply_gate);
+        ctx.connect(asdr, apply_gate);
+        let final_mul = ctx.add_mono_node(Mul::new(volume));
+        ctx.connect(apply_gate, final_mul);
+        ctx.connect(final_mul, self.sum_node);
+        vec![source, gate, asdr, apply_gate, final_mul]
+    }
+
+    fn set_frequency(&self, nodes: &[NodeIndex], freq: f32) {
+        with_context_lock!(self.context, ctx, {
+            ctx.send_msg(nodes[0], Message::SetToNumber(0, freq));
         });
-        sleep(Duration::from_secs_f32(duration_sec)).await;
+    }
+
+    /// 关闭门限，包络进入释放段
+    fn release(&self, nodes: &[NodeIndex]) {
         with_context_lock!(self.context, ctx, {
-            let gate = nodes[1];
-            ctx.send_msg(gate, Message::SetToNumber(0, 0.0));
+            ctx.send_msg(nodes[1], Message::SetToNumber(0, 0.0));
         });
-        sleep(Duration::from_secs_f32(0.5)).await;
+    }
+
+    fn remove(&self, nodes: &[NodeIndex]) {
         with_context_lock!(self.context, ctx, {
-            for &node in &nodes {
+            for &node in nodes {
                 ctx.graph.remove_node(node);
             }
         });
     }
 }
 
+/// 整曲播放中某个音符的一步
+#[derive(Debug, Clone, Copy)]
+enum SequencerStep {
+    Start(usize),
+    /// 滑音中途将振荡器设为该频率
+    Glide(usize, f32),
+    Release(usize),
+    Remove(usize),
+}
+
+/// 从 `from` 滑向 `to` 的各步：距音符开始的秒数与该步的频率；音高不变时没有滑音
+fn glide_steps(from: f32, to: f32, duration_sec: f32) -> Vec<(f32, f32)> {
+    let glide_sec = if from == to {
+        0.0
+    } else {
+        GLIDE_MAX_SEC.min(duration_sec)
+    };
+    let steps = (glide_sec / GLIDE_STEP_SEC) as usize;
+    (1..=steps)
+        .map(|i| {
+            let freq = from * (to / from).powf(i as f32 / steps as f32);
+            (i as f32 * GLIDE_STEP_SEC, freq)
+        })
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use tokio::task::JoinSet;
@@ -150,7 +348,7 @@ mod tests {
     use super::*;
 
     // #[tokio::test]
-    #[allow(unused)]
+    #[allow(unused, clippy::single_element_loop)]
     async fn test_init_audio() {
         let h = Arc::new(AudioHandle::new().unwrap());
 
@@ -158,9 +356,20 @@ mod tests {
         for f in &[261.63] {
             let h = Arc::clone(&h);
             join_set.spawn(async move {
-                h.play_note(*f, 2.0).await;
+                h.play_note(*f, 2.0, 1.0).await;
             });
         }
         join_set.join_all().await;
     }
+
+    #[test]
+    fn glide_steps_reach_the_target() {
+        let steps = glide_steps(220.0, 440.0, 1.0);
+        assert_eq!(steps.len(), (GLIDE_MAX_SEC / GLIDE_STEP_SEC) as usize);
+        let (at, freq) = *steps.last().unwrap();
+        assert!((at - GLIDE_MAX_SEC).abs() < 1e-4);
+        assert!((freq - 440.0).abs() < 1e-3);
+        // 音高不变时不滑动
+        assert!(glide_steps(440.0, 440.0, 1.0).is_empty());
+    }
 }
@@ -2,12 +2,20 @@
 #![feature(coroutines)]
 #![feature(yield_expr)]
 pub mod compiler;
+pub mod decompile;
+pub mod format;
+pub mod fuzz;
 pub mod glicol;
 pub mod rowan;
 pub mod midi;
 pub use {
-    compiler::{compile::Compiler, types::*},
+    compiler::{compile::Compiler, piece::CompiledPiece, timeline::Timeline, types::*},
+    format::format,
     glicol::audio::*,
-    rowan::{lexer::SyntaxKind, parse_fn::parse_source, parser::Parse},
+    rowan::{
+        lexer::{SyntaxKind, lex},
+        parse_fn::{parse_lossy, parse_source, parse_source_with_options},
+        parser::{Parse, ParseOptions},
+    },
 };
 
@@ -1 +1,6 @@
+pub mod clip;
+pub mod import;
+pub mod reader;
 pub mod writer;
+
+pub use import::{ImportOptions, PitchSpelling, import_to_source};
@@ -0,0 +1,454 @@
+/*
+* symi转MIDI 2.0 Clip File（SMF2CLIP），与SMF Format 1导出并列：
+*   - 输入events与MidiWriterConfig：沿用其中的MIDI分辨率、力度曲线、松开力度、打击乐声部、渐变速度细分与导出范围；
+*     弯音范围、MTS、分轨策略与各类容差只用于SMF，此处不需要；不支持SMPTE时间码
+*   - 文件为 "SMF2CLIP" 文件头加一串大端序的UMP（Universal MIDI Packet）：
+*     Clip配置头写入分辨率（DCTPQ），Clip序列数据以Start of Clip开头、End of Clip结尾，每个UMP前都有一个Delta Clockstamp
+*
+* 具体操作流程为：
+*  1. 与SMF相同地推算秒与tick的换算关系，速度与拍号写成Flex Data的Set Tempo（每四分音符的10纳秒数）与Set Time Signature消息
+*  2. 音符写成MIDI 2.0通道声音消息（UMP类型4），音高不借助Pitch Bend：
+*    - NoteOn带Pitch 7.9属性，直接给出音符的精确音高（7位半音、9位小数）；力度与松开力度按MIDI 2.0的规则由7位放大到16位
+*    - 音符号只用来区分同时发声的音符：取最接近的MIDI音高，同一通道上已被发声中的音符占用时改用最近的空闲音符号
+*    - 弯音包络与滑音写成逐音符的注册控制器 Pitch 7.25（RPNC 3），在音符内逐点给出绝对音高；
+*      滑音在音符的前1/4时长内从前一音高滑到本音高
+*    - 每个声部（无声部的音符算作一个声部）占用一个通道，按出现顺序分配，一个组（Group）的通道用尽时使用下一个组；
+*      指定了通道的音符使用第一组中的该通道；旋律通道跳过通道10
+*    - 打击乐音符与打击乐声部（percussion_voices）写入第一组的通道10，不带音高属性
+*    - 指定了音色的通道在音色切换处写入Program Change
+*  3. 按tick排序写出：同一tick上NoteOff在前，其后依次为Program Change、NoteOn与其逐音符控制器
+*/
+use std::collections::HashMap;
+
+use anyhow::{Result, bail};
+
+use crate::{
+    compiler::{
+        piece::CompiledPiece,
+        playback::playback_events,
+        types::{CompileEvent, EventBody},
+    },
+    midi::writer::{
+        BEND_ENVELOPE_STEPS, MidiTiming, MidiWriterConfig, PERCUSSION_CHANNEL, TempoPoint,
+        build_tempo_points, collect_tempos, collect_time_signatures, normalize_tpq,
+        ramp_tempo_changes, seconds_to_tick, window_events,
+    },
+};
+
+const CLIP_FILE_HEADER: &[u8; 8] = b"SMF2CLIP";
+/// Most ticks a single Delta Clockstamp counts.
+const MAX_DELTA_CLOCKSTAMP: u64 = 0xF_FFFF;
+/// Note On attribute giving the pitch of the note in 7.9 fixed point.
+const ATTRIBUTE_PITCH_7_9: u8 = 0x03;
+/// Registered per-note controller setting the pitch of the note in 7.25 fixed point.
+const RPNC_PITCH_7_25: u8 = 0x03;
+/// A glide takes the first 1/GLIDE_PARTS of its note.
+const GLIDE_PARTS: u64 = 4;
+
+/// Group and channel of a part of the clip.
+type Address = (u8, u8);
+
+/// A note as exported, pitches in MIDI semitones.
+#[derive(Debug, Clone)]
+struct ClipNote {
+    start_tick: u64,
+    end_tick: u64,
+    address: Address,
+    /// Exact pitch, or the drum key of a percussion note
+    pitch: f64,
+    percussion: bool,
+    /// Pitch the note glides from when marked with portamento
+    glide_from: Option<f64>,
+    /// Cent offsets of the bend envelope, spread evenly across the note
+    envelope: Vec<i32>,
+    program: Option<u8>,
+    velocity: u16,
+    release: u16,
+}
+
+impl ClipNote {
+    /// Pitch `t` of the way through the note, following its envelope and glide.
+    fn pitch_at(&self, t: f64) -> f64 {
+        let mut pitch = self.pitch;
+        if self.envelope.len() >= 2 {
+            let x = t.clamp(0.0, 1.0) * (self.envelope.len() - 1) as f64;
+            let i = (x as usize).min(self.envelope.len() - 2);
+            let (from, to) = (f64::from(self.envelope[i]), f64::from(self.envelope[i + 1]));
+            pitch += (from + (to - from) * (x - i as f64)) / 100.0;
+        }
+        let glide = t * GLIDE_PARTS as f64;
+        if let Some(from) = self.glide_from
+            && glide < 1.0
+        {
+            pitch += (from - pitch) * (1.0 - glide);
+        }
+        pitch
+    }
+}
+
+/// A UMP of the clip, written after those at the same tick with a lower `priority`.
+#[derive(Debug, Clone)]
+struct ClipEvent {
+    tick: u64,
+    priority: u8,
+    words: Vec<u32>,
+}
+
+/// Writes `events` as a MIDI 2.0 Clip File, every note carrying its exact pitch.
+pub fn export_clip_file(events: &[CompileEvent], config: &MidiWriterConfig) -> Result<Vec<u8>> {
+    if config.timing != MidiTiming::Metrical {
+        bail!("MIDI 2.0 clip files count ticks per quarter note, not SMPTE timecode");
+    }
+    let tpq = normalize_tpq(config.ticks_per_quarter)?;
+    let mut piece = CompiledPiece::new(playback_events(events));
+    let raw_tempos = collect_tempos(&piece, config.start_second, config.end_second)?;
+    if config.start_second != 0.0 || config.end_second.is_some() {
+        piece = CompiledPiece::new(window_events(
+            &piece,
+            config.start_second,
+            config.end_second,
+        )?);
+    }
+    let time_signatures = collect_time_signatures(&piece)?;
+    let tempo_points = build_tempo_points(&raw_tempos, tpq);
+
+    let mut clip_events = Vec::new();
+    for (tick, mpq) in ramp_tempo_changes(&tempo_points, config.tempo_ramp_steps) {
+        // in units of 10 nanoseconds per quarter note
+        clip_events.push(ClipEvent {
+            tick,
+            priority: 0,
+            words: flex_data(0x00, mpq * 100),
+        });
+    }
+    for sig in &time_signatures {
+        let data =
+            (u32::from(sig.numerator) << 24) | (sig.denominator.trailing_zeros() << 16) | (8 << 8);
+        clip_events.push(ClipEvent {
+            tick: seconds_to_tick(sig.second, &tempo_points, tpq),
+            priority: 0,
+            words: flex_data(0x01, data),
+        });
+    }
+    let notes = collect_clip_notes(&piece, config, &tempo_points, tpq)?;
+    clip_events.extend(note_events(&notes)?);
+
+    clip_events.sort_by_key(|event| (event.tick, event.priority));
+    Ok(write_clip(tpq, &clip_events))
+}
+
+/// Notes of `piece` in order of their start, on the group and channel of their part.
+fn collect_clip_notes(
+    piece: &CompiledPiece,
+    config: &MidiWriterConfig,
+    tempo_points: &[TempoPoint],
+    tpq: u16,
+) -> Result<Vec<ClipNote>> {
+    // parts take the channels explicit ones leave, group after group
+    let explicit: Vec<u8> = piece.events.iter().filter_map(|e| e.channel).collect();
+    let mut free = (0..16_u8)
+        .flat_map(|group| (0..16_u8).map(move |channel| (group, channel)))
+        .filter(|&(group, channel)| {
+            channel != PERCUSSION_CHANNEL && !(group == 0 && explicit.contains(&channel))
+        });
+    let mut parts: HashMap<Option<&str>, Address> = HashMap::new();
+
+    let mut notes = Vec::new();
+    for event in piece.iter_sorted() {
+        let EventBody::Note(note) = &event.body else {
+            continue;
+        };
+        if note.is_rest() {
+            continue;
+        }
+        if note.freq <= 0.0 {
+            bail!("Note frequency must be > 0 for MIDI export");
+        }
+        if note.duration_seconds <= 0.0 {
+            bail!("Note duration_seconds must be > 0 for MIDI export");
+        }
+        let exact = midi_pitch(note.freq);
+        let percussion_voice = event
+            .voice
+            .as_deref()
+            .and_then(|name| config.percussion_voices.iter().find(|p| p.voice == name));
+        let (address, drum_key) = match (note.drum_key, percussion_voice) {
+            (Some(key), _) => ((0, PERCUSSION_CHANNEL), Some(key.min(127))),
+            (None, Some(voice)) => {
+                let key = voice.drum_key(exact.round().clamp(0.0, 127.0) as u8);
+                ((0, PERCUSSION_CHANNEL), Some(key))
+            }
+            (None, None) => {
+                let address = match event.channel {
+                    Some(channel) if channel > 15 => {
+                        bail!("MIDI channel {} is out of range (0-15)", channel)
+                    }
+                    Some(PERCUSSION_CHANNEL) => {
+                        bail!("MIDI channel 10 is reserved for percussion voices")
+                    }
+                    Some(channel) => (0, channel),
+                    None => match parts.get(&event.voice.as_deref()) {
+                        Some(&address) => address,
+                        None => {
+                            let Some(address) = free.next() else {
+                                bail!("Too many voices for the 16 groups of a MIDI 2.0 clip");
+                            };
+                            parts.insert(event.voice.as_deref(), address);
+                            address
+                        }
+                    },
+                };
+                (address, None)
+            }
+        };
+
+        let percussion = drum_key.is_some();
+        let end_second = event.start_time.seconds + note.duration_seconds;
+        let start_tick = seconds_to_tick(event.start_time.seconds, tempo_points, tpq);
+        let end_tick = seconds_to_tick(end_second, tempo_points, tpq).max(start_tick + 1);
+        let velocity = config.velocity_curve.velocity(note.gain());
+        let release = note.release.unwrap_or(config.note_off_velocity);
+        notes.push(ClipNote {
+            start_tick,
+            end_tick,
+            address,
+            pitch: drum_key.map_or(exact, f64::from),
+            percussion,
+            glide_from: note
+                .portamento_from
+                .filter(|&from| from > 0.0 && !percussion)
+                .map(midi_pitch),
+            envelope: match &note.bend_envelope {
+                Some(envelope) if !percussion => envelope.cents.clone(),
+                _ => Vec::new(),
+            },
+            program: event.instrument,
+            velocity: scale_7_to_16(velocity),
+            release: scale_7_to_16(release),
+        });
+    }
+    Ok(notes)
+}
+
+/// Messages of `notes`: their program changes, the notes on free note numbers and the
+/// per-note pitch of their envelopes and glides.
+fn note_events(notes: &[ClipNote]) -> Result<Vec<ClipEvent>> {
+    let mut events = Vec::new();
+    let mut programs: HashMap<Address, u8> = HashMap::new();
+    // note numbers sounding on each channel, with the tick each is released at
+    let mut sounding: HashMap<Address, Vec<(u8, u64)>> = HashMap::new();
+    for note in notes {
+        if let Some(program) = note.program
+            && programs.insert(note.address, program) != Some(program)
+        {
+            events.push(ClipEvent {
+                tick: note.start_tick,
+                priority: 1,
+                words: channel_voice(note.address, 0xC, 0, 0, u32::from(program) << 24),
+            });
+        }
+
+        let key = note.pitch.round().clamp(0.0, 127.0) as u8;
+        let (number, attribute, attribute_data) = if note.percussion {
+            (key, 0, 0)
+        } else {
+            let busy = sounding.entry(note.address).or_default();
+            busy.retain(|&(_, end)| end > note.start_tick);
+            let Some(number) = (0..128_u8)
+                .filter(|&n| busy.iter().all(|&(taken, _)| taken != n))
+                .min_by_key(|&n| n.abs_diff(key))
+            else {
+                bail!("More than 128 notes sound at once on a MIDI 2.0 channel");
+            };
+            busy.push((number, note.end_tick));
+            (number, ATTRIBUTE_PITCH_7_9, pitch_7_9(note.pitch_at(0.0)))
+        };
+        events.push(ClipEvent {
+            tick: note.start_tick,
+            priority: 2,
+            words: channel_voice(
+                note.address,
+                0x9,
+                number,
+                attribute,
+                (u32::from(note.velocity) << 16) | attribute_data,
+            ),
+        });
+        events.push(ClipEvent {
+            tick: note.end_tick,
+            priority: 0,
+            words: channel_voice(note.address, 0x8, number, 0, u32::from(note.release) << 16),
+        });
+
+        if note.envelope.len() >= 2 || note.glide_from.is_some() {
+            let segments = note.envelope.len().saturating_sub(1).max(1) as u64;
+            let mut steps = segments * BEND_ENVELOPE_STEPS;
+            if note.glide_from.is_some() {
+                steps = steps.max(GLIDE_PARTS * BEND_ENVELOPE_STEPS);
+            }
+            // the last point lands before the note ends, where its number may be taken again
+            let length = note.end_tick - 1 - note.start_tick;
+            for step in 0..=steps {
+                let pitch = note.pitch_at(step as f64 / steps as f64);
+                events.push(ClipEvent {
+                    tick: note.start_tick + length * step / steps,
+                    priority: 3,
+                    words: channel_voice(
+                        note.address,
+                        0x0,
+                        number,
+                        RPNC_PITCH_7_25,
+                        pitch_7_25(pitch),
+                    ),
+                });
+            }
+        }
+    }
+    Ok(events)
+}
+
+/// The clip file of `events`, sorted by tick.
+fn write_clip(tpq: u16, events: &[ClipEvent]) -> Vec<u8> {
+    let mut words = vec![
+        // clip configuration header: the ticks per quarter note
+        delta_clockstamp(0),
+        0x0030_0000 | u32::from(tpq),
+        // clip sequence data
+        delta_clockstamp(0),
+        0xF020_0000, // Start of Clip
+        0,
+        0,
+        0,
+    ];
+    let mut cursor = 0;
+    for event in events {
+        let mut delta = event.tick.saturating_sub(cursor);
+        // longer gaps than a clockstamp counts pass in no-ops
+        while delta > MAX_DELTA_CLOCKSTAMP {
+            words.extend([delta_clockstamp(MAX_DELTA_CLOCKSTAMP), 0]);
+            delta -= MAX_DELTA_CLOCKSTAMP;
+        }
+        words.push(delta_clockstamp(delta));
+        words.extend(&event.words);
+        cursor = cursor.max(event.tick);
+    }
+    words.extend([delta_clockstamp(0), 0xF021_0000, 0, 0, 0]); // End of Clip
+
+    let mut bytes = CLIP_FILE_HEADER.to_vec();
+    for word in words {
+        bytes.extend(word.to_be_bytes());
+    }
+    bytes
+}
+
+fn delta_clockstamp(ticks: u64) -> u32 {
+    0x0040_0000 | ticks.min(MAX_DELTA_CLOCKSTAMP) as u32
+}
+
+/// A Flex Data message of the first group, addressed to the whole group.
+fn flex_data(status: u8, data: u32) -> Vec<u32> {
+    vec![0xD010_0000 | u32::from(status), data, 0, 0]
+}
+
+/// A MIDI 2.0 channel voice message.
+fn channel_voice(address: Address, opcode: u8, index: u8, attribute: u8, data: u32) -> Vec<u32> {
+    let (group, channel) = address;
+    let word = 0x4000_0000
+        | (u32::from(group) << 24)
+        | (u32::from(opcode) << 20)
+        | (u32::from(channel) << 16)
+        | (u32::from(index) << 8)
+        | u32::from(attribute);
+    vec![word, data]
+}
+
+fn midi_pitch(freq: f64) -> f64 {
+    69.0 + 12.0 * (freq / 440.0).log2()
+}
+
+fn pitch_7_9(pitch: f64) -> u32 {
+    (pitch * 512.0).round().clamp(0.0, f64::from(u16::MAX)) as u32
+}
+
+fn pitch_7_25(pitch: f64) -> u32 {
+    (pitch * 33_554_432.0)
+        .round()
+        .clamp(0.0, f64::from(u32::MAX)) as u32
+}
+
+/// A 7-bit value as 16 bits, scaled the way MIDI 2.0 translates MIDI 1.0 values: the
+/// center stays the center, and the top value reaches the top.
+fn scale_7_to_16(value: u8) -> u16 {
+    let value = u32::from(value.min(127));
+    let mut scaled = value << 9;
+    if value > 64 {
+        // repeat the bits under the top one through the new low bits
+        let mut repeat = (value & 0x3F) << 3;
+        while repeat != 0 {
+            scaled |= repeat;
+            repeat >>= 6;
+        }
+    }
+    scaled as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{compiler::compile::Compiler, rowan::parse_fn::parse_source};
+
+    #[test]
+    fn clip_notes_carry_their_exact_pitch() {
+        let parsed = parse_source(Arc::from("(4/4)\n450.0,C4:262.0,D4{bend 0c..+100c},\n"));
+        let mut compiler = Compiler::new();
+        compiler.compile(&parsed.syntax_node());
+        let bytes = export_clip_file(&compiler.events, &MidiWriterConfig::default())
+            .expect("clip export should succeed");
+        assert_eq!(&bytes[..8], b"SMF2CLIP");
+
+        let words: Vec<u32> = bytes[8..]
+            .chunks(4)
+            .map(|w| u32::from_be_bytes(w.try_into().unwrap()))
+            .collect();
+        assert_eq!(words[..2], [0x0040_0000, 0x0030_0000 | 480]);
+        let mut tick = 0;
+        let (mut note_ons, mut pitches) = (Vec::new(), Vec::new());
+        let mut i = 0;
+        while i < words.len() {
+            let word = words[i];
+            match word >> 20 {
+                0x004 => tick += word & 0xF_FFFF,
+                0x409 => note_ons.push((tick, (word >> 8) & 0xFF, words[i + 1] & 0xFFFF)),
+                0x400 => pitches.push(f64::from(words[i + 1]) / 33_554_432.0),
+                _ => {}
+            }
+            i += match word >> 28 {
+                0x0..=0x2 => 1,
+                0x3 | 0x4 => 2,
+                _ => 4,
+            };
+        }
+        // the near-unison keeps its pitch on the next free note number
+        note_ons[1..3].sort_unstable();
+        assert_eq!(
+            note_ons,
+            [
+                (0, 69, 35527),
+                (480, 59, 30733),
+                (480, 60, 30720),
+                (960, 62, 31744)
+            ]
+        );
+        assert!((pitches.last().unwrap() - 63.0).abs() < 0.01);
+        assert_eq!(words[words.len() - 4..], [0xF021_0000, 0, 0, 0]);
+    }
+
+    #[test]
+    fn scale_velocities_to_16_bits() {
+        assert_eq!(scale_7_to_16(0), 0);
+        assert_eq!(scale_7_to_16(64), 0x8000);
+        assert_eq!(scale_7_to_16(127), 0xFFFF);
+    }
+}
@@ -0,0 +1,227 @@
+//! Imports a Standard MIDI File as symi source: the file is read into compile events,
+//! snapped to a grid, spelled against a base note and decompiled.
+
+use anyhow::Result;
+
+use crate::{
+    compiler::{
+        rational::Rational64,
+        types::{CompileEvent, EventBody, Pitch, TimeSignature},
+    },
+    decompile::decompile,
+    midi::reader::read_smf,
+};
+
+/// How imported pitches are written, relative to the base note.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum PitchSpelling {
+    /// The nearest note name, with a cents offset when the pitch is off it
+    #[default]
+    Cents,
+    /// The nearest frequency ratio with a denominator up to `max_denominator`
+    Ratio { max_denominator: u32 },
+    /// The nearest step of the octave divided into `divisions` equal steps
+    Edo { divisions: u32 },
+}
+
+/// Choices made when writing an imported file as source.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImportOptions {
+    /// Slots per whole note that note starts and ends snap to, e.g. 16 for sixteenths;
+    /// `None` keeps them as they are, down to the finest quantize a bar allows
+    pub quantize: Option<u32>,
+    /// Time signature laying out the bars in place of those of the file
+    pub time_signature: Option<TimeSignature>,
+    /// Base note pitches are spelled against, tuned to A4 = 440 Hz
+    pub base_note: i16,
+    pub spelling: PitchSpelling,
+    /// Farthest in cents a ratio or EDO spelling may sound from the imported pitch; pitches
+    /// farther from every candidate keep their note name and cents offset
+    pub spelling_tolerance_cents: f64,
+}
+
+impl Default for ImportOptions {
+    fn default() -> Self {
+        Self {
+            quantize: None,
+            time_signature: None,
+            base_note: 60,
+            spelling: PitchSpelling::Cents,
+            spelling_tolerance_cents: 15.0,
+        }
+    }
+}
+
+/// Reads a Standard MIDI File and writes it as symi source.
+pub fn import_to_source(bytes: &[u8], options: &ImportOptions) -> Result<String> {
+    let mut events = read_smf(bytes)?;
+    let base_frequency = 440.0 * 2f64.powf(f64::from(options.base_note - 69) / 12.0);
+
+    for event in &mut events {
+        match &mut event.body {
+            EventBody::BaseNoteDef(note) => *note = options.base_note,
+            EventBody::BaseFequencyDef(freq) => *freq = base_frequency,
+            // whole microseconds per quarter note leave tempos a hair off the BPM written
+            EventBody::BPMDef(bpm) => *bpm = (*bpm * 100.0).round() / 100.0,
+            EventBody::Note(note) if note.drum_key.is_none() => {
+                if let Some((pitch, freq)) = spell(
+                    note.freq / base_frequency,
+                    options.spelling,
+                    options.spelling_tolerance_cents,
+                ) {
+                    note.pitch_chain = vec![pitch];
+                    note.freq = base_frequency * freq;
+                    note.pitch_ratio = freq;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if let Some(grid) = options.quantize.filter(|&grid| grid > 0) {
+        let grid = i64::from(grid);
+        for event in &mut events {
+            let start = snap(event.start_time.position, grid);
+            if let EventBody::Note(note) = &mut event.body {
+                let end = snap(event.start_time.position + note.duration, grid);
+                note.duration = if end > start {
+                    (end - start).reduce()
+                } else {
+                    Rational64::new(1, grid)
+                };
+            }
+            event.start_time.position = start;
+        }
+    }
+
+    if let Some(signature) = &options.time_signature {
+        events.retain(|e| !matches!(e.body, EventBody::TimeSignatureDef(_)));
+        if let Some(first) = events.first() {
+            let layout = CompileEvent {
+                body: EventBody::TimeSignatureDef(signature.clone()),
+                ..first.clone()
+            };
+            events.insert(0, layout);
+        }
+    }
+
+    Ok(decompile(&events))
+}
+
+/// Nearest slot of a `grid` per whole note to `position`.
+fn snap(position: Rational64, grid: i64) -> Rational64 {
+    let (numer, denom) = (i128::from(*position.numer()), i128::from(*position.denom()));
+    let slot = (2 * numer * i128::from(grid) + denom).div_euclid(2 * denom) as i64;
+    Rational64::new(slot, grid).reduce()
+}
+
+/// Pitch spelling `ratio` above the base note, and the ratio it stands for exactly, if one
+/// is within `tolerance_cents`.
+fn spell(ratio: f64, spelling: PitchSpelling, tolerance_cents: f64) -> Option<(Pitch, f64)> {
+    let cents = |exact: f64| (1200.0 * (exact / ratio).log2()).abs();
+    let (pitch, exact) = match spelling {
+        PitchSpelling::Cents => return None,
+        PitchSpelling::Ratio { max_denominator } => (1..=i64::from(max_denominator))
+            .filter_map(|denom| {
+                let numer = (ratio * denom as f64).round() as i64;
+                (numer > 0).then_some((numer, denom))
+            })
+            .map(|(numer, denom)| {
+                let candidate = Rational64::new(numer, denom).reduce();
+                (Pitch::Ratio(candidate), candidate.to_f64())
+            })
+            .min_by(|a, b| cents(a.1).total_cmp(&cents(b.1)))?,
+        PitchSpelling::Edo { divisions } if divisions > 0 => {
+            let step = (ratio.log2() * f64::from(divisions)).round() as i64;
+            let exact = 2f64.powf(step as f64 / f64::from(divisions));
+            (
+                Pitch::Edo(Rational64::new(step, i64::from(divisions))),
+                exact,
+            )
+        }
+        PitchSpelling::Edo { .. } => return None,
+    };
+    (cents(exact) <= tolerance_cents).then_some((pitch, exact))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{
+        compiler::compile::Compiler,
+        midi::writer::{MidiWriterConfig, export_smf_format1},
+        rowan::parse_fn::parse_source,
+    };
+
+    fn compile(source: &str) -> Vec<CompileEvent> {
+        let parsed = parse_source(Arc::from(source));
+        let mut compiler = Compiler::new();
+        compiler.compile(&parsed.syntax_node());
+        compiler.events
+    }
+
+    fn export(source: &str) -> Vec<u8> {
+        export_smf_format1(&compile(source), MidiWriterConfig::default())
+            .expect("midi export should succeed")
+    }
+
+    /// Start, duration and rounded frequency of every note.
+    fn notes(events: &[CompileEvent]) -> Vec<(Rational64, Rational64, f64)> {
+        let mut notes: Vec<_> = events
+            .iter()
+            .filter_map(|e| match &e.body {
+                EventBody::Note(note) if note.freq > 0.0 => Some((
+                    e.start_time.position.reduce(),
+                    note.duration.reduce(),
+                    (note.freq * 10.0).round() / 10.0,
+                )),
+                _ => None,
+            })
+            .collect();
+        notes.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        notes
+    }
+
+    #[test]
+    fn imported_source_compiles_to_the_same_notes() {
+        let source = "(3/4)\n(90)\nC4,E4:G4,,\n{8}D4,F4,A4,,B4,,\n";
+        let imported = import_to_source(&export(source), &ImportOptions::default())
+            .expect("midi import should succeed");
+        assert_eq!(
+            notes(&compile(&imported)),
+            notes(&compile(source)),
+            "{imported}"
+        );
+    }
+
+    #[test]
+    fn import_with_quantize_layout_and_spelling() {
+        let bytes = export("(4/4)\n{16}C4,G4,,,E4,,,,\n");
+        let options = ImportOptions {
+            quantize: Some(8),
+            time_signature: Some(TimeSignature::simple(2, 4)),
+            spelling: PitchSpelling::Ratio { max_denominator: 8 },
+            ..ImportOptions::default()
+        };
+        let imported = import_to_source(&bytes, &options).expect("midi import should succeed");
+        assert!(imported.contains("(2/4)"), "{imported}");
+        assert!(imported.contains("3/2"), "{imported}");
+        assert!(imported.contains("5/4"), "{imported}");
+        let snapped = notes(&compile(&imported));
+        assert!(
+            snapped.iter().all(|(start, duration, _)| {
+                *(*start * 8i64).reduce().denom() == 1 && *(*duration * 8i64).reduce().denom() == 1
+            }),
+            "{imported}"
+        );
+
+        let options = ImportOptions {
+            spelling: PitchSpelling::Edo { divisions: 12 },
+            ..ImportOptions::default()
+        };
+        let imported = import_to_source(&bytes, &options).expect("midi import should succeed");
+        assert!(imported.contains("7\\12"), "{imported}");
+    }
+}
@@ -0,0 +1,482 @@
+/*
+* midi转symi事件，读取标准MIDI文件（SMF Format 0/1/2）：
+*   - 输入: MIDI文件字节
+*   - 输出: Vec<CompileEvent>，与编译器输出的事件同构
+*
+* 具体操作流程为：
+*  1. 将各Track的事件按绝对tick合并到同一时间轴上（同一tick内保持Track顺序与轨内顺序）
+*  2. 先收集Tempo与TimeSignature元事件，构建时间转换关系：
+*    - 按四分音符计tick的文件：位置（全音符数）= tick / (4 × 每四分音符tick数)，为精确分数；秒数由速度表推算
+*    - SMPTE时间码文件：秒数 = tick / (帧率 × 每帧子帧数)，位置由速度表反推，取整到1/3840全音符
+*    - 小节号与小节内偏移按拍号推算；拍号从小节中间开始时，从该处起算新的一小节
+*  3. 逐个转换事件：
+*    - 开头写入基准音 A4 = 440Hz，使MIDI音高不经换算即为12平均律的音高
+*    - Tempo写为以四分音符为一拍的BPMDef；TimeSignature、KeySignature、Marker、CuePoint、Lyric写为对应的事件
+*      文本为 "loopStart"、"loopEnd" 的Marker写为循环区间的开头与结尾
+*    - NoteOn（力度为0视为NoteOff）与同通道同音高的NoteOff按先后配对，得到音符的起止时间
+*    - 音符频率由音高加上NoteOn时该通道的Pitch Bend得到；Pitch Bend Range按各通道的RPN 0（CC6半音、CC38音分）计算，缺省为2个半音
+*      音符开始后的Pitch Bend变化不计入音符
+*    - 通道10的音符为打击乐，保留其GM打击乐音高
+*    - 音符保留所在通道与Program Change设定的音色；力度不为100时记为音符的velocity，NoteOff的非零力度记为音符的release
+*    - 音符分布在多个Track上时，每个Track的音符与歌词记为一个声部（t1、t2……，按Track序号命名）
+*  4. 按开始时间排序输出；不生成NewMeasure事件
+*/
+use std::collections::HashMap;
+
+use anyhow::{Result, bail};
+use midly::{MetaMessage, MidiMessage, Smf, Timing, TrackEventKind};
+use rowan::TextRange;
+
+use crate::{
+    compiler::{
+        rational::Rational64,
+        types::{
+            CompileEvent, DEFAULT_VELOCITY, EventBody, KeySignature, Note, Pitch, SmolStr,
+            TempoMap, TimeSignature, TimeStamp,
+        },
+    },
+    midi::writer::PERCUSSION_CHANNEL,
+};
+
+/// Pitch bend range of a channel until RPN 0 sets one.
+const DEFAULT_BEND_RANGE_CENTS: u32 = 200;
+/// Subdivision of a whole note that positions in timecode files are rounded to.
+const TIMECODE_GRID: i64 = 3840;
+/// Base pitch written at the start, so that MIDI keys keep their 12-TET pitches.
+const BASE_NOTE: i16 = 69;
+const BASE_FREQUENCY: f64 = 440.0;
+
+/// Controllers of the Registered Parameter Number selecting the pitch bend range.
+const CC_RPN_MSB: u8 = 101;
+const CC_RPN_LSB: u8 = 100;
+const CC_DATA_ENTRY_MSB: u8 = 6;
+const CC_DATA_ENTRY_LSB: u8 = 38;
+
+/// Parses a Standard MIDI File into compile events: tempo, time and key signatures,
+/// markers, lyrics and notes, with note frequencies from their keys and pitch bends.
+pub fn read_smf(bytes: &[u8]) -> Result<Vec<CompileEvent>> {
+    let smf = Smf::parse(bytes)?;
+
+    let mut timeline = Vec::new();
+    for (track, events) in smf.tracks.iter().enumerate() {
+        let mut tick = 0u64;
+        for event in events {
+            tick += u64::from(event.delta.as_int());
+            timeline.push((tick, track, event.kind));
+        }
+    }
+    timeline.sort_by_key(|&(tick, _, _)| tick);
+
+    let clock = Clock::new(smf.header.timing, &timeline)?;
+
+    let note_tracks = {
+        let mut tracks: Vec<usize> = timeline
+            .iter()
+            .filter(|(_, _, kind)| {
+                matches!(
+                    kind,
+                    TrackEventKind::Midi {
+                        message: MidiMessage::NoteOn { .. },
+                        ..
+                    }
+                )
+            })
+            .map(|&(_, track, _)| track)
+            .collect();
+        tracks.sort_unstable();
+        tracks.dedup();
+        tracks
+    };
+    let voice_of = |track: usize| -> Option<SmolStr> {
+        (note_tracks.len() > 1).then(|| SmolStr::new(format!("t{track}")))
+    };
+
+    let mut events = vec![
+        (0, clock.event(EventBody::BaseNoteDef(BASE_NOTE), 0, None)),
+        (
+            0,
+            clock.event(EventBody::BaseFequencyDef(BASE_FREQUENCY), 0, None),
+        ),
+    ];
+    let mut channels = [ChannelState::default(); 16];
+    let mut sounding: HashMap<(u8, u8), Vec<SoundingNote>> = HashMap::new();
+
+    for &(tick, track, kind) in &timeline {
+        match kind {
+            TrackEventKind::Meta(meta) => {
+                let (body, voice) = match meta {
+                    MetaMessage::Tempo(mpq) => {
+                        let bpm = 60_000_000.0 / f64::from(mpq.as_int());
+                        (EventBody::BPMDef(bpm as f32), None)
+                    }
+                    MetaMessage::TimeSignature(numerator, power, _, _) => {
+                        let signature =
+                            TimeSignature::simple(u32::from(numerator), 1 << power.min(31));
+                        (EventBody::TimeSignatureDef(signature), None)
+                    }
+                    MetaMessage::KeySignature(sharps, minor) => (
+                        EventBody::KeySignatureDef(KeySignature { sharps, minor }),
+                        None,
+                    ),
+                    MetaMessage::Marker(b"loopStart") => (EventBody::LoopStart, None),
+                    MetaMessage::Marker(b"loopEnd") => (EventBody::LoopEnd, None),
+                    MetaMessage::Marker(text) => (EventBody::Marker(decode_text(text)), None),
+                    MetaMessage::CuePoint(text) => (EventBody::CuePoint(decode_text(text)), None),
+                    MetaMessage::Lyric(text) => {
+                        (EventBody::Lyric(decode_text(text)), voice_of(track))
+                    }
+                    _ => continue,
+                };
+                events.push((tick, clock.event(body, tick, voice)));
+            }
+            TrackEventKind::Midi { channel, message } => {
+                let channel = channel.as_int();
+                let state = &mut channels[channel as usize];
+                match message {
+                    MidiMessage::NoteOn { key, vel } if vel.as_int() > 0 => {
+                        sounding
+                            .entry((channel, key.as_int()))
+                            .or_default()
+                            .push(SoundingNote {
+                                start: tick,
+                                track,
+                                velocity: vel.as_int(),
+                                bend_cents: state.bend_cents(),
+                                program: state.program,
+                            });
+                    }
+                    MidiMessage::NoteOn { key, vel } | MidiMessage::NoteOff { key, vel } => {
+                        let Some(queue) = sounding.get_mut(&(channel, key.as_int())) else {
+                            continue;
+                        };
+                        if queue.is_empty() {
+                            continue;
+                        }
+                        let note = queue.remove(0);
+                        let voice = voice_of(note.track);
+                        events.push((
+                            note.start,
+                            clock.note(&note, channel, key.as_int(), tick, vel.as_int(), voice),
+                        ));
+                    }
+                    MidiMessage::PitchBend { bend } => state.bend = bend.0.as_int(),
+                    MidiMessage::ProgramChange { program } => {
+                        state.program = Some(program.as_int())
+                    }
+                    MidiMessage::Controller { controller, value } => {
+                        state.control(controller.as_int(), value.as_int());
+                    }
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // notes still sounding at the end of the file end with it
+    let end = timeline.last().map_or(0, |&(tick, _, _)| tick);
+    let mut unterminated: Vec<_> = sounding
+        .into_iter()
+        .flat_map(|((channel, key), queue)| queue.into_iter().map(move |n| (channel, key, n)))
+        .filter(|(_, _, note)| note.start < end)
+        .collect();
+    unterminated.sort_by_key(|(channel, key, note)| (note.start, *channel, *key));
+    for (channel, key, note) in unterminated {
+        let voice = voice_of(note.track);
+        events.push((note.start, clock.note(&note, channel, key, end, 0, voice)));
+    }
+
+    events.sort_by_key(|(tick, _)| *tick);
+    Ok(events.into_iter().map(|(_, event)| event).collect())
+}
+
+/// Text of a meta event, decoded as UTF-8 with invalid bytes replaced.
+fn decode_text(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes).into_owned()
+}
+
+/// A NoteOn waiting for its NoteOff.
+struct SoundingNote {
+    start: u64,
+    track: usize,
+    velocity: u8,
+    bend_cents: f64,
+    program: Option<u8>,
+}
+
+/// Pitch state of a MIDI channel.
+#[derive(Clone, Copy)]
+struct ChannelState {
+    bend: u16,
+    bend_range_cents: u32,
+    /// Registered parameter selected by CC101/CC100
+    rpn: (u8, u8),
+    program: Option<u8>,
+}
+
+impl Default for ChannelState {
+    fn default() -> Self {
+        Self {
+            bend: 0x2000,
+            bend_range_cents: DEFAULT_BEND_RANGE_CENTS,
+            rpn: (127, 127),
+            program: None,
+        }
+    }
+}
+
+impl ChannelState {
+    fn bend_cents(&self) -> f64 {
+        (f64::from(self.bend) - 8192.0) / 8192.0 * f64::from(self.bend_range_cents)
+    }
+
+    fn control(&mut self, controller: u8, value: u8) {
+        let value = u32::from(value);
+        match controller {
+            CC_RPN_MSB => self.rpn.0 = value as u8,
+            CC_RPN_LSB => self.rpn.1 = value as u8,
+            CC_DATA_ENTRY_MSB if self.rpn == (0, 0) => {
+                self.bend_range_cents = value * 100 + self.bend_range_cents % 100;
+            }
+            CC_DATA_ENTRY_LSB if self.rpn == (0, 0) => {
+                self.bend_range_cents = self.bend_range_cents / 100 * 100 + value.min(99);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Converts ticks of the file to time stamps.
+struct Clock {
+    timing: Timing,
+    tempo: TempoMap,
+    /// Time signatures from their position on: start position, bar length, first bar number
+    bars: Vec<(Rational64, Rational64, u32)>,
+}
+
+impl Clock {
+    fn new(timing: Timing, timeline: &[(u64, usize, TrackEventKind)]) -> Result<Self> {
+        match timing {
+            Timing::Metrical(tpq) if tpq.as_int() == 0 => {
+                bail!("MIDI file has zero ticks per quarter note")
+            }
+            Timing::Timecode(_, 0) => bail!("MIDI file has zero subframes per frame"),
+            _ => {}
+        }
+        let mut clock = Self {
+            timing,
+            tempo: TempoMap::new(2.0),
+            bars: vec![(Rational64::zero(), Rational64::new(1, 1), 0)],
+        };
+        for &(tick, _, kind) in timeline {
+            if let TrackEventKind::Meta(MetaMessage::Tempo(mpq)) = kind {
+                let position = clock.position(tick);
+                clock
+                    .tempo
+                    .set_tempo(position, f64::from(mpq.as_int()) * 4.0 / 1_000_000.0);
+            }
+        }
+        for &(tick, _, kind) in timeline {
+            if let TrackEventKind::Meta(MetaMessage::TimeSignature(numerator, power, _, _)) = kind {
+                let position = clock.position(tick);
+                let (bar, offset) = clock.bar_at(position);
+                let bar = if offset.is_zero() { bar } else { bar + 1 };
+                let length = Rational64::new(i64::from(numerator.max(1)), 1 << power.min(62));
+                clock.bars.retain(|&(start, _, _)| start < position);
+                clock.bars.push((position, length, bar));
+            }
+        }
+        Ok(clock)
+    }
+
+    /// Whole notes from the start at `tick`.
+    fn position(&self, tick: u64) -> Rational64 {
+        match self.timing {
+            Timing::Metrical(tpq) => {
+                Rational64::new(tick as i64, 4 * i64::from(tpq.as_int())).reduce()
+            }
+            Timing::Timecode(..) => {
+                let position = self.tempo.position_at(self.seconds(tick));
+                Rational64::new(
+                    (position * TIMECODE_GRID as f64).round() as i64,
+                    TIMECODE_GRID,
+                )
+                .reduce()
+            }
+        }
+    }
+
+    fn seconds(&self, tick: u64) -> f64 {
+        match self.timing {
+            Timing::Metrical(tpq) => self
+                .tempo
+                .seconds_at(Rational64::new(tick as i64, 4 * i64::from(tpq.as_int()))),
+            Timing::Timecode(fps, subframes) => {
+                tick as f64 / (f64::from(fps.as_f32()) * f64::from(subframes))
+            }
+        }
+    }
+
+    /// Bar number and offset within the bar at `position`.
+    fn bar_at(&self, position: Rational64) -> (u32, Rational64) {
+        let index = self
+            .bars
+            .partition_point(|&(start, _, _)| start <= position)
+            .saturating_sub(1);
+        let (start, length, first_bar) = self.bars[index];
+        let elapsed = position - start;
+        let whole_bars = (elapsed / length).reduce();
+        let count = whole_bars.numer().div_euclid(*whole_bars.denom());
+        (
+            first_bar + count as u32,
+            (elapsed - length * count).reduce(),
+        )
+    }
+
+    fn stamp(&self, tick: u64) -> TimeStamp {
+        let position = self.position(tick);
+        let (bars, ticks) = self.bar_at(position);
+        TimeStamp {
+            seconds: self.seconds(tick),
+            bars,
+            ticks,
+            position,
+        }
+    }
+
+    fn event(&self, body: EventBody, tick: u64, voice: Option<SmolStr>) -> CompileEvent {
+        CompileEvent {
+            body,
+            start_time: self.stamp(tick),
+            range: TextRange::default(),
+            range_invoked: None,
+            macro_trace: Vec::new(),
+            voice,
+            channel: None,
+            instrument: None,
+        }
+    }
+
+    fn note(
+        &self,
+        sounding: &SoundingNote,
+        channel: u8,
+        key: u8,
+        end: u64,
+        release: u8,
+        voice: Option<SmolStr>,
+    ) -> CompileEvent {
+        let start_time = self.stamp(sounding.start);
+        let end_time = self.stamp(end);
+        let drum = channel == PERCUSSION_CHANNEL;
+        let cents = if drum { 0.0 } else { sounding.bend_cents };
+        let freq = BASE_FREQUENCY
+            * 2f64.powf((f64::from(key) - f64::from(BASE_NOTE)) / 12.0 + cents / 1200.0);
+        let spelled = Pitch::SpellOctave(i16::from(key));
+        let pitch_chain = if drum {
+            vec![Pitch::Frequency(freq)]
+        } else if cents.abs() < 1e-6 {
+            vec![spelled]
+        } else {
+            vec![Pitch::Cents(cents), spelled]
+        };
+        let note = Note {
+            pitch_chain,
+            freq,
+            duration: (end_time.position - start_time.position).reduce(),
+            duration_seconds: end_time.seconds - start_time.seconds,
+            pitch_ratio: freq / BASE_FREQUENCY,
+            drum_key: drum.then_some(key),
+            portamento_from: None,
+            bend_envelope: None,
+            volume: 1.0,
+            velocity: (sounding.velocity != DEFAULT_VELOCITY).then_some(sounding.velocity),
+            release: (release > 0).then_some(release),
+            tie: false,
+            vibrato: false,
+        };
+        CompileEvent {
+            body: EventBody::Note(note),
+            start_time,
+            range: TextRange::default(),
+            range_invoked: None,
+            macro_trace: Vec::new(),
+            voice,
+            channel: Some(channel),
+            instrument: sounding.program,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{
+        compiler::compile::Compiler,
+        midi::writer::{MidiWriterConfig, export_smf_format1},
+        rowan::parse_fn::parse_source,
+    };
+
+    #[test]
+    fn read_back_exported_piece() {
+        let parsed = parse_source(Arc::from("(3/4)\n(90)\nC4,E4:G4,,450.0,,\n"));
+        let mut compiler = Compiler::new();
+        compiler.compile(&parsed.syntax_node());
+        let bytes = export_smf_format1(&compiler.events, MidiWriterConfig::default())
+            .expect("midi export should succeed");
+
+        let events = read_smf(&bytes).expect("midi import should succeed");
+        assert!(
+            events
+                .iter()
+                .any(|e| matches!(&e.body, EventBody::BPMDef(bpm) if (bpm - 90.0).abs() < 1e-3))
+        );
+        assert!(
+            events
+                .iter()
+                .any(|e| e.body == EventBody::TimeSignatureDef(TimeSignature::simple(3, 4)))
+        );
+
+        let expected: Vec<_> = compiler
+            .events
+            .iter()
+            .filter_map(|e| match &e.body {
+                EventBody::Note(n) if n.freq > 0.0 => {
+                    Some((e.start_time.position, n.duration, n.freq))
+                }
+                _ => None,
+            })
+            .collect();
+        let mut notes: Vec<_> = events
+            .iter()
+            .filter_map(|e| match &e.body {
+                EventBody::Note(n) => Some((e.start_time.position, n.duration, n.freq)),
+                _ => None,
+            })
+            .collect();
+        notes.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap().then(a.2.total_cmp(&b.2)));
+        assert_eq!(notes.len(), expected.len());
+        for ((position, duration, freq), (want_position, want_duration, want_freq)) in
+            notes.iter().zip(&expected)
+        {
+            assert_eq!(position, want_position);
+            assert_eq!(duration, want_duration);
+            let cents = 1200.0 * (freq / want_freq).log2();
+            assert!(
+                cents.abs() < 0.1,
+                "{freq} Hz is {cents} cents off {want_freq} Hz"
+            );
+        }
+
+        let last = events
+            .iter()
+            .rev()
+            .find(|e| matches!(e.body, EventBody::Note(_)))
+            .unwrap();
+        assert_eq!(last.start_time.bars, 1);
+        assert!(last.start_time.ticks.is_zero());
+        assert!((last.start_time.seconds - 2.0).abs() < 1e-4);
+    }
+}
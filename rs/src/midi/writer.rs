@@ -1,22 +1,52 @@
 /*
 * symi转midi接收以下参数：
 *   - 输入events: Vec<CompileEvent>
-*   - 弯音事件最大半音数定义(RPN): u16
-*   - MIDI事件分辨率: u32
+*   - 弯音事件最大半音数定义(RPN): u16，另可附加音分（写入RPN数据输入LSB，如2.5个半音）
+*   - MIDI事件分辨率: u32，或SMPTE时间码（帧率与每帧子帧数）
 *   - 时间容差（秒）: f64
 *   - 音高容差（音分）: f64
 *
+* 按声部分别导出（export_split_by_voice）时，每个声部单独按以下流程导出一个文件：
+*   文件含该声部的音符、歌词与设置，以及各声部共用的速度、拍号、调号、段落标记等；
+*   无声部的音符合为一个文件（main），打击乐音符合为一个文件（drums）
+*
 * 具体操作流程为：
 *  1. 先收集所有拍号、BPM变化事件，构建“基于秒反推MIDI tick”的时间转换关系，并构建元事件列表
+*    - 设置了导出范围（start_second、end_second）时，只导出范围内的事件，时间从范围开头算起；
+*      范围开头之前的各类设置（速度、拍号、调号、音量等）移到开头生效，跨越范围边界的音符在边界处截断
 *    - 拍号使用 TimeSignatureDef；若分母不是2的幂，立即返回错误并中断导出
+*      加法拍号（如 3+2/8）按各拍组之和写入分子，节拍器按分母音符计拍
 *    - BPM由 BeatDurationDef + BPMDef 共同定义：
 *      BeatDurationDef 定义“以什么音符为一拍”，BPMDef 定义“一分钟有多少拍”
 *      需要转换成MIDI支持的“每分钟四分音符拍数（quarter-note BPM）”后写入Tempo元事件
+*    - 使用SMPTE时间码时，tick直接按秒换算（帧率×每帧子帧数），不随速度变化；Tempo元事件仍按其时间写出
+*    - 歌词由 Lyric 事件定义，每个音节写成一个Lyric元事件：
+*      放入其所唱音符（同声部、同时开始）所在的Track，与该音符的NoteOn处于同一tick；找不到所唱音符时按其时间戳写入元事件轨
+*      词内音节以连字符相连，连字符统一写在前一音节末尾（"Hap-" "py" 与 "Hap" "-py" 等价）；"_" 表示拖腔，不写出
+*    - 音量（VolumeDef，`(volume ...)`）与声像（PanDef，`(pan ...)`）写入所属声部Track的控制器：
+*      音量为CC7（可改为CC11表情控制器），声像为CC10；带 `~` 的设置按设定频率插值渐变到下一个同类设置，数值不变时不重复写出
+*      无声部的设置作用于所有自动分配的Track
+*    - 延音踏板（SustainPedal，`(pedal down)`、`(pedal up)`）同样按声部写入CC64（踏下127、松开0），供采样钢琴等音源使用；
+*      可在 controller_automation 中关闭
+*    - 带颤音记号（`~vib`）的音组在前一半时长内将Channel Aftertouch（可改为CC1调制轮）按设定频率从0渐增到127，
+*      保持到音组结束时归0，使导出的演奏保留颤音表情
+*    - 连续三个以上同向变化的速度视为渐变（accelerando/ritardando）；设置了 tempo_ramp_steps 时，
+*      渐变中的每一段拆成若干个逐步过渡的Tempo元事件，每段的tick长度与平均速度不变，音符仍按原速度换算tick
+*    - 调号由 KeySignatureDef 事件（`(key ...)` 指令）定义，写入KeySignature元事件；
+*      曲中没有调号指令时，由基准音（BaseNoteDef）推断为以其为主音、升降号最少的大调
+*    - 段落标记由 Marker 事件定义（`(section "...")`），按其时间戳写入元事件轨的Marker元事件
+*    - 提示点由 CuePoint 事件定义（`(cue "...")`），写入元事件轨的CuePoint元事件，供采样器与DAW定位
+*    - 循环区间由 LoopStart、LoopEnd 事件定义（`(loop start)`、`(loop end)`），写成成对的 "loopStart"、"loopEnd" Marker元事件：
+*      每个开头与其后的第一个结尾配对，没有结尾的循环延续到乐曲末尾，多余的开头与结尾不写出；
+*      导出范围开头处于循环之中时，循环从范围开头算起
+*    - 曲目元数据（PieceMetadata）写入元事件轨开头：标题为TrackName，版权为Copyright，作曲者为Text
 *  2. 将所有NoteEvent在时间轴上布局，具体规则如下：
 *    - 原则上每个Track在同一时刻只能有一个激活的NoteEvent
 *    - 允许例外：满足“可同轨合并”条件时，同一Track同一时刻可以有多个NoteEvent
-*    - 任何NoteEvent优先放入index更低的Track
-*    - 在“优先低index轨道”基础上，若存在并列可选方案，再选择使该Track与上一个NoteEvent音高跳变最小的方案
+*    - 按分轨策略（TrackAssignment）选择可放置的Track：缺省优先放入index更低的Track；
+*      PitchProximity 选择与该Track上一个音组音高跳变最小的Track，并列时取index更低的
+*    - ExplicitVoice 策略只按声部分轨，无声部的NoteEvent共用一个Track，允许重叠；
+*      SingleTrack 策略将所有旋律NoteEvent（不论声部与通道）写入同一个Track和通道，允许重叠
 *    - 如果一个NoteEven
 *
 t在某个Track上与已有的NoteEvent时间重叠，则将其放入下一个Track，直到找到可放置Track
@@ -24,66 +54,403 @@ t在某个Track上与已有的NoteEvent时间重叠，则将其放入下一个Tr
 *    - 对于每个NoteEvent，根据频率计算MIDI note number和Pitch Bend值
 *    - 若两个或多个同时开始的NoteEvent，其Pitch Bend对应音分差小于音高容差，则可同轨合并，Pitch Bend取平均值
 *    - Rest事件直接忽略，不生成NoteOn/NoteOff
-*    - 全局使用同一个RPN Pitch Bend Range设置
+*    - 打击乐音符（`drums:` 行）使用固定的GM打击乐音高，统一写入通道10的打击乐Track，不参与自动分配，也不写Pitch Bend；旋律Track跳过通道10
+*      配置为打击乐声部（percussion_voices）的声部同样写入打击乐Track，其音高按键位映射（key_map）换成打击乐音高；
+*      为旋律音符指定通道10视为错误
+*    - 带声部前缀（如 `v2:`）的NoteEvent固定放入该声部专属的Track，不参与上述自动分配，也不与其他声部同轨合并
+*    - 指定了通道（channel）的NoteEvent同样放入专属Track并使用该通道；自动分配的Track跳过已被指定的通道
+*    - 第一个端口的通道用尽时，其余自动分配的Track依次使用下一个MIDI端口的旋律通道（同样跳过通道10），
+*      此时每个Track开头写入MIDI Port元事件标明其端口；指定了通道的Track与打击乐Track使用第一个端口
+*    - 指定了音色（instrument，由 `(instrument ...)` 指令设定）的Track在开头写入Program Change，
+*      之后音色切换时在切换处的NoteEvent开始处写入；音色不同的NoteEvent不同轨合并
+*    - 设置了人性化（Humanize）时，按种子确定地随机偏移音符的起止时间与力度；
+*      同一声部中同时开始或结束的音符偏移相同，和弦仍然齐奏，连奏仍然相接
+*    - 力度由音符的velocity（缺省为100）与音量系数得到响度，再经力度曲线（VelocityCurve）映射为NoteOn力度
+*      NoteOff力度（松开力度）取音符的release，未设置时取 note_off_velocity（缺省为0）
+*    - 全局使用同一个RPN Pitch Bend Range设置：半音数写入CC6，附加音分写入CC38
+*      可按声部单独设置（voice_bend_ranges，如滑音的主旋律用48个半音、铺底用2个半音），该声部的Track按其自己的范围写入RPN并计算Pitch Bend；
+*      同一通道上的Track范围不同视为错误；SingleTrack 策略下各声部共用全局设置
+*    - MTS模式下不写音组的Pitch Bend，改为在音组开始处发送MTS单音调音SysEx，将各音符所用的键调到其精确频率；
+*      每个通道经RPN选择与通道号相同的调音程序，互不影响；弯音包络仍以Pitch Bend写出，只含包络本身的偏移
 *  3. 将所有元事件和NoteEvent转换为MIDI事件，按时间顺序排序，输出SMF Format 1标准MIDI文件Buffer
+*
+* 导出前可用 validate_export 检查：逐个列出导出会失败或失真的问题（配置无效、通道不足、使用额外MIDI端口、
+* 弯音超出范围、音符无时长、超出MIDI音高范围、通道不可用、循环边界不成对），附带出问题的事件的源码范围；没有音符出错时再试导出一次
 */
+use std::{collections::HashMap, mem};
+
 use anyhow::{Result, bail};
 use midly::{
-    Format, Header, MetaMessage, MidiMessage, PitchBend, Smf, Timing, TrackEvent, TrackEventKind,
+    Format, Fps, Header, MetaMessage, MidiMessage, PitchBend, Smf, Timing, TrackEvent,
+    TrackEventKind,
     num::{u4, u7, u14, u15, u24, u28},
 };
+use rowan::TextRange;
 
 use crate::compiler::{
-    rational::Rational32,
-    types::{CompileEvent, EventBody, Note},
+    piece::CompiledPiece,
+    playback::playback_events,
+    random::SeededRng,
+    timeline::Timeline,
+    types::{CompileEvent, DEFAULT_VELOCITY, EventBody, KeySignature, Note, PieceMetadata},
 };
 
-#[derive(Debug, Clone, Copy)]
+/// How the writer carries pitches between the 12-TET keys.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MidiTuning {
+    /// A Pitch Bend per note group, within the RPN Pitch Bend Range
+    #[default]
+    PitchBend,
+    /// MIDI Tuning Standard single-note tuning changes retuning each key to its exact
+    /// frequency, on a tuning program per channel
+    Mts,
+}
+
+/// How notes without a voice or channel of their own are laid out on tracks, each track
+/// on a channel of its own.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TrackAssignment {
+    /// Each note group on the lowest-index track free when it starts
+    #[default]
+    LowestIndex,
+    /// Each note group on the free track whose last group is nearest in pitch
+    PitchProximity,
+    /// Tracks by voice only: notes without a voice share one track, overlapping or not
+    ExplicitVoice,
+    /// Every pitched note on one track and channel, overlapping or not, whatever its voice
+    /// or channel; overlapping note groups share its pitch bend
+    SingleTrack,
+}
+
+/// Time base of the exported file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MidiTiming {
+    /// Ticks per quarter note, `ticks_per_quarter` of them, following the tempo
+    #[default]
+    Metrical,
+    /// SMPTE timecode ticks, `subframes` per frame at `fps` frames per second (24, 25, 29 for
+    /// 29.97 drop-frame, or 30), whatever the tempo
+    Timecode { fps: u8, subframes: u8 },
+}
+
+/// Maps the loudness of a note, its volume times its velocity relative to
+/// [`DEFAULT_VELOCITY`], to the velocity of its NoteOn.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VelocityCurve {
+    /// Velocity of a note at full loudness
+    pub full: f32,
+    /// Shape of the curve: 1 is linear, above 1 makes soft notes softer, below 1 louder
+    pub exponent: f32,
+    pub min: u8,
+    pub max: u8,
+}
+
+impl Default for VelocityCurve {
+    fn default() -> Self {
+        Self {
+            full: f32::from(DEFAULT_VELOCITY),
+            exponent: 1.0,
+            min: 1,
+            max: 127,
+        }
+    }
+}
+
+impl VelocityCurve {
+    /// NoteOn velocity of a note of loudness `gain`, see [`Note::gain`].
+    pub fn velocity(&self, gain: f32) -> u8 {
+        let (min, max) = (self.min.clamp(1, 127), self.max.clamp(1, 127));
+        (self.full * gain.max(0.0).powf(self.exponent))
+            .round()
+            .clamp(f32::from(min), f32::from(max.max(min))) as u8
+    }
+}
+
+/// How volume and pan settings are written as controller automation.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ControllerAutomation {
+    /// Controller changes per second along a volume or pan ramp
+    pub rate_hz: f64,
+    /// Writes volume to Expression (CC11) instead of Channel Volume (CC7)
+    pub expression: bool,
+    /// Writes pedal directives as Sustain Pedal (CC64) presses and releases
+    pub sustain_pedal: bool,
+    /// Writes vibrato marks to Modulation (CC1) instead of Channel Aftertouch
+    pub vibrato_modulation: bool,
+}
+
+impl Default for ControllerAutomation {
+    fn default() -> Self {
+        Self {
+            rate_hz: 20.0,
+            expression: false,
+            sustain_pedal: true,
+            vibrato_modulation: false,
+        }
+    }
+}
+
+/// Random deviations from the written timing and velocity, the same for the same seed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Humanize {
+    /// Largest shift of a note start or end, in seconds, either way
+    pub timing_seconds: f64,
+    /// Largest change of a note velocity, either way
+    pub velocity: u8,
+    pub seed: u64,
+}
+
+/// A voice played as drum hits on the percussion channel.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PercussionVoice {
+    pub voice: String,
+    /// `(key, drum key)` pairs mapping the MIDI keys of the voice's notes to General MIDI
+    /// percussion keys; other keys are played as they are
+    pub key_map: Vec<(u8, u8)>,
+}
+
+impl PercussionVoice {
+    pub(crate) fn drum_key(&self, key: u8) -> u8 {
+        self.key_map
+            .iter()
+            .find(|&&(from, _)| from == key)
+            .map_or(key, |&(_, drum)| drum.min(127))
+    }
+}
+
+/// Pitch bend range of the tracks of a voice, in place of the one of the whole piece.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VoiceBendRange {
+    pub voice: String,
+    pub semitones: u16,
+    /// Cents added to `semitones`, below 100
+    pub cents: u8,
+}
+
+#[derive(Debug, Clone)]
 pub struct MidiWriterConfig {
     pub pitch_bend_range_semitones: u16,
+    /// Cents added to the pitch bend range, for ranges such as 2.5 semitones
+    pub pitch_bend_range_cents: u8,
     pub ticks_per_quarter: u32,
+    pub timing: MidiTiming,
     pub time_tolerance_seconds: f64,
     pub pitch_tolerance_cents: f64,
+    pub tuning: MidiTuning,
+    pub track_assignment: TrackAssignment,
+    pub velocity_curve: VelocityCurve,
+    /// Release velocity of the NoteOffs of notes without a release of their own; some
+    /// samplers pick release samples by it
+    pub note_off_velocity: u8,
+    /// Tempos each segment of a tempo ramp is written as; 0 or 1 writes tempo changes as given
+    pub tempo_ramp_steps: u32,
+    pub controller_automation: ControllerAutomation,
+    pub humanize: Option<Humanize>,
+    /// Voices written to the percussion track, besides the notes of `drums:` lines
+    pub percussion_voices: Vec<PercussionVoice>,
+    /// Voices whose tracks set a pitch bend range of their own on their channels; they all
+    /// share the one above with [`TrackAssignment::SingleTrack`]
+    pub voice_bend_ranges: Vec<VoiceBendRange>,
+    /// Start of the exported part of the piece, which the exported times count from
+    pub start_second: f64,
+    /// End of the exported part of the piece, the whole rest of it when unset
+    pub end_second: Option<f64>,
 }
 
 impl Default for MidiWriterConfig {
     fn default() -> Self {
         Self {
             pitch_bend_range_semitones: 2,
+            pitch_bend_range_cents: 0,
             ticks_per_quarter: 480,
+            timing: MidiTiming::Metrical,
             time_tolerance_seconds: 1e-4,
             pitch_tolerance_cents: 3.0,
+            tuning: MidiTuning::PitchBend,
+            track_assignment: TrackAssignment::LowestIndex,
+            velocity_curve: VelocityCurve::default(),
+            note_off_velocity: 0,
+            tempo_ramp_steps: 0,
+            controller_automation: ControllerAutomation::default(),
+            humanize: None,
+            percussion_voices: Vec::new(),
+            voice_bend_ranges: Vec::new(),
+            start_second: 0.0,
+            end_second: None,
         }
     }
 }
 
+/// What a [`MidiExportIssue`] is about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+pub enum MidiExportIssueKind {
+    /// Settings of the export that cannot be written, such as a resolution of 0
+    InvalidConfig,
+    /// A note track left without a MIDI channel
+    TooManyTracks,
+    /// A note track past the channels of the first MIDI port, on a port players may not route
+    ExtraPort,
+    /// A pitch bend beyond the pitch bend range, clipped at its edge
+    BendOutOfRange,
+    /// A note without length or frequency, which cannot be written
+    ZeroLengthNote,
+    /// A note below MIDI key 0 or above key 127, played on the nearest key
+    KeyOutOfRange,
+    /// A note on a channel it cannot take
+    InvalidChannel,
+    /// A loop start or end without a partner, left out or closed at the end of the piece
+    UnpairedLoop,
+    /// Anything else the export fails on
+    Unexportable,
+}
+
+impl MidiExportIssueKind {
+    /// Whether the export fails on it; the others only cost accuracy.
+    pub fn is_error(self) -> bool {
+        !matches!(
+            self,
+            Self::BendOutOfRange | Self::KeyOutOfRange | Self::ExtraPort | Self::UnpairedLoop
+        )
+    }
+}
+
+/// A problem of exporting a piece, at the source of the event it comes from.
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct MidiExportIssue {
+    pub kind: MidiExportIssueKind,
+    pub message: String,
+    /// Source of the event, `None` for problems of the whole export
+    pub range: Option<TextRange>,
+}
+
+/// Every problem [`validate_export`] finds, errors and warnings alike.
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize)]
+pub struct MidiExportReport {
+    pub issues: Vec<MidiExportIssue>,
+}
+
+impl MidiExportReport {
+    /// Whether exporting with the same settings would fail.
+    pub fn has_errors(&self) -> bool {
+        self.issues.iter().any(|issue| issue.kind.is_error())
+    }
+
+    fn push(&mut self, kind: MidiExportIssueKind, message: String, range: Option<TextRange>) {
+        self.issues.push(MidiExportIssue {
+            kind,
+            message,
+            range,
+        });
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
-struct TempoPoint {
+pub(crate) struct TempoPoint {
     second: f64,
     mpq: u32,
     start_tick: u64,
 }
 
 #[derive(Debug, Clone, Copy)]
-struct RawTempoPoint {
+pub(crate) struct RawTempoPoint {
     second: f64,
     mpq: u32,
 }
 
 #[derive(Debug, Clone, Copy)]
-struct MetaPoint {
+pub(crate) struct MetaPoint {
+    pub(crate) second: f64,
+    pub(crate) numerator: u8,
+    pub(crate) denominator: u8,
+    /// MIDI clocks (24 per quarter note) between metronome clicks
+    clocks_per_click: u8,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct TextPoint<'a> {
+    second: f64,
+    text: &'a str,
+}
+
+/// What the meta track carries besides the piece metadata, each kind in time order.
+#[derive(Debug, Clone)]
+struct MetaTrackPoints<'a> {
+    /// Tempo changes to write, their ramps stepped `tempo_ramp_steps` times
+    tempos: Vec<TempoPoint>,
+    tempo_ramp_steps: u32,
+    time_signatures: Vec<MetaPoint>,
+    key_signatures: Vec<KeyPoint>,
+    markers: Vec<TextPoint<'a>>,
+    cue_points: Vec<TextPoint<'a>>,
+    /// Lyrics no note track takes
+    lyrics: Vec<TextPoint<'a>>,
+}
+
+/// A loop region as exported, from a `(loop start)` to the next `(loop end)`.
+#[derive(Debug, Clone, Copy)]
+struct LoopRegion {
+    start_second: f64,
+    /// `None` when the loop runs to the end of the piece
+    end_second: Option<f64>,
+    /// Source of the loop start
+    range: TextRange,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct KeyPoint {
     second: f64,
-    numerator: u8,
-    denominator: u8,
+    key: KeySignature,
 }
 
+/// A volume, pan or pedal setting of a voice, as a controller level from 0 to 1.
 #[derive(Debug, Clone, Copy)]
+struct MixPoint {
+    second: f64,
+    voice: Option<usize>,
+    controller: u8,
+    value: f64,
+    ramp: bool,
+}
+
+/// A lyric syllable as exported, hyphenated when the next syllable continues its word.
+#[derive(Debug, Clone)]
+struct LyricPoint {
+    second: f64,
+    voice: Option<usize>,
+    text: String,
+}
+
+/// A single-note tuning change SysEx, without the leading `0xF7`.
+#[derive(Debug, Clone)]
+struct TuningChange {
+    second: f64,
+    data: Vec<u8>,
+}
+
+#[derive(Debug, Clone)]
 struct NoteSpec {
     start_second: f64,
     end_second: f64,
     midi_key: u8,
     bend14: u16,
     bend_cents: f64,
+    /// Index of the explicitly assigned voice, in order of first appearance
+    voice: Option<usize>,
+    /// Explicitly assigned MIDI channel
+    channel: Option<u8>,
+    program: Option<u8>,
+    percussion: bool,
+    /// MIDI key the note glides from when marked with portamento
+    portamento_from_key: Option<u8>,
+    /// Pitch-bend breakpoints spread evenly across the note
+    bend_envelope: Option<Vec<u16>>,
+    /// Whether the note is marked with vibrato
+    vibrato: bool,
+    velocity: u8,
+    /// Velocity of the NoteOff
+    release: u8,
+    /// Source of the note, for the export report
+    range: TextRange,
 }
 
 #[derive(Debug, Clone)]
@@ -92,60 +459,444 @@ struct NoteGroup {
     end_second: f64,
     bend14: u16,
     bend_cents: f64,
+    voice: Option<usize>,
+    channel: Option<u8>,
+    program: Option<u8>,
+    bend_envelope: Option<Vec<u16>>,
     notes: Vec<NoteSpec>,
 }
 
 #[derive(Debug, Clone)]
 struct TrackLayout {
+    voice: Option<usize>,
+    channel: Option<u8>,
     groups: Vec<NoteGroup>,
 }
 
+impl TrackLayout {
+    /// Whether the track was opened for a voice or channel instead of by automatic assignment.
+    fn is_explicit(&self) -> bool {
+        self.voice.is_some() || self.channel.is_some()
+    }
+}
+
 #[derive(Debug, Clone)]
-struct AbsEvent {
+struct AbsEvent<'a> {
     tick: u64,
     priority: u8,
-    kind: TrackEventKind<'static>,
+    kind: TrackEventKind<'a>,
 }
 
 const PITCH_BEND_CENTER: i32 = 8192;
 const PITCH_BEND_MIN_SIGNED: i32 = -8192;
 const PITCH_BEND_MAX_SIGNED: i32 = 8191;
+/// General MIDI reserves channel 10 (index 9) for percussion.
+pub(crate) const PERCUSSION_CHANNEL: u8 = 9;
+/// Highest port a MIDI Port meta event can name.
+const MAX_MIDI_PORT: u8 = 127;
+/// A vibrato swells in over the first 1/VIBRATO_SWELL_PARTS of its note.
+const VIBRATO_SWELL_PARTS: f64 = 2.0;
+/// Portamento time (CC5) sent before a gliding note.
+const PORTAMENTO_TIME: u8 = 16;
+/// Interpolated pitch bends per segment of a bend envelope.
+pub(crate) const BEND_ENVELOPE_STEPS: u64 = 8;
+/// Highest pitch MTS frequency data can hold, in semitones; `7F 7F 7F` means "no change".
+const MTS_MAX_SEMITONES: f64 = 127.0 + 16382.0 / 16384.0;
+/// Keys a single-note tuning change may retune at once.
+const MTS_MAX_KEYS: usize = 127;
+/// Marker texts samplers and game engines read as the edges of a loop.
+const LOOP_START_MARKER: &str = "loopStart";
+const LOOP_END_MARKER: &str = "loopEnd";
 
 pub fn export_smf_format1(events: &[CompileEvent], config: MidiWriterConfig) -> Result<Vec<u8>> {
-    let tpq = normalize_tpq(config.ticks_per_quarter)?;
-    let (raw_tempos, time_signatures) = collect_tempo_and_signature(events)?;
-    let tempo_points = build_tempo_points(&raw_tempos, tpq);
+    export_smf_format1_with_metadata(events, &PieceMetadata::default(), config)
+}
 
-    let mut note_specs = collect_note_specs(events, config.pitch_bend_range_semitones)?;
-    note_specs.sort_by(|a, b| {
-        a.start_second
-            .total_cmp(&b.start_second)
-            .then_with(|| a.midi_key.cmp(&b.midi_key))
-    });
+pub fn export_smf_format1_with_metadata(
+    events: &[CompileEvent],
+    metadata: &PieceMetadata,
+    config: MidiWriterConfig,
+) -> Result<Vec<u8>> {
+    write_smf(
+        CompiledPiece::new(playback_events(events)),
+        metadata,
+        &config,
+    )
+}
 
-    let grouped = build_same_start_groups(note_specs, config.pitch_tolerance_cents);
-    let layouts = assign_groups_to_tracks(grouped, config.time_tolerance_seconds);
+pub fn export_split_by_voice(
+    events: &[CompileEvent],
+    config: MidiWriterConfig,
+) -> Result<Vec<(String, Vec<u8>)>> {
+    export_split_by_voice_with_metadata(events, &PieceMetadata::default(), config)
+}
+
+/// One SMF per voice, named after it, with the notes, lyrics and settings of the voice and
+/// everything the voices share. Notes without a voice make a part named `main`, drums one
+/// named `drums`; parts come in that order around the voices, which keep their own.
+pub fn export_split_by_voice_with_metadata(
+    events: &[CompileEvent],
+    metadata: &PieceMetadata,
+    config: MidiWriterConfig,
+) -> Result<Vec<(String, Vec<u8>)>> {
+    let events = playback_events(events);
+    // (voice, drums) of each part
+    let mut parts: Vec<(Option<&str>, bool)> = Vec::new();
+    for event in &events {
+        if let EventBody::Note(note) = &event.body
+            && !note.is_rest()
+        {
+            let part = match note.drum_key {
+                Some(_) => (None, true),
+                None => (event.voice.as_deref(), false),
+            };
+            if !parts.contains(&part) {
+                parts.push(part);
+            }
+        }
+    }
+    parts.sort_by_key(|&(voice, drums)| (drums, voice.is_some()));
+
+    parts
+        .into_iter()
+        .map(|(voice, drums)| {
+            let part: Vec<CompileEvent> = events
+                .iter()
+                .filter(|e| match &e.body {
+                    EventBody::Note(note) if note.drum_key.is_some() => drums,
+                    EventBody::Note(_) | EventBody::Lyric(_) => {
+                        !drums && e.voice.as_deref() == voice
+                    }
+                    _ => e.voice.is_none() || e.voice.as_deref() == voice,
+                })
+                .cloned()
+                .collect();
+            let name = match (voice, drums) {
+                (_, true) => "drums",
+                (Some(voice), false) => voice,
+                (None, false) => "main",
+            };
+            let smf = write_smf(CompiledPiece::new(part), metadata, &config)?;
+            Ok((name.to_string(), smf))
+        })
+        .collect()
+}
+
+/// Checks `events` for everything that would fail or lose accuracy when exported with
+/// `config` as a single file, listing each problem at the source of its note. When no note
+/// is at fault the export is tried, and a failure reported for the whole piece.
+pub fn validate_export(events: &[CompileEvent], config: &MidiWriterConfig) -> MidiExportReport {
+    use MidiExportIssueKind::*;
+
+    let mut report = MidiExportReport::default();
+    let mut piece = CompiledPiece::new(playback_events(events));
+    if config.start_second != 0.0 || config.end_second.is_some() {
+        match window_events(&piece, config.start_second, config.end_second) {
+            Ok(window) => piece = CompiledPiece::new(window),
+            Err(e) => {
+                report.push(InvalidConfig, e.to_string(), None);
+                return report;
+            }
+        }
+    }
+    let timing = match config.timing {
+        MidiTiming::Metrical => normalize_tpq(config.ticks_per_quarter).map(drop),
+        MidiTiming::Timecode { fps, subframes } => timecode_clock(fps, subframes).map(drop),
+    };
+    if let Err(e) = timing {
+        report.push(InvalidConfig, e.to_string(), None);
+    }
+    let voices = collect_voices(&piece.events);
+    let bend_ranges = match BendRanges::new(config, &voices) {
+        Ok(ranges) => ranges,
+        Err(e) => {
+            report.push(InvalidConfig, e.to_string(), None);
+            return report;
+        }
+    };
+
+    let (loops, unpaired) = collect_loops(&piece);
+    for event in unpaired {
+        let message = match event.body {
+            EventBody::LoopStart => "Loop start inside another loop is left out",
+            _ => "Loop end without a loop start is left out",
+        };
+        report.push(UnpairedLoop, message.to_string(), Some(event.range));
+    }
+    for region in loops.iter().filter(|region| region.end_second.is_none()) {
+        let message = "Loop without an end runs to the end of the piece".to_string();
+        report.push(UnpairedLoop, message, Some(region.range));
+    }
+
+    for event in &piece.events {
+        let EventBody::Note(note) = &event.body else {
+            continue;
+        };
+        if note.is_rest() {
+            continue;
+        }
+        let range = Some(event.range);
+        if note.freq <= 0.0 || note.duration_seconds <= 0.0 {
+            let message = "Note has no length or pitch to export".to_string();
+            report.push(ZeroLengthNote, message, range);
+            continue;
+        }
+        let percussion = note.drum_key.is_some()
+            || event
+                .voice
+                .as_deref()
+                .is_some_and(|name| config.percussion_voices.iter().any(|p| p.voice == name));
+        if percussion {
+            continue;
+        }
+        if let Some(channel) = event.channel
+            && config.track_assignment != TrackAssignment::SingleTrack
+        {
+            if channel > 15 {
+                let message = format!("MIDI channel {} is out of range (0-15)", channel);
+                report.push(InvalidChannel, message, range);
+            } else if channel == PERCUSSION_CHANNEL {
+                let message = "MIDI channel 10 is reserved for percussion voices".to_string();
+                report.push(InvalidChannel, message, range);
+            }
+        }
+        let exact = 69.0 + 12.0 * (note.freq / 440.0).log2();
+        if !(-0.5..127.5).contains(&exact) {
+            let message = format!("{:.1} Hz is outside the MIDI key range", note.freq);
+            report.push(KeyOutOfRange, message, range);
+            continue;
+        }
+        // MTS tuning leaves only the bend envelope to the pitch bend
+        let offset = match config.tuning {
+            MidiTuning::PitchBend => (exact - exact.round()) * 100.0,
+            MidiTuning::Mts => 0.0,
+        };
+        let envelope = note.bend_envelope.iter().flat_map(|e| &e.cents);
+        let peak = envelope
+            .map(|&cents| (offset + f64::from(cents)).abs())
+            .fold(offset.abs(), f64::max);
+        let voice = event
+            .voice
+            .as_deref()
+            .and_then(|name| voices.iter().position(|v| *v == name));
+        let bend_range = bend_ranges.of(voice);
+        if peak > f64::from(bend_range) {
+            let message = format!(
+                "Pitch bend of {:.1} cents exceeds the pitch bend range of {} cents",
+                peak, bend_range
+            );
+            report.push(BendOutOfRange, message, range);
+        }
+    }
+    if report.has_errors() {
+        return report;
+    }
+
+    // automatic tracks take the channels explicit ones leave, in order, then further ports
+    if let Ok(specs) = collect_note_specs(
+        &piece.events,
+        &voices,
+        &config.percussion_voices,
+        &bend_ranges,
+        config.velocity_curve,
+        config.note_off_velocity,
+        config.humanize,
+    ) {
+        let layouts = layout_note_tracks(
+            specs.into_iter().filter(|spec| !spec.percussion).collect(),
+            config,
+        );
+        let mut explicit: Vec<u8> = layouts.iter().filter_map(|l| l.channel).collect();
+        explicit.sort_unstable();
+        explicit.dedup();
+        let free = 15 - explicit.len();
+        let capacity = free + 15 * usize::from(MAX_MIDI_PORT);
+        let automatic: Vec<&TrackLayout> = layouts.iter().filter(|l| l.channel.is_none()).collect();
+        for (idx, layout) in automatic.iter().enumerate().skip(free) {
+            let range = layout.groups.first().map(|group| group.notes[0].range);
+            if idx >= capacity {
+                let message = format!(
+                    "No MIDI channel left for this note track: {} tracks share {} free channels",
+                    automatic.len(),
+                    capacity
+                );
+                report.push(TooManyTracks, message, range);
+            } else {
+                let message = format!(
+                    "This note track plays on MIDI port {}, which players ignoring MIDI Port events merge into port 1",
+                    (idx - free) / 15 + 2
+                );
+                report.push(ExtraPort, message, range);
+            }
+        }
+    }
+    if !report.has_errors()
+        && let Err(e) = write_smf(piece, &PieceMetadata::default(), config)
+    {
+        report.push(Unexportable, e.to_string(), None);
+    }
+    report
+}
 
-    if layouts.len() > 16 {
-        bail!("Too many note tracks ({}) for MIDI channels", layouts.len());
+/// Writes `piece`, made of playback events, as an SMF Format 1 buffer.
+fn write_smf(
+    mut piece: CompiledPiece,
+    metadata: &PieceMetadata,
+    config: &MidiWriterConfig,
+) -> Result<Vec<u8>> {
+    let raw_tempos = collect_tempos(&piece, config.start_second, config.end_second)?;
+    if config.start_second != 0.0 || config.end_second.is_some() {
+        piece = CompiledPiece::new(window_events(
+            &piece,
+            config.start_second,
+            config.end_second,
+        )?);
     }
+    let events = &piece.events;
+    let time_signatures = collect_time_signatures(&piece)?;
+    // `tempo_points` convert seconds to ticks; `tempos` are the tempo changes to write
+    let (timing, tpq, tempo_points, tempos) = match config.timing {
+        MidiTiming::Metrical => {
+            let tpq = normalize_tpq(config.ticks_per_quarter)?;
+            let tempo_points = build_tempo_points(&raw_tempos, tpq);
+            let timing = Timing::Metrical(u15::new(tpq));
+            (timing, tpq, tempo_points.clone(), tempo_points)
+        }
+        MidiTiming::Timecode { fps, subframes } => {
+            let (timing, tpq, clock) = timecode_clock(fps, subframes)?;
+            let tempos = raw_tempos
+                .iter()
+                .map(|point| TempoPoint {
+                    second: point.second,
+                    mpq: point.mpq,
+                    start_tick: seconds_to_tick(point.second, &clock, tpq),
+                })
+                .collect();
+            (timing, tpq, clock, tempos)
+        }
+    };
+    let voices = collect_voices(events);
+    let bend_ranges = BendRanges::new(config, &voices)?;
+    let lyrics = collect_lyrics(&piece, &voices);
+    let mut markers = collect_markers(&piece);
+    let piece_end = piece_end_second(events);
+    for region in collect_loops(&piece).0 {
+        let end_second = region.end_second.unwrap_or(piece_end);
+        markers.push(TextPoint {
+            second: region.start_second,
+            text: LOOP_START_MARKER,
+        });
+        markers.push(TextPoint {
+            second: end_second,
+            text: LOOP_END_MARKER,
+        });
+    }
+    // stable, so a loop ending where the next starts closes first
+    markers.sort_by(|a, b| a.second.total_cmp(&b.second));
+    let cue_points = collect_cue_points(&piece);
+    let key_signatures = collect_key_signatures(&piece);
+    let mix_points = collect_mix_points(&piece, &voices, config.controller_automation);
+
+    let (drum_specs, note_specs): (Vec<NoteSpec>, Vec<NoteSpec>) = collect_note_specs(
+        events,
+        &voices,
+        &config.percussion_voices,
+        &bend_ranges,
+        config.velocity_curve,
+        config.note_off_velocity,
+        config.humanize,
+    )?
+    .into_iter()
+    .partition(|spec| spec.percussion);
+    let layouts = layout_note_tracks(note_specs, config);
+
+    let channels = assign_channels(&layouts)?;
+    for (idx, (layout, &(port, channel))) in layouts.iter().zip(&channels).enumerate() {
+        let range = bend_ranges.of(layout.voice);
+        if layouts[..idx]
+            .iter()
+            .zip(&channels)
+            .any(|(other, &c)| c == (port, channel) && bend_ranges.of(other.voice) != range)
+        {
+            bail!(
+                "Tracks on MIDI channel {} of port {} need the same pitch bend range",
+                channel + 1,
+                port + 1
+            );
+        }
+    }
+    // a single port is left implicit
+    let multi_port = channels.iter().any(|&(port, _)| port > 0);
+    let (track_lyrics, meta_lyrics) =
+        place_lyrics(&lyrics, &layouts, config.time_tolerance_seconds);
+    // with MTS every channel retunes its own tuning program, numbered after the channel
+    let tuning_changes: Vec<Vec<TuningChange>> = match config.tuning {
+        MidiTuning::Mts => layouts
+            .iter()
+            .zip(&channels)
+            .map(|(layout, &(_, channel))| collect_tuning_changes(layout, channel))
+            .collect(),
+        MidiTuning::PitchBend => Vec::new(),
+    };
 
-    let mut tracks: Vec<Vec<TrackEvent<'static>>> = Vec::new();
-    tracks.push(build_meta_track(&tempo_points, &time_signatures, tpq));
-    for (channel, layout) in layouts.iter().enumerate() {
-        tracks.push(build_note_track(
+    let mut tracks: Vec<Vec<TrackEvent>> = Vec::new();
+    let meta_points = MetaTrackPoints {
+        tempos,
+        tempo_ramp_steps: config.tempo_ramp_steps,
+        time_signatures,
+        key_signatures,
+        markers,
+        cue_points,
+        lyrics: meta_lyrics,
+    };
+    tracks.push(build_meta_track(metadata, &meta_points, &tempo_points, tpq));
+    for (idx, (layout, (port, channel))) in layouts.iter().zip(channels).enumerate() {
+        let mut extra_events = controller_events(
+            &mix_points,
+            layout,
+            channel,
+            config.controller_automation.rate_hz,
+            &tempo_points,
+            tpq,
+        );
+        extra_events.extend(vibrato_events(
             layout,
-            channel as u8,
-            config.pitch_bend_range_semitones,
+            channel,
+            config.controller_automation,
             &tempo_points,
             tpq,
         ));
+        extra_events.extend(
+            track_lyrics[idx]
+                .iter()
+                .map(|lyric| lyric_event(lyric, &tempo_points, tpq)),
+        );
+        let mut track = build_note_track(
+            layout,
+            channel,
+            bend_ranges.of(layout.voice),
+            tuning_changes.get(idx).map(Vec::as_slice),
+            extra_events,
+            &tempo_points,
+            tpq,
+        );
+        if multi_port {
+            track.insert(0, midi_port_event(port));
+        }
+        tracks.push(track);
+    }
+    if !drum_specs.is_empty() {
+        let mut track = build_percussion_track(&drum_specs, &tempo_points, tpq);
+        if multi_port {
+            track.insert(0, midi_port_event(0));
+        }
+        tracks.push(track);
     }
 
     let smf = Smf {
         header: Header {
             format: Format::Parallel,
-            timing: Timing::Metrical(u15::new(tpq)),
+            timing,
         },
         tracks,
     };
@@ -155,98 +906,285 @@ pub fn export_smf_format1(events: &[CompileEvent], config: MidiWriterConfig) ->
     Ok(buffer)
 }
 
-fn normalize_tpq(tpq: u32) -> Result<u16> {
-    if tpq == 0 {
-        bail!("ticks_per_quarter must be > 0");
+/// Note tracks of `note_specs`, grouped and laid out as `config` asks.
+fn layout_note_tracks(
+    mut note_specs: Vec<NoteSpec>,
+    config: &MidiWriterConfig,
+) -> Vec<TrackLayout> {
+    note_specs.sort_by(|a, b| {
+        a.start_second
+            .total_cmp(&b.start_second)
+            .then_with(|| a.midi_key.cmp(&b.midi_key))
+    });
+    let grouped = build_same_start_groups(note_specs, config.pitch_tolerance_cents);
+    assign_groups_to_tracks(
+        grouped,
+        config.time_tolerance_seconds,
+        config.track_assignment,
+    )
+}
+
+/// Events of the part of the piece from `start` to `end` seconds, moved to start at 0.
+/// Settings made before `start`, the last of each kind for each voice, apply from its
+/// beginning, as does a loop open at `start`. Notes sounding across an edge are cut there,
+/// and lose their bend envelope; cut at the start, they lose their glide as well.
+pub(crate) fn window_events(
+    piece: &CompiledPiece,
+    start: f64,
+    end: Option<f64>,
+) -> Result<Vec<CompileEvent>> {
+    let end = end.unwrap_or(f64::INFINITY);
+    if !(start >= 0.0 && end > start) {
+        bail!("Invalid export range: {} to {} seconds", start, end);
     }
-    if tpq > 0x7FFF {
-        bail!("ticks_per_quarter exceeds MIDI metrical range (32767)");
+    let mut window: Vec<CompileEvent> = Vec::new();
+    let mut open_loop: Option<&CompileEvent> = None;
+    for event in piece
+        .iter_sorted()
+        .take_while(|e| e.start_time.seconds < start)
+    {
+        match event.body {
+            EventBody::Note(_)
+            | EventBody::NewMeasure(_)
+            | EventBody::Lyric(_)
+            | EventBody::Marker(_)
+            | EventBody::CuePoint(_) => continue,
+            EventBody::LoopStart => {
+                open_loop.get_or_insert(event);
+                continue;
+            }
+            EventBody::LoopEnd => {
+                open_loop = None;
+                continue;
+            }
+            _ => {}
+        }
+        // a later setting replaces the earlier one of its kind for its voice
+        window.retain(|setting| {
+            mem::discriminant(&setting.body) != mem::discriminant(&event.body)
+                || setting.voice != event.voice
+        });
+        let mut setting = event.clone();
+        setting.start_time.seconds = 0.0;
+        window.push(setting);
     }
-    Ok(tpq as u16)
+    if let Some(event) = open_loop {
+        let mut loop_start = event.clone();
+        loop_start.start_time.seconds = 0.0;
+        window.push(loop_start);
+    }
+
+    for event in piece.events_in_seconds(start..end) {
+        let mut event = event.clone();
+        let from = event.start_time.seconds;
+        if let EventBody::Note(note) = &mut event.body {
+            let to = from + note.duration_seconds;
+            if from < start {
+                note.portamento_from = None;
+            }
+            if from < start || to > end {
+                note.bend_envelope = None;
+            }
+            note.duration_seconds = to.min(end) - from.max(start);
+        }
+        event.start_time.seconds = from.max(start) - start;
+        window.push(event);
+    }
+    Ok(window)
 }
 
-fn collect_tempo_and_signature(
-    events: &[CompileEvent],
-) -> Result<(Vec<RawTempoPoint>, Vec<MetaPoint>)> {
-    let mut sorted = events.to_vec();
-    sorted.sort_by(|a, b| a.start_time.seconds.total_cmp(&b.start_time.seconds));
+/// Port and channel of each track. Tracks with an explicit channel keep it on the first port;
+/// the others take, in order, the channels of the first port left free, then the channels of
+/// the following ports. No note track may take the percussion channel.
+fn assign_channels(layouts: &[TrackLayout]) -> Result<Vec<(u8, u8)>> {
+    let mut taken = [false; 16];
+    taken[PERCUSSION_CHANNEL as usize] = true;
+    for channel in layouts.iter().filter_map(|layout| layout.channel) {
+        if channel > 15 {
+            bail!("MIDI channel {} is out of range (0-15)", channel);
+        }
+        if channel == PERCUSSION_CHANNEL {
+            bail!("MIDI channel 10 is reserved for percussion voices");
+        }
+        taken[channel as usize] = true;
+    }
+    let mut free = (0..=MAX_MIDI_PORT)
+        .flat_map(|port| (0..16u8).map(move |channel| (port, channel)))
+        .filter(|&(port, channel)| {
+            channel != PERCUSSION_CHANNEL && !(port == 0 && taken[channel as usize])
+        });
+    let automatic = layouts.iter().filter(|l| l.channel.is_none()).count();
+    layouts
+        .iter()
+        .map(|layout| match layout.channel {
+            Some(channel) => Ok((0, channel)),
+            None => free.next().ok_or_else(|| {
+                anyhow::anyhow!("Too many note tracks ({}) for MIDI channels", automatic)
+            }),
+        })
+        .collect()
+}
 
-    let mut beat_duration = Rational32::new(1, 4);
-    let mut bpm = 120.0_f64;
+/// MIDI Port meta event at the start of a track.
+fn midi_port_event(port: u8) -> TrackEvent<'static> {
+    TrackEvent {
+        delta: 0.into(),
+        kind: TrackEventKind::Meta(MetaMessage::MidiPort(u7::new(port))),
+    }
+}
 
-    let mut raw_tempos: Vec<(f64, u32)> = vec![(0.0, bpm_beat_to_mpq(bpm, beat_duration)?)];
-    let mut time_sigs = Vec::new();
+/// Pitch bend range in cents of the tracks of each voice, and of the other tracks.
+struct BendRanges {
+    piece: u32,
+    /// By voice index, empty when every track uses the range of the piece
+    voices: Vec<u32>,
+}
 
-    for event in &sorted {
-        match event.body {
-            EventBody::BeatDurationDef(dur) => {
-                beat_duration = dur;
-                raw_tempos.push((
-                    event.start_time.seconds,
-                    bpm_beat_to_mpq(bpm, beat_duration)?,
-                ));
-            }
-            EventBody::BPMDef(next_bpm) => {
-                bpm = next_bpm as f64;
-                raw_tempos.push((
-                    event.start_time.seconds,
-                    bpm_beat_to_mpq(bpm, beat_duration)?,
-                ));
-            }
-            EventBody::TimeSignatureDef(ts) => {
-                let numerator = *ts.numer();
-                let denominator = *ts.denom();
-                if numerator <= 0 || denominator <= 0 {
-                    bail!("Invalid time signature: {}/{}", numerator, denominator);
-                }
-                if !(denominator as u32).is_power_of_two() {
-                    bail!(
-                        "Time signature denominator {} is not a power of 2",
-                        denominator
-                    );
-                }
-                if numerator > u8::MAX as i32 || denominator > u8::MAX as i32 {
-                    bail!(
-                        "Time signature out of MIDI range: {}/{}",
-                        numerator,
-                        denominator
-                    );
+impl BendRanges {
+    fn new(config: &MidiWriterConfig, voices: &[&str]) -> Result<Self> {
+        if config.pitch_bend_range_cents > 99 {
+            bail!("pitch_bend_range_cents must be < 100");
+        }
+        let piece = u32::from(config.pitch_bend_range_semitones) * 100
+            + u32::from(config.pitch_bend_range_cents);
+        if config.track_assignment == TrackAssignment::SingleTrack {
+            return Ok(Self {
+                piece,
+                voices: Vec::new(),
+            });
+        }
+        let mut ranges = Vec::with_capacity(voices.len());
+        for &voice in voices {
+            let range = match config.voice_bend_ranges.iter().find(|r| r.voice == voice) {
+                Some(range) if range.cents > 99 => {
+                    bail!("Pitch bend range cents of voice {} must be < 100", voice)
                 }
-                time_sigs.push(MetaPoint {
-                    second: event.start_time.seconds,
-                    numerator: numerator as u8,
-                    denominator: denominator as u8,
-                });
-            }
-            _ => {}
+                Some(range) => u32::from(range.semitones) * 100 + u32::from(range.cents),
+                None => piece,
+            };
+            ranges.push(range);
         }
+        Ok(Self {
+            piece,
+            voices: ranges,
+        })
     }
 
-    raw_tempos.sort_by(|a, b| a.0.total_cmp(&b.0));
-    let mut dedup: Vec<(f64, u32)> = Vec::new();
-    for (sec, mpq) in raw_tempos {
-        if let Some((last_sec, last_mpq)) = dedup.last_mut() {
-            if (*last_sec - sec).abs() < 1e-9 {
-                *last_mpq = mpq;
-                continue;
-            }
-        }
-        dedup.push((sec, mpq));
+    fn of(&self, voice: Option<usize>) -> u32 {
+        voice
+            .and_then(|voice| self.voices.get(voice).copied())
+            .unwrap_or(self.piece)
+    }
+}
+
+/// Header timing of a timecode file, with the ticks per quarter note and tempo that make
+/// ticks count subframes: a steady tempo of a second per quarter note, or of 1.001 seconds
+/// with 29.97 drop-frame timecode counted as 30 frames.
+fn timecode_clock(fps: u8, subframes: u8) -> Result<(Timing, u16, Vec<TempoPoint>)> {
+    let Some(smpte_fps) = Fps::from_int(fps) else {
+        bail!(
+            "SMPTE frame rate must be 24, 25, 29 (drop-frame) or 30, not {}",
+            fps
+        );
+    };
+    if subframes == 0 {
+        bail!("SMPTE subframes must be > 0");
+    }
+    let (frames, mpq) = match smpte_fps {
+        Fps::Fps29 => (30, 1_001_000),
+        _ => (u16::from(fps), 1_000_000),
+    };
+    let clock = vec![TempoPoint {
+        second: 0.0,
+        mpq,
+        start_tick: 0,
+    }];
+    Ok((
+        Timing::Timecode(smpte_fps, subframes),
+        frames * u16::from(subframes),
+        clock,
+    ))
+}
+
+pub(crate) fn normalize_tpq(tpq: u32) -> Result<u16> {
+    if tpq == 0 {
+        bail!("ticks_per_quarter must be > 0");
+    }
+    if tpq > 0x7FFF {
+        bail!("ticks_per_quarter exceeds MIDI metrical range (32767)");
     }
+    Ok(tpq as u16)
+}
 
-    if dedup.first().is_none_or(|(sec, _)| *sec > 0.0) {
-        dedup.insert(0, (0.0, bpm_beat_to_mpq(120.0, Rational32::new(1, 4))?));
+/// Tempo changes of the whole `piece` before `end`, in seconds from `start`, where the tempo
+/// in effect then begins. Windowed events keep their positions, so the timeline of the whole
+/// piece is read before windowing.
+pub(crate) fn collect_tempos(
+    piece: &CompiledPiece,
+    start: f64,
+    end: Option<f64>,
+) -> Result<Vec<RawTempoPoint>> {
+    let end = end.unwrap_or(f64::INFINITY);
+    let mut points: Vec<RawTempoPoint> = Vec::new();
+    for (second, seconds_per_whole_note) in Timeline::new(&piece.events).tempo_changes() {
+        if second >= end {
+            break;
+        }
+        let point = RawTempoPoint {
+            second: (second - start).max(0.0),
+            mpq: whole_note_seconds_to_mpq(seconds_per_whole_note)?,
+        };
+        match points.last_mut() {
+            Some(last) if last.second == point.second => *last = point,
+            _ => points.push(point),
+        }
     }
+    Ok(points)
+}
 
-    let mut tempo_points = Vec::with_capacity(dedup.len());
-    for (sec, mpq) in dedup {
-        tempo_points.push(RawTempoPoint { second: sec, mpq });
+pub(crate) fn collect_time_signatures(piece: &CompiledPiece) -> Result<Vec<MetaPoint>> {
+    let mut time_sigs = Vec::new();
+    for event in piece.iter_sorted() {
+        let EventBody::TimeSignatureDef(ref ts) = event.body else {
+            continue;
+        };
+        let numerator = i64::from(ts.numerator());
+        let denominator = i64::from(ts.denominator);
+        if numerator <= 0 || denominator <= 0 {
+            bail!("Invalid time signature: {}/{}", numerator, denominator);
+        }
+        if !(denominator as u32).is_power_of_two() {
+            bail!(
+                "Time signature denominator {} is not a power of 2",
+                denominator
+            );
+        }
+        if numerator > i64::from(u8::MAX) || denominator > i64::from(u8::MAX) {
+            bail!(
+                "Time signature out of MIDI range: {}/{}",
+                numerator,
+                denominator
+            );
+        }
+        // an additive meter has no steady beat longer than its pulse, so the
+        // metronome clicks on every denominator note instead of every quarter
+        let clocks_per_click = if ts.is_additive() {
+            (96 / denominator).max(1) as u8
+        } else {
+            24
+        };
+        time_sigs.push(MetaPoint {
+            second: event.start_time.seconds,
+            numerator: numerator as u8,
+            denominator: denominator as u8,
+            clocks_per_click,
+        });
     }
 
-    Ok((tempo_points, time_sigs))
+    Ok(time_sigs)
 }
 
-fn build_tempo_points(raw_points: &[RawTempoPoint], tpq: u16) -> Vec<TempoPoint> {
+pub(crate) fn build_tempo_points(raw_points: &[RawTempoPoint], tpq: u16) -> Vec<TempoPoint> {
     let mut out = Vec::with_capacity(raw_points.len());
     let mut accum_tick = 0_u64;
     for (idx, point) in raw_points.iter().enumerate() {
@@ -264,33 +1202,301 @@ fn build_tempo_points(raw_points: &[RawTempoPoint], tpq: u16) -> Vec<TempoPoint>
     out
 }
 
-fn bpm_beat_to_mpq(bpm: f64, beat_duration: Rational32) -> Result<u32> {
-    if bpm <= 0.0 {
-        bail!("BPM must be > 0");
-    }
-    let beat_full_note = rational_to_f64(beat_duration)?;
-    if beat_full_note <= 0.0 {
-        bail!("BeatDurationDef must be > 0");
+/// Tempo changes to write as `(tick, mpq)`. A run of three or more tempos each faster (or
+/// each slower) than the one before is a ramp: with `steps` above 1, each segment of it is
+/// split into `steps` tempos gliding through the segment's own. A segment keeps its ticks
+/// and its average tempo, so bar lines and notes sound when the unramped tempos put them.
+pub(crate) fn ramp_tempo_changes(points: &[TempoPoint], steps: u32) -> Vec<(u64, u32)> {
+    let mut changes: Vec<(u64, u32)> = Vec::with_capacity(points.len());
+    for point in points {
+        match changes.last_mut() {
+            Some(last) if last.1 == point.mpq => {}
+            Some(last) if last.0 == point.start_tick => last.1 = point.mpq,
+            _ => changes.push((point.start_tick, point.mpq)),
+        }
     }
-    let quarter_bpm = bpm * (beat_full_note / 0.25);
-    if quarter_bpm <= 0.0 {
-        bail!("Derived quarter BPM must be > 0");
+    if steps < 2 || changes.len() < 3 {
+        return changes;
     }
-    let mpq_f = 60_000_000.0 / quarter_bpm;
-    let mpq = mpq_f.round().clamp(1.0, 16_777_215.0) as u32;
-    Ok(mpq)
-}
 
-fn rational_to_f64(v: Rational32) -> Result<f64> {
-    let d = *v.denom();
-    if d == 0 {
-        bail!("Rational denominator cannot be zero");
-    }
-    Ok(*v.numer() as f64 / d as f64)
+    let mpq = |i: usize| f64::from(changes[i].1);
+    // direction of the change from tempo `i` to the next
+    let direction = |i: usize| (mpq(i + 1) - mpq(i)).signum();
+    let last = changes.len() - 1;
+    let mut out = Vec::new();
+    for (i, &(tick, value)) in changes.iter().enumerate() {
+        let ramp_before = i > 0 && i < last && direction(i - 1) == direction(i);
+        let ramp_after = i + 1 < last && direction(i + 1) == direction(i);
+        let length = if i < last { changes[i + 1].0 - tick } else { 0 };
+        let steps = u64::from(steps).min(length);
+        if !(ramp_before || ramp_after) || steps < 2 {
+            out.push((tick, value));
+            continue;
+        }
+        // change of tempo across the segment, centered between its neighbours inside the run
+        let span = if ramp_before {
+            (mpq(i + 1) - mpq(i - 1)) / 2.0
+        } else {
+            mpq(i + 1) - mpq(i)
+        };
+        let bounds: Vec<u64> = (0..=steps).map(|k| tick + length * k / steps).collect();
+        let offsets: Vec<f64> = (0..steps)
+            .map(|k| span * ((k as f64 + 0.5) / steps as f64 - 0.5))
+            .collect();
+        // unequal step lengths would shift the average, so take it back out
+        let drift = offsets
+            .iter()
+            .zip(bounds.windows(2))
+            .map(|(offset, b)| offset * (b[1] - b[0]) as f64)
+            .sum::<f64>()
+            / length as f64;
+        for (offset, b) in offsets.iter().zip(&bounds) {
+            let stepped = (mpq(i) + offset - drift).round().clamp(1.0, 16_777_215.0) as u32;
+            out.push((*b, stepped));
+        }
+    }
+    out
+}
+
+/// Microseconds per quarter note of a tempo given in seconds per whole note.
+fn whole_note_seconds_to_mpq(seconds_per_whole_note: f64) -> Result<u32> {
+    if !(seconds_per_whole_note.is_finite() && seconds_per_whole_note > 0.0) {
+        bail!("Tempo must be > 0");
+    }
+    let mpq_f = seconds_per_whole_note * 1_000_000.0 / 4.0;
+    let mpq = mpq_f.round().clamp(1.0, 16_777_215.0) as u32;
+    Ok(mpq)
+}
+
+/// Names of the voices assigned to sounding notes, in order of first appearance.
+fn collect_voices(events: &[CompileEvent]) -> Vec<&str> {
+    let mut voices: Vec<&str> = Vec::new();
+    for event in events {
+        if let EventBody::Note(note) = &event.body
+            && !note.is_rest()
+            && let Some(name) = event.voice.as_deref()
+            && !voices.contains(&name)
+        {
+            voices.push(name);
+        }
+    }
+    voices
+}
+
+/// Lyric syllables in time order. A syllable continued by the next one of its voice ends
+/// with a hyphen, whether it was written there (`"Hap-" "py"`) or before the next one
+/// (`"Hap" "-py"`); melisma extensions (`"_"`) keep the syllable sung and are left out.
+fn collect_lyrics(piece: &CompiledPiece, voices: &[&str]) -> Vec<LyricPoint> {
+    let mut lyrics: Vec<LyricPoint> = Vec::new();
+    // last syllable of each voice, hyphenated when the next one continues its word
+    let mut last: HashMap<Option<usize>, usize> = HashMap::new();
+    for event in piece.iter_sorted() {
+        let EventBody::Lyric(text) = &event.body else {
+            continue;
+        };
+        let voice = event
+            .voice
+            .as_deref()
+            .and_then(|name| voices.iter().position(|v| *v == name));
+        let mut text = text.trim();
+        if let Some(rest) = text.strip_prefix('-') {
+            if let Some(&prev) = last.get(&voice) {
+                hyphenate(&mut lyrics[prev].text);
+            }
+            text = rest.trim_start();
+        }
+        let syllable = text.trim_end_matches('-').trim_end();
+        if syllable.is_empty() || syllable.chars().all(|c| c == '_') {
+            continue;
+        }
+        let mut syllable = syllable.to_string();
+        if text.ends_with('-') {
+            hyphenate(&mut syllable);
+        }
+        last.insert(voice, lyrics.len());
+        lyrics.push(LyricPoint {
+            second: event.start_time.seconds,
+            voice,
+            text: syllable,
+        });
+    }
+    lyrics
+}
+
+fn hyphenate(syllable: &mut String) {
+    if !syllable.ends_with('-') {
+        syllable.push('-');
+    }
 }
 
-fn collect_note_specs(events: &[CompileEvent], bend_range: u16) -> Result<Vec<NoteSpec>> {
+/// Places each lyric on the track of the note it is sung to, a note of its voice starting
+/// with it, at the start of that note. The others stay in the meta track at their own time.
+fn place_lyrics<'a>(
+    lyrics: &'a [LyricPoint],
+    layouts: &[TrackLayout],
+    tolerance: f64,
+) -> (Vec<Vec<TextPoint<'a>>>, Vec<TextPoint<'a>>) {
+    let tolerance = tolerance.max(1e-6);
+    let mut placed = vec![Vec::new(); layouts.len()];
+    let mut unplaced = Vec::new();
+    for lyric in lyrics {
+        let sung = layouts.iter().enumerate().find_map(|(idx, layout)| {
+            layout
+                .groups
+                .iter()
+                .find(|group| {
+                    (group.start_second - lyric.second).abs() <= tolerance
+                        && group.notes.iter().any(|note| note.voice == lyric.voice)
+                })
+                .map(|group| (idx, group.start_second))
+        });
+        let text = lyric.text.as_str();
+        match sung {
+            Some((idx, second)) => placed[idx].push(TextPoint { second, text }),
+            None => unplaced.push(TextPoint {
+                second: lyric.second,
+                text,
+            }),
+        }
+    }
+    (placed, unplaced)
+}
+
+/// Key signatures of the `(key ...)` directives, or when there are none, the major keys of
+/// the base notes, each written when the key changes.
+fn collect_key_signatures(piece: &CompiledPiece) -> Vec<KeyPoint> {
+    let explicit = piece
+        .events
+        .iter()
+        .any(|e| matches!(e.body, EventBody::KeySignatureDef(_)));
+    let mut keys: Vec<KeyPoint> = Vec::new();
+    for event in piece.iter_sorted() {
+        let key = match event.body {
+            EventBody::KeySignatureDef(key) => key,
+            EventBody::BaseNoteDef(spell) if !explicit => KeySignature::major_on_semitone(spell),
+            _ => continue,
+        };
+        let second = event.start_time.seconds;
+        match keys.last_mut() {
+            Some(last) if last.key == key => {}
+            // a later key at the same time replaces the earlier one
+            Some(last) if (last.second - second).abs() < 1e-9 => last.key = key,
+            _ => keys.push(KeyPoint { second, key }),
+        }
+    }
+    keys
+}
+
+/// Volume, pan and pedal settings in time order, volume on the controller `automation` asks
+/// for, pedals only when it writes them.
+fn collect_mix_points(
+    piece: &CompiledPiece,
+    voices: &[&str],
+    automation: ControllerAutomation,
+) -> Vec<MixPoint> {
+    piece
+        .iter_sorted()
+        .filter_map(|event| {
+            let (controller, value, ramp) = match event.body {
+                EventBody::VolumeDef(level) => {
+                    let controller = if automation.expression { 11 } else { 7 };
+                    (controller, f64::from(level.value), level.ramp)
+                }
+                EventBody::PanDef(level) => (10, (f64::from(level.value) + 1.0) / 2.0, level.ramp),
+                EventBody::SustainPedal(down) if automation.sustain_pedal => {
+                    (64, if down { 1.0 } else { 0.0 }, false)
+                }
+                _ => return None,
+            };
+            Some(MixPoint {
+                second: event.start_time.seconds,
+                voice: event
+                    .voice
+                    .as_deref()
+                    .and_then(|name| voices.iter().position(|v| *v == name)),
+                controller,
+                value,
+                ramp,
+            })
+        })
+        .collect()
+}
+
+fn collect_markers(piece: &CompiledPiece) -> Vec<TextPoint<'_>> {
+    piece
+        .iter_sorted()
+        .filter_map(|event| match &event.body {
+            EventBody::Marker(name) => Some(TextPoint {
+                second: event.start_time.seconds,
+                text: name.as_str(),
+            }),
+            _ => None,
+        })
+        .collect()
+}
+
+fn collect_cue_points(piece: &CompiledPiece) -> Vec<TextPoint<'_>> {
+    piece
+        .iter_sorted()
+        .filter_map(|event| match &event.body {
+            EventBody::CuePoint(name) => Some(TextPoint {
+                second: event.start_time.seconds,
+                text: name.as_str(),
+            }),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Loop regions of the piece, each start paired with the next end, and the loop starts
+/// and ends left without a partner.
+fn collect_loops(piece: &CompiledPiece) -> (Vec<LoopRegion>, Vec<&CompileEvent>) {
+    let mut loops: Vec<LoopRegion> = Vec::new();
+    let mut unpaired = Vec::new();
+    for event in piece.iter_sorted() {
+        let open = loops
+            .last()
+            .is_some_and(|region| region.end_second.is_none());
+        match event.body {
+            EventBody::LoopStart if !open => loops.push(LoopRegion {
+                start_second: event.start_time.seconds,
+                end_second: None,
+                range: event.range,
+            }),
+            EventBody::LoopEnd if open => {
+                if let Some(region) = loops.last_mut() {
+                    region.end_second = Some(event.start_time.seconds);
+                }
+            }
+            EventBody::LoopStart | EventBody::LoopEnd => unpaired.push(event),
+            _ => {}
+        }
+    }
+    (loops, unpaired)
+}
+
+/// Second the last of `events` ends at, notes sounding to their end.
+fn piece_end_second(events: &[CompileEvent]) -> f64 {
+    events
+        .iter()
+        .map(|event| match &event.body {
+            EventBody::Note(note) => event.start_time.seconds + note.duration_seconds,
+            _ => event.start_time.seconds,
+        })
+        .fold(0.0, f64::max)
+}
+
+fn collect_note_specs(
+    events: &[CompileEvent],
+    voices: &[&str],
+    percussion_voices: &[PercussionVoice],
+    bend_ranges: &BendRanges,
+    velocity_curve: VelocityCurve,
+    note_off_velocity: u8,
+    humanize: Option<Humanize>,
+) -> Result<Vec<NoteSpec>> {
     let mut notes = Vec::new();
+    let mut humanizer = humanize.map(Humanizer::new);
     for event in events {
         let EventBody::Note(note) = &event.body else {
             continue;
@@ -298,45 +1504,148 @@ fn collect_note_specs(events: &[CompileEvent], bend_range: u16) -> Result<Vec<No
         if note.is_rest() {
             continue;
         }
-        let spec = note_to_spec(event.start_time.seconds, note, bend_range)?;
+        let voice = event
+            .voice
+            .as_deref()
+            .and_then(|name| voices.iter().position(|v| *v == name));
+        let mut spec = note_to_spec(
+            event.start_time.seconds,
+            note,
+            voice,
+            bend_ranges.of(voice),
+            velocity_curve,
+            note_off_velocity,
+        )?;
+        spec.channel = event.channel;
+        spec.program = event.instrument;
+        spec.range = event.range;
+        if let Some(percussion) = event
+            .voice
+            .as_deref()
+            .and_then(|name| percussion_voices.iter().find(|p| p.voice == name))
+        {
+            spec.midi_key = percussion.drum_key(spec.midi_key);
+            spec.percussion = true;
+        }
         if spec.end_second > spec.start_second {
+            if let Some(humanizer) = &mut humanizer {
+                humanizer.apply(&mut spec);
+            }
             notes.push(spec);
         }
     }
     Ok(notes)
 }
 
-fn note_to_spec(start_second: f64, note: &Note, bend_range: u16) -> Result<NoteSpec> {
+/// Applies [`Humanize`] to notes in a fixed order. A start or end shared by notes of a voice
+/// moves them together, so chords stay chords and legato stays legato.
+struct Humanizer {
+    config: Humanize,
+    rng: SeededRng,
+    /// Shift of each time of each voice, by microsecond
+    shifts: HashMap<(i64, Option<usize>), f64>,
+}
+
+impl Humanizer {
+    fn new(config: Humanize) -> Self {
+        Self {
+            config,
+            rng: SeededRng::new(config.seed),
+            shifts: HashMap::new(),
+        }
+    }
+
+    fn shift(&mut self, second: f64, voice: Option<usize>) -> f64 {
+        let key = ((second * 1e6).round() as i64, voice);
+        let range = self.config.timing_seconds.max(0.0);
+        *self
+            .shifts
+            .entry(key)
+            .or_insert_with(|| self.rng.jitter(range))
+    }
+
+    fn apply(&mut self, spec: &mut NoteSpec) {
+        let duration = spec.end_second - spec.start_second;
+        let start = (spec.start_second + self.shift(spec.start_second, spec.voice)).max(0.0);
+        let mut end = spec.end_second + self.shift(spec.end_second, spec.voice);
+        if end <= start {
+            end = start + duration;
+        }
+        spec.start_second = start;
+        spec.end_second = end;
+        let velocity = f64::from(spec.velocity) + self.rng.jitter(f64::from(self.config.velocity));
+        spec.velocity = velocity.round().clamp(1.0, 127.0) as u8;
+    }
+}
+
+fn note_to_spec(
+    start_second: f64,
+    note: &Note,
+    voice: Option<usize>,
+    bend_range_cents: u32,
+    velocity_curve: VelocityCurve,
+    note_off_velocity: u8,
+) -> Result<NoteSpec> {
     if note.freq <= 0.0 {
         bail!("Note frequency must be > 0 for MIDI export");
     }
     if note.duration_seconds <= 0.0 {
         bail!("Note duration_seconds must be > 0 for MIDI export");
     }
-    let (midi_key, bend14, bend_cents) = freq_to_key_and_bend(note.freq as f64, bend_range)?;
+    let (midi_key, bend14, bend_cents) = match note.drum_key {
+        Some(key) => (key.min(127), PITCH_BEND_CENTER as u16, 0.0),
+        None => freq_to_key_and_bend(note.freq, bend_range_cents)?,
+    };
+    let portamento_from_key = match note.portamento_from {
+        Some(from) if from > 0.0 => Some(freq_to_key_and_bend(from, bend_range_cents)?.0),
+        _ => None,
+    };
+    let bend_envelope = match (&note.bend_envelope, note.drum_key) {
+        (Some(envelope), None) => Some(
+            envelope
+                .cents
+                .iter()
+                .map(|&cents| cents_to_bend14(bend_cents + f64::from(cents), bend_range_cents))
+                .collect(),
+        ),
+        _ => None,
+    };
     Ok(NoteSpec {
         start_second,
         end_second: start_second + note.duration_seconds,
         midi_key,
         bend14,
         bend_cents,
+        voice,
+        channel: None,
+        program: None,
+        percussion: note.drum_key.is_some(),
+        portamento_from_key,
+        bend_envelope,
+        vibrato: note.vibrato && note.drum_key.is_none(),
+        velocity: velocity_curve.velocity(note.gain()),
+        release: note.release.unwrap_or(note_off_velocity).min(127),
+        range: TextRange::default(),
     })
 }
 
-fn freq_to_key_and_bend(freq: f64, bend_range: u16) -> Result<(u8, u16, f64)> {
-    if bend_range == 0 {
-        bail!("pitch_bend_range_semitones must be > 0");
+fn freq_to_key_and_bend(freq: f64, bend_range_cents: u32) -> Result<(u8, u16, f64)> {
+    if bend_range_cents == 0 {
+        bail!("Pitch bend range must be > 0");
     }
     let exact = 69.0 + 12.0 * (freq / 440.0).log2();
     let key = exact.round().clamp(0.0, 127.0) as u8;
     let semitone_delta = exact - f64::from(key);
     let bend_cents = semitone_delta * 100.0;
-    let ratio = semitone_delta / bend_range as f64;
-    let bend_signed = (ratio * PITCH_BEND_CENTER as f64).round() as i32;
-    let bend14 = signed_to_bend14(bend_signed);
+    let bend14 = cents_to_bend14(bend_cents, bend_range_cents);
     Ok((key, bend14, bend_cents))
 }
 
+fn cents_to_bend14(cents: f64, bend_range_cents: u32) -> u16 {
+    let ratio = cents / f64::from(bend_range_cents);
+    signed_to_bend14((ratio * PITCH_BEND_CENTER as f64).round() as i32)
+}
+
 fn bend14_to_signed(bend14: u16) -> i32 {
     i32::from(bend14).clamp(0, 16383) - PITCH_BEND_CENTER
 }
@@ -358,9 +1667,14 @@ fn build_same_start_groups(mut notes: Vec<NoteSpec>, pitch_tolerance_cents: f64)
     for note in notes {
         if let Some(group) = groups.iter_mut().find(|group| {
             (group.start_second - note.start_second).abs() < 1e-9
+                && group.voice == note.voice
+                && group.channel == note.channel
+                && group.program == note.program
+                && group.bend_envelope.is_none()
+                && note.bend_envelope.is_none()
                 && (group.bend_cents - note.bend_cents).abs() <= pitch_tolerance_cents
         }) {
-            group.notes.push(note);
+            group.notes.push(note.clone());
             group.end_second = group.end_second.max(note.end_second);
             let n = group.notes.len() as f64;
             group.bend_cents = ((group.bend_cents * (n - 1.0)) + note.bend_cents) / n;
@@ -376,6 +1690,10 @@ fn build_same_start_groups(mut notes: Vec<NoteSpec>, pitch_tolerance_cents: f64)
             end_second: note.end_second,
             bend14: note.bend14,
             bend_cents: note.bend_cents,
+            voice: note.voice,
+            channel: note.channel,
+            program: note.program,
+            bend_envelope: note.bend_envelope.clone(),
             notes: vec![note],
         });
     }
@@ -388,68 +1706,131 @@ fn build_same_start_groups(mut notes: Vec<NoteSpec>, pitch_tolerance_cents: f64)
     groups
 }
 
-fn assign_groups_to_tracks(groups: Vec<NoteGroup>, tolerance_seconds: f64) -> Vec<TrackLayout> {
+fn assign_groups_to_tracks(
+    groups: Vec<NoteGroup>,
+    tolerance_seconds: f64,
+    strategy: TrackAssignment,
+) -> Vec<TrackLayout> {
     let mut tracks: Vec<TrackLayout> = Vec::new();
 
     for group in groups {
-        let mut placed = false;
-        for track in &mut tracks {
-            let can_place = match track.groups.last_mut() {
-                None => true,
-                Some(last) => {
-                    if group.start_second >= last.end_second {
-                        true
-                    } else {
-                        let overlap = last.end_second - group.start_second;
-                        if overlap > 0.0 && overlap <= tolerance_seconds {
-                            last.end_second = group.start_second;
-                            for n in &mut last.notes {
-                                if n.end_second > group.start_second {
-                                    n.end_second = group.start_second;
-                                }
-                            }
-                            true
-                        } else {
-                            false
+        let own_track = match strategy {
+            TrackAssignment::SingleTrack => Some((None, None)),
+            TrackAssignment::ExplicitVoice => Some((group.voice, group.channel)),
+            _ if group.voice.is_some() || group.channel.is_some() => {
+                Some((group.voice, group.channel))
+            }
+            _ => None,
+        };
+        if let Some((voice, channel)) = own_track {
+            match tracks
+                .iter_mut()
+                .find(|t| t.voice == voice && t.channel == channel)
+            {
+                Some(track) => track.groups.push(group),
+                None => tracks.push(TrackLayout {
+                    voice,
+                    channel,
+                    groups: vec![group],
+                }),
+            }
+            continue;
+        }
+
+        let fits = |track: &TrackLayout| {
+            track.groups.last().is_none_or(|last| {
+                group.start_second >= last.end_second
+                    || last.end_second - group.start_second <= tolerance_seconds
+            })
+        };
+        let mut free = (0..tracks.len()).filter(|&i| !tracks[i].is_explicit() && fits(&tracks[i]));
+        let chosen = match strategy {
+            TrackAssignment::PitchProximity => free.min_by(|&a, &b| {
+                pitch_leap(&tracks[a], &group).total_cmp(&pitch_leap(&tracks[b], &group))
+            }),
+            _ => free.next(),
+        };
+
+        match chosen {
+            Some(idx) => {
+                let track = &mut tracks[idx];
+                // an overlap within the tolerance ends the earlier group where this one starts
+                if let Some(last) = track.groups.last_mut()
+                    && last.end_second > group.start_second
+                {
+                    last.end_second = group.start_second;
+                    for n in &mut last.notes {
+                        if n.end_second > group.start_second {
+                            n.end_second = group.start_second;
                         }
                     }
                 }
-            };
-
-            if can_place {
-                track.groups.push(group.clone());
-                placed = true;
-                break;
+                track.groups.push(group);
             }
-        }
-
-        if !placed {
-            tracks.push(TrackLayout {
+            None => tracks.push(TrackLayout {
+                voice: None,
+                channel: None,
                 groups: vec![group],
-            });
+            }),
         }
     }
 
     tracks
 }
 
-fn build_meta_track(
+/// Average pitch of a group in semitones, bends included.
+fn group_pitch(group: &NoteGroup) -> f64 {
+    let keys: f64 = group.notes.iter().map(|n| f64::from(n.midi_key)).sum();
+    keys / group.notes.len().max(1) as f64 + group.bend_cents / 100.0
+}
+
+/// Leap in semitones from the last group of `track` to `group`.
+fn pitch_leap(track: &TrackLayout, group: &NoteGroup) -> f64 {
+    track
+        .groups
+        .last()
+        .map_or(0.0, |last| (group_pitch(last) - group_pitch(group)).abs())
+}
+
+fn build_meta_track<'a>(
+    metadata: &'a PieceMetadata,
+    points: &MetaTrackPoints<'a>,
     tempo_points: &[TempoPoint],
-    time_signatures: &[MetaPoint],
     tpq: u16,
-) -> Vec<TrackEvent<'static>> {
+) -> Vec<TrackEvent<'a>> {
     let mut abs_events = Vec::new();
 
-    for tempo in tempo_points {
-        let tick = seconds_to_tick(tempo.second, tempo_points, tpq);
+    let header_fields = [
+        metadata
+            .title
+            .as_deref()
+            .map(|t| MetaMessage::TrackName(t.as_bytes())),
+        metadata
+            .copyright
+            .as_deref()
+            .map(|c| MetaMessage::Copyright(c.as_bytes())),
+        metadata
+            .composer
+            .as_deref()
+            .map(|c| MetaMessage::Text(c.as_bytes())),
+    ];
+    for message in header_fields.into_iter().flatten() {
+        abs_events.push(AbsEvent {
+            tick: 0,
+            priority: 0,
+            kind: TrackEventKind::Meta(message),
+        });
+    }
+
+    for (tick, mpq) in ramp_tempo_changes(&points.tempos, points.tempo_ramp_steps) {
         abs_events.push(AbsEvent {
             tick,
             priority: 0,
-            kind: TrackEventKind::Meta(MetaMessage::Tempo(u24::new(tempo.mpq))),
+            kind: TrackEventKind::Meta(MetaMessage::Tempo(u24::new(mpq))),
         });
     }
 
-    for sig in time_signatures {
+    for sig in &points.time_signatures {
         let tick = seconds_to_tick(sig.second, tempo_points, tpq);
         abs_events.push(AbsEvent {
             tick,
@@ -457,40 +1838,246 @@ fn build_meta_track(
             kind: TrackEventKind::Meta(MetaMessage::TimeSignature(
                 sig.numerator,
                 sig.denominator.trailing_zeros() as u8,
-                24,
+                sig.clocks_per_click,
                 8,
             )),
         });
     }
 
+    for point in &points.key_signatures {
+        let tick = seconds_to_tick(point.second, tempo_points, tpq);
+        abs_events.push(AbsEvent {
+            tick,
+            priority: 1,
+            kind: TrackEventKind::Meta(MetaMessage::KeySignature(
+                point.key.sharps,
+                point.key.minor,
+            )),
+        });
+    }
+
+    for marker in &points.markers {
+        let tick = seconds_to_tick(marker.second, tempo_points, tpq);
+        abs_events.push(AbsEvent {
+            tick,
+            priority: 1,
+            kind: TrackEventKind::Meta(MetaMessage::Marker(marker.text.as_bytes())),
+        });
+    }
+
+    for cue in &points.cue_points {
+        let tick = seconds_to_tick(cue.second, tempo_points, tpq);
+        abs_events.push(AbsEvent {
+            tick,
+            priority: 1,
+            kind: TrackEventKind::Meta(MetaMessage::CuePoint(cue.text.as_bytes())),
+        });
+    }
+
+    for lyric in &points.lyrics {
+        abs_events.push(lyric_event(lyric, tempo_points, tpq));
+    }
+
     to_delta_track(abs_events)
 }
 
-fn build_note_track(
+fn lyric_event<'a>(lyric: &TextPoint<'a>, tempo_points: &[TempoPoint], tpq: u16) -> AbsEvent<'a> {
+    AbsEvent {
+        tick: seconds_to_tick(lyric.second, tempo_points, tpq),
+        priority: 1,
+        kind: TrackEventKind::Meta(MetaMessage::Lyric(lyric.text.as_bytes())),
+    }
+}
+
+/// Controller changes of the volume, pan and pedal settings of the voice of `layout`, ramps
+/// stepped `rate_hz` times a second. A level already set is not sent again.
+fn controller_events(
+    points: &[MixPoint],
     layout: &TrackLayout,
     channel: u8,
-    bend_range: u16,
+    rate_hz: f64,
     tempo_points: &[TempoPoint],
     tpq: u16,
-) -> Vec<TrackEvent<'static>> {
+) -> Vec<AbsEvent<'static>> {
+    let mut abs_events = Vec::new();
+    for controller in [7, 10, 11, 64] {
+        let series: Vec<&MixPoint> = points
+            .iter()
+            .filter(|p| p.controller == controller && p.voice == layout.voice)
+            .collect();
+        let mut sent = None;
+        for (idx, point) in series.iter().enumerate() {
+            let mut levels = vec![(point.second, point.value)];
+            if point.ramp
+                && let Some(next) = series.get(idx + 1)
+            {
+                let span = next.second - point.second;
+                let steps = (span * rate_hz).floor();
+                if steps.is_finite() && steps >= 2.0 {
+                    let steps = steps as u64;
+                    levels.extend((1..steps).map(|k| {
+                        let t = k as f64 / steps as f64;
+                        (
+                            point.second + span * t,
+                            point.value + (next.value - point.value) * t,
+                        )
+                    }));
+                }
+            }
+            for (second, level) in levels {
+                let value = (level * 127.0).round().clamp(0.0, 127.0) as u8;
+                if sent == Some(value) {
+                    continue;
+                }
+                sent = Some(value);
+                abs_events.push(AbsEvent {
+                    tick: seconds_to_tick(second, tempo_points, tpq),
+                    priority: 1,
+                    kind: TrackEventKind::Midi {
+                        channel: u4::new(channel),
+                        message: MidiMessage::Controller {
+                            controller: u7::new(controller),
+                            value: u7::new(value),
+                        },
+                    },
+                });
+            }
+        }
+    }
+    abs_events
+}
+
+/// Channel Aftertouch, or Modulation with `vibrato_modulation`, swelling in across the
+/// note groups of `layout` with vibrato, stepped `rate_hz` times a second, and back to none
+/// where they end.
+fn vibrato_events(
+    layout: &TrackLayout,
+    channel: u8,
+    automation: ControllerAutomation,
+    tempo_points: &[TempoPoint],
+    tpq: u16,
+) -> Vec<AbsEvent<'static>> {
+    let message = |value: u8| {
+        let value = u7::new(value);
+        if automation.vibrato_modulation {
+            MidiMessage::Controller {
+                controller: u7::new(1),
+                value,
+            }
+        } else {
+            MidiMessage::ChannelAftertouch { vel: value }
+        }
+    };
     let mut abs_events = Vec::new();
+    for group in &layout.groups {
+        if !group.notes.iter().any(|note| note.vibrato) {
+            continue;
+        }
+        let swell = (group.end_second - group.start_second) / VIBRATO_SWELL_PARTS;
+        let steps = ((swell * automation.rate_hz).floor() as u64).max(1);
+        let levels = (0..=steps).map(|k| {
+            let t = k as f64 / steps as f64;
+            (group.start_second + swell * t, (127.0 * t).round() as u8)
+        });
+        for (second, value) in levels.chain([(group.end_second, 0)]) {
+            abs_events.push(AbsEvent {
+                tick: seconds_to_tick(second, tempo_points, tpq),
+                priority: 1,
+                kind: TrackEventKind::Midi {
+                    channel: u4::new(channel),
+                    message: message(value),
+                },
+            });
+        }
+    }
+    abs_events
+}
+
+/// Events of a note track, along with `extra_events` laid out for it such as the lyrics sung
+/// to its notes. With `tuning_changes` the keys are retuned by MTS instead of bent, and pitch
+/// bends only carry bend envelopes.
+fn build_note_track<'a>(
+    layout: &TrackLayout,
+    channel: u8,
+    bend_range_cents: u32,
+    tuning_changes: Option<&'a [TuningChange]>,
+    extra_events: Vec<AbsEvent<'a>>,
+    tempo_points: &[TempoPoint],
+    tpq: u16,
+) -> Vec<TrackEvent<'a>> {
+    let mut abs_events = extra_events;
 
-    append_rpn_pitch_bend_setup(&mut abs_events, channel, bend_range);
+    append_rpn_pitch_bend_setup(&mut abs_events, channel, bend_range_cents);
+    if let Some(changes) = tuning_changes {
+        append_rpn_tuning_program_select(&mut abs_events, channel, channel);
+        for change in changes {
+            abs_events.push(AbsEvent {
+                tick: seconds_to_tick(change.second, tempo_points, tpq),
+                priority: 1,
+                kind: TrackEventKind::SysEx(&change.data),
+            });
+        }
+    }
 
+    let mut program = None;
+    // whether a bend envelope left the channel bent under MTS tuning
+    let mut bent = false;
     for group in &layout.groups {
         let start_tick = seconds_to_tick(group.start_second, tempo_points, tpq);
-        abs_events.push(AbsEvent {
-            tick: start_tick,
-            priority: 1,
-            kind: TrackEventKind::Midi {
-                channel: u4::new(channel),
-                message: MidiMessage::PitchBend {
-                    bend: PitchBend(u14::new(group.bend14)),
+        if group.program.is_some() && group.program != program {
+            // the first instrument of a track is set up at its start
+            let tick = if program.is_none() { 0 } else { start_tick };
+            program = group.program;
+            abs_events.push(AbsEvent {
+                tick,
+                priority: 1,
+                kind: TrackEventKind::Midi {
+                    channel: u4::new(channel),
+                    message: MidiMessage::ProgramChange {
+                        program: u7::new(group.program.unwrap_or_default().min(127)),
+                    },
                 },
-            },
-        });
+            });
+        }
+        let bend = match tuning_changes {
+            None => Some(group.bend14),
+            Some(_) if bent => Some(PITCH_BEND_CENTER as u16),
+            Some(_) => None,
+        };
+        if let Some(bend) = bend {
+            bent = false;
+            abs_events.push(AbsEvent {
+                tick: start_tick,
+                priority: 1,
+                kind: TrackEventKind::Midi {
+                    channel: u4::new(channel),
+                    message: MidiMessage::PitchBend {
+                        bend: PitchBend(u14::new(bend)),
+                    },
+                },
+            });
+        }
+        if let Some(points) = &group.bend_envelope {
+            let end_tick = seconds_to_tick(group.end_second, tempo_points, tpq);
+            if tuning_changes.is_some() {
+                // the key is tuned to the pitch already, so bend only by the envelope
+                let offset = bend14_to_signed(group.bend14);
+                let relative: Vec<u16> = points
+                    .iter()
+                    .map(|&point| signed_to_bend14(bend14_to_signed(point) - offset))
+                    .collect();
+                append_bend_envelope(&mut abs_events, channel, &relative, start_tick, end_tick);
+                bent = true;
+            } else {
+                append_bend_envelope(&mut abs_events, channel, points, start_tick, end_tick);
+            }
+        }
 
         for note in &group.notes {
+            let end_tick = seconds_to_tick(note.end_second, tempo_points, tpq).max(start_tick + 1);
+            if let Some(from_key) = note.portamento_from_key {
+                append_portamento(&mut abs_events, channel, from_key, start_tick, end_tick);
+            }
             abs_events.push(AbsEvent {
                 tick: start_tick,
                 priority: 2,
@@ -498,12 +2085,11 @@ fn build_note_track(
                     channel: u4::new(channel),
                     message: MidiMessage::NoteOn {
                         key: u7::new(note.midi_key),
-                        vel: u7::new(100),
+                        vel: u7::new(note.velocity),
                     },
                 },
             });
 
-            let end_tick = seconds_to_tick(note.end_second, tempo_points, tpq).max(start_tick + 1);
             abs_events.push(AbsEvent {
                 tick: end_tick,
                 priority: 0,
@@ -511,7 +2097,7 @@ fn build_note_track(
                     channel: u4::new(channel),
                     message: MidiMessage::NoteOff {
                         key: u7::new(note.midi_key),
-                        vel: u7::new(0),
+                        vel: u7::new(note.release),
                     },
                 },
             });
@@ -521,8 +2107,46 @@ fn build_note_track(
     to_delta_track(abs_events)
 }
 
-fn append_rpn_pitch_bend_setup(abs_events: &mut Vec<AbsEvent>, channel: u8, bend_range: u16) {
-    let coarse = bend_range.min(127) as u8;
+fn build_percussion_track(
+    notes: &[NoteSpec],
+    tempo_points: &[TempoPoint],
+    tpq: u16,
+) -> Vec<TrackEvent<'static>> {
+    let channel = u4::new(PERCUSSION_CHANNEL);
+    let mut abs_events = Vec::new();
+    for note in notes {
+        let start_tick = seconds_to_tick(note.start_second, tempo_points, tpq);
+        abs_events.push(AbsEvent {
+            tick: start_tick,
+            priority: 2,
+            kind: TrackEventKind::Midi {
+                channel,
+                message: MidiMessage::NoteOn {
+                    key: u7::new(note.midi_key),
+                    vel: u7::new(note.velocity),
+                },
+            },
+        });
+        let end_tick = seconds_to_tick(note.end_second, tempo_points, tpq).max(start_tick + 1);
+        abs_events.push(AbsEvent {
+            tick: end_tick,
+            priority: 0,
+            kind: TrackEventKind::Midi {
+                channel,
+                message: MidiMessage::NoteOff {
+                    key: u7::new(note.midi_key),
+                    vel: u7::new(note.release),
+                },
+            },
+        });
+    }
+    to_delta_track(abs_events)
+}
+
+fn append_rpn_pitch_bend_setup(abs_events: &mut Vec<AbsEvent>, channel: u8, bend_range_cents: u32) {
+    // semitones on the data entry MSB, cents on the LSB
+    let coarse = (bend_range_cents / 100).min(127) as u8;
+    let fine = (bend_range_cents % 100) as u8;
     let set_cc = |controller: u8, value: u8| AbsEvent {
         tick: 0,
         priority: 0,
@@ -538,28 +2162,161 @@ fn append_rpn_pitch_bend_setup(abs_events: &mut Vec<AbsEvent>, channel: u8, bend
     abs_events.push(set_cc(101, 0));
     abs_events.push(set_cc(100, 0));
     abs_events.push(set_cc(6, coarse));
-    abs_events.push(set_cc(38, 0));
+    abs_events.push(set_cc(38, fine));
 }
 
-fn seconds_to_tick(second: f64, tempo_points: &[TempoPoint], tpq: u16) -> u64 {
-    if tempo_points.is_empty() {
-        return 0;
-    }
-    let mut idx = 0;
-    for (i, tp) in tempo_points.iter().enumerate() {
-        if tp.second <= second {
-            idx = i;
-        } else {
-            break;
-        }
-    }
-    let base = tempo_points[idx];
-    let dt = (second - base.second).max(0.0);
-    base.start_tick
-        .saturating_add(seconds_to_ticks_with_mpq(dt, base.mpq, tpq))
+/// Selects MTS tuning program `program` on `channel` (RPN 3).
+fn append_rpn_tuning_program_select(abs_events: &mut Vec<AbsEvent>, channel: u8, program: u8) {
+    let set_cc = |controller: u8, value: u8| AbsEvent {
+        tick: 0,
+        priority: 0,
+        kind: TrackEventKind::Midi {
+            channel: u4::new(channel),
+            message: MidiMessage::Controller {
+                controller: u7::new(controller),
+                value: u7::new(value),
+            },
+        },
+    };
+
+    abs_events.push(set_cc(101, 0));
+    abs_events.push(set_cc(100, 3));
+    abs_events.push(set_cc(6, program.min(127)));
 }
 
-fn seconds_to_ticks_with_mpq(second: f64, mpq: u32, tpq: u16) -> u64 {
+/// Single-note tuning changes retuning the keys of each group of `layout`, as it starts, to
+/// the exact pitches of its notes. Keys already tuned that way are left alone.
+fn collect_tuning_changes(layout: &TrackLayout, program: u8) -> Vec<TuningChange> {
+    let mut tuned: HashMap<u8, [u8; 3]> = HashMap::new();
+    let mut changes = Vec::new();
+    for group in &layout.groups {
+        let keys: Vec<(u8, [u8; 3])> = group
+            .notes
+            .iter()
+            .map(|note| {
+                (
+                    note.midi_key,
+                    mts_frequency_data(note.midi_key, note.bend_cents),
+                )
+            })
+            .filter(|&(key, data)| tuned.insert(key, data) != Some(data))
+            .collect();
+        for chunk in keys.chunks(MTS_MAX_KEYS) {
+            changes.push(TuningChange {
+                second: group.start_second,
+                data: mts_single_note_tuning(program, chunk),
+            });
+        }
+    }
+    changes
+}
+
+/// Real-time single-note tuning change (`F0 7F 7F 08 02 tt ll [kk xx yy zz].. F7`) of
+/// tuning program `program`, without the leading `F0`.
+fn mts_single_note_tuning(program: u8, keys: &[(u8, [u8; 3])]) -> Vec<u8> {
+    let mut data = vec![0x7F, 0x7F, 0x08, 0x02, program.min(127), keys.len() as u8];
+    for (key, frequency) in keys {
+        data.push(*key);
+        data.extend_from_slice(frequency);
+    }
+    data.push(0xF7);
+    data
+}
+
+/// MTS frequency data of the pitch `cents` away from `key`: the semitone below it, then
+/// the 14-bit fraction of a semitone above that one.
+fn mts_frequency_data(key: u8, cents: f64) -> [u8; 3] {
+    let exact = (f64::from(key) + cents / 100.0).clamp(0.0, MTS_MAX_SEMITONES);
+    let mut semitone = exact.floor() as u8;
+    let mut fraction = ((exact - exact.floor()) * 16384.0).round() as u16;
+    if fraction >= 16384 {
+        // rounded up to the next semitone
+        semitone += 1;
+        fraction = 0;
+    }
+    [semitone, (fraction >> 7) as u8, (fraction & 0x7F) as u8]
+}
+
+/// Interpolates the envelope breakpoints into PitchBend messages between the two ticks.
+fn append_bend_envelope(
+    abs_events: &mut Vec<AbsEvent>,
+    channel: u8,
+    points: &[u16],
+    start_tick: u64,
+    end_tick: u64,
+) {
+    if points.len() < 2 || end_tick <= start_tick {
+        return;
+    }
+    let segments = points.len() as u64 - 1;
+    let total_steps = segments * BEND_ENVELOPE_STEPS;
+    for step in 0..=total_steps {
+        let segment = (step / BEND_ENVELOPE_STEPS).min(segments - 1) as usize;
+        let t = (step - segment as u64 * BEND_ENVELOPE_STEPS) as f64 / BEND_ENVELOPE_STEPS as f64;
+        let from = bend14_to_signed(points[segment]) as f64;
+        let to = bend14_to_signed(points[segment + 1]) as f64;
+        let bend = signed_to_bend14((from + (to - from) * t).round() as i32);
+        abs_events.push(AbsEvent {
+            tick: start_tick + (end_tick - start_tick) * step / total_steps,
+            priority: 1,
+            kind: TrackEventKind::Midi {
+                channel: u4::new(channel),
+                message: MidiMessage::PitchBend {
+                    bend: PitchBend(u14::new(bend)),
+                },
+            },
+        });
+    }
+}
+
+/// Switches portamento on (CC65) for one note, gliding from `from_key` (CC84) over CC5 time.
+fn append_portamento(
+    abs_events: &mut Vec<AbsEvent>,
+    channel: u8,
+    from_key: u8,
+    start_tick: u64,
+    end_tick: u64,
+) {
+    let set_cc = |tick: u64, controller: u8, value: u8| AbsEvent {
+        tick,
+        priority: 1,
+        kind: TrackEventKind::Midi {
+            channel: u4::new(channel),
+            message: MidiMessage::Controller {
+                controller: u7::new(controller),
+                value: u7::new(value),
+            },
+        },
+    };
+
+    abs_events.push(set_cc(start_tick, 5, PORTAMENTO_TIME));
+    abs_events.push(set_cc(start_tick, 84, from_key.min(127)));
+    abs_events.push(set_cc(start_tick, 65, 127));
+    abs_events.push(AbsEvent {
+        priority: 0,
+        ..set_cc(end_tick, 65, 0)
+    });
+}
+
+pub(crate) fn seconds_to_tick(second: f64, tempo_points: &[TempoPoint], tpq: u16) -> u64 {
+    if tempo_points.is_empty() {
+        return 0;
+    }
+    let mut idx = 0;
+    for (i, tp) in tempo_points.iter().enumerate() {
+        if tp.second <= second {
+            idx = i;
+        } else {
+            break;
+        }
+    }
+    let base = tempo_points[idx];
+    let dt = (second - base.second).max(0.0);
+    base.start_tick
+        .saturating_add(seconds_to_ticks_with_mpq(dt, base.mpq, tpq))
+}
+
+fn seconds_to_ticks_with_mpq(second: f64, mpq: u32, tpq: u16) -> u64 {
     let ticks = second * (1_000_000.0 / mpq as f64) * tpq as f64;
     if ticks.is_finite() && ticks > 0.0 {
         ticks.round() as u64
@@ -568,7 +2325,7 @@ fn seconds_to_ticks_with_mpq(second: f64, mpq: u32, tpq: u16) -> u64 {
     }
 }
 
-fn to_delta_track(mut abs_events: Vec<AbsEvent>) -> Vec<TrackEvent<'static>> {
+fn to_delta_track(mut abs_events: Vec<AbsEvent>) -> Vec<TrackEvent> {
     abs_events.sort_by(|a, b| {
         a.tick
             .cmp(&b.tick)
@@ -596,7 +2353,14 @@ mod tests {
     use std::sync::Arc;
 
     use super::*;
-    use crate::{compiler::compile::Compiler, rowan::parse_fn::parse_source};
+    use crate::{
+        compiler::{
+            compile::Compiler,
+            rational::Rational64,
+            types::{CompilerConfig, Pitch},
+        },
+        rowan::parse_fn::parse_source,
+    };
 
     #[test]
     fn export_compiled_events_to_smf1() {
@@ -683,14 +2447,1256 @@ mod tests {
         println!("Extracted pitch bends: {:?}", bends);
     }
 
+    #[test]
+    fn export_lyrics_as_meta_events() {
+        let source = Arc::from("(4/4)\n(120)\nC4\"la\",D4\"di\",\n");
+        let parsed = parse_source(source);
+        let mut compiler = Compiler::new();
+        compiler.compile(&parsed.syntax_node());
+        let bytes = export_smf_format1(&compiler.events, MidiWriterConfig::default())
+            .expect("midi export should succeed");
+        let parsed_midi = Smf::parse(&bytes).expect("generated bytes should be valid SMF");
+
+        // lyrics go with the notes they are sung to
+        assert!(track_lyrics(&parsed_midi.tracks[0]).is_empty());
+        assert_eq!(
+            track_lyrics(&parsed_midi.tracks[1]),
+            vec![(0, "la".to_string()), (480, "di".to_string())]
+        );
+    }
+
+    fn track_lyrics(track: &[TrackEvent]) -> Vec<(u32, String)> {
+        let mut tick = 0_u32;
+        let mut lyrics = Vec::new();
+        for event in track {
+            tick += event.delta.as_int();
+            if let TrackEventKind::Meta(MetaMessage::Lyric(text)) = event.kind {
+                lyrics.push((tick, String::from_utf8_lossy(text).into_owned()));
+            }
+        }
+        lyrics
+    }
+
+    #[test]
+    fn export_lyric_syllables_on_their_voice_tracks() {
+        let source = Arc::from(
+            "=v1: C4\"Hap\",D4\"-py\",E4\"_\",F4\"day\",\nv2: G4\"ah-\",A4\"men\",.\"oh\",,\n",
+        );
+        let parsed = parse_source(source);
+        let mut compiler = Compiler::new();
+        compiler.compile(&parsed.syntax_node());
+        let bytes = export_smf_format1(&compiler.events, MidiWriterConfig::default())
+            .expect("midi export should succeed");
+        let parsed_midi = Smf::parse(&bytes).expect("generated bytes should be valid SMF");
+
+        let lyrics = |track: usize| track_lyrics(&parsed_midi.tracks[track]);
+        assert_eq!(
+            lyrics(1),
+            vec![
+                (0, "Hap-".to_string()),
+                (480, "py".to_string()),
+                (1440, "day".to_string())
+            ]
+        );
+        assert_eq!(
+            lyrics(2),
+            vec![(0, "ah-".to_string()), (480, "men".to_string())]
+        );
+        // a lyric on a rest has no note to go with
+        assert_eq!(lyrics(0), vec![(960, "oh".to_string())]);
+    }
+
+    #[test]
+    fn export_sections_as_markers() {
+        let source = Arc::from("(section \"Intro\")C4,D4,E4,F4,\n(section \"Verse\")G4,,,,\n");
+        let parsed = parse_source(source);
+        let mut compiler = Compiler::new();
+        compiler.compile(&parsed.syntax_node());
+        let bytes = export_smf_format1(&compiler.events, MidiWriterConfig::default())
+            .expect("midi export should succeed");
+        let parsed_midi = Smf::parse(&bytes).expect("generated bytes should be valid SMF");
+
+        let mut tick = 0_u32;
+        let mut markers = Vec::new();
+        for event in &parsed_midi.tracks[0] {
+            tick += event.delta.as_int();
+            if let TrackEventKind::Meta(MetaMessage::Marker(text)) = event.kind {
+                markers.push((tick, text.to_vec()));
+            }
+        }
+        assert_eq!(
+            markers,
+            vec![(0, b"Intro".to_vec()), (1920, b"Verse".to_vec())]
+        );
+    }
+
+    #[test]
+    fn export_cue_points_and_loop_markers() {
+        let source =
+            "(loop end)(cue \"Hit\")C4,(loop start)D4,E4,(loop end)F4,\n(loop start)G4,,,,\n";
+        let parsed = parse_source(Arc::from(source));
+        let mut compiler = Compiler::new();
+        compiler.compile(&parsed.syntax_node());
+        let bytes = export_smf_format1(&compiler.events, MidiWriterConfig::default())
+            .expect("midi export should succeed");
+        let parsed_midi = Smf::parse(&bytes).expect("generated bytes should be valid SMF");
+
+        let mut tick = 0_u32;
+        let (mut markers, mut cues) = (Vec::new(), Vec::new());
+        for event in &parsed_midi.tracks[0] {
+            tick += event.delta.as_int();
+            match event.kind {
+                TrackEventKind::Meta(MetaMessage::Marker(text)) => markers.push((tick, text)),
+                TrackEventKind::Meta(MetaMessage::CuePoint(text)) => cues.push((tick, text)),
+                _ => {}
+            }
+        }
+        assert_eq!(cues, vec![(0, &b"Hit"[..])]);
+        // the stray end is left out, the open loop runs to the end of the piece
+        assert_eq!(
+            markers,
+            vec![
+                (480, &b"loopStart"[..]),
+                (1440, &b"loopEnd"[..]),
+                (1920, &b"loopStart"[..]),
+                (3840, &b"loopEnd"[..]),
+            ]
+        );
+
+        let report = validate_export(&compiler.events, &MidiWriterConfig::default());
+        assert!(!report.has_errors());
+        let unpaired: Vec<&str> = report
+            .issues
+            .iter()
+            .filter(|issue| issue.kind == MidiExportIssueKind::UnpairedLoop)
+            .filter_map(|issue| issue.range)
+            .map(|range| &source[range])
+            .collect();
+        assert_eq!(unpaired, vec!["(loop end)", "(loop start)"]);
+    }
+
+    #[test]
+    fn export_tempo_ramps_as_gliding_tempos() {
+        let tempos = |source: &str, steps: u32| -> Vec<(u32, u32)> {
+            let parsed = parse_source(Arc::from(source));
+            let mut compiler = Compiler::new();
+            compiler.compile(&parsed.syntax_node());
+            let config = MidiWriterConfig {
+                tempo_ramp_steps: steps,
+                ..MidiWriterConfig::default()
+            };
+            let bytes =
+                export_smf_format1(&compiler.events, config).expect("midi export should succeed");
+            let parsed_midi = Smf::parse(&bytes).expect("generated bytes should be valid SMF");
+            let mut tick = 0_u32;
+            let mut tempos = Vec::new();
+            for event in &parsed_midi.tracks[0] {
+                tick += event.delta.as_int();
+                if let TrackEventKind::Meta(MetaMessage::Tempo(mpq)) = event.kind {
+                    tempos.push((tick, mpq.as_int()));
+                }
+            }
+            tempos
+        };
+        let ramp = "(100)C4,,,,\n(110)C4,,,,\n(120)C4,,,,\n(130)C4,,,,\n";
+        assert_eq!(
+            tempos(ramp, 0),
+            vec![
+                (0, 600_000),
+                (1920, 545_455),
+                (3840, 500_000),
+                (5760, 461_538)
+            ]
+        );
+
+        let glide = tempos(ramp, 4);
+        assert_eq!(glide.len(), 13);
+        assert!(glide.windows(2).all(|w| w[1].1 < w[0].1));
+        assert_eq!(glide.last(), Some(&(5760, 461_538)));
+        // every bar keeps its average tempo
+        for (bar, mpq) in [600_000, 545_455, 500_000].into_iter().enumerate() {
+            let sum: u32 = glide[bar * 4..bar * 4 + 4].iter().map(|&(_, m)| m).sum();
+            assert!((sum / 4).abs_diff(mpq) <= 1);
+        }
+
+        // back and forth is no ramp
+        let jumps = "(100)C4,,,,\n(120)C4,,,,\n(100)C4,,,,\n";
+        assert_eq!(tempos(jumps, 4), tempos(jumps, 0));
+    }
+
+    #[test]
+    fn export_volume_and_pan_as_controllers() {
+        let source = Arc::from("(volume 0.5~)(pan -1)C4,D4,\n(volume 1)E4,F4,\n");
+        let parsed = parse_source(source);
+        let mut compiler = Compiler::new();
+        compiler.compile(&parsed.syntax_node());
+        let config = MidiWriterConfig {
+            controller_automation: ControllerAutomation {
+                rate_hz: 4.0,
+                expression: false,
+                sustain_pedal: true,
+                vibrato_modulation: false,
+            },
+            ..MidiWriterConfig::default()
+        };
+        let bytes =
+            export_smf_format1(&compiler.events, config).expect("midi export should succeed");
+        let parsed_midi = Smf::parse(&bytes).expect("generated bytes should be valid SMF");
+
+        let mut tick = 0_u32;
+        let mut controls = Vec::new();
+        for event in &parsed_midi.tracks[1] {
+            tick += event.delta.as_int();
+            if let TrackEventKind::Midi {
+                message: MidiMessage::Controller { controller, value },
+                ..
+            } = event.kind
+                && matches!(controller.as_int(), 7 | 10 | 11)
+            {
+                controls.push((tick, controller.as_int(), value.as_int()));
+            }
+        }
+        // the volume glides over the first second in quarters of a second
+        assert_eq!(
+            controls,
+            vec![
+                (0, 7, 64),
+                (0, 10, 0),
+                (240, 7, 79),
+                (480, 7, 95),
+                (720, 7, 111),
+                (960, 7, 127)
+            ]
+        );
+    }
+
+    #[test]
+    fn export_sustain_pedal_as_controller() {
+        let parsed = parse_source(Arc::from("(pedal down)C4,E4,(pedal up)G4,\n"));
+        let mut compiler = Compiler::new();
+        compiler.compile(&parsed.syntax_node());
+        let pedals = |sustain_pedal: bool| -> Vec<(u32, u8)> {
+            let config = MidiWriterConfig {
+                controller_automation: ControllerAutomation {
+                    sustain_pedal,
+                    ..ControllerAutomation::default()
+                },
+                ..MidiWriterConfig::default()
+            };
+            let bytes =
+                export_smf_format1(&compiler.events, config).expect("midi export should succeed");
+            let parsed_midi = Smf::parse(&bytes).expect("generated bytes should be valid SMF");
+            let mut tick = 0_u32;
+            let mut pedals = Vec::new();
+            for event in &parsed_midi.tracks[1] {
+                tick += event.delta.as_int();
+                if let TrackEventKind::Midi {
+                    message: MidiMessage::Controller { controller, value },
+                    ..
+                } = event.kind
+                    && controller.as_int() == 64
+                {
+                    pedals.push((tick, value.as_int()));
+                }
+            }
+            pedals
+        };
+        assert_eq!(pedals(true), [(0, 127), (960, 0)]);
+        assert!(pedals(false).is_empty());
+    }
+
+    #[test]
+    fn export_vibrato_as_aftertouch_or_modulation() {
+        let parsed = parse_source(Arc::from("C4~vib,D4,\n"));
+        let mut compiler = Compiler::new();
+        compiler.compile(&parsed.syntax_node());
+        let levels = |vibrato_modulation: bool| -> Vec<(u32, u8)> {
+            let config = MidiWriterConfig {
+                controller_automation: ControllerAutomation {
+                    vibrato_modulation,
+                    ..ControllerAutomation::default()
+                },
+                ..MidiWriterConfig::default()
+            };
+            let bytes =
+                export_smf_format1(&compiler.events, config).expect("midi export should succeed");
+            let parsed_midi = Smf::parse(&bytes).expect("generated bytes should be valid SMF");
+            let mut tick = 0_u32;
+            let mut levels = Vec::new();
+            for event in &parsed_midi.tracks[1] {
+                tick += event.delta.as_int();
+                match event.kind {
+                    TrackEventKind::Midi {
+                        message: MidiMessage::ChannelAftertouch { vel },
+                        ..
+                    } if !vibrato_modulation => levels.push((tick, vel.as_int())),
+                    TrackEventKind::Midi {
+                        message: MidiMessage::Controller { controller, value },
+                        ..
+                    } if vibrato_modulation && controller.as_int() == 1 => {
+                        levels.push((tick, value.as_int()))
+                    }
+                    _ => {}
+                }
+            }
+            levels
+        };
+        // swelling over the first half of the quarter note, in steps of a twentieth of a second
+        let expected = [
+            (0, 0),
+            (48, 25),
+            (96, 51),
+            (144, 76),
+            (192, 102),
+            (240, 127),
+            (480, 0),
+        ];
+        assert_eq!(levels(false), expected);
+        assert_eq!(levels(true), expected);
+    }
+
+    #[test]
+    fn export_humanized_notes_deterministically() {
+        let parsed = parse_source(Arc::from("C4:E4,D4,F4,G4,\nA4:C5,B4,D5,E5,\n"));
+        let mut compiler = Compiler::new();
+        compiler.compile(&parsed.syntax_node());
+        let note_ons = |humanize: Option<Humanize>| -> Vec<(u32, u8, u8)> {
+            let config = MidiWriterConfig {
+                humanize,
+                ..MidiWriterConfig::default()
+            };
+            let bytes =
+                export_smf_format1(&compiler.events, config).expect("midi export should succeed");
+            let parsed_midi = Smf::parse(&bytes).expect("generated bytes should be valid SMF");
+            let mut note_ons = Vec::new();
+            for track in &parsed_midi.tracks {
+                let mut tick = 0_u32;
+                for event in track {
+                    tick += event.delta.as_int();
+                    if let TrackEventKind::Midi {
+                        message: MidiMessage::NoteOn { key, vel },
+                        ..
+                    } = event.kind
+                        && vel.as_int() > 0
+                    {
+                        note_ons.push((tick, key.as_int(), vel.as_int()));
+                    }
+                }
+            }
+            note_ons.sort();
+            note_ons
+        };
+        let humanize = Humanize {
+            timing_seconds: 0.02,
+            velocity: 10,
+            seed: 7,
+        };
+        let plain = note_ons(None);
+        let human = note_ons(Some(humanize));
+        assert_eq!(human, note_ons(Some(humanize)));
+        assert_ne!(human, plain);
+        assert_eq!(human.len(), plain.len());
+
+        let by_key =
+            |notes: &[(u32, u8, u8)], key: u8| notes.iter().find(|n| n.1 == key).copied().unwrap();
+        for &(tick, key, vel) in &plain {
+            let (human_tick, _, human_vel) = by_key(&human, key);
+            // 0.02s is 19.2 ticks at 120 bpm
+            assert!(human_tick.abs_diff(tick) <= 20);
+            assert!(human_vel.abs_diff(vel) <= 10);
+        }
+        // notes of a chord move together
+        assert_eq!(by_key(&human, 60).0, by_key(&human, 64).0);
+    }
+
+    #[test]
+    fn export_tempo_and_meter_of_the_compiler_config() {
+        let parsed = parse_source(Arc::from("C4,D4,E4,\nF4,\n"));
+        let mut compiler = Compiler::with_config(CompilerConfig {
+            bpm: 60.0,
+            time_signature: Rational64::new(3, 4),
+            ..Default::default()
+        });
+        compiler.compile(&parsed.syntax_node());
+        let bytes = export_smf_format1(&compiler.events, MidiWriterConfig::default())
+            .expect("midi export should succeed");
+        let parsed_midi = Smf::parse(&bytes).expect("generated bytes should be valid SMF");
+
+        let meta: Vec<u32> = parsed_midi.tracks[0]
+            .iter()
+            .filter_map(|event| match event.kind {
+                TrackEventKind::Meta(MetaMessage::Tempo(mpq)) => Some(mpq.as_int()),
+                TrackEventKind::Meta(MetaMessage::TimeSignature(numerator, ..)) => {
+                    Some(u32::from(numerator))
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(meta, vec![1_000_000, 3]);
+    }
+
+    #[test]
+    fn export_time_range_from_its_start() {
+        let parsed = parse_source(Arc::from("(100)(3/4)C4,D4,E4,\n(150)F4,G4,A4,\n"));
+        let mut compiler = Compiler::new();
+        compiler.compile(&parsed.syntax_node());
+        // from the middle of E4 to the middle of G4
+        let config = MidiWriterConfig {
+            start_second: 1.5,
+            end_second: Some(2.4),
+            ..MidiWriterConfig::default()
+        };
+        let bytes =
+            export_smf_format1(&compiler.events, config).expect("midi export should succeed");
+        let parsed_midi = Smf::parse(&bytes).expect("generated bytes should be valid SMF");
+
+        let mut tick = 0_u32;
+        let mut meta = Vec::new();
+        for event in &parsed_midi.tracks[0] {
+            tick += event.delta.as_int();
+            match event.kind {
+                TrackEventKind::Meta(MetaMessage::Tempo(mpq)) => meta.push((tick, mpq.as_int())),
+                TrackEventKind::Meta(MetaMessage::TimeSignature(numerator, ..)) => {
+                    meta.push((tick, u32::from(numerator)))
+                }
+                _ => {}
+            }
+        }
+        assert_eq!(meta, vec![(0, 600_000), (0, 3), (240, 400_000)]);
+
+        let mut tick = 0_u32;
+        let mut notes = Vec::new();
+        for event in &parsed_midi.tracks[1] {
+            tick += event.delta.as_int();
+            match event.kind {
+                TrackEventKind::Midi {
+                    message: MidiMessage::NoteOn { key, vel },
+                    ..
+                } if vel.as_int() > 0 => notes.push((tick, key.as_int(), true)),
+                TrackEventKind::Midi {
+                    message: MidiMessage::NoteOff { key, .. } | MidiMessage::NoteOn { key, .. },
+                    ..
+                } => notes.push((tick, key.as_int(), false)),
+                _ => {}
+            }
+        }
+        assert_eq!(
+            notes,
+            vec![
+                (0, 64, true),
+                (240, 64, false),
+                (240, 65, true),
+                (720, 65, false),
+                (720, 67, true),
+                (960, 67, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn export_split_by_voice_as_separate_files() {
+        let parsed = parse_source(Arc::from(
+            "v2: (volume 0.5)C4,D4,\nE4,F4,\nv1: G4,A4,\ndrums: kick,,\n",
+        ));
+        let mut compiler = Compiler::new();
+        compiler.compile(&parsed.syntax_node());
+        let files = export_split_by_voice(&compiler.events, MidiWriterConfig::default())
+            .expect("midi export should succeed");
+        let names: Vec<&str> = files.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, vec!["main", "v2", "v1", "drums"]);
+
+        // (keys of the notes, whether a volume controller is written) of each file
+        let contents: Vec<(Vec<u8>, bool)> = files
+            .iter()
+            .map(|(_, bytes)| {
+                let parsed_midi = Smf::parse(bytes).expect("generated bytes should be valid SMF");
+                let mut keys = Vec::new();
+                let mut volume = false;
+                for event in parsed_midi.tracks.iter().flatten() {
+                    match event.kind {
+                        TrackEventKind::Midi {
+                            message: MidiMessage::NoteOn { key, vel },
+                            ..
+                        } if vel.as_int() > 0 => keys.push(key.as_int()),
+                        TrackEventKind::Midi {
+                            message: MidiMessage::Controller { controller, .. },
+                            ..
+                        } if controller.as_int() == 7 => volume = true,
+                        _ => {}
+                    }
+                }
+                (keys, volume)
+            })
+            .collect();
+        assert_eq!(
+            contents,
+            vec![
+                (vec![64, 65], false),
+                (vec![60, 62], true),
+                (vec![67, 69], false),
+                (vec![36], false),
+            ]
+        );
+    }
+
+    #[test]
+    fn export_smpte_timecode() {
+        let parsed = parse_source(Arc::from("(60)C4,,,,\n(120)D4,,,,\nE4,\n"));
+        let mut compiler = Compiler::new();
+        compiler.compile(&parsed.syntax_node());
+        struct Exported {
+            timing: Timing,
+            /// Tempo changes as (tick, microseconds per quarter)
+            tempos: Vec<(u32, u32)>,
+            note_ons: Vec<u32>,
+        }
+        let export = |fps: u8, subframes: u8| -> Result<Exported> {
+            let config = MidiWriterConfig {
+                timing: MidiTiming::Timecode { fps, subframes },
+                ..MidiWriterConfig::default()
+            };
+            let bytes = export_smf_format1(&compiler.events, config)?;
+            let parsed_midi = Smf::parse(&bytes).expect("generated bytes should be valid SMF");
+            let mut tempos = Vec::new();
+            let mut note_ons = Vec::new();
+            for track in &parsed_midi.tracks {
+                let mut tick = 0_u32;
+                for event in track {
+                    tick += event.delta.as_int();
+                    match event.kind {
+                        TrackEventKind::Meta(MetaMessage::Tempo(mpq)) => {
+                            tempos.push((tick, mpq.as_int()))
+                        }
+                        TrackEventKind::Midi {
+                            message: MidiMessage::NoteOn { vel, .. },
+                            ..
+                        } if vel.as_int() > 0 => note_ons.push(tick),
+                        _ => {}
+                    }
+                }
+            }
+            note_ons.sort();
+            Ok(Exported {
+                timing: parsed_midi.header.timing,
+                tempos,
+                note_ons,
+            })
+        };
+
+        // 1000 ticks a second at any tempo
+        let exported = export(25, 40).expect("midi export should succeed");
+        assert_eq!(exported.timing, Timing::Timecode(Fps::Fps25, 40));
+        assert_eq!(exported.tempos, vec![(0, 1_000_000), (4000, 500_000)]);
+        assert_eq!(exported.note_ons, vec![0, 4000, 6000]);
+
+        // 29.97 frames a second
+        let exported = export(29, 100).expect("midi export should succeed");
+        assert_eq!(exported.timing, Timing::Timecode(Fps::Fps29, 100));
+        assert_eq!(exported.note_ons, vec![0, 11988, 17982]);
+
+        assert!(export(23, 40).is_err());
+        assert!(export(30, 0).is_err());
+    }
+
+    #[test]
+    fn export_key_signatures() {
+        let key_signatures = |source: &str| -> Vec<(u32, i8, bool)> {
+            let parsed = parse_source(Arc::from(source));
+            let mut compiler = Compiler::new();
+            compiler.compile(&parsed.syntax_node());
+            let bytes = export_smf_format1(&compiler.events, MidiWriterConfig::default())
+                .expect("midi export should succeed");
+            let parsed_midi = Smf::parse(&bytes).expect("generated bytes should be valid SMF");
+            let mut tick = 0_u32;
+            let mut keys = Vec::new();
+            for event in &parsed_midi.tracks[0] {
+                tick += event.delta.as_int();
+                if let TrackEventKind::Meta(MetaMessage::KeySignature(sharps, minor)) = event.kind {
+                    keys.push((tick, sharps, minor));
+                }
+            }
+            keys
+        };
+        // inferred from the base notes, once per change of key
+        assert_eq!(
+            key_signatures("<Eb4>\nC4,D4,E4,F4,\n<20c>\n<D4>G4,,,,\n"),
+            vec![(0, -3, false), (1920, 2, false)]
+        );
+        // a key directive replaces the inference
+        assert_eq!(
+            key_signatures("<Eb4>(key C minor)\nC4,D4,E4,F4,\n(key C)G4,,,,\n"),
+            vec![(0, -3, true), (1920, 0, false)]
+        );
+        assert!(key_signatures("C4,\n").is_empty());
+    }
+
+    #[test]
+    fn export_additive_time_signature() {
+        let source = Arc::from("(3+2/8)\n{8}C4,,,D4,,\n");
+        let parsed = parse_source(source);
+        let mut compiler = Compiler::new();
+        compiler.compile(&parsed.syntax_node());
+        let bytes = export_smf_format1(&compiler.events, MidiWriterConfig::default())
+            .expect("midi export should succeed");
+        let parsed_midi = Smf::parse(&bytes).expect("generated bytes should be valid SMF");
+
+        let signatures: Vec<_> = parsed_midi.tracks[0]
+            .iter()
+            .filter_map(|e| match e.kind {
+                TrackEventKind::Meta(MetaMessage::TimeSignature(n, d, c, b)) => Some((n, d, c, b)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(signatures, vec![(5, 3, 12, 8)]);
+    }
+
+    #[test]
+    fn export_mts_tuning_instead_of_pitch_bends() {
+        let source = Arc::from("440.0,450.0,440.0,\n");
+        let parsed = parse_source(source);
+        let mut compiler = Compiler::new();
+        compiler.compile(&parsed.syntax_node());
+        let config = MidiWriterConfig {
+            tuning: MidiTuning::Mts,
+            ..MidiWriterConfig::default()
+        };
+        let bytes =
+            export_smf_format1(&compiler.events, config).expect("midi export should succeed");
+        let parsed_midi = Smf::parse(&bytes).expect("generated bytes should be valid SMF");
+
+        let track = &parsed_midi.tracks[1];
+        assert!(!track.iter().any(|e| matches!(
+            e.kind,
+            TrackEventKind::Midi {
+                message: MidiMessage::PitchBend { .. },
+                ..
+            }
+        )));
+        let sysex: Vec<&[u8]> = track
+            .iter()
+            .filter_map(|e| match e.kind {
+                TrackEventKind::SysEx(data) => Some(data),
+                _ => None,
+            })
+            .collect();
+        // 450 Hz is 38.9 cents above A4: 6374/16384 of a semitone
+        let header = [0x7F, 0x7F, 0x08, 0x02, 0x00, 0x01, 69];
+        assert_eq!(
+            sysex,
+            vec![
+                [&header[..], &[69, 0, 0, 0xF7]].concat(),
+                [&header[..], &[69, 49, 102, 0xF7]].concat(),
+                [&header[..], &[69, 0, 0, 0xF7]].concat(),
+            ]
+        );
+    }
+
+    #[test]
+    fn export_metadata_in_meta_track() {
+        let source = Arc::from("title: Jingle Bells\ncopyright: (c) 2026\n(4/4)\nC4,\n");
+        let parsed = parse_source(source);
+        let mut compiler = Compiler::new();
+        compiler.compile(&parsed.syntax_node());
+        let bytes = export_smf_format1_with_metadata(
+            &compiler.events,
+            &compiler.metadata,
+            MidiWriterConfig::default(),
+        )
+        .expect("midi export should succeed");
+        let parsed_midi = Smf::parse(&bytes).expect("generated bytes should be valid SMF");
+
+        let meta = &parsed_midi.tracks[0];
+        assert!(meta.iter().any(|e| matches!(
+            e.kind,
+            TrackEventKind::Meta(MetaMessage::TrackName(b"Jingle Bells"))
+        )));
+        assert!(meta.iter().any(|e| matches!(
+            e.kind,
+            TrackEventKind::Meta(MetaMessage::Copyright(b"(c) 2026"))
+        )));
+    }
+
+    #[test]
+    fn export_voices_to_dedicated_tracks() {
+        // the voiced C4 would otherwise share the first track with the later E4
+        let source = Arc::from("(4/4)\nv1: C4,\n= D4,\nE4,\n");
+        let parsed = parse_source(source);
+        let mut compiler = Compiler::new();
+        compiler.compile(&parsed.syntax_node());
+        let bytes = export_smf_format1(&compiler.events, MidiWriterConfig::default())
+            .expect("midi export should succeed");
+        let parsed_midi = Smf::parse(&bytes).expect("generated bytes should be valid SMF");
+
+        let keys_per_track: Vec<Vec<u8>> = parsed_midi
+            .tracks
+            .iter()
+            .skip(1)
+            .map(|track| {
+                track
+                    .iter()
+                    .filter_map(|event| match event.kind {
+                        TrackEventKind::Midi {
+                            message: MidiMessage::NoteOn { key, .. },
+                            ..
+                        } => Some(key.as_int()),
+                        _ => None,
+                    })
+                    .collect()
+            })
+            .collect();
+        assert_eq!(keys_per_track, vec![vec![60], vec![62, 64]]);
+    }
+
+    #[test]
+    fn export_percussion_on_channel_10() {
+        let source = Arc::from("(4/4)\nC4,\ndrums: kick:hihat,\n");
+        let parsed = parse_source(source);
+        let mut compiler = Compiler::new();
+        compiler.compile(&parsed.syntax_node());
+        let bytes = export_smf_format1(&compiler.events, MidiWriterConfig::default())
+            .expect("midi export should succeed");
+        let parsed_midi = Smf::parse(&bytes).expect("generated bytes should be valid SMF");
+
+        let drum_hits: Vec<u8> = parsed_midi
+            .tracks
+            .iter()
+            .flat_map(|track| track.iter())
+            .filter_map(|event| match event.kind {
+                TrackEventKind::Midi {
+                    channel,
+                    message: MidiMessage::NoteOn { key, .. },
+                } if channel.as_int() == PERCUSSION_CHANNEL => Some(key.as_int()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(drum_hits, vec![36, 42]);
+    }
+
+    #[test]
+    fn export_percussion_voices_through_key_map() {
+        let source = Arc::from("(4/4)\nv1: C4,D4,\ndrums: kick,\nv2: E4,\n");
+        let parsed = parse_source(source);
+        let mut compiler = Compiler::new();
+        compiler.compile(&parsed.syntax_node());
+        let config = MidiWriterConfig {
+            percussion_voices: vec![PercussionVoice {
+                voice: "v1".to_string(),
+                key_map: vec![(60, 38)],
+            }],
+            ..MidiWriterConfig::default()
+        };
+        let bytes =
+            export_smf_format1(&compiler.events, config).expect("midi export should succeed");
+        let parsed_midi = Smf::parse(&bytes).expect("generated bytes should be valid SMF");
+
+        // (channel, key) of the notes of each track after the meta track
+        let notes: Vec<Vec<(u8, u8)>> = parsed_midi.tracks[1..]
+            .iter()
+            .map(|track| {
+                track
+                    .iter()
+                    .filter_map(|event| match event.kind {
+                        TrackEventKind::Midi {
+                            channel,
+                            message: MidiMessage::NoteOn { key, .. },
+                        } => Some((channel.as_int(), key.as_int())),
+                        _ => None,
+                    })
+                    .collect()
+            })
+            .collect();
+        assert_eq!(notes, vec![vec![(0, 64)], vec![(9, 38), (9, 62), (9, 36)]]);
+
+        let mut events = compiler.events.clone();
+        for event in &mut events {
+            event.channel = Some(PERCUSSION_CHANNEL);
+        }
+        assert!(export_smf_format1(&events, MidiWriterConfig::default()).is_err());
+    }
+
+    #[test]
+    fn export_portamento_controllers() {
+        let source = Arc::from("(4/4)\nC4~D4,\n");
+        let parsed = parse_source(source);
+        let mut compiler = Compiler::new();
+        compiler.compile(&parsed.syntax_node());
+        let bytes = export_smf_format1(&compiler.events, MidiWriterConfig::default())
+            .expect("midi export should succeed");
+        let parsed_midi = Smf::parse(&bytes).expect("generated bytes should be valid SMF");
+
+        let controllers: Vec<(u8, u8)> = parsed_midi
+            .tracks
+            .iter()
+            .flat_map(|track| track.iter())
+            .filter_map(|event| match event.kind {
+                TrackEventKind::Midi {
+                    message: MidiMessage::Controller { controller, value },
+                    ..
+                } if [5, 65, 84].contains(&controller.as_int()) => {
+                    Some((controller.as_int(), value.as_int()))
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            controllers,
+            vec![(5, PORTAMENTO_TIME), (84, 60), (65, 127), (65, 0)]
+        );
+    }
+
+    #[test]
+    fn export_note_volume_as_velocity() {
+        let source = Arc::from("(4/4)\nC4&0.5,D4,\n");
+        let parsed = parse_source(source);
+        let mut compiler = Compiler::new();
+        compiler.compile(&parsed.syntax_node());
+        let bytes = export_smf_format1(&compiler.events, MidiWriterConfig::default())
+            .expect("midi export should succeed");
+        let parsed_midi = Smf::parse(&bytes).expect("generated bytes should be valid SMF");
+
+        let velocities: Vec<u8> = parsed_midi
+            .tracks
+            .iter()
+            .flat_map(|track| track.iter())
+            .filter_map(|event| match event.kind {
+                TrackEventKind::Midi {
+                    message: MidiMessage::NoteOn { vel, .. },
+                    ..
+                } => Some(vel.as_int()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(velocities, vec![50, 100]);
+    }
+
+    #[test]
+    fn velocity_curve_shapes_note_loudness() {
+        let linear = VelocityCurve::default();
+        assert_eq!(linear.velocity(1.0), 100);
+        assert_eq!(linear.velocity(0.5), 50);
+        assert_eq!(linear.velocity(0.0), 1);
+        assert_eq!(linear.velocity(2.0), 127);
+
+        let curve = VelocityCurve {
+            full: 110.0,
+            exponent: 2.0,
+            min: 20,
+            max: 120,
+        };
+        assert_eq!(curve.velocity(1.0), 110);
+        assert_eq!(curve.velocity(0.5), 28);
+        assert_eq!(curve.velocity(0.1), 20);
+    }
+
+    #[test]
+    fn export_event_channel_instrument_and_velocity() {
+        let source = Arc::from("(4/4)\nC4&0.5,D4,\nE4,\n");
+        let parsed = parse_source(source);
+        let mut compiler = Compiler::new();
+        compiler.compile(&parsed.syntax_node());
+        let mut events = compiler.events;
+        for event in &mut events {
+            let EventBody::Note(note) = &mut event.body else {
+                continue;
+            };
+            if note.pitch_chain == [Pitch::SpellOctave(60)] {
+                note.velocity = Some(80);
+            } else if note.pitch_chain == [Pitch::SpellOctave(62)] {
+                event.channel = Some(0);
+                event.instrument = Some(40);
+            }
+        }
+        let bytes = export_smf_format1(&events, MidiWriterConfig::default())
+            .expect("midi export should succeed");
+        let parsed_midi = Smf::parse(&bytes).expect("generated bytes should be valid SMF");
+
+        let messages: Vec<(u8, MidiMessage)> = parsed_midi
+            .tracks
+            .iter()
+            .flat_map(|track| track.iter())
+            .filter_map(|event| match event.kind {
+                TrackEventKind::Midi { channel, message } => match message {
+                    MidiMessage::NoteOn { .. } | MidiMessage::ProgramChange { .. } => {
+                        Some((channel.as_int(), message))
+                    }
+                    _ => None,
+                },
+                _ => None,
+            })
+            .collect();
+        // D4 keeps channel 0 to itself, so the automatic track moves to channel 1
+        assert_eq!(
+            messages,
+            vec![
+                (
+                    1,
+                    MidiMessage::NoteOn {
+                        key: u7::new(60),
+                        vel: u7::new(40)
+                    }
+                ),
+                (
+                    1,
+                    MidiMessage::NoteOn {
+                        key: u7::new(64),
+                        vel: u7::new(100)
+                    }
+                ),
+                (
+                    0,
+                    MidiMessage::ProgramChange {
+                        program: u7::new(40)
+                    }
+                ),
+                (
+                    0,
+                    MidiMessage::NoteOn {
+                        key: u7::new(62),
+                        vel: u7::new(100)
+                    }
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn export_bend_envelope_as_interpolated_bends() {
+        let source = Arc::from("(4/4)\nC4{bend 0c..+100c},\n");
+        let parsed = parse_source(source);
+        let mut compiler = Compiler::new();
+        compiler.compile(&parsed.syntax_node());
+        let bytes = export_smf_format1(&compiler.events, MidiWriterConfig::default())
+            .expect("midi export should succeed");
+        let parsed_midi = Smf::parse(&bytes).expect("generated bytes should be valid SMF");
+
+        let bends: Vec<i32> = parsed_midi
+            .tracks
+            .iter()
+            .flat_map(|track| track.iter())
+            .filter_map(|event| match event.kind {
+                TrackEventKind::Midi {
+                    message: MidiMessage::PitchBend { bend },
+                    ..
+                } => Some(bend14_to_signed(bend.0.as_int())),
+                _ => None,
+            })
+            .collect();
+        // the initial bend, then one bend per interpolation step including both ends
+        assert_eq!(bends.len(), BEND_ENVELOPE_STEPS as usize + 2);
+        assert!(bends[1].abs() <= 1);
+        assert!(bends.windows(2).skip(1).all(|w| w[0] <= w[1]));
+        assert!((bends[bends.len() - 1] - 4096).abs() <= 1);
+    }
+
     #[test]
     fn pitch_bend_neutral_is_8192() {
-        let (key, bend14, cents) = freq_to_key_and_bend(440.0, 2).expect("A4 should convert");
+        let (key, bend14, cents) = freq_to_key_and_bend(440.0, 200).expect("A4 should convert");
         assert_eq!(key, 69);
         assert_eq!(bend14, 8192);
         assert!(cents.abs() < 1e-9);
     }
 
+    #[test]
+    fn fractional_pitch_bend_range_keeps_cent_accuracy() {
+        for cents in (-2490..=2490).map(|c| f64::from(c) / 10.0) {
+            let bend14 = cents_to_bend14(cents, 250);
+            let decoded = f64::from(bend14_to_signed(bend14)) / 8192.0 * 250.0;
+            assert!((decoded - cents).abs() <= 250.0 / 16384.0 + 1e-9);
+        }
+
+        let parsed = parse_source(Arc::from("(4/4)\n450.0,\n"));
+        let mut compiler = Compiler::new();
+        compiler.compile(&parsed.syntax_node());
+        let config = MidiWriterConfig {
+            pitch_bend_range_semitones: 2,
+            pitch_bend_range_cents: 50,
+            ..MidiWriterConfig::default()
+        };
+        let bytes =
+            export_smf_format1(&compiler.events, config).expect("midi export should succeed");
+        let parsed_midi = Smf::parse(&bytes).expect("generated bytes should be valid SMF");
+        let mut controllers = Vec::new();
+        let mut bends = Vec::new();
+        for event in &parsed_midi.tracks[1] {
+            match event.kind {
+                TrackEventKind::Midi {
+                    message: MidiMessage::Controller { controller, value },
+                    ..
+                } => controllers.push((controller.as_int(), value.as_int())),
+                TrackEventKind::Midi {
+                    message: MidiMessage::PitchBend { bend },
+                    ..
+                } => bends.push(bend.0.as_int()),
+                _ => {}
+            }
+        }
+        assert_eq!(controllers[..4], [(101, 0), (100, 0), (6, 2), (38, 50)]);
+        // 450 Hz is 38.9 cents above A4
+        let expected = 1200.0 * (450.0_f64 / 440.0).log2();
+        let decoded = f64::from(bend14_to_signed(bends[0])) / 8192.0 * 250.0;
+        assert!((decoded - expected).abs() < 0.02);
+
+        let config = MidiWriterConfig {
+            pitch_bend_range_cents: 100,
+            ..MidiWriterConfig::default()
+        };
+        assert!(export_smf_format1(&compiler.events, config).is_err());
+    }
+
+    #[test]
+    fn voice_pitch_bend_ranges_set_up_their_own_channels() {
+        let parsed = parse_source(Arc::from("(4/4)\nv1: 450.0,\nv2: 450.0,\n"));
+        let mut compiler = Compiler::new();
+        compiler.compile(&parsed.syntax_node());
+        let config = MidiWriterConfig {
+            voice_bend_ranges: vec![VoiceBendRange {
+                voice: "v1".to_string(),
+                semitones: 48,
+                cents: 0,
+            }],
+            ..MidiWriterConfig::default()
+        };
+        let bytes = export_smf_format1(&compiler.events, config.clone())
+            .expect("midi export should succeed");
+        let parsed_midi = Smf::parse(&bytes).expect("generated bytes should be valid SMF");
+        // (data entry MSB, first pitch bend) of each note track
+        let mut setups = Vec::new();
+        for track in &parsed_midi.tracks[1..] {
+            let mut coarse = None;
+            let mut bend = None;
+            for event in track {
+                match event.kind {
+                    TrackEventKind::Midi {
+                        message: MidiMessage::Controller { controller, value },
+                        ..
+                    } if controller.as_int() == 6 => coarse = coarse.or(Some(value.as_int())),
+                    TrackEventKind::Midi {
+                        message: MidiMessage::PitchBend { bend: b },
+                        ..
+                    } => bend = bend.or(Some(b)),
+                    _ => {}
+                }
+            }
+            setups.push((coarse.unwrap(), bend.unwrap()));
+        }
+        setups.sort_by_key(|&(coarse, _)| coarse);
+        assert_eq!(setups.iter().map(|s| s.0).collect::<Vec<_>>(), [2, 48]);
+        // 450 Hz is 38.9 cents above A4 on either range
+        let expected = 1200.0 * (450.0_f64 / 440.0).log2();
+        for (coarse, bend) in setups {
+            let range = f64::from(coarse) * 100.0;
+            let decoded = f64::from(bend.as_int()) / 8192.0 * range;
+            assert!(
+                (decoded - expected).abs() < range / 8192.0,
+                "{decoded} on {range}"
+            );
+        }
+
+        // both voices on one channel cannot have two ranges
+        let mut shared = compiler.events.clone();
+        for event in &mut shared {
+            event.channel = Some(3);
+        }
+        assert!(export_smf_format1(&shared, config).is_err());
+    }
+
+    #[test]
+    fn validate_export_lists_every_issue_at_its_source() {
+        let compile = |source: &str| {
+            let parsed = parse_source(Arc::from(source));
+            let mut compiler = Compiler::new();
+            compiler.compile(&parsed.syntax_node());
+            compiler.events
+        };
+        let config = MidiWriterConfig::default();
+        let clean = validate_export(&compile("(4/4)\nC4,\n"), &config);
+        assert!(clean.issues.is_empty());
+
+        let mut source = String::from("(4/4)\nC4{bend 0c..+300c},\n20000.0,\n");
+        for voice in 1..=16 {
+            source += &format!("v{voice}: E4,\n");
+        }
+        let report = validate_export(&compile(&source), &config);
+        let kinds: Vec<_> = report.issues.iter().map(|issue| issue.kind).collect();
+        assert_eq!(
+            kinds,
+            [
+                MidiExportIssueKind::BendOutOfRange,
+                MidiExportIssueKind::KeyOutOfRange,
+                MidiExportIssueKind::ExtraPort,
+                MidiExportIssueKind::ExtraPort,
+            ]
+        );
+        assert!(!report.has_errors());
+        let starts: Vec<usize> = report
+            .issues
+            .iter()
+            .map(|issue| usize::from(issue.range.expect("issue should have a source").start()))
+            .collect();
+        assert!(starts[0] < source.find("20000").unwrap());
+        assert!(starts[1] >= source.find("20000").unwrap());
+        // the two voices past the fifteen free channels, on the second port
+        assert!(starts[2] > source.find("v15:").unwrap());
+        assert!(starts[3] > source.find("v16:").unwrap());
+
+        let config = MidiWriterConfig {
+            ticks_per_quarter: 0,
+            ..MidiWriterConfig::default()
+        };
+        let report = validate_export(&compile("(4/4)\nC4,\n"), &config);
+        assert_eq!(report.issues[0].kind, MidiExportIssueKind::InvalidConfig);
+        assert_eq!(report.issues[0].range, None);
+    }
+
+    #[test]
+    fn tracks_past_the_free_channels_take_the_next_port() {
+        let mut source = String::from("(4/4)\n");
+        for voice in 1..=17 {
+            source += &format!("v{voice}: E4,\n");
+        }
+        source += "drums: kick,\n";
+        let parsed = parse_source(Arc::from(source.as_str()));
+        let mut compiler = Compiler::new();
+        compiler.compile(&parsed.syntax_node());
+        let bytes = export_smf_format1(&compiler.events, MidiWriterConfig::default())
+            .expect("midi export should succeed");
+        let parsed_midi = Smf::parse(&bytes).expect("generated bytes should be valid SMF");
+        assert_eq!(parsed_midi.tracks.len(), 19);
+
+        let addresses: Vec<(u8, u8)> = parsed_midi.tracks[1..]
+            .iter()
+            .map(|track| {
+                let port = track.iter().find_map(|event| match event.kind {
+                    TrackEventKind::Meta(MetaMessage::MidiPort(port)) => Some(port.as_int()),
+                    _ => None,
+                });
+                let channel = track.iter().find_map(|event| match event.kind {
+                    TrackEventKind::Midi {
+                        channel,
+                        message: MidiMessage::NoteOn { .. },
+                    } => Some(channel.as_int()),
+                    _ => None,
+                });
+                (
+                    port.expect("every track should name its port"),
+                    channel.expect("every track should play a note"),
+                )
+            })
+            .collect();
+        assert_eq!(addresses[14], (0, 15));
+        assert_eq!(addresses[15], (1, 0));
+        assert_eq!(addresses[16], (1, 1));
+        // the percussion track stays on the first port
+        assert_eq!(addresses[17], (0, PERCUSSION_CHANNEL));
+
+        // a single port is left implicit
+        let parsed = parse_source(Arc::from("(4/4)\nv1: E4,\n"));
+        let mut compiler = Compiler::new();
+        compiler.compile(&parsed.syntax_node());
+        let bytes = export_smf_format1(&compiler.events, MidiWriterConfig::default())
+            .expect("midi export should succeed");
+        let parsed_midi = Smf::parse(&bytes).expect("generated bytes should be valid SMF");
+        assert!(
+            parsed_midi
+                .tracks
+                .iter()
+                .flatten()
+                .all(|event| !matches!(event.kind, TrackEventKind::Meta(MetaMessage::MidiPort(_))))
+        );
+    }
+
+    #[test]
+    fn note_off_velocity_unless_the_note_has_a_release() {
+        let parsed = parse_source(Arc::from("(4/4)\nC4,D4,\n"));
+        let mut compiler = Compiler::new();
+        compiler.compile(&parsed.syntax_node());
+        let mut events = compiler.events.clone();
+        for event in &mut events {
+            if let EventBody::Note(note) = &mut event.body
+                && note.freq > 280.0
+            {
+                note.release = Some(20);
+            }
+        }
+        let config = MidiWriterConfig {
+            note_off_velocity: 64,
+            ..MidiWriterConfig::default()
+        };
+        let bytes = export_smf_format1(&events, config).expect("midi export should succeed");
+        let parsed_midi = Smf::parse(&bytes).expect("generated bytes should be valid SMF");
+        let note_offs: Vec<(u8, u8)> = parsed_midi.tracks[1]
+            .iter()
+            .filter_map(|event| match event.kind {
+                TrackEventKind::Midi {
+                    message: MidiMessage::NoteOff { key, vel },
+                    ..
+                } => Some((key.as_int(), vel.as_int())),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(note_offs, [(60, 64), (62, 20)]);
+    }
+
+    #[test]
+    fn track_assignment_strategies() {
+        let spec =
+            |start_second: f64, end_second: f64, midi_key: u8, voice: Option<usize>| NoteSpec {
+                start_second,
+                end_second,
+                midi_key,
+                bend14: 8192,
+                bend_cents: 0.0,
+                voice,
+                channel: None,
+                program: None,
+                percussion: false,
+                portamento_from_key: None,
+                bend_envelope: None,
+                vibrato: false,
+                velocity: 100,
+                release: 0,
+                range: TextRange::default(),
+            };
+        let keys_by_track = |strategy: TrackAssignment| -> Vec<Vec<u8>> {
+            let groups = build_same_start_groups(
+                vec![
+                    spec(0.0, 1.0, 48, None),
+                    spec(0.1, 1.0, 72, None),
+                    spec(1.0, 2.0, 71, None),
+                    spec(0.0, 2.0, 60, Some(0)),
+                ],
+                3.0,
+            );
+            assign_groups_to_tracks(groups, 1e-4, strategy)
+                .iter()
+                .map(|track| {
+                    track
+                        .groups
+                        .iter()
+                        .flat_map(|g| g.notes.iter().map(|n| n.midi_key))
+                        .collect()
+                })
+                .collect()
+        };
+
+        assert_eq!(
+            keys_by_track(TrackAssignment::LowestIndex),
+            vec![vec![48, 71], vec![60], vec![72]]
+        );
+        assert_eq!(
+            keys_by_track(TrackAssignment::PitchProximity),
+            vec![vec![48], vec![60], vec![72, 71]]
+        );
+        assert_eq!(
+            keys_by_track(TrackAssignment::ExplicitVoice),
+            vec![vec![48, 72, 71], vec![60]]
+        );
+        assert_eq!(
+            keys_by_track(TrackAssignment::SingleTrack),
+            vec![vec![48, 60, 72, 71]]
+        );
+    }
+
     #[test]
     fn same_start_group_averages_bend_around_center() {
         let groups = build_same_start_groups(
@@ -701,6 +3707,16 @@ mod tests {
                     midi_key: 60,
                     bend14: 8191,
                     bend_cents: -0.1,
+                    voice: None,
+                    channel: None,
+                    program: None,
+                    percussion: false,
+                    portamento_from_key: None,
+                    bend_envelope: None,
+                    vibrato: false,
+                    velocity: 100,
+                    release: 0,
+                    range: TextRange::default(),
                 },
                 NoteSpec {
                     start_second: 0.0,
@@ -708,6 +3724,16 @@ mod tests {
                     midi_key: 64,
                     bend14: 8193,
                     bend_cents: 0.1,
+                    voice: None,
+                    channel: None,
+                    program: None,
+                    percussion: false,
+                    portamento_from_key: None,
+                    bend_envelope: None,
+                    vibrato: false,
+                    velocity: 100,
+                    release: 0,
+                    range: TextRange::default(),
                 },
             ],
             1.0,
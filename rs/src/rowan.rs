@@ -4,6 +4,8 @@ pub mod marker;
 pub mod sink;
 pub mod types;
 pub mod parse_fn;
+pub mod ast;
+pub mod semantic;
 
 pub use rowan::*;
 pub use logos::*;
\ No newline at end of file
@@ -0,0 +1,284 @@
+//! 建立在 rowan 具体语法树之上的类型化 AST 包装。
+//!
+//! 每个包装类型只持有一个 `SyntaxNode`，按节点种类做一次检查，
+//! 然后以访问器方法代替手写的 `children_with_tokens()` 过滤。
+
+use crate::rowan::{
+    lexer::SyntaxKind,
+    parser::{SyntaxNode, SyntaxToken},
+};
+
+/// 类型化语法节点的公共接口。
+pub trait AstNode: Sized {
+    /// 该类型能否包装此种类的节点。
+    fn can_cast(kind: SyntaxKind) -> bool;
+
+    /// 种类匹配时包装节点，否则返回 `None`。
+    fn cast(node: SyntaxNode) -> Option<Self>;
+
+    /// 取回底层的语法节点。
+    fn syntax(&self) -> &SyntaxNode;
+}
+
+macro_rules! ast_node {
+    ($(#[$meta:meta])* $name:ident, $($kind:ident)|+) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+        pub struct $name(SyntaxNode);
+
+        impl AstNode for $name {
+            fn can_cast(kind: SyntaxKind) -> bool {
+                matches!(kind, $(SyntaxKind::$kind)|+)
+            }
+
+            fn cast(node: SyntaxNode) -> Option<Self> {
+                Self::can_cast(node.kind()).then_some(Self(node))
+            }
+
+            fn syntax(&self) -> &SyntaxNode {
+                &self.0
+            }
+        }
+    };
+}
+
+ast_node!(
+    /// 音符组，如 `C4:E4;G4,`，由 `:`、`;`、`~` 分隔的音符与嵌套组构成。
+    NoteGroup,
+    NODE_NOTE_GROUP
+);
+ast_node!(
+    /// 单个音符位置，包含音高链以及时值、装饰、音量、歌词等后缀。
+    Note,
+    NODE_NOTE
+);
+ast_node!(
+    /// 音高链，如 `3/2@E4` 或宏调用 `m@D4*2`。
+    PitchChain,
+    NODE_PITCH_CHAIN
+);
+ast_node!(
+    /// 宏调用，出现在音高链内部。
+    MacroInvoke,
+    NODE_MACRO_INVOKE
+);
+ast_node!(
+    /// 宏定义：别名、简单、复杂宏以及命名律制。
+    MacroDef,
+    NODE_MACRODEF_ALIAS | NODE_MACRODEF_SIMPLE | NODE_MACRODEF_COMPLEX | NODE_MACRODEF_TUNING
+);
+
+/// 宏定义的种类。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MacroDefKind {
+    /// `m = 3/2@E4`
+    Alias,
+    /// `m = C4:E4:G4`
+    Simple,
+    /// `m =` 后跟多行，以空行结束
+    Complex,
+    /// `t = 19edo`
+    Tuning,
+}
+
+fn child_token(node: &SyntaxNode, kind: SyntaxKind) -> Option<SyntaxToken> {
+    node.children_with_tokens()
+        .filter_map(|nt| nt.into_token())
+        .find(|t| t.kind() == kind)
+}
+
+fn child_nodes<N: AstNode>(node: &SyntaxNode) -> impl Iterator<Item = N> {
+    node.children().filter_map(N::cast)
+}
+
+impl NoteGroup {
+    /// 直接包含的音符（不含嵌套组内的音符）。
+    pub fn notes(&self) -> impl Iterator<Item = Note> {
+        child_nodes(&self.0)
+    }
+
+    /// 直接包含的嵌套音符组 `(...)`。
+    pub fn groups(&self) -> impl Iterator<Item = NoteGroup> {
+        child_nodes(&self.0)
+    }
+
+    /// 由 `;` 与 `~` 划分出的子组数量。
+    pub fn sub_group_count(&self) -> usize {
+        self.0
+            .children_with_tokens()
+            .filter_map(|nt| nt.into_token())
+            .filter(|t| t.kind().is_semicolon() || t.kind().is_portamento())
+            .count()
+            + 1
+    }
+}
+
+impl Note {
+    /// 音符中的音高链；和弦符号与 JI 和弦不产生音高链。
+    pub fn pitch_chains(&self) -> impl Iterator<Item = PitchChain> {
+        child_nodes(&self.0)
+    }
+
+    /// 音符中的宏调用（若有）。
+    pub fn macro_invoke(&self) -> Option<MacroInvoke> {
+        self.pitch_chains().find_map(|chain| chain.macro_invoke())
+    }
+
+    /// 时值标记，逗号 `,,` 或分数 `[3/8]`。
+    pub fn duration(&self) -> Option<SyntaxToken> {
+        self.0
+            .children_with_tokens()
+            .filter_map(|nt| nt.into_token())
+            .find(|t| t.kind().is_duration_commas() || t.kind().is_duration_fraction())
+    }
+
+    /// 装饰音前缀 `(g ...)` 节点。
+    pub fn grace(&self) -> Option<SyntaxNode> {
+        self.0.children().find(|c| c.kind().is_node_grace())
+    }
+
+    /// 随机选择 `?{A|B}` 节点。
+    pub fn choice(&self) -> Option<SyntaxNode> {
+        self.0.children().find(|c| c.kind().is_node_choice())
+    }
+
+    pub fn ji_chord(&self) -> Option<SyntaxToken> {
+        child_token(&self.0, SyntaxKind::JiChord)
+    }
+
+    pub fn ornament(&self) -> Option<SyntaxToken> {
+        child_token(&self.0, SyntaxKind::Ornament)
+    }
+
+    pub fn fermata(&self) -> Option<SyntaxToken> {
+        child_token(&self.0, SyntaxKind::Fermata)
+    }
+
+    pub fn bend_envelope(&self) -> Option<SyntaxToken> {
+        child_token(&self.0, SyntaxKind::BendEnvelope)
+    }
+
+    pub fn volume(&self) -> Option<SyntaxToken> {
+        child_token(&self.0, SyntaxKind::Volume)
+    }
+
+    pub fn lyric(&self) -> Option<SyntaxToken> {
+        child_token(&self.0, SyntaxKind::Lyric)
+    }
+
+    /// 音符是否带有延音线后缀 `_`。
+    pub fn is_tied(&self) -> bool {
+        child_token(&self.0, SyntaxKind::PitchTie).is_some()
+    }
+}
+
+impl PitchChain {
+    /// 参与求值的音高链元素：音高、形式音高、标识符、`@` 与 `+`。
+    pub fn atoms(&self) -> Vec<SyntaxToken> {
+        self.0
+            .descendants_with_tokens()
+            .filter_map(|nt| nt.into_token())
+            .filter(|t| {
+                t.kind().is_pitch()
+                    || t.kind().is_formal_pitch()
+                    || t.kind().is_identifier()
+                    || t.kind().is_at()
+                    || t.kind().is_plus()
+            })
+            .collect()
+    }
+
+    pub fn macro_invoke(&self) -> Option<MacroInvoke> {
+        child_nodes(&self.0).next()
+    }
+}
+
+impl MacroInvoke {
+    /// 被调用的宏名。
+    pub fn name(&self) -> Option<SyntaxToken> {
+        child_token(&self.0, SyntaxKind::Identifier)
+    }
+
+    /// 重复次数标记 `*N`。
+    pub fn repeat_count(&self) -> Option<SyntaxToken> {
+        child_token(&self.0, SyntaxKind::RepeatCount)
+    }
+}
+
+impl MacroDef {
+    pub fn kind(&self) -> MacroDefKind {
+        match self.0.kind() {
+            SyntaxKind::NODE_MACRODEF_ALIAS => MacroDefKind::Alias,
+            SyntaxKind::NODE_MACRODEF_SIMPLE => MacroDefKind::Simple,
+            SyntaxKind::NODE_MACRODEF_COMPLEX => MacroDefKind::Complex,
+            _ => MacroDefKind::Tuning,
+        }
+    }
+
+    /// 被定义的宏名，可带命名空间前缀（如 `lib.chord1`）。
+    pub fn name(&self) -> Option<SyntaxToken> {
+        child_token(&self.0, SyntaxKind::Identifier)
+    }
+
+    /// 别名宏右侧的音高链。
+    pub fn pitch_chain(&self) -> Option<PitchChain> {
+        child_nodes(&self.0).next()
+    }
+
+    /// 简单宏右侧的各个音符。
+    pub fn notes(&self) -> impl Iterator<Item = Note> {
+        child_nodes(&self.0)
+    }
+
+    /// 复杂宏的多行主体。
+    pub fn body(&self) -> Option<SyntaxNode> {
+        self.0
+            .children()
+            .find(|c| c.kind().is_node_macrodef_complex_body())
+    }
+
+    /// 命名律制定义的律制说明，如 `19edo` 或 `tri.scl`。
+    pub fn tuning_spec(&self) -> Option<SyntaxToken> {
+        child_token(&self.0, SyntaxKind::TuningSpec)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::rowan::parse_fn::parse_source;
+
+    fn root(source: &str) -> SyntaxNode {
+        parse_source(Arc::from(source)).syntax_node()
+    }
+
+    #[test]
+    fn typed_accessors_over_note_groups_and_macros() {
+        let root = root("m = C4:E4\nC4&0.5_;m@D4*2,,\n");
+        let def = root
+            .descendants()
+            .find_map(MacroDef::cast)
+            .expect("macro definition");
+        assert_eq!(def.kind(), MacroDefKind::Simple);
+        assert!(def.name().is_some_and(|t| t.text() == "m"));
+        assert_eq!(def.notes().count(), 2);
+
+        let group = root
+            .descendants()
+            .find_map(NoteGroup::cast)
+            .expect("note group");
+        assert_eq!(group.sub_group_count(), 2);
+        let notes: Vec<Note> = group.notes().collect();
+        assert_eq!(notes.len(), 2);
+        assert!(notes[0].is_tied());
+        assert!(notes[0].volume().is_some_and(|t| t.text() == "&0.5"));
+        let invoke = notes[1].macro_invoke().expect("macro invoke");
+        assert!(invoke.name().is_some_and(|t| t.text() == "m"));
+        assert!(invoke.repeat_count().is_some());
+        let atoms = notes[1].pitch_chains().next().expect("pitch chain").atoms();
+        assert_eq!(atoms.len(), 3);
+        assert!(NoteGroup::cast(def.syntax().clone()).is_none());
+    }
+}
@@ -1,6 +1,7 @@
-use std::vec;
+use std::{ops::Range, vec};
 
 use logos::{Lexer, Logos};
+use rowan::{TextRange, TextSize};
 
 /// 词法与语法种类枚举。
 ///
@@ -35,11 +36,16 @@ pub enum SyntaxKind {
     /// Whitespace (spaces and tabs, not newlines)
     #[regex("[ \t]+")]
     Whitespace,
-    /// Newline
-    #[regex("\r?\n")]
+    /// Newline (`\n`, `\r\n`, or a lone `\r` from old Mac editors)
+    #[regex("\r\n|\r|\n")]
     Newline,
+    /// Line continuation: a trailing '\' joins the next physical line to the current one
+    #[regex("\\\\[ \t]*(\r\n|\r|\n)")]
+    LineContinuation,
     /// Comment (from '//' to end of line), including the ending line break
+    /// or block comment ('/* ... */'), whose inner line breaks do not end the line
     #[regex("//[^\r\n]*", allow_greedy = true)]
+    #[regex(r"/\*([^*]|\*+[^*/])*\*+/")]
     Comment,
     /// Comma ','
     #[token(",")]
@@ -58,32 +64,41 @@ pub enum SyntaxKind {
     /// Used as pitch-chain octave-up suffix
     #[token("+")]
     Plus,
-    /// PitchSpellOctave (e.g. C#4, Db3, A5, Gb6)
+    /// PitchSpellOctave (e.g. C#4, Db3, A5, Gb6, F♯4, E𝄳4, Ed4, C+4)
     /// Octave is -9 to 19
-    #[regex(r"[A-G](#|b)*(-[1-9]|1?[0-9])")]
+    /// Half-sharp `+` is only accepted here, since a trailing `+` raises a simple spelling by an octave
+    #[regex(r"(\^|v)*[A-G](#|b|d|\+|♯|♭|𝄪|𝄫|𝄲|𝄳|\\|/|!|¡)*(-[1-9]|1?[0-9])")]
     PitchSpellOctave,
     /// PitchSpellSimple
     /// Octave is omitted or +/-
-    #[regex(r"[A-G](#|b)*")]
+    #[regex(r"(\^|v)*[A-G](#|b|d|♯|♭|𝄪|𝄫|𝄲|𝄳|\\|/|!|¡)*")]
     PitchSpellSimple,
     /// PitchFrequency in Hz (e.g. 440.0, 261.63)
     /// Must be greater than 1, less than 1e8
     /// Allows negative and zero for edo grammar sugar.
     #[regex(r"-?\d+(\.\d+)?", |lex| lex.slice().parse::<f32>().ok().filter(|&f| f.abs() < 1e8).is_some())]
     PitchFrequency,
-    /// PitchRatio (e.g. 3/2, 5/4)
+    /// PitchRatio (e.g. 3/2, 5/4), optionally raised to an integer power (3/2^2, (3/2)^-1)
     /// Numerator and denominator are positive integers (u16) and >0
     /// !!Also used for TimeSignature denominators!!
     #[regex(r"\d+/\d+", |lex|check_u16_groups(lex,"","/",2..3))]
+    #[regex(r"\d+/\d+\^-?\d+", check_ratio_power)]
+    #[regex(r"\(\d+/\d+\)\^-?\d+", check_ratio_power)]
     PitchRatio,
     /// PitchEdo (e.g. 7\12, 5\19)
     /// Step is integer (i16), Divisions is positive integer (u16) and >0
     #[regex(r"-?\d+\\\d+", |lex|check_edo_groups(lex,"","\\",2..3))]
     PitchEdo,
-    /// PitchCents (e.g. 100c, -50c)
-    /// Cents value is a signed integer (i32)
-    #[regex(r"-?\d+c", |lex| lex.slice()[..lex.slice().len()-1].parse::<i32>().is_ok())]
+    /// PitchCents (e.g. 100c, -50c, +14.7c)
+    /// Cents value is a signed decimal, less than 1e6 in magnitude
+    #[regex(r"[+-]?\d+(\.\d+)?c", |lex| lex.slice()[..lex.slice().len()-1].parse::<f64>().ok().filter(|&c| c.abs() < 1e6).is_some())]
     PitchCents,
+    /// PitchSubharmonic (e.g. u7), the Nth undertone of the base frequency
+    #[regex(r"u[1-9][0-9]*", |lex| lex.slice()[1..].parse::<u16>().is_ok())]
+    PitchSubharmonic,
+    /// Absolute time in seconds (e.g. 35.5s), used by `(at ...)`
+    #[regex(r"\d+(\.\d+)?s")]
+    TimeSeconds,
     /// PitchRest
     #[regex(r"\.+", priority = 1)]
     PitchRest,
@@ -91,8 +106,17 @@ pub enum SyntaxKind {
     /// Also used as a suffix for minus octave in pitch chain
     #[token("-", priority = 1)]
     PitchSustain,
-    /// Identifier (macro names, etc.)
-    #[regex(r"[A-Za-z_][A-Za-z0-9_]*", priority = 0)]
+    /// PitchTie '_'
+    /// Tie suffix after a note (`C4_`), or a slot continuing the tied note (`_`)
+    #[token("_")]
+    PitchTie,
+    /// Identifier (macro names, etc.), optionally dotted into namespaces (e.g. `lib.chord1`)
+    /// Segments cannot end with '_', which is left for the tie suffix: `a_` lexes as `a` and a
+    /// tie, and a macro definition named like that reports an error asking to rename it
+    #[regex(
+        r"[A-Za-z_]([A-Za-z0-9_]*[A-Za-z0-9])?(\.[A-Za-z_]([A-Za-z0-9_]*[A-Za-z0-9])?)*",
+        priority = 0
+    )]
     Identifier,
     /// DurationCommas
     #[regex(r"\[,+\]")]
@@ -101,8 +125,64 @@ pub enum SyntaxKind {
     #[regex(r"\[-?\d+(:\d+)?\]", |lex|check_u16_groups(lex,"[-]",":",1..3))]
     DurationFraction,
     /// Quantize
+    /// Also accepts a value macro name (e.g. {q})
     #[regex(r"\{\d+(:\d+)?\}", |lex|check_u16_groups(lex,"{}",":",1..3))]
+    #[regex(r"\{[A-Za-z_][A-Za-z0-9_]*(\.[A-Za-z_][A-Za-z0-9_]*)*\}")]
     Quantize,
+    /// QuantizeOpen (e.g. `{8:` in `{8: C4,D4,}`), opens a block with its own quantize
+    #[regex(r"\{\d+(:\d+)?:")]
+    #[regex(r"\{[A-Za-z_][A-Za-z0-9_]*(\.[A-Za-z_][A-Za-z0-9_]*)*:")]
+    QuantizeOpen,
+    /// RepeatCount (e.g. *4 after a macro invoke)
+    #[regex(r"\*[1-9][0-9]*")]
+    RepeatCount,
+    /// BarRepeat '%'
+    /// Repeats the previous measure
+    #[token("%")]
+    BarRepeat,
+    /// MultiBarRest (e.g. R8 rests for 8 full measures)
+    #[regex(r"R[1-9][0-9]*")]
+    MultiBarRest,
+    /// Voice prefix at the start of a line (e.g. v2:)
+    #[regex(r"v[0-9]+:")]
+    VoicePrefix,
+    /// Percussion prefix at the start of a line (drums:)
+    #[token("drums:")]
+    PercussionPrefix,
+    /// Octave entry mode switch ((relative) or (absolute))
+    #[regex(r"\((relative|absolute)\)")]
+    OctaveMode,
+    /// Ornament suffix (e.g. `~tr` trill, `~trem` tremolo, `~vib` vibrato)
+    #[regex(r"~(tr|trem|vib)")]
+    Ornament,
+    /// Fermata suffix (`~fermata` or `𝄐`), holding the note longer in real time
+    #[token("~fermata")]
+    #[token("𝄐")]
+    Fermata,
+    /// JI chord of harmonics or subharmonics (e.g. `o:4-5-6`, `u:4-5-6`)
+    #[regex(r"[ou]:\d+(-\d+)+")]
+    JiChord,
+    /// Pitch-bend envelope suffix (e.g. `{bend 0c..+50c}`), cent offsets across the note
+    #[regex(r"\{bend[ \t]+[+-]?\d+c([ \t]*\.\.[ \t]*[+-]?\d+c)+\}")]
+    BendEnvelope,
+    /// Per-note volume suffix (e.g. `C4&0.6`), scaling only that note
+    #[regex(r"&\d+(\.\d+)?")]
+    Volume,
+    /// Portamento connector '~' (e.g. `C4~D4`), gliding into the next note
+    #[token("~")]
+    Portamento,
+    /// IfDirective (e.g. `#if variant_a`), opens a conditional section
+    #[regex(r"#if[ \t]+[A-Za-z_][A-Za-z0-9_]*")]
+    IfDirective,
+    /// EndifDirective '#endif', closes a conditional section
+    #[token("#endif")]
+    EndifDirective,
+    /// Header metadata field (e.g. `title: Song`), value runs to end of line
+    /// Only lexed by `spanned` in the file header, see `META_FIELDS`
+    MetaField,
+    /// Lyric text attached to a note (e.g. "la")
+    #[regex(r#""[^"\r\n]*""#)]
+    Lyric,
 
     // ==== Other Tokens ====
     /// Equals '='
@@ -125,24 +205,122 @@ pub enum SyntaxKind {
     /// Used for BPM / TimeSignature changes
     #[token(")")]
     RParen,
+    /// TransposeOpen '(transpose'
+    /// Opens a transpose directive (e.g. `(transpose 3/2)`, `(transpose +200c)`)
+    #[token("(transpose")]
+    TransposeOpen,
+    /// CapoOpen '(capo'
+    /// Transposes playback and export only (e.g. `(capo +2\12)`)
+    #[token("(capo")]
+    CapoOpen,
+    /// InstrumentOpen '(instrument'
+    /// Selects the General MIDI program of the line's voice (e.g. `(instrument violin)`)
+    #[token("(instrument")]
+    InstrumentOpen,
+    /// SectionOpen '(section'
+    /// Marks the start of a section of the piece (e.g. `(section "Chorus")`)
+    #[token("(section")]
+    SectionOpen,
+    /// CueOpen '(cue'
+    /// Places a cue point for samplers and DAWs (e.g. `(cue "Hit")`)
+    #[token("(cue")]
+    CueOpen,
+    /// LoopOpen '(loop'
+    /// Marks the start or end of a loop region (e.g. `(loop start)`, `(loop end)`)
+    #[token("(loop")]
+    LoopOpen,
+    /// KeyOpen '(key'
+    /// Sets the key signature by its tonic and mode (e.g. `(key Eb)`, `(key F# minor)`)
+    #[token("(key")]
+    KeyOpen,
+    /// VolumeOpen '(volume'
+    /// Sets the volume of the line's voice, gliding to the next with `~` (e.g. `(volume 0.4~)`)
+    #[token("(volume")]
+    VolumeOpen,
+    /// PanOpen '(pan'
+    /// Sets the pan of the line's voice from -1 (left) to 1 (right) (e.g. `(pan -0.5)`)
+    #[token("(pan")]
+    PanOpen,
+    /// PedalOpen '(pedal'
+    /// Presses or releases the sustain pedal of the line's voice (e.g. `(pedal down)`, `(pedal up)`)
+    #[token("(pedal")]
+    PedalOpen,
+    /// PickupOpen '(pickup'
+    /// Opens an anacrusis directive (e.g. `(pickup 1/4)`)
+    #[token("(pickup")]
+    PickupOpen,
+    /// AtOpen '(at'
+    /// Jumps to an absolute position (e.g. `(at 2:1/4)`, `(at 35.5s)`)
+    #[token("(at")]
+    AtOpen,
+    /// TuningOpen '(tuning'
+    /// Switches the active tuning (e.g. `(tuning tun_a)`, `(tuning 19edo)`)
+    #[token("(tuning")]
+    TuningOpen,
+    /// TuningSpec, an EDO or a Scala scale file (e.g. `19edo`, `scales/meantone.scl`)
+    #[regex(r"\d+edo")]
+    #[regex(r"[A-Za-z0-9_./\-]+\.scl")]
+    TuningSpec,
+    /// ChoiceOpen '?{'
+    /// Opens an aleatoric choice (e.g. `?{C4|E4|G4}`)
+    #[token("?{")]
+    ChoiceOpen,
+    /// ArpeggioOpen '^{'
+    /// Opens an arpeggiated note group (e.g. `^{C4:E4:G4}`)
+    #[token("^{")]
+    ArpeggioOpen,
+    /// Pipe '|'
+    /// Separates alternatives of an aleatoric choice
+    #[token("|")]
+    Pipe,
+    /// RBrace '}'
+    /// Closes an aleatoric choice or arpeggio
+    #[token("}")]
+    RBrace,
+    /// GraceOpen '(g'
+    /// Opens a grace note prefix (e.g. `(g D4)C4`)
+    #[token("(g")]
+    GraceOpen,
 
     Error,
 
     // ==== Rowan Nodes ====
     NODE_ROOT,
+    NODE_METADATA,
     NODE_MACRODEF_ALIAS,
     NODE_MACRODEF_SIMPLE,
     NODE_MACRODEF_COMPLEX,
     NODE_MACRODEF_COMPLEX_BODY,
+    NODE_MACRODEF_TUNING,
     NODE_GHOST_LINE,
     NODE_NORMAL_LINE,
     NODE_NOTE_GROUP,
     NODE_NOTE,
+    NODE_GRACE,
+    NODE_CHOICE,
     NODE_PITCH_CHAIN,
     NODE_MACRO_INVOKE,
     NODE_BASE_PITCH_DEF,
     NODE_BPM_DEF,
     NODE_TIME_SIGNATURE_DEF,
+    NODE_PICKUP_DEF,
+    NODE_TRANSPOSE_DEF,
+    NODE_CAPO_DEF,
+    NODE_INSTRUMENT_DEF,
+    NODE_SECTION_DEF,
+    NODE_CUE_DEF,
+    NODE_LOOP_DEF,
+    NODE_KEY_DEF,
+    NODE_VOLUME_DEF,
+    NODE_PAN_DEF,
+    NODE_PEDAL_DEF,
+    NODE_AT_DEF,
+    NODE_ARPEGGIO,
+    NODE_CONDITIONAL,
+    NODE_QUANTIZE_BLOCK,
+    NODE_TUNING_DEF,
+    /// 解析器无法识别的一段连续 Token，错误已由解析器报告，编译时跳过
+    NODE_ERROR,
 }
 
 /// 检查分隔后的各段是否能解析为正的 `u16`。
@@ -193,6 +371,19 @@ fn check_edo_groups(
         })
 }
 
+/// 校验带指数的比率（如 `3/2^2`、`(3/2)^-1`）：底数同 PitchRatio，指数绝对值不超过 64。
+fn check_ratio_power(lex: &Lexer<SyntaxKind>) -> bool {
+    let Some((base, exponent)) = lex.slice().split_once('^') else {
+        return false;
+    };
+    let base = base.trim_start_matches('(').trim_end_matches(')');
+    base.split('/')
+        .all(|part| part.parse::<u16>().map(|v| v > 0).unwrap_or(false))
+        && exponent
+            .parse::<i32>()
+            .is_ok_and(|e| e.unsigned_abs() <= 64)
+}
+
 impl From<SyntaxKind> for rowan::SyntaxKind {
     /// 将自定义的 `SyntaxKind` 转为 rowan 可识别的 `rowan::SyntaxKind`。
     ///
@@ -240,30 +431,54 @@ impl SyntaxKind {
     /// assert!(!SyntaxKind::Identifier.is_trivia());
     /// ```
     pub fn is_trivia(&self) -> bool {
-        matches!(self, SyntaxKind::Whitespace | SyntaxKind::Comment)
+        matches!(
+            self,
+            SyntaxKind::Whitespace | SyntaxKind::Comment | SyntaxKind::LineContinuation
+        )
     }
 
     pub fn is_token(&self) -> bool {
         !self.is_node()
     }
     pub fn is_node(&self) -> bool {
-        match self {
+        matches!(
+            self,
             SyntaxKind::NODE_ROOT
-            | SyntaxKind::NODE_MACRODEF_ALIAS
-            | SyntaxKind::NODE_MACRODEF_SIMPLE
-            | SyntaxKind::NODE_MACRODEF_COMPLEX
-            | SyntaxKind::NODE_MACRODEF_COMPLEX_BODY
-            | SyntaxKind::NODE_GHOST_LINE
-            | SyntaxKind::NODE_NORMAL_LINE
-            | SyntaxKind::NODE_NOTE_GROUP
-            | SyntaxKind::NODE_NOTE
-            | SyntaxKind::NODE_PITCH_CHAIN
-            | SyntaxKind::NODE_MACRO_INVOKE
-            | SyntaxKind::NODE_BASE_PITCH_DEF
-            | SyntaxKind::NODE_BPM_DEF
-            | SyntaxKind::NODE_TIME_SIGNATURE_DEF => true,
-            _ => false,
-        }
+                | SyntaxKind::NODE_METADATA
+                | SyntaxKind::NODE_MACRODEF_ALIAS
+                | SyntaxKind::NODE_MACRODEF_SIMPLE
+                | SyntaxKind::NODE_MACRODEF_COMPLEX
+                | SyntaxKind::NODE_MACRODEF_COMPLEX_BODY
+                | SyntaxKind::NODE_MACRODEF_TUNING
+                | SyntaxKind::NODE_GHOST_LINE
+                | SyntaxKind::NODE_NORMAL_LINE
+                | SyntaxKind::NODE_NOTE_GROUP
+                | SyntaxKind::NODE_NOTE
+                | SyntaxKind::NODE_GRACE
+                | SyntaxKind::NODE_CHOICE
+                | SyntaxKind::NODE_PITCH_CHAIN
+                | SyntaxKind::NODE_MACRO_INVOKE
+                | SyntaxKind::NODE_BASE_PITCH_DEF
+                | SyntaxKind::NODE_BPM_DEF
+                | SyntaxKind::NODE_TIME_SIGNATURE_DEF
+                | SyntaxKind::NODE_PICKUP_DEF
+                | SyntaxKind::NODE_TRANSPOSE_DEF
+                | SyntaxKind::NODE_CAPO_DEF
+                | SyntaxKind::NODE_INSTRUMENT_DEF
+                | SyntaxKind::NODE_SECTION_DEF
+                | SyntaxKind::NODE_CUE_DEF
+                | SyntaxKind::NODE_LOOP_DEF
+                | SyntaxKind::NODE_KEY_DEF
+                | SyntaxKind::NODE_VOLUME_DEF
+                | SyntaxKind::NODE_PAN_DEF
+                | SyntaxKind::NODE_PEDAL_DEF
+                | SyntaxKind::NODE_AT_DEF
+                | SyntaxKind::NODE_ARPEGGIO
+                | SyntaxKind::NODE_CONDITIONAL
+                | SyntaxKind::NODE_QUANTIZE_BLOCK
+                | SyntaxKind::NODE_TUNING_DEF
+                | SyntaxKind::NODE_ERROR
+        )
     }
 
     pub fn is_pitch(&self) -> bool {
@@ -274,15 +489,185 @@ impl SyntaxKind {
                 | SyntaxKind::PitchFrequency
                 | SyntaxKind::PitchRatio
                 | SyntaxKind::PitchEdo
+                | SyntaxKind::PitchSubharmonic
                 | SyntaxKind::PitchCents
         )
     }
 
     pub fn is_formal_pitch(&self) -> bool {
-        matches!(self, SyntaxKind::PitchSustain | SyntaxKind::PitchRest)
+        matches!(
+            self,
+            SyntaxKind::PitchSustain | SyntaxKind::PitchRest | SyntaxKind::PitchTie
+        )
+    }
+}
+
+impl SyntaxKind {
+    /// 该 Token 种类对应的 TextMate 作用域名，供 VS Code、Neovim 等外部编辑器的主题着色。
+    ///
+    /// 只依据词法种类，不区分同一 Token 在不同位置的语义；需要更精确的分类时使用
+    /// [`crate::rowan::semantic::semantic_tokens`]。语法树节点没有作用域，返回 `None`。
+    ///
+    /// # 示例
+    /// ```rust
+    /// use symi::rowan::lexer::SyntaxKind;
+    ///
+    /// assert_eq!(SyntaxKind::Comment.textmate_scope(), Some("comment.symi"));
+    /// assert_eq!(SyntaxKind::NODE_ROOT.textmate_scope(), None);
+    /// ```
+    pub fn textmate_scope(&self) -> Option<&'static str> {
+        let scope = match self {
+            SyntaxKind::Whitespace | SyntaxKind::Newline => "text.whitespace.symi",
+            SyntaxKind::Comment => "comment.symi",
+            SyntaxKind::LineContinuation => "constant.character.escape.symi",
+            SyntaxKind::PitchSpellOctave | SyntaxKind::PitchSpellSimple => {
+                "constant.language.pitch.symi"
+            }
+            SyntaxKind::PitchFrequency
+            | SyntaxKind::PitchRatio
+            | SyntaxKind::PitchEdo
+            | SyntaxKind::PitchCents
+            | SyntaxKind::PitchSubharmonic
+            | SyntaxKind::JiChord => "constant.numeric.pitch.symi",
+            SyntaxKind::PitchRest
+            | SyntaxKind::PitchSustain
+            | SyntaxKind::PitchTie
+            | SyntaxKind::BarRepeat
+            | SyntaxKind::MultiBarRest => "keyword.operator.rest.symi",
+            SyntaxKind::DurationCommas | SyntaxKind::DurationFraction | SyntaxKind::RepeatCount => {
+                "constant.numeric.duration.symi"
+            }
+            SyntaxKind::TimeSeconds => "constant.numeric.time.symi",
+            SyntaxKind::Quantize | SyntaxKind::QuantizeOpen => "storage.modifier.quantize.symi",
+            SyntaxKind::Identifier => "entity.name.function.macro.symi",
+            SyntaxKind::VoicePrefix | SyntaxKind::PercussionPrefix => "entity.name.tag.voice.symi",
+            SyntaxKind::OctaveMode
+            | SyntaxKind::TransposeOpen
+            | SyntaxKind::CapoOpen
+            | SyntaxKind::InstrumentOpen
+            | SyntaxKind::SectionOpen
+            | SyntaxKind::CueOpen
+            | SyntaxKind::LoopOpen
+            | SyntaxKind::KeyOpen
+            | SyntaxKind::VolumeOpen
+            | SyntaxKind::PanOpen
+            | SyntaxKind::PedalOpen
+            | SyntaxKind::PickupOpen
+            | SyntaxKind::AtOpen
+            | SyntaxKind::TuningOpen
+            | SyntaxKind::GraceOpen => "keyword.control.directive.symi",
+            SyntaxKind::IfDirective | SyntaxKind::EndifDirective => {
+                "keyword.control.conditional.symi"
+            }
+            SyntaxKind::TuningSpec => "string.unquoted.tuning.symi",
+            SyntaxKind::Ornament
+            | SyntaxKind::Fermata
+            | SyntaxKind::Portamento
+            | SyntaxKind::BendEnvelope
+            | SyntaxKind::Volume => "keyword.operator.articulation.symi",
+            SyntaxKind::MetaField => "meta.header.symi",
+            SyntaxKind::Lyric => "string.quoted.double.lyric.symi",
+            SyntaxKind::Comma | SyntaxKind::Colon | SyntaxKind::Semicolon => {
+                "punctuation.separator.symi"
+            }
+            SyntaxKind::At | SyntaxKind::Plus | SyntaxKind::Equals | SyntaxKind::Pipe => {
+                "keyword.operator.symi"
+            }
+            SyntaxKind::LAngle
+            | SyntaxKind::RAngle
+            | SyntaxKind::LParen
+            | SyntaxKind::RParen
+            | SyntaxKind::ChoiceOpen
+            | SyntaxKind::ArpeggioOpen
+            | SyntaxKind::RBrace => "punctuation.section.symi",
+            SyntaxKind::Error => "invalid.illegal.symi",
+            _ => return None,
+        };
+        Some(scope)
     }
 }
 
+/// 独立的词法分析入口：不构建语法树，只返回 Token 种类与字节范围。
+///
+/// 供不经过 Tauri 后端的外部高亮器使用。无法识别的文本返回 `SyntaxKind::Error`，
+/// 各 Token 的范围首尾相接，覆盖整个源码。
+///
+/// # 示例
+/// ```rust
+/// use symi::rowan::lexer::SyntaxKind;
+///
+/// let tokens = symi::lex("C4, D4");
+/// assert_eq!(tokens[0].0, SyntaxKind::PitchSpellOctave);
+/// assert_eq!(u32::from(tokens.last().unwrap().1.end()), 6);
+/// ```
+pub fn lex(source: &str) -> Vec<(SyntaxKind, TextRange)> {
+    spanned(source)
+        .map(|(tok, span)| {
+            let range = TextRange::new(
+                TextSize::from(span.start as u32),
+                TextSize::from(span.end as u32),
+            );
+            (tok.unwrap_or(SyntaxKind::Error), range)
+        })
+        .collect()
+}
+
+/// 文件头部可用的元数据字段名。
+const META_FIELDS: [&str; 3] = ["title", "composer", "copyright"];
+
+/// 逐个产出 Token 种类与字节范围。
+///
+/// 开头的 UTF-8 BOM 作为一个空白 Token 保留，其后的范围仍是原始源码中的偏移，
+/// 与按原文构建的字符映射保持一致。
+///
+/// 元数据字段只在文件头部（第一个非琐碎 Token 之前）的行首识别，
+/// 因此正文中名为 `title` 的宏等仍按普通 Token 处理。
+pub(crate) fn spanned(
+    source: &str,
+) -> impl Iterator<Item = (Result<SyntaxKind, ()>, Range<usize>)> + '_ {
+    let bom = if source.starts_with('\u{FEFF}') {
+        '\u{FEFF}'.len_utf8()
+    } else {
+        0
+    };
+    let head = (bom > 0).then_some((Ok(SyntaxKind::Whitespace), 0..bom));
+    let mut lexer = SyntaxKind::lexer(&source[bom..]);
+    let mut in_header = true;
+    let mut line_start = true;
+    let rest = std::iter::from_fn(move || {
+        if in_header
+            && line_start
+            && let Some(len) = meta_field_len(lexer.remainder())
+        {
+            let start = lexer.span().end;
+            lexer.bump(len);
+            line_start = false;
+            return Some((Ok(SyntaxKind::MetaField), start..start + len));
+        }
+        let tok = lexer.next()?;
+        match tok {
+            Ok(SyntaxKind::Newline) => line_start = true,
+            Ok(SyntaxKind::Whitespace) => {}
+            Ok(kind) if kind.is_trivia() => line_start = false,
+            _ => in_header = false,
+        }
+        Some((tok, lexer.span()))
+    })
+    .map(move |(tok, span)| (tok, span.start + bom..span.end + bom));
+    head.into_iter().chain(rest)
+}
+
+/// `rest` 开头若是元数据字段（`title: ...`），返回其到行尾的字节长度。
+fn meta_field_len(rest: &str) -> Option<usize> {
+    let name = META_FIELDS
+        .into_iter()
+        .find(|name| rest.starts_with(name))?;
+    rest[name.len()..]
+        .trim_start_matches([' ', '\t'])
+        .starts_with(':')
+        .then(|| rest.find(['\r', '\n']).unwrap_or(rest.len()))
+}
+
 #[cfg(test)]
 mod tests {
     use std::{fs, path::Path};
@@ -292,7 +677,7 @@ mod tests {
     #[test]
     fn test_lexer() {
         let path = Path::new("src/tests/sample.symi");
-        let source = fs::read_to_string(&path).unwrap();
+        let source = fs::read_to_string(path).unwrap();
         let mut lex = SyntaxKind::lexer(&source);
         // output all tokens to `tests/sample_tokens.txt`
         let mut output = String::new();
@@ -308,4 +693,248 @@ mod tests {
         let out_path = path.with_file_name("sample_tokens.txt");
         fs::write(&out_path, output).unwrap();
     }
+
+    #[test]
+    fn lex_block_comment_as_single_trivia() {
+        let source = "C4, /* a * b\n// c */ D4,";
+        let mut lex = SyntaxKind::lexer(source);
+        let mut comments = Vec::new();
+        while let Some(token) = lex.next() {
+            if token == Ok(SyntaxKind::Comment) {
+                comments.push(lex.span());
+            }
+        }
+        assert_eq!(comments, vec![4..20]);
+        assert_eq!(&source[4..20], "/* a * b\n// c */");
+    }
+
+    #[test]
+    fn lex_unicode_and_half_accidentals() {
+        let kinds: Vec<_> = SyntaxKind::lexer("F♯4 B♭ C𝄲4 E𝄳 Ed4 C+4 C+")
+            .filter_map(Result::ok)
+            .filter(|k| !k.is_whitespace())
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                SyntaxKind::PitchSpellOctave,
+                SyntaxKind::PitchSpellSimple,
+                SyntaxKind::PitchSpellOctave,
+                SyntaxKind::PitchSpellSimple,
+                SyntaxKind::PitchSpellOctave,
+                SyntaxKind::PitchSpellOctave,
+                SyntaxKind::PitchSpellSimple,
+                SyntaxKind::Plus,
+            ]
+        );
+    }
+
+    #[test]
+    fn lex_ratio_powers() {
+        let kinds: Vec<_> = SyntaxKind::lexer("3/2^2 (3/2)^-1 (3/2) 3/2^99")
+            .filter_map(Result::ok)
+            .filter(|k| !k.is_whitespace())
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                SyntaxKind::PitchRatio,
+                SyntaxKind::PitchRatio,
+                SyntaxKind::LParen,
+                SyntaxKind::PitchRatio,
+                SyntaxKind::RParen,
+            ]
+        );
+    }
+
+    #[test]
+    fn lex_subharmonics() {
+        let kinds: Vec<_> = SyntaxKind::lexer("u7 u12 u0 u7x")
+            .filter_map(Result::ok)
+            .filter(|k| !k.is_whitespace())
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                SyntaxKind::PitchSubharmonic,
+                SyntaxKind::PitchSubharmonic,
+                SyntaxKind::Identifier,
+                SyntaxKind::Identifier,
+            ]
+        );
+    }
+
+    #[test]
+    fn lex_fractional_cents() {
+        let kinds: Vec<_> = SyntaxKind::lexer("+14.7c -50c 100c C4+")
+            .filter_map(Result::ok)
+            .filter(|k| !k.is_whitespace())
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                SyntaxKind::PitchCents,
+                SyntaxKind::PitchCents,
+                SyntaxKind::PitchCents,
+                SyntaxKind::PitchSpellOctave,
+                SyntaxKind::Plus,
+            ]
+        );
+    }
+
+    #[test]
+    fn lex_heji_comma_accidentals() {
+        let kinds: Vec<_> = SyntaxKind::lexer("E\\4 F#/ B!4 A¡")
+            .filter_map(Result::ok)
+            .filter(|k| !k.is_whitespace())
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                SyntaxKind::PitchSpellOctave,
+                SyntaxKind::PitchSpellSimple,
+                SyntaxKind::PitchSpellOctave,
+                SyntaxKind::PitchSpellSimple,
+            ]
+        );
+    }
+
+    #[test]
+    fn lex_volume_suffix_apart_from_accidentals() {
+        let kinds: Vec<_> = SyntaxKind::lexer("B!&0.5 BL")
+            .filter_map(Result::ok)
+            .filter(|k| !k.is_whitespace())
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                SyntaxKind::PitchSpellSimple,
+                SyntaxKind::Volume,
+                SyntaxKind::Identifier,
+            ]
+        );
+    }
+
+    #[test]
+    fn lex_ups_and_downs() {
+        let kinds: Vec<_> = SyntaxKind::lexer("^E4 vvB ^{ v2: vx")
+            .filter_map(Result::ok)
+            .filter(|k| !k.is_whitespace())
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                SyntaxKind::PitchSpellOctave,
+                SyntaxKind::PitchSpellSimple,
+                SyntaxKind::ArpeggioOpen,
+                SyntaxKind::VoicePrefix,
+                SyntaxKind::Identifier,
+            ]
+        );
+    }
+
+    #[test]
+    fn lex_dotted_identifiers() {
+        let kinds: Vec<_> = SyntaxKind::lexer("lib.chord1 {lib.q} tri.scl lib.")
+            .filter_map(Result::ok)
+            .filter(|k| !k.is_whitespace())
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                SyntaxKind::Identifier,
+                SyntaxKind::Quantize,
+                SyntaxKind::TuningSpec,
+                SyntaxKind::Identifier,
+                SyntaxKind::PitchRest,
+            ]
+        );
+    }
+
+    #[test]
+    fn lex_tie_suffix_and_continuation() {
+        let kinds: Vec<_> = SyntaxKind::lexer("C4_ D_ _ pedal_hihat")
+            .filter_map(Result::ok)
+            .filter(|k| !k.is_whitespace())
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                SyntaxKind::PitchSpellOctave,
+                SyntaxKind::PitchTie,
+                SyntaxKind::PitchSpellSimple,
+                SyntaxKind::PitchTie,
+                SyntaxKind::PitchTie,
+                SyntaxKind::Identifier,
+            ]
+        );
+    }
+
+    #[test]
+    fn lex_metadata_only_in_header() {
+        let kinds: Vec<_> = lex("// head\ntitle: A\nC4,\ntitle: B\n")
+            .into_iter()
+            .map(|(kind, _)| kind)
+            .filter(|k| !k.is_trivia() && !k.is_newline())
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                SyntaxKind::MetaField,
+                SyntaxKind::PitchSpellOctave,
+                SyntaxKind::Comma,
+                SyntaxKind::Identifier,
+                SyntaxKind::Colon,
+                SyntaxKind::PitchSpellSimple,
+            ]
+        );
+    }
+
+    #[test]
+    fn lex_covers_source_and_maps_scopes() {
+        let source = "C4,$ // x\n";
+        let tokens = lex(source);
+        let mut end = 0;
+        for (_, range) in &tokens {
+            assert_eq!(u32::from(range.start()), end);
+            end = u32::from(range.end());
+        }
+        assert_eq!(end as usize, source.len());
+        let scopes: Vec<_> = tokens
+            .iter()
+            .filter(|(kind, _)| !kind.is_whitespace())
+            .map(|(kind, _)| kind.textmate_scope())
+            .collect();
+        assert_eq!(
+            scopes,
+            vec![
+                Some("constant.language.pitch.symi"),
+                Some("punctuation.separator.symi"),
+                Some("invalid.illegal.symi"),
+                Some("comment.symi"),
+                Some("text.whitespace.symi"),
+            ]
+        );
+    }
+
+    #[test]
+    fn lex_bom_and_lone_carriage_returns() {
+        let source = "\u{FEFF}C4,\rD4, \\\r\r\n";
+        let kinds: Vec<_> = lex(source).into_iter().map(|(kind, _)| kind).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                SyntaxKind::Whitespace,
+                SyntaxKind::PitchSpellOctave,
+                SyntaxKind::Comma,
+                SyntaxKind::Newline,
+                SyntaxKind::PitchSpellOctave,
+                SyntaxKind::Comma,
+                SyntaxKind::Whitespace,
+                SyntaxKind::LineContinuation,
+                SyntaxKind::Newline,
+            ]
+        );
+        assert_eq!(lex(source)[1].1, TextRange::new(3.into(), 5.into()));
+    }
 }
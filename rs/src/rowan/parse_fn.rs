@@ -1,38 +1,133 @@
 use std::sync::Arc;
 
+use rowan::{GreenNode, GreenToken, NodeOrToken, TextRange, TextSize};
+
 use crate::rowan::{
     lexer::SyntaxKind,
-    parser::{Parse, Parser, parse},
+    parser::{Parse, ParseOptions, Parser, parse_with_options},
+    types::{ParseError, Token},
 };
 
 /// 解析入口：构建语法树结构。
 pub fn parse_source(source: Arc<str>) -> Parse {
-    parse(source, parse_root)
+    parse_source_with_options(source, ParseOptions::default())
+}
+
+/// 解析入口（带选项）：按 `options` 启用或关闭可选语法扩展。
+pub fn parse_source_with_options(source: Arc<str>, options: ParseOptions) -> Parse {
+    parse_with_options(source, options, parse_root)
+}
+
+/// 永不 panic 的解析入口。
+///
+/// 正常情况下与 `parse_source` 相同；若解析器内部仍出现意外 panic，则把整段源码
+/// 包进一个 `NODE_ERROR` 节点返回，并附带一条解析错误，保证调用方（如 Tauri 后端）不会崩溃。
+pub fn parse_lossy(source: Arc<str>) -> Parse {
+    let input = source.clone();
+    std::panic::catch_unwind(move || parse_source(input)).unwrap_or_else(|_| {
+        let range = TextRange::up_to(TextSize::of(source.as_ref()));
+        let children = (!source.is_empty()).then(|| {
+            let token = GreenToken::new(SyntaxKind::Error.into(), source.as_ref());
+            NodeOrToken::Node(GreenNode::new(
+                SyntaxKind::NODE_ERROR.into(),
+                [NodeOrToken::Token(token)],
+            ))
+        });
+        Parse {
+            green_node: GreenNode::new(SyntaxKind::NODE_ROOT.into(), children),
+            errors: vec![ParseError::new("Internal parser error", range)],
+            tokens: vec![Token {
+                kind: SyntaxKind::Error,
+                source: source.clone(),
+                range,
+            }],
+            options: ParseOptions::default(),
+        }
+    })
+}
+
+/// 语法扩展未启用时在当前 Token 处报告错误；语法本身仍照常解析。
+fn check_feature(parser: &mut Parser, enabled: fn(&ParseOptions) -> bool, feature: &str) {
+    if !enabled(parser.options()) {
+        parser.error(format!("{feature} are not enabled in the parse options"));
+    }
 }
 
 /// 根节点解析函数（由 `parse` 调用）。
 fn parse_root(parser: &mut Parser) {
     while let Some(tok) = parser.peek() {
         match tok {
-            SyntaxKind::Whitespace | SyntaxKind::Comment => {
+            SyntaxKind::Whitespace | SyntaxKind::Comment | SyntaxKind::LineContinuation => {
                 unreachable!("trivia should be skipped in peek");
             }
-            SyntaxKind::Identifier
-                if parser.look_for_before(SyntaxKind::Equals, SyntaxKind::Newline) =>
-            {
-                parse_macro_def(parser);
-            }
-            SyntaxKind::Equals => {
-                parse_normal_line(parser, true);
+            SyntaxKind::MetaField => {
+                parse_metadata(parser);
             }
             SyntaxKind::Newline => {
                 parser.bump(); // consume newline
             }
+            SyntaxKind::EndifDirective => {
+                parser.error("Unmatched #endif");
+                parser.bump();
+            }
             _ => {
-                parse_normal_line(parser, false);
+                parse_item(parser, tok);
+            }
+        }
+    }
+}
+
+/// 解析顶层条目：宏定义、条件段或普通行。
+fn parse_item(parser: &mut Parser, tok: SyntaxKind) {
+    match tok {
+        SyntaxKind::Identifier
+            if parser.look_for_before(SyntaxKind::Equals, SyntaxKind::Newline) =>
+        {
+            parse_macro_def(parser);
+        }
+        SyntaxKind::IfDirective => {
+            parse_conditional(parser);
+        }
+        SyntaxKind::Equals => {
+            parse_normal_line(parser, true);
+        }
+        _ => {
+            parse_normal_line(parser, false);
+        }
+    }
+}
+
+/// 解析条件段 `#if name ... #endif`，可嵌套。
+fn parse_conditional(parser: &mut Parser) {
+    let m = parser.start_node();
+    check_feature(parser, |o| o.allow_conditionals, "Conditionals");
+    parser.expect(SyntaxKind::IfDirective);
+    loop {
+        match parser.peek() {
+            None => {
+                parser.error("Unterminated #if, expected #endif");
+                break;
+            }
+            Some(SyntaxKind::EndifDirective) => {
+                parser.bump(); // consume '#endif'
+                break;
             }
+            Some(SyntaxKind::Newline) => {
+                parser.bump(); // consume newline
+            }
+            Some(tok) => parse_item(parser, tok),
         }
     }
+    m.complete(parser, SyntaxKind::NODE_CONDITIONAL);
+}
+
+/// 解析文件头部的元数据块（连续的 `key: value` 行）。
+fn parse_metadata(parser: &mut Parser) {
+    let m = parser.start_node();
+    while parser.eat(SyntaxKind::MetaField) {
+        parser.eat(SyntaxKind::Newline);
+    }
+    m.complete(parser, SyntaxKind::NODE_METADATA);
 }
 
 macro_rules! SyntaxKindPitches {
@@ -41,6 +136,7 @@ macro_rules! SyntaxKindPitches {
             | SyntaxKind::PitchRatio
             | SyntaxKind::PitchFrequency
             | SyntaxKind::PitchEdo
+            | SyntaxKind::PitchSubharmonic
             | SyntaxKind::PitchSpellOctave
             | SyntaxKind::PitchSpellSimple
             | SyntaxKind::PitchRest
@@ -54,35 +150,14 @@ fn parse_normal_line(parser: &mut Parser, is_ghost: bool) {
     if is_ghost {
         parser.eat(SyntaxKind::Equals); // consume '=' for ghost line
     }
+    parser.eat(SyntaxKind::VoicePrefix); // optional voice assignment, e.g. `v2:`
+    parser.eat(SyntaxKind::PercussionPrefix); // optional `drums:` mode
     while let Some(tok) = parser.peek() {
-        match tok {
-            SyntaxKind::Newline => {
-                parser.bump(); // consume newline
-                break; // reach EOL
-            }
-            SyntaxKind::Comma | SyntaxKind::Quantize => {
-                parser.bump(); // consume simple tokens
-            }
-            SyntaxKind::LAngle => {
-                parse_base_pitch(parser);
-            }
-            SyntaxKind::LParen if parser.nth(1).is_some_and(|s| s.is_pitch_ratio()) => {
-                parse_time_signature(parser);
-            }
-            SyntaxKind::LParen
-                if parser.nth(1).is_some_and(|s| s.is_duration_fraction())
-                    || parser.nth(1).is_some_and(|s| s.is_pitch_frequency()) =>
-            {
-                parse_bpm(parser);
-            }
-            SyntaxKindPitches!() | SyntaxKind::Identifier | SyntaxKind::Semicolon => {
-                parse_note_group(parser);
-            }
-            _ => {
-                parser.error("Unexpected token in normal line");
-                parser.bump(); // consume to avoid infinite loop
-            }
+        if tok == SyntaxKind::Newline {
+            parser.bump(); // consume newline
+            break; // reach EOL
         }
+        parse_line_item(parser, tok);
     }
     m.complete(
         parser,
@@ -94,13 +169,215 @@ fn parse_normal_line(parser: &mut Parser, is_ghost: bool) {
     );
 }
 
+/// 解析普通行中的单个元素（音符组、设置、逗号等）。
+fn parse_line_item(parser: &mut Parser, tok: SyntaxKind) {
+    match tok {
+        SyntaxKind::Comma
+        | SyntaxKind::Quantize
+        | SyntaxKind::MultiBarRest
+        | SyntaxKind::BarRepeat
+        | SyntaxKind::OctaveMode => {
+            parser.bump(); // consume simple tokens
+        }
+        SyntaxKind::LAngle => {
+            parse_base_pitch(parser);
+        }
+        SyntaxKind::PickupOpen => {
+            parse_pickup(parser);
+        }
+        SyntaxKind::QuantizeOpen => {
+            parse_quantize_block(parser);
+        }
+        SyntaxKind::TransposeOpen => {
+            parse_transpose(parser);
+        }
+        SyntaxKind::CapoOpen => {
+            parse_capo(parser);
+        }
+        SyntaxKind::InstrumentOpen => {
+            parse_instrument(parser);
+        }
+        SyntaxKind::SectionOpen => {
+            parse_section(parser);
+        }
+        SyntaxKind::CueOpen => {
+            parse_cue(parser);
+        }
+        SyntaxKind::LoopOpen => {
+            parse_loop(parser);
+        }
+        SyntaxKind::KeyOpen => {
+            parse_key(parser);
+        }
+        SyntaxKind::VolumeOpen => {
+            parse_mix_def(
+                parser,
+                SyntaxKind::VolumeOpen,
+                SyntaxKind::NODE_VOLUME_DEF,
+                "volume",
+            );
+        }
+        SyntaxKind::PanOpen => {
+            parse_mix_def(parser, SyntaxKind::PanOpen, SyntaxKind::NODE_PAN_DEF, "pan");
+        }
+        SyntaxKind::PedalOpen => {
+            parse_pedal(parser);
+        }
+        SyntaxKind::TuningOpen => {
+            parse_tuning(parser);
+        }
+        SyntaxKind::AtOpen => {
+            parse_at(parser);
+        }
+        SyntaxKind::ArpeggioOpen => {
+            parse_arpeggio(parser);
+        }
+        SyntaxKind::LParen if parser.nth(1).is_some_and(|s| s.is_pitch_ratio()) => {
+            parse_time_signature(parser);
+        }
+        // `(3+2/8)` additive time signature
+        SyntaxKind::LParen
+            if parser.nth(1).is_some_and(|s| s.is_pitch_frequency())
+                && parser.nth(2).is_some_and(|s| s.is_plus()) =>
+        {
+            parse_time_signature(parser);
+        }
+        // `(name)` references a value macro (BPM or time signature)
+        SyntaxKind::LParen
+            if parser.nth(1).is_some_and(|s| s.is_identifier())
+                && parser.nth(2).is_some_and(|s| s.is_r_paren()) =>
+        {
+            parse_bpm(parser);
+        }
+        SyntaxKind::LParen
+            if parser.nth(1).is_some_and(|s| {
+                s.is_pitch_spell_octave() || s.is_pitch_spell_simple() || s.is_identifier()
+            }) =>
+        {
+            parse_note_group(parser);
+        }
+        SyntaxKind::LParen
+            if parser.nth(1).is_some_and(|s| s.is_duration_fraction())
+                || parser.nth(1).is_some_and(|s| s.is_pitch_frequency()) =>
+        {
+            parse_bpm(parser);
+        }
+        SyntaxKindPitches!()
+        | SyntaxKind::PitchTie
+        | SyntaxKind::Identifier
+        | SyntaxKind::Semicolon
+        | SyntaxKind::GraceOpen
+        | SyntaxKind::JiChord
+        | SyntaxKind::ChoiceOpen => {
+            parse_note_group(parser);
+        }
+        _ => {
+            parse_error_region(parser, "Unexpected token in normal line");
+        }
+    }
+}
+
+/// 能够结束错误区域的 Token：行尾、逗号、闭合括号以及可以开始行内元素的 Token。
+fn is_recovery_point(tok: SyntaxKind) -> bool {
+    matches!(
+        tok,
+        SyntaxKindPitches!()
+            | SyntaxKind::Newline
+            | SyntaxKind::Comma
+            | SyntaxKind::RParen
+            | SyntaxKind::RBrace
+            | SyntaxKind::Pipe
+            | SyntaxKind::Quantize
+            | SyntaxKind::MultiBarRest
+            | SyntaxKind::BarRepeat
+            | SyntaxKind::OctaveMode
+            | SyntaxKind::LAngle
+            | SyntaxKind::LParen
+            | SyntaxKind::PickupOpen
+            | SyntaxKind::QuantizeOpen
+            | SyntaxKind::TransposeOpen
+            | SyntaxKind::CapoOpen
+            | SyntaxKind::InstrumentOpen
+            | SyntaxKind::SectionOpen
+            | SyntaxKind::CueOpen
+            | SyntaxKind::LoopOpen
+            | SyntaxKind::KeyOpen
+            | SyntaxKind::VolumeOpen
+            | SyntaxKind::PanOpen
+            | SyntaxKind::PedalOpen
+            | SyntaxKind::TuningOpen
+            | SyntaxKind::AtOpen
+            | SyntaxKind::ArpeggioOpen
+            | SyntaxKind::PitchTie
+            | SyntaxKind::Identifier
+            | SyntaxKind::Semicolon
+            | SyntaxKind::GraceOpen
+            | SyntaxKind::JiChord
+            | SyntaxKind::ChoiceOpen
+    )
+}
+
+/// 将当前 Token 及其后直到恢复点的 Token 包入 `NODE_ERROR` 节点，整段只报告一次错误，
+/// 使编译器可以跳过该区域继续编译行内其余部分。
+fn parse_error_region(parser: &mut Parser, message: &str) {
+    let m = parser.start_node();
+    parser.error(message);
+    parser.bump(); // consume at least one token to avoid infinite loop
+    while parser.peek().is_some_and(|tok| !is_recovery_point(tok)) {
+        parser.bump();
+    }
+    m.complete(parser, SyntaxKind::NODE_ERROR);
+}
+
+/// 解析限定范围的量化块 `{8: ...}`，块内元素与普通行相同，块结束后恢复原量化。
+fn parse_quantize_block(parser: &mut Parser) {
+    let m = parser.start_node();
+    parser.expect(SyntaxKind::QuantizeOpen); // consume '{8:'
+    loop {
+        match parser.peek() {
+            Some(SyntaxKind::RBrace) => {
+                parser.bump(); // consume '}'
+                break;
+            }
+            None | Some(SyntaxKind::Newline) => {
+                parser.error("Unterminated quantize block, expected '}'");
+                break;
+            }
+            Some(tok) => parse_line_item(parser, tok),
+        }
+    }
+    m.complete(parser, SyntaxKind::NODE_QUANTIZE_BLOCK);
+}
+
 fn parse_note_group(parser: &mut Parser) {
     let note_group_marker = parser.start_node();
+    if parse_note_group_body(parser) {
+        note_group_marker.complete(parser, SyntaxKind::NODE_NOTE_GROUP);
+    } else {
+        note_group_marker.abandon(parser);
+    }
+}
+
+/// 解析括号内的嵌套音符组（如 `(D4;E4)`），整体占据外层的一个子组。
+fn parse_nested_note_group(parser: &mut Parser) {
+    let m = parser.start_node();
+    check_feature(parser, |o| o.allow_tuplets, "Tuplets");
+    parser.expect(SyntaxKind::LParen); // consume '('
+    parse_note_group_body(parser);
+    parser.expect(SyntaxKind::RParen); // consume ')'
+    m.complete(parser, SyntaxKind::NODE_NOTE_GROUP);
+}
+
+/// 解析音符组内容，返回是否包含分隔符（即是否构成音符组）。
+fn parse_note_group_body(parser: &mut Parser) -> bool {
     let mut is_group = false;
     let mut note_marker = None;
     while let Some(tok) = parser.peek() {
         match tok {
-            SyntaxKindPitches!() => {
+            SyntaxKind::PitchTie if note_marker.is_some() => {
+                parser.bump(); // consume tie suffix of current note
+            }
+            SyntaxKindPitches!() | SyntaxKind::PitchTie => {
                 note_marker.get_or_insert_with(|| parser.start_node());
                 let chain_marker = parser.start_node();
                 parser.bump(); // consume pitch token
@@ -113,24 +390,60 @@ fn parse_note_group(parser: &mut Parser) {
                 let mm = parser.start_node();
                 parser.bump(); // consume macro name
                 parse_pitch_chain_tail(parser);
+                parser.eat(SyntaxKind::RepeatCount); // optional `*N` repetition
                 mm.complete(parser, SyntaxKind::NODE_MACRO_INVOKE);
                 chain_marker.complete(parser, SyntaxKind::NODE_PITCH_CHAIN);
             }
-            SyntaxKind::Colon | SyntaxKind::Semicolon => {
+            SyntaxKind::JiChord => {
+                note_marker.get_or_insert_with(|| parser.start_node());
+                parser.bump(); // consume JI chord, a whole chord in one token
+            }
+            SyntaxKind::Colon | SyntaxKind::Semicolon | SyntaxKind::Portamento => {
                 is_group = true;
                 note_marker
                     .take()
                     .map(|m| m.complete(parser, SyntaxKind::NODE_NOTE));
-                parser.bump(); // consume colon/semicolon
+                parser.bump(); // consume colon/semicolon/portamento
             }
             SyntaxKind::DurationCommas | SyntaxKind::DurationFraction => {
                 parser.bump(); // consume duration token
             }
+            SyntaxKind::ChoiceOpen => {
+                note_marker.get_or_insert_with(|| parser.start_node());
+                parse_choice(parser);
+            }
+            SyntaxKind::LParen if note_marker.is_none() => {
+                is_group = true;
+                parse_nested_note_group(parser);
+            }
+            SyntaxKind::GraceOpen => {
+                // grace notes belong to the note that follows them
+                if note_marker.is_some() {
+                    parser.error("Grace notes must precede the note they decorate");
+                }
+                note_marker.get_or_insert_with(|| parser.start_node());
+                parse_grace(parser);
+            }
+            SyntaxKind::Ornament
+            | SyntaxKind::Fermata
+            | SyntaxKind::BendEnvelope
+            | SyntaxKind::Volume
+                if note_marker.is_some() =>
+            {
+                parser.bump(); // consume ornament/fermata/bend/volume suffix of current note
+            }
+            SyntaxKind::Lyric if note_marker.is_some() => {
+                parser.bump(); // consume lyric attached to current note
+            }
 
             SyntaxKind::Newline => {
                 parser.error("unexpected end of line in note group");
                 break; // end of line
             }
+            // 分隔符之后的意外 Token 留在组内，组内其余音符照常解析
+            _ if note_marker.is_none() && !is_recovery_point(tok) => {
+                parse_error_region(parser, "Unexpected token in note group");
+            }
             _ => {
                 break; // end of note group
             }
@@ -140,11 +453,63 @@ fn parse_note_group(parser: &mut Parser) {
     note_marker
         .take()
         .map(|m| m.complete(parser, SyntaxKind::NODE_NOTE));
-    if is_group {
-        note_group_marker.complete(parser, SyntaxKind::NODE_NOTE_GROUP);
-    } else {
-        note_group_marker.abandon(parser);
+    is_group
+}
+
+/// 解析装饰音前缀 `(g ...)`，其中可包含一个或多个音高链。
+fn parse_grace(parser: &mut Parser) {
+    let m = parser.start_node();
+    parser.expect(SyntaxKind::GraceOpen); // consume '(g'
+    let mut has_pitch = false;
+    while parser
+        .peek()
+        .is_some_and(|k| k.is_pitch() || k.is_identifier())
+    {
+        let chain_marker = parser.start_node();
+        parser.bump(); // consume pitch token or macro name
+        parse_pitch_chain_tail(parser);
+        chain_marker.complete(parser, SyntaxKind::NODE_PITCH_CHAIN);
+        has_pitch = true;
+    }
+    if !has_pitch {
+        parser.error("Grace note prefix must contain at least one pitch");
     }
+    parser.expect(SyntaxKind::RParen); // consume ')'
+    m.complete(parser, SyntaxKind::NODE_GRACE);
+}
+
+/// 解析随机选择 `?{A|B|C}`，每个候选项为一条音高链。
+fn parse_choice(parser: &mut Parser) {
+    let m = parser.start_node();
+    check_feature(parser, |o| o.allow_choices, "Random choices");
+    parser.expect(SyntaxKind::ChoiceOpen); // consume '?{'
+    loop {
+        if parser
+            .peek()
+            .is_some_and(|k| k.is_pitch() || k.is_formal_pitch() || k.is_identifier())
+        {
+            let chain_marker = parser.start_node();
+            parser.bump(); // consume pitch token or macro name
+            parse_pitch_chain_tail(parser);
+            chain_marker.complete(parser, SyntaxKind::NODE_PITCH_CHAIN);
+        } else {
+            parser.error("Expected pitch token or identifier in choice");
+        }
+        if !parser.eat(SyntaxKind::Pipe) {
+            break;
+        }
+    }
+    parser.expect(SyntaxKind::RBrace); // consume '}'
+    m.complete(parser, SyntaxKind::NODE_CHOICE);
+}
+
+/// 解析琶音 `^{...}`，内部为一个普通音符组。
+fn parse_arpeggio(parser: &mut Parser) {
+    let m = parser.start_node();
+    parser.expect(SyntaxKind::ArpeggioOpen); // consume '^{'
+    parse_note_group(parser);
+    parser.expect(SyntaxKind::RBrace); // consume '}'
+    m.complete(parser, SyntaxKind::NODE_ARPEGGIO);
 }
 
 fn parse_pitch_chain_tail(parser: &mut Parser) {
@@ -173,24 +538,194 @@ fn parse_bpm(parser: &mut Parser) {
     if parser.eat(SyntaxKind::DurationFraction) {
         parser.expect(SyntaxKind::Equals);
     }
-    parser.expect(SyntaxKind::PitchFrequency);
+    if !parser.eat(SyntaxKind::Identifier) {
+        parser.expect(SyntaxKind::PitchFrequency);
+    }
     parser.expect(SyntaxKind::RParen); // consume ')'
     m.complete(parser, SyntaxKind::NODE_BPM_DEF);
 }
 
+/// 解析拍号 `(3/4)`，以及由若干拍组相加的拍号 `(3+2/8)`。
 fn parse_time_signature(parser: &mut Parser) {
     let m = parser.start_node();
     parser.expect(SyntaxKind::LParen); // consume '('
+    while parser.eat(SyntaxKind::PitchFrequency) {
+        parser.expect(SyntaxKind::Plus); // beat groups before the last one
+    }
     parser.expect(SyntaxKind::PitchRatio);
     parser.expect(SyntaxKind::RParen); // consume ')'
     m.complete(parser, SyntaxKind::NODE_TIME_SIGNATURE_DEF);
 }
 
+fn parse_pickup(parser: &mut Parser) {
+    let m = parser.start_node();
+    parser.expect(SyntaxKind::PickupOpen); // consume '(pickup'
+    parser.expect(SyntaxKind::PitchRatio);
+    parser.expect(SyntaxKind::RParen); // consume ')'
+    m.complete(parser, SyntaxKind::NODE_PICKUP_DEF);
+}
+
+fn parse_at(parser: &mut Parser) {
+    let m = parser.start_node();
+    parser.expect(SyntaxKind::AtOpen); // consume '(at'
+    if !parser.eat(SyntaxKind::TimeSeconds) {
+        parser.expect(SyntaxKind::PitchFrequency); // bar number
+        if parser.eat(SyntaxKind::Colon) {
+            parser.expect(SyntaxKind::PitchRatio); // tick within the bar
+        }
+    }
+    parser.expect(SyntaxKind::RParen); // consume ')'
+    m.complete(parser, SyntaxKind::NODE_AT_DEF);
+}
+
+fn parse_transpose(parser: &mut Parser) {
+    parse_interval_def(
+        parser,
+        SyntaxKind::TransposeOpen,
+        SyntaxKind::NODE_TRANSPOSE_DEF,
+        "transpose",
+    );
+}
+
+/// 解析仅作用于播放与导出的变调夹 `(capo +2\12)`。
+fn parse_capo(parser: &mut Parser) {
+    parse_interval_def(
+        parser,
+        SyntaxKind::CapoOpen,
+        SyntaxKind::NODE_CAPO_DEF,
+        "capo",
+    );
+}
+
+/// 解析音色切换 `(instrument violin)`，参数为 General MIDI 音色名或 1-128 的音色号。
+fn parse_instrument(parser: &mut Parser) {
+    let m = parser.start_node();
+    parser.expect(SyntaxKind::InstrumentOpen); // consume '(instrument'
+    if parser.at_any(&[SyntaxKind::Identifier, SyntaxKind::PitchFrequency]) {
+        parser.bump();
+    } else {
+        parser.error("Expected instrument name or program number in instrument definition");
+    }
+    parser.expect(SyntaxKind::RParen); // consume ')'
+    m.complete(parser, SyntaxKind::NODE_INSTRUMENT_DEF);
+}
+
+/// 解析段落标记 `(section "Chorus")`，参数为带引号的段落名。
+fn parse_section(parser: &mut Parser) {
+    let m = parser.start_node();
+    parser.expect(SyntaxKind::SectionOpen); // consume '(section'
+    if !parser.eat(SyntaxKind::Lyric) {
+        parser.error("Expected quoted name in section definition");
+    }
+    parser.expect(SyntaxKind::RParen); // consume ')'
+    m.complete(parser, SyntaxKind::NODE_SECTION_DEF);
+}
+
+/// 解析提示点 `(cue "Hit")`，参数为带引号的提示点名。
+fn parse_cue(parser: &mut Parser) {
+    let m = parser.start_node();
+    parser.expect(SyntaxKind::CueOpen); // consume '(cue'
+    if !parser.eat(SyntaxKind::Lyric) {
+        parser.error("Expected quoted name in cue definition");
+    }
+    parser.expect(SyntaxKind::RParen); // consume ')'
+    m.complete(parser, SyntaxKind::NODE_CUE_DEF);
+}
+
+/// 解析循环区间的边界 `(loop start)`、`(loop end)`。
+fn parse_loop(parser: &mut Parser) {
+    let m = parser.start_node();
+    parser.expect(SyntaxKind::LoopOpen); // consume '(loop'
+    if !parser.eat(SyntaxKind::Identifier) {
+        parser.error("Expected start or end in loop definition");
+    }
+    parser.expect(SyntaxKind::RParen); // consume ')'
+    m.complete(parser, SyntaxKind::NODE_LOOP_DEF);
+}
+
+/// 解析调号 `(key Eb)`、`(key F# minor)`，参数为不带八度的主音音名及可选的调式。
+fn parse_key(parser: &mut Parser) {
+    let m = parser.start_node();
+    parser.expect(SyntaxKind::KeyOpen); // consume '(key'
+    if parser.eat(SyntaxKind::PitchSpellSimple) {
+        parser.eat(SyntaxKind::Identifier); // optional `major` or `minor`
+    } else {
+        parser.error("Expected tonic without octave in key definition");
+    }
+    parser.expect(SyntaxKind::RParen); // consume ')'
+    m.complete(parser, SyntaxKind::NODE_KEY_DEF);
+}
+
+/// 解析混音设置 `(volume 0.8)`、`(pan -0.5~)`，数值后的 `~` 表示渐变到下一个同类设置。
+fn parse_mix_def(parser: &mut Parser, open: SyntaxKind, node: SyntaxKind, name: &str) {
+    let m = parser.start_node();
+    parser.expect(open); // consume '(volume' or '(pan'
+    if parser.eat(SyntaxKind::PitchFrequency) {
+        parser.eat(SyntaxKind::Portamento); // optional ramp to the next setting
+    } else {
+        parser.error(format!("Expected level in {name} definition"));
+    }
+    parser.expect(SyntaxKind::RParen); // consume ')'
+    m.complete(parser, node);
+}
+
+/// 解析延音踏板 `(pedal down)`、`(pedal up)`。
+fn parse_pedal(parser: &mut Parser) {
+    let m = parser.start_node();
+    parser.expect(SyntaxKind::PedalOpen); // consume '(pedal'
+    if !parser.eat(SyntaxKind::Identifier) {
+        parser.error("Expected down or up in pedal definition");
+    }
+    parser.expect(SyntaxKind::RParen); // consume ')'
+    m.complete(parser, SyntaxKind::NODE_PEDAL_DEF);
+}
+
+/// 解析以音程为参数的指令，如 `(transpose 3/2)`、`(capo +200c)`。
+fn parse_interval_def(parser: &mut Parser, open: SyntaxKind, node: SyntaxKind, name: &str) {
+    let m = parser.start_node();
+    parser.expect(open); // consume '(transpose' / '(capo'
+    parser.eat(SyntaxKind::Plus); // optional sign, e.g. `+200c`
+    if parser.at_any(&[
+        SyntaxKind::PitchRatio,
+        SyntaxKind::PitchCents,
+        SyntaxKind::PitchEdo,
+    ]) {
+        parser.bump();
+    } else {
+        parser.error(format!(
+            "Expected ratio, cents or edo interval in {} definition",
+            name
+        ));
+    }
+    parser.expect(SyntaxKind::RParen); // consume ')'
+    m.complete(parser, node);
+}
+
+/// 解析调律切换 `(tuning name)`，参数为已命名的调律或直接给出的 `19edo` / `.scl` 文件。
+fn parse_tuning(parser: &mut Parser) {
+    let m = parser.start_node();
+    parser.expect(SyntaxKind::TuningOpen); // consume '(tuning'
+    if parser.at_any(&[SyntaxKind::Identifier, SyntaxKind::TuningSpec]) {
+        parser.bump();
+    } else {
+        parser.error("Expected tuning name, EDO or .scl file in tuning definition");
+    }
+    parser.expect(SyntaxKind::RParen); // consume ')'
+    m.complete(parser, SyntaxKind::NODE_TUNING_DEF);
+}
+
 fn parse_base_pitch(parser: &mut Parser) {
     let m = parser.start_node();
     parser.expect(SyntaxKind::LAngle); // consume '<'
-    let has_spell =
-        parser.eat(SyntaxKind::PitchSpellOctave) || parser.eat(SyntaxKind::PitchSpellSimple);
+    // `<root=C4>` defines a named base reference instead of the active base pitch
+    let is_named = parser.peek().is_some_and(|s| s.is_identifier())
+        && parser.nth(1).is_some_and(|s| s.is_equals());
+    if is_named {
+        parser.bump(); // consume reference name
+        parser.bump(); // consume '='
+    }
+    let has_spell = !is_named
+        && (parser.eat(SyntaxKind::PitchSpellOctave) || parser.eat(SyntaxKind::PitchSpellSimple));
     if has_spell {
         if parser.eat(SyntaxKind::Equals) {
             if parser.peek().is_some_and(|s| s.is_pitch() || s.is_identifier()) {
@@ -217,9 +752,23 @@ fn parse_base_pitch(parser: &mut Parser) {
 fn parse_macro_def(parser: &mut Parser) {
     let m = parser.start_node();
     parser.expect(SyntaxKind::Identifier); // consume macro name
+    if parser.at(SyntaxKind::PitchTie) {
+        // 宏名结尾的 `_` 会被读作连音线，旧文件中的此类宏名需要改名
+        parser.error("Macro names cannot end with '_', which marks a tie; rename this macro");
+        parser.bump();
+    }
     parser.expect(SyntaxKind::Equals); // consume '='
     if parser.peek().is_some_and(|s| s.is_newline()) {
         parse_multi_line_macro_def(parser, m, false);
+    } else if parser.peek().is_some_and(|s| s.is_tuning_spec()) {
+        parser.bump(); // consume tuning spec, e.g. `19edo`
+        if !parser.peek().is_none_or(|s| s.is_newline()) {
+            parser.error("Unexpected token after tuning definition");
+            while !parser.peek().is_none_or(|s| s.is_newline()) {
+                parser.bump();
+            }
+        }
+        m.complete(parser, SyntaxKind::NODE_MACRODEF_TUNING);
     } else if parser.peek().is_some_and(|s| s.is_pitch() || s.is_identifier()) {
         if parser.look_for_before(SyntaxKind::Colon, SyntaxKind::Newline) {
             parse_simple_macro_def(parser, m);
@@ -325,18 +874,21 @@ fn parse_multi_line_macro_def(parser: &mut Parser, m: super::marker::Marker, is_
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::rowan::lexer::SyntaxKind;
+    use crate::rowan::{
+        lexer::SyntaxKind,
+        parser::{TextEdit, reparse},
+    };
     use std::{fs, path::Path};
 
     fn collect_kinds(root: &crate::rowan::parser::SyntaxNode) -> Vec<SyntaxKind> {
-        root.descendants().map(|n| n.kind().into()).collect()
+        root.descendants().map(|n| n.kind()).collect()
     }
 
     #[test]
     fn parse_empty_source_ok() {
         let result = parse_source(Arc::from(""));
         assert!(result.errors().is_empty());
-        let root_kind: SyntaxKind = result.syntax_node().kind().into();
+        let root_kind: SyntaxKind = result.syntax_node().kind();
         assert_eq!(root_kind, SyntaxKind::NODE_ROOT);
     }
 
@@ -344,7 +896,7 @@ mod tests {
     fn parse_newline_only_ok() {
         let result = parse_source(Arc::from("\n"));
         assert!(result.errors().is_empty());
-        let root_kind: SyntaxKind = result.syntax_node().kind().into();
+        let root_kind: SyntaxKind = result.syntax_node().kind();
         assert_eq!(root_kind, SyntaxKind::NODE_ROOT);
     }
 
@@ -354,7 +906,7 @@ mod tests {
         assert!(result.errors().is_empty());
         let root = result.syntax_node();
         let first_child = root.children().next().expect("expected a line node");
-        let kind: SyntaxKind = first_child.kind().into();
+        let kind: SyntaxKind = first_child.kind();
         assert_eq!(kind, SyntaxKind::NODE_GHOST_LINE);
     }
 
@@ -364,10 +916,10 @@ mod tests {
         assert!(result.errors().is_empty());
         let root = result.syntax_node();
         let line = root.children().next().expect("expected line node");
-        let line_kind: SyntaxKind = line.kind().into();
+        let line_kind: SyntaxKind = line.kind();
         assert_eq!(line_kind, SyntaxKind::NODE_NORMAL_LINE);
         let note = line.children().find(|n| {
-            let kind: SyntaxKind = n.kind().into();
+            let kind: SyntaxKind = n.kind();
             kind == SyntaxKind::NODE_NOTE
         });
         assert!(note.is_some());
@@ -380,7 +932,7 @@ mod tests {
         let root = result.syntax_node();
         let line = root.children().next().expect("expected line node");
         let group = line.children().find(|n| {
-            let kind: SyntaxKind = n.kind().into();
+            let kind: SyntaxKind = n.kind();
             kind == SyntaxKind::NODE_NOTE_GROUP
         });
         assert!(group.is_some());
@@ -392,7 +944,7 @@ mod tests {
         assert!(result.errors().is_empty());
         let root = result.syntax_node();
         let def = root.children().find(|n| {
-            let kind: SyntaxKind = n.kind().into();
+            let kind: SyntaxKind = n.kind();
             kind == SyntaxKind::NODE_MACRODEF_ALIAS
         });
         assert!(def.is_some());
@@ -404,14 +956,14 @@ mod tests {
         assert!(result.errors().is_empty());
         let root = result.syntax_node();
         let has_simple_def = root.children().any(|n| {
-            let kind: SyntaxKind = n.kind().into();
+            let kind: SyntaxKind = n.kind();
             kind == SyntaxKind::NODE_MACRODEF_SIMPLE
         });
         assert!(has_simple_def);
         let note_count = root
             .descendants()
             .filter(|n| {
-                let kind: SyntaxKind = n.kind().into();
+                let kind: SyntaxKind = n.kind();
                 kind == SyntaxKind::NODE_NOTE
             })
             .count();
@@ -424,19 +976,29 @@ mod tests {
         assert!(result.errors().is_empty());
         let root = result.syntax_node();
         let def = root.children().find(|n| {
-            let kind: SyntaxKind = n.kind().into();
+            let kind: SyntaxKind = n.kind();
             kind == SyntaxKind::NODE_MACRODEF_COMPLEX
         });
         assert!(def.is_some());
     }
 
+    #[test]
+    fn parse_macro_name_ending_in_tie_reports_rename() {
+        let result = parse_source(Arc::from("a_ = C4\na,\n"));
+        let messages: Vec<_> = result.errors().iter().map(|e| e.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            vec!["Macro names cannot end with '_', which marks a tie; rename this macro"]
+        );
+    }
+
     #[test]
     fn parse_base_pitch_ok() {
         let result = parse_source(Arc::from("<C4=440>\n"));
         assert!(result.errors().is_empty());
         let root = result.syntax_node();
         let def = root.children().flat_map(|n| n.children()).find(|n| {
-            let kind: SyntaxKind = n.kind().into();
+            let kind: SyntaxKind = n.kind();
             kind == SyntaxKind::NODE_BASE_PITCH_DEF
         });
         assert!(def.is_some());
@@ -448,7 +1010,7 @@ mod tests {
         assert!(result.errors().is_empty());
         let root = result.syntax_node();
         let def = root.children().flat_map(|n| n.children()).find(|n| {
-            let kind: SyntaxKind = n.kind().into();
+            let kind: SyntaxKind = n.kind();
             kind == SyntaxKind::NODE_BASE_PITCH_DEF
         });
         assert!(def.is_some());
@@ -460,23 +1022,39 @@ mod tests {
         assert!(result.errors().is_empty());
         let root = result.syntax_node();
         let has_chain = root.descendants().any(|n| {
-            let kind: SyntaxKind = n.kind().into();
+            let kind: SyntaxKind = n.kind();
             kind == SyntaxKind::NODE_BASE_PITCH_DEF
                 && n.children().any(|c| {
-                    let ck: SyntaxKind = c.kind().into();
+                    let ck: SyntaxKind = c.kind();
                     ck == SyntaxKind::NODE_PITCH_CHAIN
                 })
         });
         assert!(has_chain);
     }
 
+    #[test]
+    fn parse_named_base_pitch_ok() {
+        let result = parse_source(Arc::from("<root=C4><drone=196>\n3/2@root,\n"));
+        assert!(result.errors().is_empty());
+        let defs: Vec<_> = result
+            .syntax_node()
+            .descendants()
+            .filter(|n| n.kind() == SyntaxKind::NODE_BASE_PITCH_DEF)
+            .collect();
+        assert_eq!(defs.len(), 2);
+        assert!(defs.iter().all(|d| {
+            d.children()
+                .any(|c| c.kind() == SyntaxKind::NODE_PITCH_CHAIN)
+        }));
+    }
+
     #[test]
     fn parse_bpm_ok() {
         let result = parse_source(Arc::from("(120)\n"));
         assert!(result.errors().is_empty());
         let root = result.syntax_node();
         let def = root.children().flat_map(|n| n.children()).find(|n| {
-            let kind: SyntaxKind = n.kind().into();
+            let kind: SyntaxKind = n.kind();
             kind == SyntaxKind::NODE_BPM_DEF
         });
         assert!(def.is_some());
@@ -488,25 +1066,37 @@ mod tests {
         assert!(result.errors().is_empty());
         let root = result.syntax_node();
         let def = root.children().flat_map(|n| n.children()).find(|n| {
-            let kind: SyntaxKind = n.kind().into();
+            let kind: SyntaxKind = n.kind();
             kind == SyntaxKind::NODE_TIME_SIGNATURE_DEF
         });
         assert!(def.is_some());
     }
 
+    #[test]
+    fn parse_additive_time_signature_ok() {
+        let result = parse_source(Arc::from("(2+2+3/8)\n"));
+        assert!(result.errors().is_empty());
+        let root = result.syntax_node();
+        let def = root
+            .descendants()
+            .find(|n| n.kind() == SyntaxKind::NODE_TIME_SIGNATURE_DEF)
+            .unwrap();
+        assert_eq!(def.text(), "(2+2+3/8)");
+    }
+
     #[test]
     fn parse_pitch_chain_note_ok() {
         let result = parse_source(Arc::from("C4@3/2@100c,\n"));
         assert!(result.errors().is_empty());
         let root = result.syntax_node();
         let has_chain_node = root.descendants().any(|n| {
-            let kind: SyntaxKind = n.kind().into();
+            let kind: SyntaxKind = n.kind();
             kind == SyntaxKind::NODE_PITCH_CHAIN
         });
         assert!(has_chain_node);
         let has_at = root.descendants_with_tokens().any(|nt| {
             nt.into_token()
-                .is_some_and(|t| t.kind() == SyntaxKind::At.into())
+                .is_some_and(|t| t.kind() == SyntaxKind::At)
         });
         assert!(has_at);
     }
@@ -518,11 +1108,11 @@ mod tests {
         let root = result.syntax_node();
         let has_plus = root.descendants_with_tokens().any(|nt| {
             nt.into_token()
-                .is_some_and(|t| t.kind() == SyntaxKind::Plus.into())
+                .is_some_and(|t| t.kind() == SyntaxKind::Plus)
         });
         let has_minus = root.descendants_with_tokens().any(|nt| {
             nt.into_token()
-                .is_some_and(|t| t.kind() == SyntaxKind::PitchSustain.into())
+                .is_some_and(|t| t.kind() == SyntaxKind::PitchSustain)
         });
         assert!(has_plus);
         assert!(has_minus);
@@ -534,13 +1124,13 @@ mod tests {
         assert!(result.errors().is_empty());
         let root = result.syntax_node();
         let has_invoke = root.descendants().any(|n| {
-            let kind: SyntaxKind = n.kind().into();
+            let kind: SyntaxKind = n.kind();
             kind == SyntaxKind::NODE_MACRO_INVOKE
         });
         assert!(has_invoke);
         let has_at = root.descendants_with_tokens().any(|nt| {
             nt.into_token()
-                .is_some_and(|t| t.kind() == SyntaxKind::At.into())
+                .is_some_and(|t| t.kind() == SyntaxKind::At)
         });
         assert!(has_at);
     }
@@ -552,7 +1142,7 @@ mod tests {
         let root = result.syntax_node();
         let has_identifier = root.descendants_with_tokens().any(|nt| {
             nt.into_token()
-                .is_some_and(|t| t.kind() == SyntaxKind::Identifier.into())
+                .is_some_and(|t| t.kind() == SyntaxKind::Identifier)
         });
         assert!(has_identifier);
     }
@@ -569,10 +1159,26 @@ mod tests {
         assert!(result.errors().is_empty());
         let root = result.syntax_node();
         let line = root.children().next().expect("expected line node");
-        let line_kind: SyntaxKind = line.kind().into();
+        let line_kind: SyntaxKind = line.kind();
         assert_eq!(line_kind, SyntaxKind::NODE_NORMAL_LINE);
     }
 
+    #[test]
+    fn parse_quantize_block_ok() {
+        let result = parse_source(Arc::from("C4,{8: D4,E4,{16: F4,F4,}},G4,\n"));
+        assert!(result.errors().is_empty());
+        let kinds = collect_kinds(&result.syntax_node());
+        assert_eq!(
+            kinds
+                .iter()
+                .filter(|k| **k == SyntaxKind::NODE_QUANTIZE_BLOCK)
+                .count(),
+            2
+        );
+        let result = parse_source(Arc::from("{8: D4,E4,\n"));
+        assert!(!result.errors().is_empty());
+    }
+
     #[test]
     fn parse_duration_commas_ok() {
         let result = parse_source(Arc::from("C4[,,],\n"));
@@ -581,6 +1187,412 @@ mod tests {
         assert!(kinds.contains(&SyntaxKind::NODE_NOTE));
     }
 
+    #[test]
+    fn parse_lyric_attached_to_note_ok() {
+        let result = parse_source(Arc::from("C4\"la\":E4[8]\"di\",\n"));
+        assert!(result.errors().is_empty());
+        let lyric_parents = result
+            .syntax_node()
+            .descendants_with_tokens()
+            .filter_map(|nt| nt.into_token())
+            .filter(|t| t.kind() == SyntaxKind::Lyric)
+            .map(|t| t.parent().map(|p| p.kind()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            lyric_parents,
+            vec![Some(SyntaxKind::NODE_NOTE), Some(SyntaxKind::NODE_NOTE)]
+        );
+    }
+
+    #[test]
+    fn parse_lyric_without_note_reports_error() {
+        let result = parse_source(Arc::from("\"la\",\n"));
+        assert!(!result.errors().is_empty());
+    }
+
+    #[test]
+    fn parse_metadata_block_ok() {
+        let result = parse_source(Arc::from(
+            "title: Für Elise\ncomposer: L. v. B.\n(3/8)\nE5,\n",
+        ));
+        assert!(result.errors().is_empty());
+        let root = result.syntax_node();
+        let first = root.children().next().expect("expected metadata node");
+        assert_eq!(first.kind(), SyntaxKind::NODE_METADATA);
+        let fields = first
+            .children_with_tokens()
+            .filter(|nt| nt.kind() == SyntaxKind::MetaField)
+            .count();
+        assert_eq!(fields, 2);
+    }
+
+    #[test]
+    fn parse_title_macro_after_header_ok() {
+        let result = parse_source(Arc::from("title = C4\nE4,title:G4,\n"));
+        assert!(result.errors().is_empty());
+        let has_meta_field = result
+            .syntax_node()
+            .descendants_with_tokens()
+            .any(|nt| nt.kind() == SyntaxKind::MetaField);
+        assert!(!has_meta_field);
+    }
+
+    #[test]
+    fn parse_grace_note_ok() {
+        let result = parse_source(Arc::from("(g D4 E4)C4,\n"));
+        assert!(result.errors().is_empty());
+        let root = result.syntax_node();
+        let note = root
+            .descendants()
+            .find(|n| n.kind() == SyntaxKind::NODE_NOTE)
+            .expect("expected note node");
+        let grace = note
+            .children()
+            .find(|n| n.kind() == SyntaxKind::NODE_GRACE)
+            .expect("expected grace node inside note");
+        assert_eq!(grace.children().count(), 2);
+    }
+
+    #[test]
+    fn parse_grace_after_pitch_reports_error() {
+        let result = parse_source(Arc::from("C4(g D4),\n"));
+        assert!(!result.errors().is_empty());
+    }
+
+    #[test]
+    fn parse_ornament_suffix_ok() {
+        let result = parse_source(Arc::from("C4~tr,D4~trem:E4,F4~vib,\n"));
+        assert!(result.errors().is_empty());
+        let root = result.syntax_node();
+        let ornaments = root
+            .descendants()
+            .filter(|n| n.kind() == SyntaxKind::NODE_NOTE)
+            .filter(|n| {
+                n.children_with_tokens()
+                    .any(|nt| nt.kind() == SyntaxKind::Ornament)
+            })
+            .count();
+        assert_eq!(ornaments, 3);
+    }
+
+    #[test]
+    fn parse_multi_bar_rest_ok() {
+        let result = parse_source(Arc::from("C4,,,,\nR8\n"));
+        assert!(result.errors().is_empty());
+        let has_rest = result
+            .syntax_node()
+            .descendants_with_tokens()
+            .any(|nt| nt.kind() == SyntaxKind::MultiBarRest);
+        assert!(has_rest);
+    }
+
+    #[test]
+    fn parse_pickup_def_ok() {
+        let result = parse_source(Arc::from("(pickup 1/4)\nG4,\n"));
+        assert!(result.errors().is_empty());
+        let kinds = collect_kinds(&result.syntax_node());
+        assert!(kinds.contains(&SyntaxKind::NODE_PICKUP_DEF));
+    }
+
+    #[test]
+    fn parse_macro_repeat_suffix_ok() {
+        let result = parse_source(Arc::from("m = C4:E4\nm*4,\n"));
+        assert!(result.errors().is_empty());
+        let invoke = result
+            .syntax_node()
+            .descendants()
+            .find(|n| n.kind() == SyntaxKind::NODE_MACRO_INVOKE)
+            .expect("expected macro invoke node");
+        assert!(
+            invoke
+                .children_with_tokens()
+                .any(|nt| nt.kind() == SyntaxKind::RepeatCount)
+        );
+    }
+
+    #[test]
+    fn parse_choice_ok() {
+        let result = parse_source(Arc::from("?{C4|E4@3/2|.}[1:2],\n"));
+        assert!(result.errors().is_empty());
+        let choice = result
+            .syntax_node()
+            .descendants()
+            .find(|n| n.kind() == SyntaxKind::NODE_CHOICE)
+            .expect("expected choice node");
+        assert_eq!(choice.children().count(), 3);
+    }
+
+    #[test]
+    fn parse_unclosed_choice_reports_error() {
+        let result = parse_source(Arc::from("?{C4|E4,\n"));
+        assert!(!result.errors().is_empty());
+    }
+
+    #[test]
+    fn parse_at_def_ok() {
+        for src in ["(at 2:1/4) C4,\n", "(at 3) C4,\n", "(at 35.5s) C4,\n"] {
+            let result = parse_source(Arc::from(src));
+            assert!(result.errors().is_empty(), "{src}");
+            let kinds = collect_kinds(&result.syntax_node());
+            assert!(kinds.contains(&SyntaxKind::NODE_AT_DEF));
+        }
+    }
+
+    #[test]
+    fn parse_transpose_def_ok() {
+        for source in [
+            "(transpose 3/2)\n",
+            "(transpose +200c)\n",
+            "(transpose -3\\12)\n",
+        ] {
+            let result = parse_source(Arc::from(source));
+            assert!(result.errors().is_empty(), "{source}");
+            let kinds = collect_kinds(&result.syntax_node());
+            assert!(kinds.contains(&SyntaxKind::NODE_TRANSPOSE_DEF));
+        }
+    }
+
+    #[test]
+    fn parse_tuning_defs_ok() {
+        let result = parse_source(Arc::from(
+            "tun_a = 19edo\ntun_b = scales/meantone.scl\n(tuning tun_a) 3,\n(tuning 12edo) 7,\n",
+        ));
+        assert!(result.errors().is_empty());
+        let kinds = collect_kinds(&result.syntax_node());
+        assert_eq!(
+            kinds
+                .iter()
+                .filter(|k| **k == SyntaxKind::NODE_MACRODEF_TUNING)
+                .count(),
+            2
+        );
+        assert_eq!(
+            kinds
+                .iter()
+                .filter(|k| **k == SyntaxKind::NODE_TUNING_DEF)
+                .count(),
+            2
+        );
+    }
+
+    #[test]
+    fn parse_arpeggio_ok() {
+        let result = parse_source(Arc::from("^{C4:E4:G4},\n"));
+        assert!(result.errors().is_empty());
+        let arpeggio = result
+            .syntax_node()
+            .descendants()
+            .find(|n| n.kind() == SyntaxKind::NODE_ARPEGGIO)
+            .expect("expected arpeggio node");
+        assert_eq!(
+            arpeggio.children().next().map(|n| n.kind()),
+            Some(SyntaxKind::NODE_NOTE_GROUP)
+        );
+    }
+
+    #[test]
+    fn parse_nested_note_group_ok() {
+        let result = parse_source(Arc::from("C4;(D4;E4:G4);G4,\n"));
+        assert!(result.errors().is_empty());
+        let outer = result
+            .syntax_node()
+            .descendants()
+            .find(|n| n.kind() == SyntaxKind::NODE_NOTE_GROUP)
+            .expect("expected outer note group");
+        let nested = outer
+            .children()
+            .find(|n| n.kind() == SyntaxKind::NODE_NOTE_GROUP)
+            .expect("expected nested note group");
+        assert_eq!(
+            nested
+                .children()
+                .filter(|n| n.kind() == SyntaxKind::NODE_NOTE)
+                .count(),
+            3
+        );
+    }
+
+    #[test]
+    fn parse_unclosed_nested_note_group_reports_error() {
+        let result = parse_source(Arc::from("C4;(D4;E4,\n"));
+        assert!(!result.errors().is_empty());
+    }
+
+    #[test]
+    fn parse_conditional_section_ok() {
+        let result = parse_source(Arc::from(
+            "#if intro\nm = C4\nC4,\n#if loud\nD4,\n#endif\n#endif\nE4,\n",
+        ));
+        assert!(result.errors().is_empty());
+        let root = result.syntax_node();
+        let outer = root
+            .children()
+            .find(|n| n.kind() == SyntaxKind::NODE_CONDITIONAL)
+            .expect("expected conditional node");
+        let kinds: Vec<SyntaxKind> = outer.children().map(|n| n.kind()).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                SyntaxKind::NODE_MACRODEF_ALIAS,
+                SyntaxKind::NODE_NORMAL_LINE,
+                SyntaxKind::NODE_CONDITIONAL,
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_unbalanced_conditional_reports_error() {
+        assert!(!parse_source(Arc::from("#if a\nC4,\n")).errors().is_empty());
+        assert!(!parse_source(Arc::from("C4,\n#endif\n")).errors().is_empty());
+    }
+
+    #[test]
+    fn parse_value_macro_references_ok() {
+        let result = parse_source(Arc::from("t = 132\nq = 8\n(t)\n([4]=t)\n{q}C4,\n"));
+        assert!(result.errors().is_empty());
+        let kinds = collect_kinds(&result.syntax_node());
+        assert_eq!(
+            kinds
+                .iter()
+                .filter(|k| **k == SyntaxKind::NODE_BPM_DEF)
+                .count(),
+            2
+        );
+    }
+
+    #[test]
+    fn parse_voice_prefix_ok() {
+        let result = parse_source(Arc::from("v2: C4,D4,\nv1:E4:G4,\n"));
+        assert!(result.errors().is_empty());
+        let prefixes = result
+            .syntax_node()
+            .descendants_with_tokens()
+            .filter(|nt| nt.kind() == SyntaxKind::VoicePrefix)
+            .count();
+        assert_eq!(prefixes, 2);
+    }
+
+    #[test]
+    fn parse_percussion_line_ok() {
+        let result = parse_source(Arc::from("v3: drums: kick,hihat,snare:hihat,hihat,\n"));
+        assert!(result.errors().is_empty());
+        let kinds = collect_kinds(&result.syntax_node());
+        assert_eq!(
+            kinds
+                .iter()
+                .filter(|k| **k == SyntaxKind::NODE_MACRO_INVOKE)
+                .count(),
+            5
+        );
+    }
+
+    #[test]
+    fn parse_octave_mode_switch_ok() {
+        let result = parse_source(Arc::from("(relative) C,D,E,\n(absolute)\n"));
+        assert!(result.errors().is_empty());
+        let switches = result
+            .syntax_node()
+            .descendants_with_tokens()
+            .filter(|nt| nt.kind() == SyntaxKind::OctaveMode)
+            .count();
+        assert_eq!(switches, 2);
+    }
+
+    #[test]
+    fn parse_voice_prefix_mid_line_reports_error() {
+        let result = parse_source(Arc::from("C4, v2: D4,\n"));
+        assert!(!result.errors().is_empty());
+    }
+
+    #[test]
+    fn parse_options_gate_syntax_extensions() {
+        let source = "#if live\nC4;(D4;E4),?{F4|G4},\n#endif\n";
+        assert!(parse_source(Arc::from(source)).errors().is_empty());
+
+        let options = ParseOptions {
+            allow_tuplets: false,
+            allow_choices: false,
+            allow_conditionals: false,
+            ..ParseOptions::default()
+        };
+        let gated = parse_source_with_options(Arc::from(source), options);
+        let messages: Vec<&str> = gated.errors().iter().map(|e| e.message.as_str()).collect();
+        assert_eq!(messages.len(), 3);
+        assert!(messages[0].starts_with("Conditionals"));
+        assert!(messages[1].starts_with("Tuplets"));
+        assert!(messages[2].starts_with("Random choices"));
+        // the tree is built the same way either way
+        assert_eq!(gated.green_node, parse_source(Arc::from(source)).green_node);
+        // incremental reparses keep the options
+        let edited = "#if live\nC4;(D4;E4),?{F4|A4},\n#endif\n";
+        let reparsed = reparse(&gated, TextEdit::between(source, edited));
+        assert_eq!(reparsed.options, options);
+        assert_eq!(reparsed.errors().len(), 3);
+    }
+
+    #[test]
+    fn parse_unexpected_tokens_are_wrapped_in_error_nodes() {
+        let result = parse_source(Arc::from("C4,= = D4,E4:=G4,\n"));
+        // one error per broken region, not per token
+        assert_eq!(result.errors().len(), 2);
+        let root = result.syntax_node();
+        let errors: Vec<_> = root
+            .descendants()
+            .filter(|n| n.kind() == SyntaxKind::NODE_ERROR)
+            .map(|n| (n.text().to_string(), n.parent().map(|p| p.kind())))
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                ("= =".to_string(), Some(SyntaxKind::NODE_NORMAL_LINE)),
+                ("=".to_string(), Some(SyntaxKind::NODE_NOTE_GROUP)),
+            ]
+        );
+        // the chord around the broken token is still one group
+        let group = root
+            .descendants()
+            .filter(|n| n.kind() == SyntaxKind::NODE_NOTE_GROUP)
+            .last()
+            .expect("expected a note group");
+        assert_eq!(group.text().to_string(), "E4:=G4");
+    }
+
+    #[test]
+    fn parse_block_comment_ok() {
+        let result = parse_source(Arc::from("C4,\n/*\nD4,\nE4,\n*/\nF4 /* inline */ ,\n"));
+        assert!(result.errors().is_empty());
+        let kinds = collect_kinds(&result.syntax_node());
+        assert_eq!(
+            kinds
+                .iter()
+                .filter(|k| **k == SyntaxKind::NODE_NORMAL_LINE)
+                .count(),
+            2
+        );
+    }
+
+    #[test]
+    fn parse_line_continuation_joins_lines() {
+        let result = parse_source(Arc::from("C4,D4, \\\nE4:G4,F4,\n"));
+        assert!(result.errors().is_empty());
+        let kinds = collect_kinds(&result.syntax_node());
+        assert_eq!(
+            kinds
+                .iter()
+                .filter(|k| **k == SyntaxKind::NODE_NORMAL_LINE)
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn parse_fermata_suffix_ok() {
+        for src in ["C4~fermata,\n", "C4:E4𝄐,\n"] {
+            let result = parse_source(Arc::from(src));
+            assert!(result.errors().is_empty(), "{src}");
+        }
+    }
+
     #[test]
     fn parse_mixed_program_ok() {
         let source = "foo = C4\nbar = C4:D4\n<C4=440>\n(120)\n(3/4)\nC4:D4,\n";
@@ -629,7 +1641,7 @@ mod tests {
         indent: usize,
         out: &mut String,
     ) {
-        let kind: SyntaxKind = node.kind().into();
+        let kind: SyntaxKind = node.kind();
         let range = node.text_range();
         let text = slice_source(source, range);
         indent_line(indent, out);
@@ -646,7 +1658,7 @@ mod tests {
                     format_node(&child, source, indent + 2, out);
                 }
                 rowan::NodeOrToken::Token(token) => {
-                    let tkind: SyntaxKind = token.kind().into();
+                    let tkind: SyntaxKind = token.kind();
                     let trange = token.text_range();
                     let ttext = slice_source(source, trange);
                     indent_line(indent + 2, out);
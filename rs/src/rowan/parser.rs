@@ -2,13 +2,14 @@ use std::{ops::Range, sync::Arc};
 
 use logos::Logos;
 use rowan::{
-    GreenNode, Language, SyntaxElement as RowanSyntaxElement, SyntaxNode as RowanSyntaxNode,
-    SyntaxToken as RowanSyntaxToken, TextRange, TextSize,
+    GreenNode, Language, NodeOrToken, SyntaxElement as RowanSyntaxElement,
+    SyntaxNode as RowanSyntaxNode, SyntaxToken as RowanSyntaxToken, TextRange, TextSize,
 };
 
 use crate::rowan::{
-    lexer::SyntaxKind,
+    lexer::{SyntaxKind, spanned},
     marker::Marker,
+    parse_fn::parse_source_with_options,
     sink::Sink,
     types::{Event, ParseError, Token},
 };
@@ -35,22 +36,34 @@ pub type SyntaxNode = RowanSyntaxNode<SymiLanguage>;
 pub type SyntaxToken = RowanSyntaxToken<SymiLanguage>;
 pub type SyntaxElementRef = RowanSyntaxElement<SymiLanguage>;
 
-/// 解析选项：控制解析驱动的基本行为。
+/// 解析选项：控制解析驱动的基本行为，以及可选语法扩展的开关。
+///
+/// 关闭某项扩展后，对应语法仍按原样构建语法树，只额外报告解析错误，
+/// 因此只支持部分语法的下游工具可以逐项启用扩展。
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct ParseOptions {
     pub root_kind: SyntaxKind,
+    /// 允许音符组内的嵌套子组（连音），如 `C4;(D4;E4;F4)`
+    pub allow_tuplets: bool,
+    /// 允许随机选择 `?{A|B}`
+    pub allow_choices: bool,
+    /// 允许条件段 `#if name ... #endif`
+    pub allow_conditionals: bool,
 }
 
 impl Default for ParseOptions {
     fn default() -> Self {
         Self {
             root_kind: SyntaxKind::NODE_ROOT,
+            allow_tuplets: true,
+            allow_choices: true,
+            allow_conditionals: true,
         }
     }
 }
 
 /// 解析入口：对 `source` 进行词法分析，并把可变 `Parser` 交给 `entry`。
-pub fn parse<'src, F>(source: Arc<str>, entry: F) -> Parse
+pub fn parse<F>(source: Arc<str>, entry: F) -> Parse
 where
     F: FnOnce(&mut Parser),
 {
@@ -58,12 +71,13 @@ where
 }
 
 /// 解析入口（带选项）：允许指定根节点类型。
-pub fn parse_with_options<'src, F>(source: Arc<str>, options: ParseOptions, entry: F) -> Parse
+pub fn parse_with_options<F>(source: Arc<str>, options: ParseOptions, entry: F) -> Parse
 where
     F: FnOnce(&mut Parser),
 {
     let (tokens, lex_errors) = tokenize(source);
     let mut parser = Parser::new(tokens);
+    parser.options = options;
 
     let root_marker = parser.start_node();
     entry(&mut parser);
@@ -79,6 +93,8 @@ pub struct Parse {
     pub green_node: GreenNode,
     pub errors: Vec<ParseError>,
     pub tokens: Vec<Token>,
+    /// 解析时使用的选项，增量重新解析沿用同一组选项
+    pub options: ParseOptions,
 }
 
 impl Parse {
@@ -101,6 +117,275 @@ impl Parse {
     pub fn errors(&self) -> &[ParseError] {
         &self.errors
     }
+
+    /// 把完整的具体语法树序列化为 JSON，供前端构建大纲等结构化功能，也便于快照测试。
+    ///
+    /// 节点为 `{"kind", "range", "children"}`，Token 为 `{"kind", "range", "text"}`，
+    /// `range` 是字节偏移 `[start, end]`。
+    ///
+    /// # 示例
+    /// ```rust
+    /// use std::sync::Arc;
+    /// use symi::rowan::parse_fn::parse_source;
+    ///
+    /// let json = parse_source(Arc::from("C4,\n")).to_json();
+    /// assert_eq!(json["kind"], "NODE_ROOT");
+    /// assert_eq!(json["range"], serde_json::json!([0, 4]));
+    /// ```
+    pub fn to_json(&self) -> serde_json::Value {
+        element_to_json(NodeOrToken::Node(self.syntax_node()))
+    }
+}
+
+fn element_to_json(element: SyntaxElementRef) -> serde_json::Value {
+    let range = element.text_range();
+    let range = [u32::from(range.start()), u32::from(range.end())];
+    match element {
+        NodeOrToken::Node(node) => {
+            let kind: &'static str = node.kind().into();
+            let children: Vec<_> = node.children_with_tokens().map(element_to_json).collect();
+            serde_json::json!({ "kind": kind, "range": range, "children": children })
+        }
+        NodeOrToken::Token(token) => {
+            let kind: &'static str = token.kind().into();
+            serde_json::json!({ "kind": kind, "range": range, "text": token.text() })
+        }
+    }
+}
+
+/// 文本编辑：把旧源码中的 `delete` 范围替换为 `insert`。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TextEdit {
+    pub delete: TextRange,
+    pub insert: String,
+}
+
+impl TextEdit {
+    /// 由新旧两份源码的公共前缀与后缀推出单个编辑。
+    ///
+    /// # 示例
+    /// ```rust
+    /// use symi::rowan::parser::TextEdit;
+    ///
+    /// let edit = TextEdit::between("C4,D4,\n", "C4,E4,\n");
+    /// assert_eq!(usize::from(edit.delete.start()), 3);
+    /// assert_eq!(edit.insert, "E");
+    /// ```
+    pub fn between(old: &str, new: &str) -> Self {
+        let mut prefix = old
+            .bytes()
+            .zip(new.bytes())
+            .take_while(|(a, b)| a == b)
+            .count();
+        while !old.is_char_boundary(prefix) || !new.is_char_boundary(prefix) {
+            prefix -= 1;
+        }
+        let max_suffix = old.len().min(new.len()) - prefix;
+        let mut suffix = old
+            .bytes()
+            .rev()
+            .zip(new.bytes().rev())
+            .take(max_suffix)
+            .take_while(|(a, b)| a == b)
+            .count();
+        while !old.is_char_boundary(old.len() - suffix) || !new.is_char_boundary(new.len() - suffix)
+        {
+            suffix -= 1;
+        }
+        Self {
+            delete: to_text_range(prefix..old.len() - suffix),
+            insert: new[prefix..new.len() - suffix].to_string(),
+        }
+    }
+}
+
+/// 增量重新解析：只重新解析受编辑影响的顶层条目，其余绿色子树原样复用。
+///
+/// 重新解析的窗口从编辑之前最近的顶层条目边界开始，到编辑之后第一个以空行结尾的
+/// 顶层条目边界结束；空行处解析器必然回到顶层（多行宏体也在空行处结束）。
+/// 窗口涉及文件头部、元数据或条件段，或位于未闭合的 `#if` 之后时，退回完整解析。
+///
+/// # 示例
+/// ```rust
+/// use std::sync::Arc;
+/// use symi::rowan::{parse_fn::parse_source, parser::{TextEdit, reparse}};
+///
+/// let old = parse_source(Arc::from("C4,\n\nD4,\n"));
+/// let new = reparse(&old, TextEdit::between("C4,\n\nD4,\n", "C4,\n\nE4,\n"));
+/// assert_eq!(new.green_node, parse_source(Arc::from("C4,\n\nE4,\n")).green_node);
+/// ```
+pub fn reparse(old: &Parse, edit: TextEdit) -> Parse {
+    let old_text = old.syntax_node().text().to_string();
+    let edit_start = usize::from(edit.delete.start());
+    let edit_end = usize::from(edit.delete.end());
+    let mut text = old_text.clone();
+    text.replace_range(edit_start..edit_end, &edit.insert);
+    let source: Arc<str> = Arc::from(text);
+    let delta = edit.insert.len() as isize - (edit_end - edit_start) as isize;
+    let shift = |pos: usize| (pos as isize + delta) as usize;
+
+    // 顶层条目的结束位置都是解析器回到顶层循环的位置
+    let root = old.syntax_node();
+    let elements: Vec<_> = root.children_with_tokens().collect();
+    let ends: Vec<usize> = elements
+        .iter()
+        .map(|e| usize::from(e.text_range().end()))
+        .collect();
+    // 窗口从行首开始，保证前一个 token 不会与窗口内的文本合并
+    let mut first = ends.iter().take_while(|&&end| end <= edit_start).count();
+    while first > 0 && !old_text[..ends[first - 1]].ends_with('\n') {
+        first -= 1;
+    }
+    let window_start = first.checked_sub(1).map_or(0, |i| ends[i]);
+    let Some(last) = (first..elements.len()).find(|&i| {
+        ends[i] >= edit_end
+            && (i + 1 == elements.len() || ends_with_blank_line(&source[..shift(ends[i])]))
+    }) else {
+        return parse_source_with_options(source, old.options);
+    };
+    let window_end = ends[last];
+    let new_window_end = shift(window_end);
+
+    // 元数据只在文件头部识别，头部的编辑可能改变其后各行的词法
+    let in_header = old
+        .tokens
+        .iter()
+        .take_while(|t| usize::from(t.range.end()) <= window_start)
+        .all(|t| t.kind.is_trivia() || t.kind.is_newline() || t.kind.is_meta_field());
+    let crosses_directive = old
+        .tokens
+        .iter()
+        .any(|t| usize::from(t.range.end()) > window_start && is_directive(t.kind));
+    // 窗口之前未闭合的 `#if` 会把窗口内的内容也收入条件段
+    let open_ifs = old
+        .tokens
+        .iter()
+        .take_while(|t| usize::from(t.range.end()) <= window_start)
+        .fold(0usize, |depth, t| match t.kind {
+            SyntaxKind::IfDirective => depth + 1,
+            SyntaxKind::EndifDirective => depth.saturating_sub(1),
+            _ => depth,
+        });
+    let unterminated_if = old
+        .errors
+        .iter()
+        .any(|e| e.message.starts_with("Unterminated #if"));
+    if in_header
+        || crosses_directive
+        || open_ifs > 0
+        || unterminated_if
+        || !ends_on_token_boundary(&source[window_start..], new_window_end - window_start)
+    {
+        return parse_source_with_options(source, old.options);
+    }
+    let fragment = parse_source_with_options(
+        Arc::from(&source[window_start..new_window_end]),
+        old.options,
+    );
+    if fragment.tokens.iter().any(|t| is_directive(t.kind)) {
+        return parse_source_with_options(source, old.options);
+    }
+
+    let offset = TextSize::from(window_start as u32);
+    let moved = |range: TextRange| {
+        TextRange::new(
+            TextSize::from(shift(usize::from(range.start())) as u32),
+            TextSize::from(shift(usize::from(range.end())) as u32),
+        )
+    };
+    let reused = |e: &SyntaxElementRef| match e {
+        NodeOrToken::Node(n) => NodeOrToken::Node(n.green().into_owned()),
+        NodeOrToken::Token(t) => NodeOrToken::Token(t.green().to_owned()),
+    };
+    let children: Vec<_> = elements[..first]
+        .iter()
+        .map(reused)
+        .chain(fragment.green_node.children().map(|c| c.to_owned()))
+        .chain(elements[last + 1..].iter().map(reused))
+        .collect();
+    let green_node = GreenNode::new(old.green_node.kind(), children);
+
+    let errors = old
+        .errors
+        .iter()
+        .filter(|e| usize::from(e.range.end()) <= window_start)
+        .cloned()
+        .chain(fragment.errors.into_iter().map(|e| ParseError {
+            range: e.range + offset,
+            ..e
+        }))
+        .chain(
+            old.errors
+                .iter()
+                .filter(|e| usize::from(e.range.start()) >= window_end)
+                .map(|e| ParseError::new(e.message.clone(), moved(e.range))),
+        )
+        .collect();
+    let tokens = old
+        .tokens
+        .iter()
+        .take_while(|t| usize::from(t.range.end()) <= window_start)
+        .map(|t| Token {
+            source: source.clone(),
+            ..t.clone()
+        })
+        .chain(fragment.tokens.into_iter().map(|t| Token {
+            kind: t.kind,
+            source: source.clone(),
+            range: t.range + offset,
+        }))
+        .chain(
+            old.tokens
+                .iter()
+                .skip_while(|t| usize::from(t.range.start()) < window_end)
+                .map(|t| Token {
+                    kind: t.kind,
+                    source: source.clone(),
+                    range: moved(t.range),
+                }),
+        )
+        .collect();
+
+    Parse {
+        green_node,
+        errors,
+        tokens,
+        options: old.options,
+    }
+}
+
+/// 元数据与条件段会影响其后所有内容的解析，无法局部重新解析。
+fn is_directive(kind: SyntaxKind) -> bool {
+    kind.is_meta_field() || kind.is_if_directive() || kind.is_endif_directive()
+}
+
+/// 文本是否以空行结尾（最后一个换行前的一行只含空白，且不是续行）。
+fn ends_with_blank_line(text: &str) -> bool {
+    let Some(rest) = text.strip_suffix('\n') else {
+        return false;
+    };
+    let rest = rest.strip_suffix('\r').unwrap_or(rest);
+    rest.trim_end_matches([' ', '\t'])
+        .strip_suffix('\n')
+        .is_some_and(|rest| {
+            !rest
+                .strip_suffix('\r')
+                .unwrap_or(rest)
+                .trim_end_matches([' ', '\t'])
+                .ends_with('\\')
+        })
+}
+
+/// 从 `text` 开头词法分析时，`end` 处是否恰好是 token 边界（块注释可能跨过它）。
+fn ends_on_token_boundary(text: &str, end: usize) -> bool {
+    let mut lexer = SyntaxKind::lexer(text);
+    while lexer.next().is_some() {
+        let span_end = lexer.span().end;
+        if span_end >= end {
+            return span_end == end;
+        }
+    }
+    end == 0
 }
 
 /// 事件驱动解析器：先记录事件，再由 `Sink` 构建绿色树。
@@ -111,6 +396,7 @@ pub struct Parser {
     pub(crate) raw_cursor: usize,
     pub(crate) events: Vec<Event>,
     pub(crate) errors: Vec<ParseError>,
+    pub(crate) options: ParseOptions,
 }
 
 impl Parser {
@@ -129,9 +415,15 @@ impl Parser {
             raw_cursor: 0,
             events: Vec::new(),
             errors: Vec::new(),
+            options: ParseOptions::default(),
         }
     }
 
+    /// 当前解析使用的选项。
+    pub fn options(&self) -> &ParseOptions {
+        &self.options
+    }
+
     /// 开始一个新的语法节点，返回 `Marker` 以便稍后完成。
     pub fn start_node(&mut self) -> Marker {
         let pos = self.events.len();
@@ -258,7 +550,7 @@ impl Parser {
     /// 结束解析：刷新剩余 token，构建绿色树，并汇总错误。
     fn finish(mut self, mut external_errors: Vec<ParseError>) -> Parse {
         self.flush_remaining_tokens();
-        external_errors.extend(self.errors.into_iter());
+        external_errors.extend(self.errors);
         let sink = Sink::new(self.tokens.clone(), self.events);
         let green = sink.finish();
 
@@ -266,6 +558,7 @@ impl Parser {
             tokens: self.tokens,
             green_node: green,
             errors: external_errors,
+            options: self.options,
         }
     }
 }
@@ -307,13 +600,11 @@ impl Drop for DropBomb {
 
 /// 对源文本进行词法分析，返回 Token 列表和词法错误列表。
 fn tokenize(source: Arc<str>) -> (Vec<Token>, Vec<ParseError>) {
-    let mut lexer = SyntaxKind::lexer(source.as_ref());
     let mut tokens = Vec::new();
     let mut errors = Vec::new();
 
-    while let Some(tok) = lexer.next() {
+    for (tok, span) in spanned(source.as_ref()) {
         if let Ok(kind) = tok {
-            let span = lexer.span();
             tokens.push(Token {
                 kind,
                 source: source.clone(),
@@ -321,7 +612,6 @@ fn tokenize(source: Arc<str>) -> (Vec<Token>, Vec<ParseError>) {
             });
         } else {
             // 词法错误
-            let span = lexer.span();
             let text_slice = &source[span.clone()];
             let text_range = to_text_range(span.clone());
             let message = format!("Unrecognized token: {:?}", text_slice);
@@ -351,12 +641,92 @@ fn to_text_size(value: usize) -> TextSize {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::rowan::parse_fn::parse_source;
 
     #[test]
     fn builds_empty_root() {
         let parse = parse(Arc::from("   "), |_| {});
         assert!(parse.errors().is_empty());
-        let root_kind: SyntaxKind = parse.syntax_node().kind().into();
+        let root_kind: SyntaxKind = parse.syntax_node().kind();
         assert_eq!(root_kind, SyntaxKind::NODE_ROOT);
     }
+
+    fn assert_same_parse(old: &Parse, new: &str) -> Parse {
+        let old_text = old.syntax_node().text().to_string();
+        let reparsed = reparse(old, TextEdit::between(&old_text, new));
+        let full = parse_source(Arc::from(new));
+        assert_eq!(
+            reparsed.green_node, full.green_node,
+            "{:?} -> {:?}",
+            old_text, new
+        );
+        // lexing errors come first in a full parse, so only the set of errors is compared
+        let errors = |p: &Parse| {
+            let mut errors: Vec<_> = p.errors.iter().map(|e| format!("{:?}", e)).collect();
+            errors.sort();
+            errors
+        };
+        assert_eq!(errors(&reparsed), errors(&full));
+        let spans = |p: &Parse| -> Vec<_> { p.tokens.iter().map(|t| (t.kind, t.range)).collect() };
+        assert_eq!(spans(&reparsed), spans(&full));
+        reparsed
+    }
+
+    #[test]
+    fn reparse_matches_full_parse() {
+        let cases = [
+            ("C4,D4,\n\nE4,F4,\n\nG4,\n", "C4,D4,\n\nA4,F4:,\n\nG4,\n"),
+            ("m =\nC4,\nD4,\n\nm,\n", "m =\nC4,\nD4,\nm,\n"),
+            (
+                "C4,\n\nD4,\n\nE4, */ F4,\n",
+                "C4,\n\nD4, /*\n\nE4, */ F4,\n",
+            ),
+            (
+                "C4,\n\nD4,\n\nE4,\n#endif\n",
+                "C4,\n\n#if a\nD4,\n\nE4,\n#endif\n",
+            ),
+            ("title: x\n\nC4,\n", "title: y\n\nC4,\n"),
+            ("C4,\n\ntitle: x\n", "\n\ntitle: x\n"),
+            ("#if a\nC4,\n// x", "#if a\nC4,\n// x\nD4,\n"),
+            ("  C4,\n\nD4,\n", "   C4,\n\nD4,\n"),
+            ("C4,\n\nD4,\n", "C4,\n\nD4,\n\n"),
+            ("C4,\n\nD4,\n", ""),
+        ];
+        for (old, new) in cases {
+            assert_same_parse(&parse_source(Arc::from(old)), new);
+        }
+    }
+
+    #[test]
+    fn reparse_reuses_untouched_green_subtrees() {
+        let old = parse_source(Arc::from("C4,D4,\n\nE4,F4,\n\nG4,\n"));
+        let new = assert_same_parse(&old, "C4,D4,\n\nA4,F4,\n\nG4,\n");
+        let line = |p: &Parse, n: usize| {
+            p.syntax_node()
+                .children()
+                .filter(|c| c.kind().is_node_normal_line())
+                .nth(n)
+                .expect("normal line")
+                .green()
+                .into_owned()
+        };
+        assert!(std::ptr::eq(&*line(&old, 0), &*line(&new, 0)));
+        assert!(std::ptr::eq(&*line(&old, 2), &*line(&new, 2)));
+        assert!(!std::ptr::eq(&*line(&old, 1), &*line(&new, 1)));
+    }
+
+    #[test]
+    fn serializes_tree_to_json() {
+        fn texts(value: &serde_json::Value, out: &mut String) {
+            match value["children"].as_array() {
+                Some(children) => children.iter().for_each(|c| texts(c, out)),
+                None => out.push_str(value["text"].as_str().unwrap()),
+            }
+        }
+        let json = parse_source(Arc::from("C4,\n")).to_json();
+        assert_eq!(json["children"][0]["kind"], "NODE_NORMAL_LINE");
+        let mut text = String::new();
+        texts(&json, &mut text);
+        assert_eq!(text, "C4,\n");
+    }
 }
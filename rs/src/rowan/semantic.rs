@@ -0,0 +1,212 @@
+//! 基于具体语法树的语义 Token 分类，供编辑器做比词法种类更准确的高亮。
+//!
+//! 同一种 Token 在不同位置含义不同：`PitchFrequency` 在 `(120)` 中是速度，
+//! `Identifier` 在 `m = C4` 左侧是宏定义、在音符中是宏调用。
+
+use rowan::TextRange;
+
+use crate::rowan::{
+    lexer::SyntaxKind,
+    parser::{SyntaxNode, SyntaxToken},
+};
+
+/// Token 的语义角色。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, strum::IntoStaticStr)]
+pub enum SemanticRole {
+    /// 注释与续行符
+    Comment,
+    /// 分隔符与括号
+    Punctuation,
+    /// 宏、调律或命名空间的名称
+    MacroName,
+    /// 基准音定义 `<D4=293.6>` 中的音高与名称
+    BasePitch,
+    /// 音名、频率、等分律、音分、下泛音与纯律和弦
+    Pitch,
+    /// 音高链中的频率比（如 `3/2`）
+    PitchRatio,
+    /// 休止、延音、连音与整小节休止/重复
+    Rest,
+    /// 时值标记与重复次数
+    Duration,
+    /// 量化设置 `{8}`
+    Quantize,
+    /// 速度设置 `(120)`、`([8]=90)` 中的数值
+    Tempo,
+    /// 拍号设置 `(3/4)` 中的拍号
+    TimeSignature,
+    /// 声部前缀、移调、调律、段落标记、条件编译等指令
+    Directive,
+    /// 装饰音、延长记号、滑音、弯音与力度后缀
+    Articulation,
+    /// 歌词
+    Lyric,
+    /// 文件头元数据
+    Metadata,
+    /// 无法识别的文本与解析失败的区域
+    Error,
+}
+
+/// 名称类 Token 的修饰：定义处还是引用处。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, strum::IntoStaticStr)]
+pub enum SemanticModifier {
+    Definition,
+    Reference,
+}
+
+/// 一个分类后的 Token。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SemanticToken {
+    pub range: TextRange,
+    pub kind: SyntaxKind,
+    pub role: SemanticRole,
+    pub modifier: Option<SemanticModifier>,
+}
+
+/// 按源码顺序分类语法树中的所有 Token，跳过空白与换行。
+pub fn semantic_tokens(root: &SyntaxNode) -> Vec<SemanticToken> {
+    root.descendants_with_tokens()
+        .filter_map(|element| element.into_token())
+        .filter(|token| !matches!(token.kind(), SyntaxKind::Whitespace | SyntaxKind::Newline))
+        .map(|token| {
+            let (role, modifier) = classify(&token);
+            SemanticToken {
+                range: token.text_range(),
+                kind: token.kind(),
+                role,
+                modifier,
+            }
+        })
+        .collect()
+}
+
+fn classify(token: &SyntaxToken) -> (SemanticRole, Option<SemanticModifier>) {
+    let kind: SyntaxKind = token.kind();
+    let parent = token.parent().map(|p| p.kind());
+    let in_base_def = token
+        .parent_ancestors()
+        .any(|n| n.kind() == SyntaxKind::NODE_BASE_PITCH_DEF);
+    match kind {
+        SyntaxKind::Identifier => match parent {
+            Some(
+                SyntaxKind::NODE_MACRODEF_ALIAS
+                | SyntaxKind::NODE_MACRODEF_SIMPLE
+                | SyntaxKind::NODE_MACRODEF_COMPLEX
+                | SyntaxKind::NODE_MACRODEF_TUNING,
+            ) => (SemanticRole::MacroName, Some(SemanticModifier::Definition)),
+            // `<root=C4>` 定义命名基准
+            Some(SyntaxKind::NODE_BASE_PITCH_DEF) => {
+                (SemanticRole::BasePitch, Some(SemanticModifier::Definition))
+            }
+            // `(key A minor)` 的调式
+            Some(
+                SyntaxKind::NODE_KEY_DEF | SyntaxKind::NODE_LOOP_DEF | SyntaxKind::NODE_PEDAL_DEF,
+            ) => (SemanticRole::Directive, None),
+            _ => (SemanticRole::MacroName, Some(SemanticModifier::Reference)),
+        },
+        _ if in_base_def && (kind.is_pitch() || kind == SyntaxKind::Plus) => {
+            (SemanticRole::BasePitch, None)
+        }
+        SyntaxKind::PitchFrequency | SyntaxKind::DurationFraction
+            if parent == Some(SyntaxKind::NODE_BPM_DEF) =>
+        {
+            (SemanticRole::Tempo, None)
+        }
+        SyntaxKind::PitchRatio | SyntaxKind::PitchFrequency | SyntaxKind::Plus
+            if parent == Some(SyntaxKind::NODE_TIME_SIGNATURE_DEF) =>
+        {
+            (SemanticRole::TimeSignature, None)
+        }
+        SyntaxKind::PitchSpellSimple if parent == Some(SyntaxKind::NODE_KEY_DEF) => {
+            (SemanticRole::Directive, None)
+        }
+        SyntaxKind::PitchRatio => (SemanticRole::PitchRatio, None),
+        _ if kind.is_pitch() => (SemanticRole::Pitch, None),
+        SyntaxKind::JiChord => (SemanticRole::Pitch, None),
+        _ if kind.is_formal_pitch() => (SemanticRole::Rest, None),
+        SyntaxKind::MultiBarRest | SyntaxKind::BarRepeat => (SemanticRole::Rest, None),
+        SyntaxKind::DurationCommas | SyntaxKind::DurationFraction | SyntaxKind::RepeatCount => {
+            (SemanticRole::Duration, None)
+        }
+        SyntaxKind::Quantize | SyntaxKind::QuantizeOpen => (SemanticRole::Quantize, None),
+        SyntaxKind::Ornament
+        | SyntaxKind::Fermata
+        | SyntaxKind::Portamento
+        | SyntaxKind::BendEnvelope
+        | SyntaxKind::Volume => (SemanticRole::Articulation, None),
+        SyntaxKind::VoicePrefix
+        | SyntaxKind::PercussionPrefix
+        | SyntaxKind::OctaveMode
+        | SyntaxKind::TransposeOpen
+        | SyntaxKind::CapoOpen
+        | SyntaxKind::InstrumentOpen
+        | SyntaxKind::SectionOpen
+        | SyntaxKind::CueOpen
+        | SyntaxKind::LoopOpen
+        | SyntaxKind::KeyOpen
+        | SyntaxKind::VolumeOpen
+        | SyntaxKind::PanOpen
+        | SyntaxKind::PedalOpen
+        | SyntaxKind::PickupOpen
+        | SyntaxKind::AtOpen
+        | SyntaxKind::TuningOpen
+        | SyntaxKind::TuningSpec
+        | SyntaxKind::TimeSeconds
+        | SyntaxKind::IfDirective
+        | SyntaxKind::EndifDirective => (SemanticRole::Directive, None),
+        SyntaxKind::Lyric
+            if matches!(
+                parent,
+                Some(SyntaxKind::NODE_SECTION_DEF | SyntaxKind::NODE_CUE_DEF)
+            ) =>
+        {
+            (SemanticRole::Directive, None)
+        }
+        SyntaxKind::Lyric => (SemanticRole::Lyric, None),
+        SyntaxKind::MetaField => (SemanticRole::Metadata, None),
+        SyntaxKind::Comment | SyntaxKind::LineContinuation => (SemanticRole::Comment, None),
+        SyntaxKind::Error => (SemanticRole::Error, None),
+        _ if parent == Some(SyntaxKind::NODE_ERROR) => (SemanticRole::Error, None),
+        _ => (SemanticRole::Punctuation, None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::rowan::parse_fn::parse_source;
+
+    #[test]
+    fn tokens_are_classified_by_their_place_in_the_tree() {
+        let source = "m = C4:E4\n<D4=293.6>\n(120)\n(3/4)\n{8}\nm,3/2@C4[4],.,\n";
+        let root = parse_source(Arc::from(source)).syntax_node();
+        let roles: Vec<(&str, SemanticRole, Option<SemanticModifier>)> = semantic_tokens(&root)
+            .into_iter()
+            .map(|t| (&source[t.range], t.role, t.modifier))
+            .collect();
+        use SemanticModifier::*;
+        use SemanticRole::*;
+        let find = |text: &str, nth: usize| {
+            roles
+                .iter()
+                .filter(|(t, ..)| *t == text)
+                .nth(nth)
+                .map(|&(_, role, modifier)| (role, modifier))
+        };
+        assert_eq!(find("m", 0), Some((MacroName, Some(Definition))));
+        assert_eq!(find("m", 1), Some((MacroName, Some(Reference))));
+        assert_eq!(find("C4", 0), Some((Pitch, None)));
+        assert_eq!(find("D4", 0), Some((BasePitch, None)));
+        assert_eq!(find("293.6", 0), Some((BasePitch, None)));
+        assert_eq!(find("120", 0), Some((Tempo, None)));
+        assert_eq!(find("3/4", 0), Some((TimeSignature, None)));
+        assert_eq!(find("{8}", 0), Some((Quantize, None)));
+        assert_eq!(find("3/2", 0), Some((PitchRatio, None)));
+        assert_eq!(find("[4]", 0), Some((Duration, None)));
+        assert_eq!(find(".", 0), Some((Rest, None)));
+        assert_eq!(find("@", 0), Some((Punctuation, None)));
+        assert!(roles.iter().all(|(t, ..)| !t.trim().is_empty()));
+    }
+}